@@ -0,0 +1,263 @@
+use windows_sys::Win32::System::Memory::{
+    MEM_COMMIT, MEM_FREE, MEM_IMAGE, MEM_MAPPED, MEM_PRIVATE, MEM_RESERVE, PAGE_EXECUTE, PAGE_EXECUTE_READ,
+    PAGE_EXECUTE_READWRITE, PAGE_EXECUTE_WRITECOPY, PAGE_GUARD, PAGE_NOACCESS, PAGE_NOCACHE, PAGE_READONLY,
+    PAGE_READWRITE, PAGE_WRITECOMBINE, PAGE_WRITECOPY,
+};
+
+use crate::memory;
+use crate::process::Process;
+use crate::target::{DebugTarget, MemoryRegionInfo};
+
+// Offsets into the x64 TEB and PEB
+const TEB_STACK_BASE: u64 = 0x8;
+const TEB_STACK_LIMIT: u64 = 0x10;
+const TEB_PEB: u64 = 0x60;
+const PEB_NUMBER_OF_HEAPS: u64 = 0xE8;
+const PEB_PROCESS_HEAPS: u64 = 0xF0;
+// In case the PEB has been overwritten with garbage
+const MAX_HEAPS: u32 = 0x100;
+
+// The things that own memory which the target can't tell us about itself
+struct Owners {
+    // The thread, and the lowest and highest addresses that are known to be in its stack
+    stacks: Vec<(u32, u64, u64)>,
+    heaps: Vec<u64>,
+}
+
+fn find_owners(target: &dyn DebugTarget, process: &Process) -> Owners {
+    let memory_source = target.memory_source();
+    let mut owners = Owners { stacks: Vec::new(), heaps: Vec::new() };
+    for thread_id in process.iterate_threads() {
+        // Windows threads have the bounds of their stack in the TEB. Everywhere else, all we know is that the stack
+        // pointer is in it.
+        let teb = target.thread_environment_block(*thread_id);
+        let teb_stack = teb.and_then(|teb| {
+            let base = memory::read_memory_data::<u64>(memory_source, teb + TEB_STACK_BASE).ok()?;
+            let limit = memory::read_memory_data::<u64>(memory_source, teb + TEB_STACK_LIMIT).ok()?;
+            (limit < base).then_some((limit, base))
+        });
+        match teb_stack {
+            Some((limit, base)) => owners.stacks.push((*thread_id, limit, base)),
+            None => {
+                if let Ok(context) = target.get_thread_context(*thread_id) {
+                    owners.stacks.push((*thread_id, context.rsp, context.rsp + 1));
+                }
+            }
+        }
+
+        // Every thread has the same PEB, so the heaps only need to be read once
+        if let (Some(teb), true) = (teb, owners.heaps.is_empty()) {
+            if let Ok(peb) = memory::read_memory_data::<u64>(memory_source, teb + TEB_PEB) {
+                let count = memory::read_memory_data::<u32>(memory_source, peb + PEB_NUMBER_OF_HEAPS).unwrap_or(0);
+                if let Ok(heaps) = memory::read_memory_data::<u64>(memory_source, peb + PEB_PROCESS_HEAPS) {
+                    owners.heaps = memory::read_memory_array::<u64>(memory_source, heaps, std::cmp::min(count, MAX_HEAPS) as usize).unwrap_or_default();
+                }
+            }
+        }
+    }
+    owners
+}
+
+fn containing_region(regions: &[MemoryRegionInfo], address: u64) -> Option<&MemoryRegionInfo> {
+    regions.iter().find(|r| address >= r.address && address - r.address < r.size)
+}
+
+// Works out what the region is for, like WinDbg's Usage column
+fn region_usage(region: &MemoryRegionInfo, regions: &[MemoryRegionInfo], process: &Process, owners: &Owners) -> String {
+    if region.state == MEM_FREE {
+        return "Free".to_string();
+    }
+    if let Some(module) = process.get_containing_module(region.address) {
+        return format!("Image {}", module.name);
+    }
+
+    // The reserved part of a stack is in the same allocation as the part that's in use. The region can go right up to
+    // the end of the address space, so its end address isn't worked out.
+    for (thread_id, low, high) in owners.stacks.iter() {
+        let overlaps = (*low < region.address || *low - region.address < region.size) && *high > region.address;
+        let same_allocation = region.allocation_base != 0 && containing_region(regions, *low).is_some_and(|r| r.allocation_base == region.allocation_base);
+        if overlaps || same_allocation {
+            return format!("Stack [{:x}]", thread_id);
+        }
+    }
+
+    if owners.heaps.contains(&region.allocation_base) || region.name.as_deref() == Some("[heap]") {
+        return "Heap".to_string();
+    }
+    match (&region.name, region.region_type) {
+        // Linux has names like [vdso] for the special mappings
+        (Some(name), _) if name.starts_with('[') => name.clone(),
+        (Some(name), MEM_IMAGE) => format!("Image {}", name),
+        (Some(name), _) => format!("MappedFile {}", name),
+        (None, MEM_MAPPED) => "MappedFile".to_string(),
+        (None, MEM_IMAGE) => "Image".to_string(),
+        (None, MEM_PRIVATE) => "Private".to_string(),
+        _ => "<unknown>".to_string(),
+    }
+}
+
+fn state_name(state: u32) -> &'static str {
+    match state {
+        MEM_COMMIT => "MEM_COMMIT",
+        MEM_RESERVE => "MEM_RESERVE",
+        MEM_FREE => "MEM_FREE",
+        _ => "",
+    }
+}
+
+fn type_name(region_type: u32) -> &'static str {
+    match region_type {
+        MEM_IMAGE => "MEM_IMAGE",
+        MEM_MAPPED => "MEM_MAPPED",
+        MEM_PRIVATE => "MEM_PRIVATE",
+        _ => "",
+    }
+}
+
+fn protect_name(protect: u32) -> String {
+    let base = match protect & 0xFF {
+        0 => return String::new(),
+        PAGE_NOACCESS => "PAGE_NOACCESS",
+        PAGE_READONLY => "PAGE_READONLY",
+        PAGE_READWRITE => "PAGE_READWRITE",
+        PAGE_WRITECOPY => "PAGE_WRITECOPY",
+        PAGE_EXECUTE => "PAGE_EXECUTE",
+        PAGE_EXECUTE_READ => "PAGE_EXECUTE_READ",
+        PAGE_EXECUTE_READWRITE => "PAGE_EXECUTE_READWRITE",
+        PAGE_EXECUTE_WRITECOPY => "PAGE_EXECUTE_WRITECOPY",
+        _ => "PAGE_UNKNOWN",
+    };
+    let mut names = vec![base];
+    for (flag, name) in [(PAGE_GUARD, "PAGE_GUARD"), (PAGE_NOCACHE, "PAGE_NOCACHE"), (PAGE_WRITECOMBINE, "PAGE_WRITECOMBINE")] {
+        if protect & flag != 0 {
+            names.push(name);
+        }
+    }
+    names.join(" | ")
+}
+
+// The address after the region, which wraps around to 0 for a region at the very top of the address space
+fn end_address(region: &MemoryRegionInfo) -> u64 {
+    region.address.wrapping_add(region.size)
+}
+
+fn memory_map(target: &dyn DebugTarget) -> Option<Vec<MemoryRegionInfo>> {
+    match target.memory_map() {
+        Ok(mut regions) => {
+            regions.sort_by_key(|r| r.address);
+            Some(regions)
+        }
+        Err(e) => {
+            println!("Could not get the memory map: {}", e);
+            None
+        }
+    }
+}
+
+// Lists every region of the address space, like !address in WinDbg
+pub fn display_address_map(target: &dyn DebugTarget, process: &Process) {
+    let Some(regions) = memory_map(target) else {
+        return;
+    };
+    let owners = find_owners(target, process);
+    println!("{:<16} {:<16} {:<16} {:<12} {:<12} {:<22} Usage", "BaseAddress", "EndAddress+1", "RegionSize", "Type", "State", "Protect");
+    for region in regions.iter() {
+        println!("{:016X} {:016X} {:016X} {:<12} {:<12} {:<22} {}",
+            region.address,
+            end_address(region),
+            region.size,
+            type_name(region.region_type),
+            state_name(region.state),
+            protect_name(region.protect),
+            region_usage(region, &regions, process, &owners));
+    }
+}
+
+// Says which region an address is in and what it's used for, which is the quickest way to find out what a pointer is
+pub fn display_address_info(target: &dyn DebugTarget, process: &Process, address: u64) {
+    let Some(regions) = memory_map(target) else {
+        return;
+    };
+    let Some(region) = containing_region(&regions, address) else {
+        println!("{:016X} is not in any region that the target knows about, so it's probably free", address);
+        return;
+    };
+    let owners = find_owners(target, process);
+    println!("Usage:           {}", region_usage(region, &regions, process, &owners));
+    println!("Base Address:    {:016X}", region.address);
+    println!("End Address:     {:016X}", end_address(region));
+    println!("Region Size:     {:016X}", region.size);
+    println!("State:           {}", state_name(region.state));
+    println!("Protect:         {}", protect_name(region.protect));
+    println!("Type:            {}", type_name(region.region_type));
+    if region.allocation_base != 0 {
+        println!("Allocation Base: {:016X}", region.allocation_base);
+    }
+    if let Some(name) = &region.name {
+        println!("Name:            {}", name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THREAD_ID: u32 = 0x200;
+
+    fn region(address: u64, size: u64, allocation_base: u64, state: u32, region_type: u32, name: Option<&str>) -> MemoryRegionInfo {
+        MemoryRegionInfo { address, size, allocation_base, state, protect: PAGE_READWRITE, region_type, name: name.map(str::to_string) }
+    }
+
+    // A few regions with gaps between them, like a Linux memory map, and one at the very top of the address space
+    fn regions() -> Vec<MemoryRegionInfo> {
+        vec![
+            region(0x1000, 0x1000, 0x1000, MEM_COMMIT, MEM_PRIVATE, None),
+            // The reserved and committed parts of a stack
+            region(0x10000, 0x2000, 0x10000, MEM_RESERVE, MEM_PRIVATE, None),
+            region(0x12000, 0x1000, 0x10000, MEM_COMMIT, MEM_PRIVATE, None),
+            region(0x20000, 0x1000, 0x20000, MEM_COMMIT, MEM_PRIVATE, None),
+            region(0x30000, 0x1000, 0, MEM_COMMIT, 0, Some("[heap]")),
+            region(0x40000, 0x1000, 0, MEM_COMMIT, MEM_IMAGE, Some("/usr/lib/libc.so.6")),
+            region(0x50000, 0x1000, 0, MEM_COMMIT, 0, Some("[vdso]")),
+            region(0x60000, 0x1000, 0, MEM_COMMIT, MEM_MAPPED, Some("/tmp/data")),
+            region(0x70000, 0x1000, 0, MEM_COMMIT, MEM_MAPPED, None),
+            region(0x80000, 0x1000, 0, MEM_FREE, 0, None),
+            region(u64::MAX - 0xfff, 0x1000, 0, MEM_COMMIT, MEM_PRIVATE, None),
+        ]
+    }
+
+    fn usages(owners: &Owners) -> Vec<String> {
+        let regions = regions();
+        regions.iter().map(|r| region_usage(r, &regions, &Process::new(), owners)).collect()
+    }
+
+    #[test]
+    fn regions_are_classified_by_owner_and_name() {
+        let owners = Owners { stacks: vec![(THREAD_ID, 0x12800, 0x12801)], heaps: vec![0x20000] };
+        assert_eq!(usages(&owners), [
+            "Private", "Stack [200]", "Stack [200]", "Heap", "Heap", "Image /usr/lib/libc.so.6", "[vdso]",
+            "MappedFile /tmp/data", "MappedFile", "Free", "Private",
+        ]);
+    }
+
+    #[test]
+    fn stack_pointer_in_a_gap_owns_nothing() {
+        let regions = regions();
+        assert!(containing_region(&regions, 0x2000).is_none());
+        assert!(containing_region(&regions, 0xffff).is_none());
+        let owners = Owners { stacks: vec![(THREAD_ID, 0x2000, 0x2001)], heaps: Vec::new() };
+        assert!(!usages(&owners).iter().any(|u| u.starts_with("Stack")));
+    }
+
+    #[test]
+    fn last_region_can_end_at_end_of_address_space() {
+        let regions = regions();
+        let last = regions.last().unwrap();
+        assert_eq!(containing_region(&regions, u64::MAX).map(|r| r.address), Some(last.address));
+        assert!(containing_region(&regions, last.address - 1).is_none());
+        assert_eq!(end_address(last), 0);
+
+        let owners = Owners { stacks: vec![(THREAD_ID, u64::MAX - 8, u64::MAX)], heaps: Vec::new() };
+        assert_eq!(usages(&owners).last().unwrap(), "Stack [200]");
+    }
+}
@@ -1,18 +1,16 @@
-use windows_sys::Win32::System::Diagnostics::Debug::GetThreadContext;
-use windows_sys::Win32::System::Diagnostics::Debug::SetThreadContext;
-use windows_sys::Win32::System::Diagnostics::Debug::CONTEXT;
-use windows_sys::Win32::System::Threading::*;
-use windows_sys::Win32::Foundation::*;
+use std::collections::HashSet;
+
 use num_traits::int::PrimInt;
 
-use crate::memory::MemorySource;
 use crate::process::Process;
 use crate::name_resolution;
-use crate::util::*;
+use crate::registers::RegisterContext;
+use crate::target::DebugTarget;
 
 const DR7_LEN_BIT: [usize; 4] = [19, 23, 27, 31];
 const DR7_RW_BIT: [usize; 4] = [17, 21, 25, 29];
 const DR7_LE_BIT: [usize; 4] = [0, 2, 4, 6];
+#[allow(dead_code)]
 const DR7_GE_BIT: [usize; 4] = [1, 3, 5, 7];
 
 const DR7_LEN_SIZE: usize = 2;
@@ -29,6 +27,8 @@ struct Breakpoint {
 
 pub struct BreakpointManager {
     breakpoints: Vec::<Breakpoint>,
+    // Every thread whose debug registers we've written, so they can all be cleaned up when we detach
+    touched_threads: HashSet<u32>,
 }
 
 fn set_bits<T: PrimInt>(val: &mut T, set_val: T, start_bit: usize, bit_count: usize) {
@@ -51,21 +51,27 @@ fn get_bit<T: PrimInt>(val: T, bit_index: usize) -> bool {
 impl BreakpointManager {
 
     pub fn new() -> BreakpointManager {
-        BreakpointManager { breakpoints: Vec::new() }
+        BreakpointManager { breakpoints: Vec::new(), touched_threads: HashSet::new() }
     }
 
-    fn get_free_id(&self) -> u32 {
-        for i in 0..4 {
-            if self.breakpoints.iter().find(|&x| x.id == i).is_none() {
-                return i;
-            }
-        }
-        panic!("Too many breakpoints!")
+    fn get_free_id(&self) -> Option<u32> {
+        (0..4).find(|i| !self.breakpoints.iter().any(|x| x.id == *i))
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u64) -> Result<u32, &'static str> {
+        let id = self.get_free_id().ok_or("Too many breakpoints")?;
+        self.breakpoints.push(Breakpoint{addr, id});
+        self.breakpoints.sort_by_key(|bp| bp.id);
+        Ok(id)
+    }
+
+    pub fn find_breakpoint(&self, addr: u64) -> Option<u32> {
+        self.breakpoints.iter().find(|x| x.addr == addr).map(|x| x.id)
     }
 
-    pub fn add_breakpoint(&mut self, addr: u64) {
-        self.breakpoints.push(Breakpoint{addr, id: self.get_free_id()});
-        self.breakpoints.sort_by(|a, b| a.id.cmp(&b.id));
+    // The ID and address of each breakpoint
+    pub fn iterate_breakpoints(&self) -> impl Iterator<Item = (u32, u64)> + '_ {
+        self.breakpoints.iter().map(|bp| (bp.id, bp.addr))
     }
 
     pub fn list_breakpoints(&self, process: &mut Process) {
@@ -82,66 +88,204 @@ impl BreakpointManager {
         self.breakpoints.retain(|x| x.id != id)
     }
 
-    pub fn was_breakpoint_hit(&self, thread_context: &CONTEXT) -> Option<u32> {
-        for idx in 0..self.breakpoints.len() {
-            if get_bit(thread_context.Dr6, DR6_B_BIT[idx]) {
-                return Some(idx as u32);
-            }
-        }
-        None
+    pub fn was_breakpoint_hit(&self, thread_context: &RegisterContext) -> Option<u32> {
+        self.breakpoints.iter().map(|bp| bp.id).find(|id| get_bit(thread_context.dr6, DR6_B_BIT[*id as usize]))
     }
 
-    pub fn apply_breakpoints(&mut self, process: &mut Process, resume_thread_id: u32, _memory_source: &dyn MemorySource) {
+    pub fn apply_breakpoints(&mut self, process: &mut Process, resume_thread_id: u32, target: &mut dyn DebugTarget) {
 
         for thread_id in process.iterate_threads() {
-            let mut ctx: AlignedContext = unsafe { std::mem::zeroed() };
-            ctx.context.ContextFlags = CONTEXT_ALL;            
-            let thread = AutoClosedHandle(unsafe {
-                OpenThread(
-                    THREAD_GET_CONTEXT | THREAD_SET_CONTEXT,
-                    FALSE,
-                    *thread_id,
-                )
-            });
-            let ret = unsafe { GetThreadContext(thread.handle(), &mut ctx.context) };
-
-            if ret == 0 {
-                println!("Could not get thread context of thread {:x}", thread_id);
-                continue;
-            }
+            let mut ctx = match target.get_thread_context(*thread_id) {
+                Ok(ctx) => ctx,
+                Err(_) => {
+                    println!("Could not get thread context of thread {:x}", thread_id);
+                    continue;
+                }
+            };
 
-            // Currently there is a limit of 4 breakpoints, since we are using hardware breakpoints.
+            // Currently there is a limit of 4 breakpoints, since we are using hardware breakpoints. Each one uses the
+            // debug register that matches its ID, so that a hit in DR6 can be traced back to the right breakpoint.
             for idx in 0..4 {
-                if self.breakpoints.len() > idx {
-                    
-                    set_bits(&mut ctx.context.Dr7, 0, DR7_LEN_BIT[idx], DR7_LEN_SIZE);
-                    set_bits(&mut ctx.context.Dr7, 0, DR7_RW_BIT[idx], DR7_RW_SIZE);
-                    set_bits(&mut ctx.context.Dr7, 1, DR7_LE_BIT[idx], 1);
+                if let Some(bp) = self.breakpoints.iter().find(|bp| bp.id as usize == idx) {
+                    set_bits(&mut ctx.dr7, 0, DR7_LEN_BIT[idx], DR7_LEN_SIZE);
+                    set_bits(&mut ctx.dr7, 0, DR7_RW_BIT[idx], DR7_RW_SIZE);
+                    set_bits(&mut ctx.dr7, 1, DR7_LE_BIT[idx], 1);
                     match idx {
-                        0 => ctx.context.Dr0 = self.breakpoints[idx].addr,
-                        1 => ctx.context.Dr1 = self.breakpoints[idx].addr,
-                        2 => ctx.context.Dr2 = self.breakpoints[idx].addr,
-                        3 => ctx.context.Dr3 = self.breakpoints[idx].addr,
+                        0 => ctx.dr0 = bp.addr,
+                        1 => ctx.dr1 = bp.addr,
+                        2 => ctx.dr2 = bp.addr,
+                        3 => ctx.dr3 = bp.addr,
                         _ => (),
                     }
                 } else {
                     // We'll assume that we own all breakpoints. This will cause problems with programs that expect to control their own debug registers.
                     // As a result, we'll disable any breakpoints that we aren't using.
-                    set_bits(&mut ctx.context.Dr7, 0, DR7_LE_BIT[idx], 1);
-                    break;
-                }    
+                    set_bits(&mut ctx.dr7, 0, DR7_LE_BIT[idx], 1);
+                }
             }
 
             // This prevents the current thread from hitting a breakpoint on the current instruction
             if *thread_id == resume_thread_id {
-                set_bits(&mut ctx.context.EFlags, 1, EFLAG_RF, 1);
+                set_bits(&mut ctx.eflags, 1, EFLAG_RF, 1);
             }
 
-            let ret = unsafe { SetThreadContext(thread.handle(), &mut ctx.context) };
-            if ret == 0 {
+            if target.set_thread_context(*thread_id, &ctx).is_err() {
                 println!("Could not set thread context of thread {:x}", thread_id);
+            } else {
+                self.touched_threads.insert(*thread_id);
             }
 
         }
     }
+
+    // Clears all of the breakpoints, and turns them off in every thread that is still running so that the target can
+    // carry on without us
+    pub fn remove_all_breakpoints(&mut self, process: &Process, target: &mut dyn DebugTarget) {
+        self.breakpoints.clear();
+        for thread_id in process.iterate_threads().filter(|t| self.touched_threads.contains(t)) {
+            let mut ctx = match target.get_thread_context(*thread_id) {
+                Ok(ctx) => ctx,
+                Err(_) => {
+                    println!("Could not get thread context of thread {:x}", thread_id);
+                    continue;
+                }
+            };
+            ctx.dr7 = 0;
+            if target.set_thread_context(*thread_id, &ctx).is_err() {
+                println!("Could not set thread context of thread {:x}", thread_id);
+            }
+        }
+        self.touched_threads.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use windows_sys::Win32::Foundation::{EXCEPTION_BREAKPOINT, EXCEPTION_SINGLE_STEP};
+
+    use super::*;
+    use crate::event::{DebugEvent, EventContext};
+    use crate::fake_target::{FakeTarget, THREAD_ID};
+    use crate::target::ContinueStatus;
+
+    const ENTRY_POINT: u64 = 0x1000;
+    const END_ADDRESS: u64 = 0x1100;
+
+    // Gets past the create process event and the initial breakpoint, like the main loop would
+    fn start() -> (FakeTarget, Process, EventContext) {
+        let mut target = FakeTarget::new(ENTRY_POINT, END_ADDRESS);
+        let mut process = Process::new();
+        let (ctx, event) = target.wait_for_event().unwrap();
+        assert!(matches!(event, DebugEvent::CreateProcess { .. }));
+        process.add_thread(ctx.thread_id);
+        let (ctx, event) = target.wait_for_event().unwrap();
+        assert!(matches!(event, DebugEvent::Exception { exception_code: EXCEPTION_BREAKPOINT, .. }));
+        (target, process, ctx)
+    }
+
+    fn resume(target: &mut FakeTarget, process: &mut Process, breakpoints: &mut BreakpointManager, ctx: &EventContext) -> DebugEvent {
+        breakpoints.apply_breakpoints(process, ctx.thread_id, target);
+        target.continue_event(ctx, ContinueStatus::Handled).unwrap();
+        target.wait_for_event().unwrap().1
+    }
+
+    #[test]
+    fn breakpoint_is_hit() {
+        let (mut target, mut process, ctx) = start();
+        let mut breakpoints = BreakpointManager::new();
+        breakpoints.add_breakpoint(0x1010).unwrap();
+        let id = breakpoints.add_breakpoint(0x1020).unwrap();
+
+        let event = resume(&mut target, &mut process, &mut breakpoints, &ctx);
+        assert!(matches!(event, DebugEvent::Exception { exception_code: EXCEPTION_SINGLE_STEP, .. }));
+        let context = target.get_thread_context(THREAD_ID).unwrap();
+        assert_eq!(context.rip, 0x1010);
+        assert_eq!(breakpoints.was_breakpoint_hit(&context), Some(0));
+
+        breakpoints.clear_breakpoint(0);
+        resume(&mut target, &mut process, &mut breakpoints, &ctx);
+        let context = target.get_thread_context(THREAD_ID).unwrap();
+        assert_eq!(context.rip, 0x1020);
+        assert_eq!(breakpoints.was_breakpoint_hit(&context), Some(id));
+    }
+
+    #[test]
+    fn resuming_does_not_hit_the_same_breakpoint() {
+        let (mut target, mut process, ctx) = start();
+        let mut breakpoints = BreakpointManager::new();
+        breakpoints.add_breakpoint(0x1010).unwrap();
+
+        resume(&mut target, &mut process, &mut breakpoints, &ctx);
+        assert_eq!(target.get_thread_context(THREAD_ID).unwrap().rip, 0x1010);
+
+        // The resume flag lets the thread carry on past the breakpoint it's sitting on
+        let event = resume(&mut target, &mut process, &mut breakpoints, &ctx);
+        assert!(matches!(event, DebugEvent::ExitProcess));
+    }
+
+    #[test]
+    fn breakpoint_at_the_current_instruction_is_skipped() {
+        let (mut target, mut process, ctx) = start();
+        let mut breakpoints = BreakpointManager::new();
+        breakpoints.add_breakpoint(ENTRY_POINT).unwrap();
+
+        let event = resume(&mut target, &mut process, &mut breakpoints, &ctx);
+        assert!(matches!(event, DebugEvent::ExitProcess));
+    }
+
+    #[test]
+    fn step_executes_one_instruction() {
+        let (mut target, mut process, ctx) = start();
+        let mut breakpoints = BreakpointManager::new();
+        breakpoints.add_breakpoint(0x1010).unwrap();
+
+        for expected_rip in ENTRY_POINT + 1..ENTRY_POINT + 4 {
+            target.step_thread(ctx.thread_id).unwrap();
+            let event = resume(&mut target, &mut process, &mut breakpoints, &ctx);
+            assert!(matches!(event, DebugEvent::Exception { exception_code: EXCEPTION_SINGLE_STEP, .. }));
+            let context = target.get_thread_context(THREAD_ID).unwrap();
+            assert_eq!(context.rip, expected_rip);
+            assert_eq!(breakpoints.was_breakpoint_hit(&context), None);
+        }
+
+        // Running normally after stepping still stops at the breakpoint
+        resume(&mut target, &mut process, &mut breakpoints, &ctx);
+        let context = target.get_thread_context(THREAD_ID).unwrap();
+        assert_eq!(context.rip, 0x1010);
+        assert_eq!(breakpoints.was_breakpoint_hit(&context), Some(0));
+    }
+
+    #[test]
+    fn continuing_after_a_step_onto_a_breakpoint_runs_past_it() {
+        let (mut target, mut process, ctx) = start();
+        let mut breakpoints = BreakpointManager::new();
+        breakpoints.add_breakpoint(ENTRY_POINT + 1).unwrap();
+
+        // Landing on the breakpoint is just the end of the step, the breakpoint itself hasn't been hit yet
+        target.step_thread(ctx.thread_id).unwrap();
+        let event = resume(&mut target, &mut process, &mut breakpoints, &ctx);
+        assert!(matches!(event, DebugEvent::Exception { exception_code: EXCEPTION_SINGLE_STEP, .. }));
+        let context = target.get_thread_context(THREAD_ID).unwrap();
+        assert_eq!(context.rip, ENTRY_POINT + 1);
+        assert_eq!(breakpoints.was_breakpoint_hit(&context), None);
+
+        // The breakpoint is checked before the next instruction runs, so continuing from here stops on it at once
+        // unless the resume flag is set, which apply_breakpoints does for the thread that's resuming
+        let event = resume(&mut target, &mut process, &mut breakpoints, &ctx);
+        assert!(matches!(event, DebugEvent::ExitProcess));
+    }
+
+    #[test]
+    fn removed_breakpoints_are_not_hit() {
+        let (mut target, mut process, ctx) = start();
+        let mut breakpoints = BreakpointManager::new();
+        breakpoints.add_breakpoint(0x1010).unwrap();
+        breakpoints.apply_breakpoints(&mut process, ctx.thread_id, &mut target);
+        assert_ne!(target.get_thread_context(THREAD_ID).unwrap().dr7, 0);
+
+        breakpoints.remove_all_breakpoints(&process, &mut target);
+        assert_eq!(target.get_thread_context(THREAD_ID).unwrap().dr7, 0);
+        target.continue_event(&ctx, ContinueStatus::Handled).unwrap();
+        assert!(matches!(target.wait_for_event().unwrap().1, DebugEvent::ExitProcess));
+    }
 }
@@ -0,0 +1,282 @@
+use anyhow::Result;
+use gimli::{BaseAddresses, CfaRule, DebugFrame, EhFrame, Register, RegisterRule, RunTimeEndian, UnwindContext, UnwindSection, X86_64};
+use object::{Object, ObjectSection};
+
+use crate::dwarf;
+use crate::memory::{read_memory_data, MemorySource};
+use crate::registers::RegisterContext;
+
+// The call frame information from an ELF image (or a PE image built by GCC), which says where to find the caller's
+// registers at each address in a function. This is what takes the place of the PE function table for unwinding. Unlike
+// DwarfInfo, we keep the section around and only parse the part we need for each frame.
+pub struct CallFrameInfo {
+    load_bias: u64,
+    data: Vec<u8>,
+    endian: RunTimeEndian,
+    // .eh_frame pointers can be relative to the section or .text, so it needs to know where those were linked
+    bases: BaseAddresses,
+    is_eh_frame: bool,
+}
+
+impl CallFrameInfo {
+    // Loads .eh_frame if the image has it, since that's always there for code that exceptions can unwind through, or
+    // .debug_frame otherwise. Returns None if the image has neither.
+    pub fn load(path: &str, module_address: u64) -> Result<Option<CallFrameInfo>> {
+        let data = std::fs::read(path)?;
+        let file = object::File::parse(&*data)?;
+        let load_bias = dwarf::load_bias(&file, module_address);
+        let endian = if file.is_little_endian() { RunTimeEndian::Little } else { RunTimeEndian::Big };
+        let text_address = file.section_by_name(".text").map_or(0, |s| s.address());
+
+        for (name, is_eh_frame) in [(".eh_frame", true), (".debug_frame", false)] {
+            if let Some(section) = file.section_by_name(name) {
+                let bases = match is_eh_frame {
+                    true => BaseAddresses::default().set_eh_frame(section.address()).set_text(text_address),
+                    false => BaseAddresses::default(),
+                };
+                let data = section.uncompressed_data()?.into_owned();
+                return Ok(Some(CallFrameInfo { load_bias, data, endian, bases, is_eh_frame }));
+            }
+        }
+        Ok(None)
+    }
+
+    // Works out the caller's registers from the registers of a frame in this module. Returns None if the address isn't
+    // covered by the call frame information, or if this is the outermost frame.
+    pub fn unwind(&self, context: &RegisterContext, memory_source: &dyn MemorySource) -> Result<Option<RegisterContext>, &'static str> {
+        let address = context.rip.wrapping_sub(self.load_bias);
+        let mut unwind_context = UnwindContext::new();
+        let row = match self.is_eh_frame {
+            true => {
+                let section = EhFrame::new(&self.data, self.endian);
+                section.unwind_info_for_address(&self.bases, &mut unwind_context, address, EhFrame::cie_from_offset)
+            }
+            false => {
+                let section = DebugFrame::new(&self.data, self.endian);
+                section.unwind_info_for_address(&self.bases, &mut unwind_context, address, DebugFrame::cie_from_offset)
+            }
+        };
+        let row = match row {
+            Ok(row) => row,
+            Err(gimli::Error::NoUnwindInfoForAddress) => return Ok(None),
+            Err(_) => return Err("Could not read call frame information"),
+        };
+
+        // The canonical frame address is the stack pointer from just before the call, and the saved registers are
+        // found relative to it
+        let cfa = match *row.cfa() {
+            CfaRule::RegisterAndOffset { register, offset } => {
+                register_value(context, register).ok_or("Unsupported register in call frame information")?.wrapping_add(offset as u64)
+            }
+            CfaRule::Expression(_) => return Err("NYI: CFA expressions"),
+        };
+
+        let mut caller = *context;
+        caller.rsp = cfa;
+        // Registers without a rule are assumed to be the same in the caller, which is true for the callee-saved ones
+        for number in 0..=X86_64::RA.0 {
+            let register = Register(number);
+            let value = match row.register(register) {
+                RegisterRule::Undefined if register == X86_64::RA => return Ok(None),
+                RegisterRule::Undefined | RegisterRule::SameValue => continue,
+                RegisterRule::Offset(offset) => read_memory_data::<u64>(memory_source, cfa.wrapping_add(offset as u64))?,
+                RegisterRule::ValOffset(offset) => cfa.wrapping_add(offset as u64),
+                RegisterRule::Register(other) => register_value(context, other).ok_or("Unsupported register in call frame information")?,
+                RegisterRule::Constant(value) => value,
+                _ => return Err("NYI: Register expressions"),
+            };
+            if let Some(caller_register) = register_mut(&mut caller, register) {
+                *caller_register = value;
+            }
+        }
+
+        // TODO: There are other conditions that should be checked
+        if caller.rip == 0 {
+            return Ok(None);
+        }
+        Ok(Some(caller))
+    }
+}
+
+// The DWARF register numbers for x64. The return address "register" is where rip comes from in the caller.
+fn register_mut(context: &mut RegisterContext, register: Register) -> Option<&mut u64> {
+    let value = match register.0 {
+        0 => &mut context.rax,
+        1 => &mut context.rdx,
+        2 => &mut context.rcx,
+        3 => &mut context.rbx,
+        4 => &mut context.rsi,
+        5 => &mut context.rdi,
+        6 => &mut context.rbp,
+        7 => &mut context.rsp,
+        8 => &mut context.r8,
+        9 => &mut context.r9,
+        10 => &mut context.r10,
+        11 => &mut context.r11,
+        12 => &mut context.r12,
+        13 => &mut context.r13,
+        14 => &mut context.r14,
+        15 => &mut context.r15,
+        16 => &mut context.rip,
+        _ => return None,
+    };
+    Some(value)
+}
+
+fn register_value(context: &RegisterContext, register: Register) -> Option<u64> {
+    let mut context = *context;
+    register_mut(&mut context, register).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::{Elf64_Ehdr, Elf64_Phdr, ELF_MAGIC, PT_LOAD};
+    use crate::fake_target::{temp_file_path, FakeMemory};
+    use crate::memory::{self, impl_pod, Pod};
+    use gimli::write::{Address, CallFrameInstruction, CommonInformationEntry, EndianVec, FrameDescriptionEntry, FrameTable};
+    use gimli::{Encoding, Format, LittleEndian};
+
+    const BASE_ADDRESS: u64 = 0x7000_0000;
+    // Where the function was linked, and how long it is
+    const FUNCTION: u64 = 0x1000;
+    const FUNCTION_SIZE: u32 = 0x20;
+    const STACK: u64 = 0x8000;
+    const EH_FRAME_OFFSET: usize = 0x100;
+
+    #[repr(C)]
+    #[derive(Default, Clone, Copy)]
+    #[allow(non_camel_case_types)]
+    struct Elf64_Shdr {
+        sh_name: u32,
+        sh_type: u32,
+        sh_flags: u64,
+        sh_addr: u64,
+        sh_offset: u64,
+        sh_size: u64,
+        sh_link: u32,
+        sh_info: u32,
+        sh_addralign: u64,
+        sh_entsize: u64,
+    }
+
+    impl_pod!(Elf64_Shdr {
+        sh_name: u32, sh_type: u32, sh_flags: u64, sh_addr: u64, sh_offset: u64, sh_size: u64, sh_link: u32,
+        sh_info: u32, sh_addralign: u64, sh_entsize: u64,
+    });
+
+    // The .eh_frame for a function that starts with "push rbp; mov rbp, rsp"
+    fn build_eh_frame() -> Vec<u8> {
+        let encoding = Encoding { format: Format::Dwarf32, version: 1, address_size: 8 };
+        let mut cie = CommonInformationEntry::new(encoding, 1, -8, X86_64::RA);
+        cie.add_instruction(CallFrameInstruction::Cfa(X86_64::RSP, 8));
+        cie.add_instruction(CallFrameInstruction::Offset(X86_64::RA, -8));
+        let mut fde = FrameDescriptionEntry::new(Address::Constant(FUNCTION), FUNCTION_SIZE);
+        fde.add_instruction(1, CallFrameInstruction::CfaOffset(16));
+        fde.add_instruction(1, CallFrameInstruction::Offset(X86_64::RBP, -16));
+        fde.add_instruction(4, CallFrameInstruction::CfaRegister(X86_64::RBP));
+
+        let mut table = FrameTable::default();
+        let cie_id = table.add_cie(cie);
+        table.add_fde(cie_id, fde);
+        let mut eh_frame = gimli::write::EhFrame(EndianVec::new(LittleEndian));
+        table.write_eh_frame(&mut eh_frame).unwrap();
+        eh_frame.0.into_vec()
+    }
+
+    // An image with one segment that holds everything, and just enough sections to find the .eh_frame
+    fn build_elf() -> Vec<u8> {
+        const SHT_PROGBITS: u32 = 1;
+        const SHT_STRTAB: u32 = 3;
+        let eh_frame = build_eh_frame();
+        let names = b"\0.eh_frame\0.shstrtab\0";
+        let names_offset = EH_FRAME_OFFSET + eh_frame.len();
+        let sections_offset = (names_offset + names.len()).next_multiple_of(8);
+        let size = (sections_offset + 3 * Elf64_Shdr::SIZE) as u64;
+
+        let mut e_ident = [0u8; 16];
+        e_ident[..4].copy_from_slice(ELF_MAGIC);
+        // 64-bit, little endian, version 1
+        e_ident[4..7].copy_from_slice(&[2, 1, 1]);
+        let header = Elf64_Ehdr {
+            e_ident, e_type: 3, e_machine: 62, e_version: 1, e_phoff: Elf64_Ehdr::SIZE as u64, e_shoff: sections_offset as u64,
+            e_ehsize: Elf64_Ehdr::SIZE as u16, e_phentsize: Elf64_Phdr::SIZE as u16, e_phnum: 1,
+            e_shentsize: Elf64_Shdr::SIZE as u16, e_shnum: 3, e_shstrndx: 2, ..Default::default()
+        };
+        let segment = Elf64_Phdr { p_type: PT_LOAD, p_flags: 5, p_filesz: size, p_memsz: size, p_align: 0x1000, ..Default::default() };
+
+        let mut data = memory::encode(&header);
+        segment.encode(&mut data);
+        data.resize(EH_FRAME_OFFSET, 0);
+        data.extend_from_slice(&eh_frame);
+        data.extend_from_slice(names);
+        data.resize(sections_offset, 0);
+        Elf64_Shdr::default().encode(&mut data);
+        Elf64_Shdr {
+            sh_name: 1, sh_type: SHT_PROGBITS, sh_flags: 2, sh_addr: EH_FRAME_OFFSET as u64, sh_offset: EH_FRAME_OFFSET as u64,
+            sh_size: eh_frame.len() as u64, sh_addralign: 8, ..Default::default()
+        }.encode(&mut data);
+        Elf64_Shdr {
+            sh_name: 11, sh_type: SHT_STRTAB, sh_offset: names_offset as u64, sh_size: names.len() as u64, sh_addralign: 1,
+            ..Default::default()
+        }.encode(&mut data);
+        data
+    }
+
+    fn load() -> CallFrameInfo {
+        let path = temp_file_path("cfi.so");
+        std::fs::write(&path, build_elf()).unwrap();
+        let info = CallFrameInfo::load(&path, BASE_ADDRESS);
+        std::fs::remove_file(&path).unwrap();
+        info.unwrap().unwrap()
+    }
+
+    fn context(rip: u64, rsp: u64, rbp: u64) -> RegisterContext {
+        RegisterContext { rip: BASE_ADDRESS + rip, rsp, rbp, rbx: 0x1234, ..Default::default() }
+    }
+
+    // The return address, then what the function will push
+    fn stack(rbp: u64, return_address: u64) -> FakeMemory {
+        let mut memory = FakeMemory::default();
+        memory.add_region(STACK - 8, &rbp.to_le_bytes());
+        memory.add_region(STACK, &return_address.to_le_bytes());
+        memory
+    }
+
+    #[test]
+    fn unwinds_from_function_entry() {
+        let info = load();
+        let memory = stack(0, 0x5000_1234);
+        let caller = info.unwind(&context(FUNCTION, STACK, 0x9999), &memory).unwrap().unwrap();
+        assert_eq!(caller.rip, 0x5000_1234);
+        assert_eq!(caller.rsp, STACK + 8);
+        assert_eq!(caller.rbp, 0x9999);
+        assert_eq!(caller.rbx, 0x1234);
+    }
+
+    #[test]
+    fn unwinds_through_frame_pointer() {
+        let info = load();
+        let memory = stack(0x9999, 0x5000_1234);
+        // After "mov rbp, rsp" the stack pointer can be anywhere, so everything comes from rbp
+        let caller = info.unwind(&context(FUNCTION + 0x10, 0x7000, STACK - 8), &memory).unwrap().unwrap();
+        assert_eq!(caller.rip, 0x5000_1234);
+        assert_eq!(caller.rsp, STACK + 8);
+        assert_eq!(caller.rbp, 0x9999);
+    }
+
+    #[test]
+    fn stops_without_unwind_info_or_return_address() {
+        let info = load();
+        let memory = stack(0, 0x5000_1234);
+        assert!(info.unwind(&context(FUNCTION + FUNCTION_SIZE as u64, STACK, 0), &memory).unwrap().is_none());
+        let memory = stack(0, 0);
+        assert!(info.unwind(&context(FUNCTION, STACK, 0), &memory).unwrap().is_none());
+    }
+
+    #[test]
+    fn unreadable_stack_is_an_error() {
+        let info = load();
+        assert!(info.unwind(&context(FUNCTION, STACK, 0), &FakeMemory::default()).is_err());
+    }
+}
@@ -1,5 +1,5 @@
+use std::collections::VecDeque;
 use std::io::Write;
-use regex::Regex;
 
 use codemap::CodeMap;
 use codemap_diagnostic::{ColorConfig, Diagnostic, Emitter, Level, SpanLabel, SpanStyle};
@@ -17,21 +17,64 @@ pub mod grammar {
         DisplaySpecificRegister(#[rust_sitter::leaf(text = "r")] (), #[rust_sitter::leaf(pattern = "([a-zA-Z]+)", transform = parse_sym)] String),
         DisplayRegisters(#[rust_sitter::leaf(text = "r")] ()),
         StackWalk(#[rust_sitter::leaf(text = "k")] ()),
-        DisplayBytes(#[rust_sitter::leaf(text = "db")] (), Box<EvalExpr>),
+        DisplayMemory(#[rust_sitter::leaf(pattern = r"(d[bwdqcpfDaus]?|dps|dqs)", transform = parse_sym)] String, DisplayRange),
+        DisplayMemoryContinue(#[rust_sitter::leaf(pattern = r"(d[bwdqcpfDaus]?|dps|dqs)", transform = parse_sym)] String),
+        EnterValues(#[rust_sitter::leaf(pattern = r"(e[bwdq])", transform = parse_element_size)] usize, Box<EvalExpr>, #[rust_sitter::repeat(non_empty = true)] Vec<EvalExpr>),
+        EnterString(#[rust_sitter::leaf(pattern = r"(e[au])", transform = parse_is_wide)] bool, Box<EvalExpr>, #[rust_sitter::leaf(pattern = r#"("[^"]*")"#, transform = parse_string)] String),
+        Fill(#[rust_sitter::leaf(text = "f")] (), RangeExpr, #[rust_sitter::repeat(non_empty = true)] Vec<EvalExpr>),
+        Move(#[rust_sitter::leaf(text = "m")] (), RangeExpr, Box<EvalExpr>),
+        SearchValues(#[rust_sitter::leaf(text = "s")] (), #[rust_sitter::leaf(pattern = r"(-[bwdq])", transform = parse_element_size)] usize, RangeExpr, #[rust_sitter::repeat(non_empty = true)] Vec<SearchValue>),
+        SearchString(#[rust_sitter::leaf(text = "s")] (), #[rust_sitter::leaf(pattern = r"(-[au])", transform = parse_is_wide)] bool, RangeExpr, #[rust_sitter::leaf(pattern = r#"("[^"]*")"#, transform = parse_string)] String),
         Evaluate(#[rust_sitter::leaf(text = "?")] (), Box<EvalExpr>),
         ListNearest(#[rust_sitter::leaf(text = "ln")] (), Box<EvalExpr>),
         Unassemble(#[rust_sitter::leaf(text = "u")] (), Box<EvalExpr>),
         UnassembleContinue(#[rust_sitter::leaf(text = "u")] ()),
         ListSource(#[rust_sitter::leaf(text = "lsa")] (), Box<EvalExpr>),
         SrcPath(#[rust_sitter::leaf(text = ".srcpath")] (), #[rust_sitter::leaf(pattern = "(.*)", transform = parse_path)] String),
+        ExamineSymbols(#[rust_sitter::leaf(text = "x")] (), #[rust_sitter::leaf(pattern = r"(\S+)", transform = parse_sym)] String),
+        DisplayFunctionEntry(#[rust_sitter::leaf(text = ".fnent")] (), Box<EvalExpr>),
+        WriteDump(#[rust_sitter::leaf(text = ".dump")] (), #[rust_sitter::leaf(pattern = "(.*)", transform = parse_path)] String),
+        Attach(#[rust_sitter::leaf(text = ".attach")] (), Box<EvalExpr>),
+        Detach(#[rust_sitter::leaf(text = ".detach")] ()),
+        RunScript(#[rust_sitter::leaf(text = "$<")] (), #[rust_sitter::leaf(pattern = "(.*)", transform = parse_path)] String),
+        LoadScript(#[rust_sitter::leaf(text = ".script")] (), #[rust_sitter::leaf(pattern = "(.*)", transform = parse_path)] String),
+        AddressMap(#[rust_sitter::leaf(text = "!address")] ()),
+        AddressInfo(#[rust_sitter::leaf(text = "!address")] (), Box<EvalExpr>),
+        ScriptCommand(#[rust_sitter::leaf(pattern = r"(![a-zA-Z_][a-zA-Z0-9_]*)", transform = parse_script_command)] String, #[rust_sitter::leaf(pattern = "(.+)", transform = parse_path)] Option<String>),
+        ListSnapshots(#[rust_sitter::leaf(text = ".snapshot")] ()),
+        TakeSnapshot(#[rust_sitter::leaf(text = ".snapshot")] (), #[rust_sitter::leaf(text = "-w")] Option<()>, #[rust_sitter::leaf(pattern = r"([a-zA-Z_][a-zA-Z0-9_]*)", transform = parse_path)] String, RangeExpr),
+        DeleteSnapshot(#[rust_sitter::leaf(text = ".snapshot")] (), #[rust_sitter::leaf(text = "-d")] (), #[rust_sitter::leaf(pattern = r"([a-zA-Z_][a-zA-Z0-9_]*)", transform = parse_path)] String),
+        DiffSnapshot(#[rust_sitter::leaf(text = ".diff")] (), #[rust_sitter::leaf(pattern = r"([a-zA-Z_][a-zA-Z0-9_]*)", transform = parse_path)] String),
+        ListProcesses(#[rust_sitter::leaf(text = "|")] ()),
+        SwitchProcess(#[rust_sitter::leaf(pattern = r"(\|\s*\d{1,9}\s*s)", transform = parse_process_index)] u32),
         Quit(#[rust_sitter::leaf(text = "q")] ()),
     }
 
+    // Ranges are either a start and an end address, which is included in the range, or a start address and a number
+    // of elements with L, like "rsp L8"
+    pub enum RangeExpr {
+        Count(Box<EvalExpr>, #[rust_sitter::leaf(pattern = r"([lL](\d+|0x[0-9a-fA-F]+))", transform = parse_count)] u64),
+        Length(Box<EvalExpr>, #[rust_sitter::leaf(pattern = "[lL]")] (), Box<EvalExpr>),
+        Span(Box<EvalExpr>, Box<EvalExpr>),
+    }
+
+    // The d commands show a default amount of memory when they're only given an address
+    pub enum DisplayRange {
+        Range(RangeExpr),
+        Address(Box<EvalExpr>),
+    }
+
+    // Each value in a search pattern is one element of the search size, and ? matches anything
+    pub enum SearchValue {
+        Value(Box<EvalExpr>),
+        Wildcard(#[rust_sitter::leaf(text = "?")] ()),
+    }
+
     #[rust_sitter::language]
     pub enum EvalExpr {
         Number(#[rust_sitter::leaf(pattern = r"(\d+|0x[0-9a-fA-F]+)", transform = parse_int)] u64),
         Symbol(#[rust_sitter::leaf(pattern = r"(([a-zA-Z0-9_@#.]+!)?[a-zA-Z0-9_@#.]+)", transform = parse_sym)] String),
-        SourceLine(#[rust_sitter::leaf(pattern = r"(`[^`!]+!(?:[a-zA-Z]+:)[^`:!]+:\d+`)", transform = parse_source_line)] (String, String, u32)),
+        SourceLine(#[rust_sitter::leaf(pattern = r"(`[^`!]+!(?:[a-zA-Z]+:)?[^`:!]+:\d+`)", transform = parse_source_line)] (String, String, u32)),
         #[rust_sitter::prec_left(1)]
         Add(
             Box<EvalExpr>,
@@ -41,6 +84,7 @@ pub mod grammar {
     }
 
     #[rust_sitter::extra]
+    #[allow(dead_code)]
     struct Whitespace {
         #[rust_sitter::leaf(pattern = r"\s")]
         _whitespace: (),
@@ -56,6 +100,10 @@ pub mod grammar {
         }
     }
 
+    fn parse_count(text: &str) -> u64 {
+        parse_int(&text.trim()[1..])
+    }
+
     fn parse_sym(text: &str) -> String {
         text.to_owned()
     }
@@ -64,8 +112,39 @@ pub mod grammar {
         text.trim().to_owned()
     }
 
+    // The last letter of commands like eb and dd is the size of each element, like WinDbg
+    fn parse_element_size(text: &str) -> usize {
+        match text.trim().chars().last() {
+            Some('b') => 1,
+            Some('w') => 2,
+            Some('d') => 4,
+            _ => 8,
+        }
+    }
+
+    // ea and eu are the same command for ASCII and Unicode strings
+    fn parse_is_wide(text: &str) -> bool {
+        text.trim().ends_with('u')
+    }
+
+    fn parse_string(text: &str) -> String {
+        text.trim().trim_matches('"').to_owned()
+    }
+
+    // Switching processes is |<index>s, where the index is the one that | shows. The pattern only allows up to 9
+    // digits, so anything too big for a u32 is a parse error instead of getting here.
+    fn parse_process_index(text: &str) -> u32 {
+        text.trim_matches(|c: char| c == '|' || c == 's' || c.is_whitespace()).parse().unwrap()
+    }
+
+    // Commands registered by scripts look like WinDbg extension commands, which is !name followed by its arguments.
+    // Built in extension commands like !address take priority.
+    fn parse_script_command(text: &str) -> String {
+        text.trim().trim_start_matches('!').to_owned()
+    }
+
     fn parse_source_line(text: &str) -> (String, String, u32) {
-        let re = regex::Regex::new(r"^`([^!]+)!((?:[a-zA-Z]+:)[^:]+):(\d+)`$").unwrap();
+        let re = regex::Regex::new(r"^`([^!]+)!((?:[a-zA-Z]+:)?[^:]+):(\d+)`$").unwrap();
         if let Some(captures) = re.captures(text) {
             let module_name = captures.get(1).unwrap().as_str().to_string();
             let file_name = captures.get(2).unwrap().as_str().to_string();
@@ -127,35 +206,209 @@ fn convert_parse_error_to_diagnostics(
     }
 }
 
-pub fn read_command() -> grammar::CommandExpr {
-    let stdin = std::io::stdin();
-    loop {
-        print!("> ");
-        std::io::stdout().flush().unwrap();
-        let mut input = String::new();
-        stdin.read_line(&mut input).unwrap();
-        let input = input.trim().to_string();
-        if !input.is_empty() {
-            let cmd = grammar::parse(&input);
-            match cmd {
-                Ok(c) => return c,
-                Err(errs) => {
-                    // This came from https://github.com/hydro-project/rust-sitter/blob/main/example/src/main.rs
-                    let mut codemap = CodeMap::new();
-                    let file_span = codemap.add_file("<input>".to_string(), input.to_string());
-                    let mut diagnostics = vec![];
-                    for error in errs {
-                        convert_parse_error_to_diagnostics(
-                            &file_span.span,
-                            &error,
-                            &mut diagnostics,
-                        );
-                    }
-
-                    let mut emitter = Emitter::stderr(ColorConfig::Always, Some(&codemap));
-                    emitter.emit(&diagnostics);
+fn parse_command(input: &str) -> Option<grammar::CommandExpr> {
+    match grammar::parse(input) {
+        Ok(c) => Some(c),
+        Err(errs) => {
+            // This came from https://github.com/hydro-project/rust-sitter/blob/main/example/src/main.rs
+            let mut codemap = CodeMap::new();
+            let file_span = codemap.add_file("<input>".to_string(), input.to_string());
+            let mut diagnostics = vec![];
+            for error in errs {
+                convert_parse_error_to_diagnostics(
+                    &file_span.span,
+                    &error,
+                    &mut diagnostics,
+                );
+            }
+
+            let mut emitter = Emitter::stderr(ColorConfig::Always, Some(&codemap));
+            emitter.emit(&diagnostics);
+            None
+        }
+    }
+}
+
+// Script files have one command on each line. Blank lines are skipped, and so are lines starting with * which are
+// comments like they are in WinDbg scripts.
+fn read_script_file(path: &str) -> Result<Vec<String>, &'static str> {
+    let text = std::fs::read_to_string(path).map_err(|_| "Could not read script file")?;
+    Ok(text.lines().map(|line| line.trim()).filter(|line| !line.is_empty() && !line.starts_with('*')).map(|line| line.to_string()).collect())
+}
+
+// Commands come from scripts first, and then from the console. Scripts given with -c and -cf run when the debugger
+// first breaks in, and $< runs a script file straight away.
+pub struct CommandReader {
+    pending: VecDeque<String>,
+    startup_commands: Vec<String>,
+    // In batch mode the startup commands run every time the debugger breaks in, and the console is never read
+    batch: bool,
+    started: bool,
+}
+
+impl CommandReader {
+    pub fn new() -> CommandReader {
+        CommandReader { pending: VecDeque::new(), startup_commands: Vec::new(), batch: false, started: false }
+    }
+
+    // Commands on the command line are separated by semicolons, so anything that needs a semicolon in it has to go
+    // in a script file instead
+    pub fn add_startup_commands(&mut self, commands: &str) {
+        self.startup_commands.extend(commands.split(';').map(|c| c.trim()).filter(|c| !c.is_empty()).map(|c| c.to_string()));
+    }
+
+    pub fn add_startup_script(&mut self, path: &str) -> Result<(), &'static str> {
+        self.startup_commands.extend(read_script_file(path)?);
+        Ok(())
+    }
+
+    pub fn set_batch(&mut self) {
+        self.batch = true;
+    }
+
+    pub fn has_script(&self) -> bool {
+        self.batch || !self.startup_commands.is_empty()
+    }
+
+    pub fn is_batch(&self) -> bool {
+        self.batch
+    }
+
+    // Runs the commands in a script file before anything else that is waiting to run
+    pub fn run_script(&mut self, path: &str) -> Result<(), &'static str> {
+        for command in read_script_file(path)?.into_iter().rev() {
+            self.pending.push_front(command);
+        }
+        Ok(())
+    }
+
+    // Called whenever the debugger breaks in for an exception or the end of the process. In batch mode the script
+    // starts over from the top, except when the target has exited since there's nothing left to look at.
+    pub fn break_in(&mut self, is_exit: bool) {
+        if self.batch && !is_exit {
+            self.pending = self.startup_commands.iter().cloned().collect();
+        } else if !self.started {
+            self.pending.extend(self.startup_commands.iter().cloned());
+        }
+        self.started = true;
+    }
+
+    // None means there are no more commands, either because a batch script has finished or the console was closed
+    pub fn read_command(&mut self) -> Option<grammar::CommandExpr> {
+        let stdin = std::io::stdin();
+        loop {
+            // Commands from scripts are echoed so that the output shows what they were
+            if let Some(input) = self.pending.pop_front() {
+                println!("> {}", input);
+                if let Some(c) = parse_command(&input) {
+                    return Some(c);
+                }
+                continue;
+            }
+            if self.batch {
+                return None;
+            }
+
+            print!("> ");
+            std::io::stdout().flush().unwrap();
+            let mut input = String::new();
+            if stdin.read_line(&mut input).unwrap_or(0) == 0 {
+                println!();
+                return None;
+            }
+            let input = input.trim().to_string();
+            if !input.is_empty() {
+                if let Some(c) = parse_command(&input) {
+                    return Some(c);
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_target::temp_file_path;
+    use grammar::CommandExpr;
+
+    // Batch mode never reads the console, so these don't wait on stdin
+    fn batch_reader(commands: &str) -> CommandReader {
+        let mut reader = CommandReader::new();
+        reader.add_startup_commands(commands);
+        reader.set_batch();
+        reader
+    }
+
+    #[test]
+    fn startup_commands_are_split_on_semicolons() {
+        let mut reader = batch_reader(" g ; ;k;;r rip ; ");
+        assert_eq!(reader.startup_commands, ["g", "k", "r rip"]);
+
+        reader.break_in(false);
+        assert!(matches!(reader.read_command(), Some(CommandExpr::Go(_))));
+        assert!(matches!(reader.read_command(), Some(CommandExpr::StackWalk(_))));
+        assert!(matches!(reader.read_command(), Some(CommandExpr::DisplaySpecificRegister(_, name)) if name == "rip"));
+        // The end of a batch script is the end of the commands
+        assert!(reader.read_command().is_none());
+    }
+
+    #[test]
+    fn empty_commands_are_not_a_script() {
+        let mut reader = CommandReader::new();
+        reader.add_startup_commands("");
+        reader.add_startup_commands(" ; ;");
+        assert!(reader.startup_commands.is_empty());
+        assert!(!reader.has_script());
+        assert!(!reader.is_batch());
+
+        // Batch mode with nothing to run just ends
+        reader.set_batch();
+        assert!(reader.has_script());
+        reader.break_in(false);
+        assert!(reader.read_command().is_none());
+    }
+
+    #[test]
+    fn batch_script_starts_over_at_each_break_in() {
+        let mut reader = batch_reader("k;g");
+        reader.break_in(false);
+        assert!(matches!(reader.read_command(), Some(CommandExpr::StackWalk(_))));
+        // Whatever was left of the last run is dropped
+        reader.break_in(false);
+        assert!(matches!(reader.read_command(), Some(CommandExpr::StackWalk(_))));
+        assert!(matches!(reader.read_command(), Some(CommandExpr::Go(_))));
+        assert!(reader.read_command().is_none());
+
+        // There's nothing to run the script against once the target has exited
+        reader.break_in(true);
+        assert!(reader.read_command().is_none());
+    }
+
+    #[test]
+    fn startup_commands_only_run_once_without_batch() {
+        let mut reader = CommandReader::new();
+        reader.add_startup_commands("k;g");
+        assert!(reader.has_script());
+        reader.break_in(false);
+        reader.break_in(false);
+        reader.break_in(true);
+        assert_eq!(reader.pending, ["k", "g"]);
+    }
+
+    #[test]
+    fn scripts_skip_blank_lines_and_comments() {
+        let path = temp_file_path("script.txt");
+        std::fs::write(&path, "* a comment\n\n  k  \nr rip\n").unwrap();
+        let mut reader = batch_reader("g");
+        reader.add_startup_script(&path).unwrap();
+        reader.break_in(false);
+        // $< runs ahead of anything that's already waiting
+        reader.run_script(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(reader.pending, ["k", "r rip", "g", "k", "r rip"]);
+
+        assert!(reader.add_startup_script(&path).is_err());
+        assert!(reader.run_script(&path).is_err());
+    }
+}
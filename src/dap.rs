@@ -0,0 +1,817 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+
+use serde_json::{json, Value};
+use windows_sys::Win32::Foundation::EXCEPTION_BREAKPOINT;
+
+use crate::command::grammar::{self, CommandExpr};
+use crate::eval;
+use crate::event_pump::{Event, EventPump, LoadedModule};
+use crate::memory::MemorySource;
+use crate::name_resolution;
+use crate::registers::{self, RegisterContext};
+use crate::source;
+use crate::stack;
+use crate::target::{ContinueStatus, DebugTarget};
+use crate::unassemble::{self, DisassembledInstruction};
+
+type LaunchFn<'a> = &'a dyn Fn(&[String]) -> Result<Box<dyn DebugTarget>, &'static str>;
+type AttachFn<'a> = &'a dyn Fn(u32) -> Result<Box<dyn DebugTarget>, &'static str>;
+
+// Stepping by source line is done one instruction at a time, so this stops a step that never reaches another line
+// from going on forever
+const MAX_STEP_INSTRUCTIONS: usize = 100000;
+const MAX_STACK_FRAMES: usize = 256;
+
+const BASE64_CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// Why the target stopped, in the terms that the client cares about
+enum Stop {
+    Entry,
+    Step,
+    Breakpoint(u64),
+    Exception(i32, bool),
+    Exited,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum StepKind {
+    Into,
+    Over,
+}
+
+// A breakpoint that the client asked for. It might not have an address yet if it's in a module that hasn't loaded.
+struct RequestedBreakpoint {
+    id: i64,
+    // The source line, for breakpoints set in a source file
+    line: Option<u32>,
+    address: Option<u64>,
+    verified: bool,
+    message: Option<String>,
+}
+
+fn encode_base64(data: &[u8]) -> String {
+    let mut text = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, b)| bits | (*b as u32) << (16 - i * 8));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(BASE64_CHARS[(bits >> (18 - i * 6)) as usize & 0x3f] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+// Memory references are addresses, in hex with a 0x prefix like the ones we hand out
+fn parse_memory_reference(value: &Value) -> Result<u64, String> {
+    let text = value.as_str().ok_or("Missing memory reference")?;
+    let parsed = match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| format!("Invalid memory reference: {}", text))
+}
+
+fn capabilities() -> Value {
+    json!({
+        "supportsConfigurationDoneRequest": true,
+        "supportsDisassembleRequest": true,
+        "supportsInstructionBreakpoints": true,
+        "supportsReadMemoryRequest": true,
+        "supportsSteppingGranularity": true,
+        "supportsEvaluateForHovers": true,
+        "supportTerminateDebuggee": true,
+    })
+}
+
+// Everything else in dbgrs reports to the console with println!, which would corrupt the protocol on stdout. So the
+// protocol gets its own handles for stdin and stdout, and stdout is pointed at stderr for everyone else.
+#[cfg(target_os = "linux")]
+pub fn take_stdio() -> Result<(File, File), &'static str> {
+    use std::os::unix::io::FromRawFd;
+
+    unsafe {
+        let input = libc::dup(0);
+        let output = libc::dup(1);
+        if input < 0 || output < 0 || libc::dup2(2, 1) < 0 {
+            return Err("Could not redirect standard output");
+        }
+        // The target inherits stdin, and it shouldn't be able to read our requests
+        let null = libc::open(c"/dev/null".as_ptr(), libc::O_RDONLY);
+        if null >= 0 {
+            libc::dup2(null, 0);
+            libc::close(null);
+        }
+        Ok((File::from_raw_fd(input), File::from_raw_fd(output)))
+    }
+}
+
+#[cfg(windows)]
+pub fn take_stdio() -> Result<(File, File), &'static str> {
+    use std::os::windows::io::FromRawHandle;
+    use windows_sys::Win32::Foundation::INVALID_HANDLE_VALUE;
+    use windows_sys::Win32::System::Console::{GetStdHandle, SetStdHandle, STD_ERROR_HANDLE, STD_INPUT_HANDLE, STD_OUTPUT_HANDLE};
+
+    // The target gets a console of its own, so only our own output needs to move
+    unsafe {
+        let input = GetStdHandle(STD_INPUT_HANDLE);
+        let output = GetStdHandle(STD_OUTPUT_HANDLE);
+        if input == INVALID_HANDLE_VALUE || output == INVALID_HANDLE_VALUE || SetStdHandle(STD_OUTPUT_HANDLE, GetStdHandle(STD_ERROR_HANDLE)) == 0 {
+            return Err("Could not redirect standard output");
+        }
+        Ok((File::from_raw_handle(input as _), File::from_raw_handle(output as _)))
+    }
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+pub fn take_stdio() -> Result<(File, File), &'static str> {
+    Err("Machine interfaces are not supported on this platform")
+}
+
+struct DapConnection {
+    reader: BufReader<File>,
+    writer: File,
+    seq: i64,
+}
+
+impl DapConnection {
+    // Each message is JSON, with an HTTP style Content-Length header in front of it. None means the client went away.
+    fn read_message(&mut self) -> Result<Option<Value>, &'static str> {
+        let mut content_length = None;
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line).map_err(|_| "Could not read from client")? == 0 {
+                return Ok(None);
+            }
+            let line = line.trim_end();
+            if line.is_empty() && content_length.is_some() {
+                break;
+            }
+            if let Some(length) = line.strip_prefix("Content-Length:") {
+                content_length = length.trim().parse::<usize>().ok();
+            }
+        }
+
+        let mut body = vec![0; content_length.unwrap_or_default()];
+        self.reader.read_exact(&mut body).map_err(|_| "Could not read from client")?;
+        serde_json::from_slice(&body).map(Some).map_err(|_| "Invalid message from client")
+    }
+
+    fn send(&mut self, mut message: Value) -> Result<(), &'static str> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        let packet = format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+        self.writer.write_all(packet.as_bytes()).and_then(|_| self.writer.flush()).map_err(|_| "Could not write to client")
+    }
+
+    fn send_response(&mut self, request: &Value, result: Result<Value, String>) -> Result<(), &'static str> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response)
+    }
+
+    fn send_event(&mut self, event: &str, body: Value) -> Result<(), &'static str> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+}
+
+// Serves the Debug Adapter Protocol for a target that dbgrs controls. Like the GDB server, the target is driven the
+// same way main_debugger_loop drives it, with breakpoints going through the BreakpointManager. The client can only
+// talk to us while the target is stopped, since we're waiting for the target the rest of the time.
+struct DapServer {
+    connection: DapConnection,
+    target: Box<dyn DebugTarget>,
+    pump: EventPump,
+    // The initial breakpoint is reported as the entry point, rather than as an exception
+    entry_seen: bool,
+    stop_on_entry: bool,
+    // What happened before the client finished setting up, which is reported once it has
+    pending_stop: Option<Stop>,
+    // Whether to kill the target or detach from it when the client disconnects, unless it says otherwise
+    terminate_on_disconnect: bool,
+    exited: bool,
+    source_breakpoints: HashMap<String, Vec<RequestedBreakpoint>>,
+    instruction_breakpoints: Vec<RequestedBreakpoint>,
+    next_breakpoint_id: i64,
+    // The BreakpointManager IDs of the requested breakpoints that have been set
+    breakpoint_ids: Vec<u32>,
+    // The temporary breakpoint used to step over calls and out of functions
+    step_breakpoint: Option<u32>,
+    // The stack frames from the last stack trace, by frame ID. These are only good until the target runs again.
+    frames: Vec<RegisterContext>,
+}
+
+impl DapServer {
+    // Runs the target until something happens that the client needs to know about. Everything else, like modules
+    // loading and threads starting, is handled the same way the command line debugger would handle it.
+    fn run_until_stop(&mut self) -> Result<Stop, &'static str> {
+        loop {
+            match self.pump.next_event(self.target.as_mut())? {
+                Event::Step => return Ok(Stop::Step),
+                Event::Breakpoint(_) => {
+                    let ctx = self.target.get_thread_context(self.pump.event_context.thread_id).unwrap_or_default();
+                    return Ok(Stop::Breakpoint(ctx.rip));
+                }
+                Event::Exception { first_chance, exception_code } => {
+                    if !self.entry_seen && exception_code == EXCEPTION_BREAKPOINT {
+                        self.entry_seen = true;
+                        self.pump.continue_status = ContinueStatus::Handled;
+                        return Ok(Stop::Entry);
+                    }
+                    return Ok(Stop::Exception(exception_code, first_chance));
+                }
+                Event::ProcessCreated(module) => {
+                    self.module_loaded(&module)?;
+                    self.connection.send_event("thread", json!({ "reason": "started", "threadId": self.pump.event_context.thread_id }))?;
+                }
+                Event::ModuleLoaded(module) => self.module_loaded(&module)?,
+                Event::ThreadCreated(thread_id) => self.connection.send_event("thread", json!({ "reason": "started", "threadId": thread_id }))?,
+                Event::ThreadExited(thread_id) => self.connection.send_event("thread", json!({ "reason": "exited", "threadId": thread_id }))?,
+                Event::Output(output) => self.send_output("stdout", &output)?,
+                Event::Message(msg) => self.send_output("console", &format!("{}\n", msg))?,
+                Event::Exited => return Ok(Stop::Exited),
+            }
+            self.pump.resume(self.target.as_mut(), None)?;
+        }
+    }
+
+    fn resume(&mut self, step_thread: Option<u32>) -> Result<(), &'static str> {
+        self.frames.clear();
+        self.pump.resume(self.target.as_mut(), step_thread)
+    }
+
+    fn send_output(&mut self, category: &str, output: &str) -> Result<(), &'static str> {
+        self.connection.send_event("output", json!({ "category": category, "output": output }))
+    }
+
+    fn module_loaded(&mut self, module: &LoadedModule) -> Result<(), &'static str> {
+        self.send_output("console", &format!("{}\n", module))?;
+
+        // Breakpoints in this module can be set now
+        self.resolve_breakpoints();
+        self.sync_breakpoints(true)
+    }
+
+    fn source_line(&mut self, address: u64) -> Option<(String, u32)> {
+        source::resolve_address_to_source_line(address, &mut self.pump.process).ok()
+    }
+
+    // Looks for an address for each source breakpoint that doesn't have one yet, in every module that's loaded
+    fn resolve_breakpoints(&mut self) {
+        let module_names: Vec<String> = self.pump.process.iterate_modules().map(|m| m.name.clone()).collect();
+        for (path, requested) in self.source_breakpoints.iter_mut() {
+            for bp in requested.iter_mut().filter(|bp| bp.address.is_none()) {
+                let line = bp.line.unwrap_or_default();
+                bp.address = module_names.iter().find_map(|name| source::resolve_source_line_to_address(name, path, line, &mut self.pump.process).ok());
+            }
+        }
+    }
+
+    // Makes the BreakpointManager match the requested breakpoints, and tells the client about any breakpoints that
+    // changed if notify is set
+    fn sync_breakpoints(&mut self, notify: bool) -> Result<(), &'static str> {
+        for id in self.breakpoint_ids.drain(..) {
+            self.pump.breakpoints.clear_breakpoint(id);
+        }
+
+        let mut set_addresses: HashMap<u64, u32> = HashMap::new();
+        let mut changed = Vec::new();
+        for bp in self.source_breakpoints.values_mut().flatten().chain(self.instruction_breakpoints.iter_mut()) {
+            let was_verified = bp.verified;
+            bp.message = None;
+            match bp.address {
+                None => {
+                    bp.verified = false;
+                    bp.message = Some("No code has been loaded for this line".to_string());
+                }
+                Some(address) if set_addresses.contains_key(&address) => bp.verified = true,
+                Some(address) => match self.pump.breakpoints.add_breakpoint(address) {
+                    Ok(id) => {
+                        set_addresses.insert(address, id);
+                        self.breakpoint_ids.push(id);
+                        bp.verified = true;
+                    }
+                    Err(e) => {
+                        bp.verified = false;
+                        bp.message = Some(e.to_string());
+                    }
+                },
+            }
+            if was_verified != bp.verified {
+                changed.push(bp.id);
+            }
+        }
+
+        if notify {
+            for id in changed {
+                let breakpoint = self.breakpoint_json(id);
+                self.connection.send_event("breakpoint", json!({ "reason": "changed", "breakpoint": breakpoint }))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn breakpoint_json(&mut self, id: i64) -> Value {
+        let bp = match self.source_breakpoints.values().flatten().chain(self.instruction_breakpoints.iter()).find(|bp| bp.id == id) {
+            Some(bp) => bp,
+            None => return json!({ "id": id, "verified": false }),
+        };
+        let mut breakpoint = json!({ "id": bp.id, "verified": bp.verified });
+        if let Some(message) = &bp.message {
+            breakpoint["message"] = json!(message);
+        }
+        if let Some(line) = bp.line {
+            breakpoint["line"] = json!(line);
+        }
+        if let Some(address) = bp.address {
+            breakpoint["instructionReference"] = json!(format!("0x{:x}", address));
+            // The code for a line might really start on a later line, so show where it will actually stop
+            if let Some((_, line)) = bp.line.and(self.source_line(address)) {
+                breakpoint["line"] = json!(line);
+            }
+        }
+        breakpoint
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let path = args["source"]["path"].as_str().ok_or("Breakpoints need a source path")?.to_string();
+        let lines: Vec<u32> = match args["breakpoints"].as_array() {
+            Some(breakpoints) => breakpoints.iter().filter_map(|bp| bp["line"].as_u64()).map(|l| l as u32).collect(),
+            None => args["lines"].as_array().map(|lines| lines.iter().filter_map(|l| l.as_u64()).map(|l| l as u32).collect()).unwrap_or_default(),
+        };
+
+        let mut requested = Vec::new();
+        for line in lines {
+            self.next_breakpoint_id += 1;
+            requested.push(RequestedBreakpoint { id: self.next_breakpoint_id, line: Some(line), address: None, verified: false, message: None });
+        }
+        let ids: Vec<i64> = requested.iter().map(|bp| bp.id).collect();
+        self.source_breakpoints.insert(path, requested);
+        self.resolve_breakpoints();
+        self.sync_breakpoints(false)?;
+
+        let breakpoints: Vec<Value> = ids.into_iter().map(|id| self.breakpoint_json(id)).collect();
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn set_instruction_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let mut requested = Vec::new();
+        for bp in args["breakpoints"].as_array().map(|a| a.as_slice()).unwrap_or_default() {
+            let address = parse_memory_reference(&bp["instructionReference"])?.wrapping_add(bp["offset"].as_i64().unwrap_or(0) as u64);
+            self.next_breakpoint_id += 1;
+            requested.push(RequestedBreakpoint { id: self.next_breakpoint_id, line: None, address: Some(address), verified: false, message: None });
+        }
+        let ids: Vec<i64> = requested.iter().map(|bp| bp.id).collect();
+        self.instruction_breakpoints = requested;
+        self.sync_breakpoints(false)?;
+
+        let breakpoints: Vec<Value> = ids.into_iter().map(|id| self.breakpoint_json(id)).collect();
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn threads(&self) -> Value {
+        let threads: Vec<Value> = self.pump.process.iterate_threads().map(|t| json!({ "id": t, "name": format!("Thread {:x}", t) })).collect();
+        json!({ "threads": threads })
+    }
+
+    fn source_json(path: &str) -> Value {
+        let name = path.rsplit(['/', '\\']).next().unwrap_or(path);
+        // Relative paths from the symbols are only useful if they happen to be relative to where we are
+        let path = source::find_source_file_match(path, &vec![".".to_string()]).map_or(path.to_string(), |p| p.to_string_lossy().to_string());
+        json!({ "name": name, "path": path })
+    }
+
+    fn stack_trace(&mut self, args: &Value) -> Result<Value, String> {
+        let thread_id = args["threadId"].as_u64().ok_or("Missing thread ID")? as u32;
+        let start_frame = args["startFrame"].as_u64().unwrap_or(0) as usize;
+        let levels = match args["levels"].as_u64() {
+            Some(levels) if levels > 0 => levels as usize,
+            _ => MAX_STACK_FRAMES,
+        };
+
+        let mut contexts = vec![self.target.get_thread_context(thread_id)?];
+        while contexts.len() < MAX_STACK_FRAMES {
+            match stack::unwind_context(&mut self.pump.process, *contexts.last().unwrap(), self.target.memory_source()) {
+                Ok(Some(unwound_context)) => contexts.push(unwound_context),
+                _ => break,
+            }
+        }
+
+        let mut frames = Vec::new();
+        for context in contexts.iter().skip(start_frame).take(levels) {
+            let id = self.frames.len();
+            self.frames.push(*context);
+            let name = name_resolution::resolve_address_to_name(context.rip, &mut self.pump.process).unwrap_or_else(|| format!("0x{:X}", context.rip));
+            let mut frame = json!({
+                "id": id,
+                "name": name,
+                "line": 0,
+                "column": 0,
+                "instructionPointerReference": format!("0x{:x}", context.rip),
+            });
+            if let Some((path, line)) = self.source_line(context.rip) {
+                frame["source"] = Self::source_json(&path);
+                frame["line"] = json!(line);
+                frame["column"] = json!(1);
+            }
+            frames.push(frame);
+        }
+        Ok(json!({ "stackFrames": frames, "totalFrames": contexts.len() }))
+    }
+
+    fn frame_context(&self, frame_id: Option<u64>) -> Result<RegisterContext, String> {
+        match frame_id {
+            Some(id) => self.frames.get(id as usize).copied().ok_or_else(|| "Unknown stack frame".to_string()),
+            None => Ok(self.target.get_thread_context(self.pump.event_context.thread_id)?),
+        }
+    }
+
+    fn scopes(&self, args: &Value) -> Result<Value, String> {
+        let frame_id = args["frameId"].as_u64().ok_or("Missing frame ID")?;
+        self.frame_context(Some(frame_id))?;
+        // Variable references can't be 0, so the registers of frame n are reference n + 1
+        Ok(json!({ "scopes": [{ "name": "Registers", "presentationHint": "registers", "variablesReference": frame_id + 1, "expensive": false }] }))
+    }
+
+    fn variables(&self, args: &Value) -> Result<Value, String> {
+        let reference = args["variablesReference"].as_u64().ok_or("Missing variables reference")?;
+        let context = self.frame_context(Some(reference.wrapping_sub(1)))?;
+        let variables: Vec<Value> = registers::REGISTER_NAMES.iter().map(|name| {
+            let value = registers::get_register(&context, name).unwrap_or_default();
+            json!({ "name": name, "value": format!("{:#018x}", value), "variablesReference": 0, "memoryReference": format!("0x{:x}", value) })
+        }).collect();
+        Ok(json!({ "variables": variables }))
+    }
+
+    fn evaluate(&mut self, args: &Value) -> Result<Value, String> {
+        let expression = args["expression"].as_str().ok_or("Missing expression")?;
+        let context = self.frame_context(args["frameId"].as_u64())?;
+        // Expressions use the same syntax as the ? command
+        let expr = match grammar::parse(&format!("? {}", expression)) {
+            Ok(CommandExpr::Evaluate(_, expr)) => expr,
+            _ => return Err(format!("Could not parse expression: {}", expression)),
+        };
+        let mut eval_context = eval::EvalContext { process: &mut self.pump.process, register_context: &context };
+        let value = eval::evaluate_expression(*expr, &mut eval_context).map_err(|e| format!("Could not evaluate expression: {}", e))?;
+        Ok(json!({ "result": format!("0x{:X}", value), "variablesReference": 0, "memoryReference": format!("0x{:x}", value) }))
+    }
+
+    fn read_memory(&self, args: &Value) -> Result<Value, String> {
+        let address = parse_memory_reference(&args["memoryReference"])?.wrapping_add(args["offset"].as_i64().unwrap_or(0) as u64);
+        let count = args["count"].as_u64().ok_or("Missing count")? as usize;
+        let data = self.target.memory_source().read_raw_memory(address, count);
+        Ok(json!({ "address": format!("0x{:x}", address), "data": encode_base64(&data), "unreadableBytes": count - data.len() }))
+    }
+
+    fn instruction_json(&mut self, instruction: &DisassembledInstruction) -> Value {
+        let bytes: Vec<String> = instruction.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        let mut json = json!({
+            "address": format!("0x{:x}", instruction.address),
+            "instructionBytes": bytes.join(" "),
+            "instruction": instruction.text,
+        });
+        // Only label the instructions that start a symbol, rather than repeating it with an offset on every line
+        if let Some(symbol) = name_resolution::resolve_address_to_name(instruction.address, &mut self.pump.process).filter(|s| !s.contains('+')) {
+            json["symbol"] = json!(symbol);
+        }
+        if let Some((path, line)) = self.source_line(instruction.address) {
+            json["location"] = Self::source_json(&path);
+            json["line"] = json!(line);
+        }
+        json
+    }
+
+    fn disassemble(&mut self, args: &Value) -> Result<Value, String> {
+        let address = parse_memory_reference(&args["memoryReference"])?.wrapping_add(args["offset"].as_i64().unwrap_or(0) as u64);
+        let instruction_offset = args["instructionOffset"].as_i64().unwrap_or(0);
+        let count = args["instructionCount"].as_u64().ok_or("Missing instruction count")? as usize;
+        let memory_source = self.target.memory_source();
+
+        let mut instructions = Vec::new();
+        let mut skip = instruction_offset.max(0) as usize;
+        if instruction_offset < 0 {
+            let wanted = instruction_offset.unsigned_abs() as usize;
+            instructions = instructions_before(memory_source, address, wanted);
+            // Anything we couldn't decode still takes up a line, so the rest of them stay where the client expects
+            let first_address = instructions.first().map_or(address, |i| i.address);
+            let missing = wanted - instructions.len();
+            let placeholders = (1..=missing as u64).rev().map(|i| DisassembledInstruction { address: first_address.wrapping_sub(i), bytes: Vec::new(), text: "??".to_string(), is_call: false });
+            instructions.splice(0..0, placeholders);
+            instructions.truncate(count);
+            skip = 0;
+        }
+        let remaining = count - instructions.len();
+        instructions.extend(unassemble::disassemble(memory_source, address, skip + remaining).into_iter().skip(skip));
+        while instructions.len() < count {
+            let next_address = instructions.last().map_or(address, |i| i.address + std::cmp::max(i.bytes.len(), 1) as u64);
+            instructions.push(DisassembledInstruction { address: next_address, bytes: Vec::new(), text: "??".to_string(), is_call: false });
+        }
+
+        let json: Vec<Value> = instructions.iter().map(|i| {
+            let mut json = self.instruction_json(i);
+            if i.bytes.is_empty() {
+                json["presentationHint"] = json!("invalid");
+            }
+            json
+        }).collect();
+        Ok(json!({ "instructions": json }))
+    }
+
+    // Runs until the thread gets to address with its stack pointer at or above stack_pointer, which is where it ends
+    // up after returning from a call. Being deeper in the stack means it's a recursive call, so that one doesn't count.
+    fn run_to(&mut self, thread_id: u32, address: u64, stack_pointer: u64) -> Result<Stop, &'static str> {
+        let id = self.pump.breakpoints.add_breakpoint(address).map_err(|_| "There are no breakpoints left to step with")?;
+        self.step_breakpoint = Some(id);
+        let stop = loop {
+            if let Err(e) = self.resume(None) {
+                break Err(e);
+            }
+            match self.run_until_stop() {
+                Ok(Stop::Breakpoint(hit)) if hit == address => {
+                    let here = self.pump.event_context.thread_id == thread_id
+                        && self.target.get_thread_context(thread_id).is_ok_and(|ctx| ctx.rsp >= stack_pointer);
+                    let requested = self.source_breakpoints.values().flatten().chain(self.instruction_breakpoints.iter()).any(|bp| bp.address == Some(address));
+                    if here {
+                        break Ok(Stop::Step);
+                    } else if requested {
+                        break Ok(Stop::Breakpoint(hit));
+                    }
+                }
+                other => break other,
+            }
+        };
+        self.pump.breakpoints.clear_breakpoint(id);
+        self.step_breakpoint = None;
+        stop
+    }
+
+    fn single_step(&mut self, thread_id: u32) -> Result<Stop, &'static str> {
+        self.resume(Some(thread_id))?;
+        self.run_until_stop()
+    }
+
+    // Steps a single instruction, or a whole source line if by_instruction isn't set. Calls into code without any
+    // source are always stepped over, since there's nothing to show for them.
+    fn step(&mut self, thread_id: u32, kind: StepKind, by_instruction: bool) -> Result<Stop, &'static str> {
+        let start_context = self.target.get_thread_context(thread_id)?;
+        let start_line = self.source_line(start_context.rip);
+        for _ in 0..MAX_STEP_INSTRUCTIONS {
+            let context = self.target.get_thread_context(thread_id)?;
+            let instruction = unassemble::disassemble(self.target.memory_source(), context.rip, 1).pop();
+            let stop = match instruction {
+                Some(call) if call.is_call => {
+                    let return_address = call.address + call.bytes.len() as u64;
+                    if kind == StepKind::Over {
+                        self.run_to(thread_id, return_address, context.rsp)?
+                    } else {
+                        match self.single_step(thread_id)? {
+                            Stop::Step if !by_instruction && start_line.is_some() && self.source_line(self.target.get_thread_context(thread_id)?.rip).is_none() => {
+                                self.run_to(thread_id, return_address, context.rsp)?
+                            }
+                            stop => stop,
+                        }
+                    }
+                }
+                _ => self.single_step(thread_id)?,
+            };
+            if !matches!(stop, Stop::Step) || by_instruction {
+                return Ok(stop);
+            }
+
+            // Keep going until we're on a different line, but stop straight away when starting from code without a line
+            let line = self.source_line(self.target.get_thread_context(thread_id)?.rip);
+            if start_line.is_none() || (line.is_some() && line != start_line) {
+                return Ok(Stop::Step);
+            }
+        }
+        Ok(Stop::Step)
+    }
+
+    fn report_stop(&mut self, stop: Stop) -> Result<(), &'static str> {
+        let mut body = json!({ "threadId": self.pump.event_context.thread_id, "allThreadsStopped": true });
+        match stop {
+            Stop::Entry => body["reason"] = json!("entry"),
+            Stop::Step => body["reason"] = json!("step"),
+            Stop::Breakpoint(address) => {
+                let ids: Vec<i64> = self.source_breakpoints.values().flatten().chain(self.instruction_breakpoints.iter())
+                    .filter(|bp| bp.address == Some(address))
+                    .map(|bp| bp.id)
+                    .collect();
+                // Instruction breakpoints have their own reason, so that the client shows them in the disassembly
+                let is_instruction = self.instruction_breakpoints.iter().any(|bp| bp.address == Some(address));
+                body["reason"] = json!(if is_instruction { "instruction breakpoint" } else { "breakpoint" });
+                body["hitBreakpointIds"] = json!(ids);
+            }
+            Stop::Exception(exception_code, first_chance) => {
+                let description = format!("Exception code {:x} ({})", exception_code, if first_chance { "first chance" } else { "second chance" });
+                body["reason"] = json!("exception");
+                body["description"] = json!(description);
+                body["text"] = json!(description);
+            }
+            Stop::Exited => {
+                self.exited = true;
+                self.connection.send_event("exited", json!({ "exitCode": 0 }))?;
+                return self.connection.send_event("terminated", json!({}));
+            }
+        }
+        self.connection.send_event("stopped", body)
+    }
+
+    // Handles the requests that let the target run, which are answered before it starts running and then followed
+    // by a stopped event once it stops again
+    fn run_request(&mut self, request: &Value) -> Result<(), &'static str> {
+        if self.exited {
+            return self.connection.send_response(request, Err("The target has exited".to_string()));
+        }
+        let args = &request["arguments"];
+        let thread_id = args["threadId"].as_u64().map_or(self.pump.event_context.thread_id, |t| t as u32);
+        let by_instruction = args["granularity"].as_str() == Some("instruction");
+
+        let stop = match request["command"].as_str().unwrap_or_default() {
+            "configurationDone" => {
+                self.connection.send_response(request, Ok(json!({})))?;
+                match self.pending_stop.take() {
+                    Some(Stop::Entry) if !self.stop_on_entry => {
+                        self.resume(None)?;
+                        self.run_until_stop()?
+                    }
+                    Some(stop) => stop,
+                    None => return Ok(()),
+                }
+            }
+            "continue" => {
+                self.connection.send_response(request, Ok(json!({ "allThreadsContinued": true })))?;
+                self.resume(None)?;
+                self.run_until_stop()?
+            }
+            "next" => {
+                self.connection.send_response(request, Ok(json!({})))?;
+                self.step(thread_id, StepKind::Over, by_instruction)?
+            }
+            "stepIn" => {
+                self.connection.send_response(request, Ok(json!({})))?;
+                self.step(thread_id, StepKind::Into, by_instruction)?
+            }
+            "stepOut" => {
+                // The unwinder finds where the function returns to, and we run until we get there
+                let context = self.target.get_thread_context(thread_id)?;
+                let caller = match stack::unwind_context(&mut self.pump.process, context, self.target.memory_source()) {
+                    Ok(Some(caller)) => caller,
+                    _ => return self.connection.send_response(request, Err("Could not find the caller of this function".to_string())),
+                };
+                self.connection.send_response(request, Ok(json!({})))?;
+                self.run_to(thread_id, caller.rip, caller.rsp)?
+            }
+            _ => return Ok(()),
+        };
+        self.report_stop(stop)
+    }
+
+    // Handles requests until the client disconnects
+    fn serve(&mut self) -> Result<(), &'static str> {
+        while let Some(request) = self.connection.read_message()? {
+            let args = &request["arguments"];
+            let result = match request["command"].as_str().unwrap_or_default() {
+                "configurationDone" | "continue" | "next" | "stepIn" | "stepOut" => {
+                    self.run_request(&request)?;
+                    continue;
+                }
+                "disconnect" => {
+                    let terminate = args["terminateDebuggee"].as_bool().unwrap_or(self.terminate_on_disconnect);
+                    let mut result = Ok(json!({}));
+                    if !terminate && !self.exited {
+                        self.pump.breakpoints.remove_all_breakpoints(&self.pump.process, self.target.as_mut());
+                        let event_context = self.pump.event_context;
+                        result = self.target.detach(&event_context, self.pump.continue_status).map(|_| json!({})).map_err(|e| e.to_string());
+                    }
+                    // Otherwise the target is killed when it's dropped, just like quitting the command line debugger
+                    return self.connection.send_response(&request, result);
+                }
+                // We only read requests while the target is stopped, so by the time we see this it already has
+                "pause" => Ok(json!({})),
+                _ if self.exited => Err("The target has exited".to_string()),
+                "setBreakpoints" => self.set_breakpoints(args),
+                "setInstructionBreakpoints" => self.set_instruction_breakpoints(args),
+                "setExceptionBreakpoints" => Ok(json!({})),
+                "threads" => Ok(self.threads()),
+                "stackTrace" => self.stack_trace(args),
+                "scopes" => self.scopes(args),
+                "variables" => self.variables(args),
+                "evaluate" => self.evaluate(args),
+                "readMemory" => self.read_memory(args),
+                "disassemble" => self.disassemble(args),
+                command => Err(format!("Unsupported request: {}", command)),
+            };
+            self.connection.send_response(&request, result)?;
+        }
+        Ok(())
+    }
+}
+
+// x86 can't be decoded backwards, but decoding from a bit before the address usually falls into step with the real
+// instructions by the time it gets there. Returns up to count instructions that end right at the address.
+fn instructions_before(memory_source: &dyn MemorySource, address: u64, count: usize) -> Vec<DisassembledInstruction> {
+    let max_length = (count * 15) as u64;
+    for start in (address.saturating_sub(max_length)..address).take(15) {
+        let mut instructions = unassemble::disassemble(memory_source, start, count * 15);
+        let Some(end) = instructions.iter().position(|i| i.address >= address) else {
+            continue;
+        };
+        if instructions[end].address == address {
+            instructions.truncate(end);
+            let first = instructions.len().saturating_sub(count);
+            return instructions.split_off(first);
+        }
+    }
+    Vec::new()
+}
+
+fn launch_from_arguments(args: &Value, launch: LaunchFn) -> Result<Box<dyn DebugTarget>, String> {
+    let program = args["program"].as_str().ok_or("No program to launch")?;
+    let mut command_line = vec![program.to_string()];
+    command_line.extend(args["args"].as_array().map(|a| a.as_slice()).unwrap_or_default().iter().filter_map(|a| a.as_str()).map(|a| a.to_string()));
+    if let Some(cwd) = args["cwd"].as_str() {
+        std::env::set_current_dir(cwd).map_err(|_| format!("Could not change to directory {}", cwd))?;
+    }
+    Ok(launch(&command_line)?)
+}
+
+fn attach_from_arguments(args: &Value, attach: AttachFn) -> Result<Box<dyn DebugTarget>, String> {
+    // Some clients send the process ID as a string, since that's what a process picker gives them
+    let process_id = match &args["processId"] {
+        Value::String(text) => text.parse().ok(),
+        value => value.as_u64(),
+    };
+    Ok(attach(process_id.ok_or("No process ID to attach to")? as u32)?)
+}
+
+pub fn run_server(launch: LaunchFn, attach: AttachFn) -> Result<(), &'static str> {
+    let (input, output) = take_stdio()?;
+    let mut connection = DapConnection { reader: BufReader::new(input), writer: output, seq: 0 };
+
+    // Nothing else can happen until the client says what to debug
+    let (target, request, stop_on_entry, terminate_on_disconnect) = loop {
+        let Some(request) = connection.read_message()? else {
+            return Ok(());
+        };
+        let args = &request["arguments"];
+        let result = match request["command"].as_str().unwrap_or_default() {
+            "initialize" => {
+                connection.send_response(&request, Ok(capabilities()))?;
+                continue;
+            }
+            "launch" => launch_from_arguments(args, launch).map(|target| (target, args["stopOnEntry"].as_bool().unwrap_or(false), true)),
+            "attach" => attach_from_arguments(args, attach).map(|target| (target, true, false)),
+            "disconnect" => {
+                return connection.send_response(&request, Ok(json!({})));
+            }
+            _ => Err("Nothing is being debugged yet".to_string()),
+        };
+        match result {
+            Ok((target, stop_on_entry, terminate_on_disconnect)) => break (target, request, stop_on_entry, terminate_on_disconnect),
+            Err(message) => connection.send_response(&request, Err(message))?,
+        }
+    };
+
+    let mut server = DapServer {
+        connection,
+        target,
+        pump: EventPump::new(),
+        entry_seen: false,
+        stop_on_entry,
+        pending_stop: None,
+        terminate_on_disconnect,
+        exited: false,
+        source_breakpoints: HashMap::new(),
+        instruction_breakpoints: Vec::new(),
+        next_breakpoint_id: 0,
+        breakpoint_ids: Vec::new(),
+        step_breakpoint: None,
+        frames: Vec::new(),
+    };
+
+    // Get the target to its initial breakpoint, so the modules are there by the time the client sets breakpoints
+    match server.run_until_stop()? {
+        Stop::Exited => {
+            server.connection.send_response(&request, Err("Target exited before it could be debugged".to_string()))?;
+            return server.connection.send_event("terminated", json!({}));
+        }
+        stop => server.pending_stop = Some(stop),
+    }
+    server.connection.send_response(&request, Ok(json!({})))?;
+    server.connection.send_event("initialized", json!({}))?;
+    server.serve()
+}
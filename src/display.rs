@@ -0,0 +1,306 @@
+use crate::memory::{MemorySource, MAX_READ_SIZE};
+use crate::name_resolution;
+use crate::process::Process;
+
+// The d* commands, which all show memory in a different way
+#[derive(Clone, Copy)]
+pub enum DisplayFormat {
+    Bytes,
+    Words,
+    Dwords,
+    Qwords,
+    DwordsAscii,
+    Pointers,
+    PointerSymbols,
+    Floats,
+    Doubles,
+    Ascii,
+    Unicode,
+    UnicodeString,
+}
+
+// Without a range, strings are read until a null terminator or this many characters, like WinDbg
+const DEFAULT_STRING_LENGTH: u64 = 0x100;
+
+impl DisplayFormat {
+    // A plain d uses the last format, so there's nothing to return for it
+    pub fn from_command(command: &str) -> Option<DisplayFormat> {
+        match command {
+            "db" => Some(DisplayFormat::Bytes),
+            "dw" => Some(DisplayFormat::Words),
+            "dd" => Some(DisplayFormat::Dwords),
+            "dq" => Some(DisplayFormat::Qwords),
+            "dc" => Some(DisplayFormat::DwordsAscii),
+            "dp" => Some(DisplayFormat::Pointers),
+            "dps" | "dqs" => Some(DisplayFormat::PointerSymbols),
+            "df" => Some(DisplayFormat::Floats),
+            "dD" => Some(DisplayFormat::Doubles),
+            "da" => Some(DisplayFormat::Ascii),
+            "du" => Some(DisplayFormat::Unicode),
+            "ds" => Some(DisplayFormat::UnicodeString),
+            _ => None,
+        }
+    }
+
+    // This is what L counts in a range. For strings it's characters, and for ds it's whole UNICODE_STRINGs.
+    pub fn element_size(self) -> u64 {
+        match self {
+            DisplayFormat::Bytes | DisplayFormat::Ascii => 1,
+            DisplayFormat::Words | DisplayFormat::Unicode => 2,
+            DisplayFormat::Dwords | DisplayFormat::DwordsAscii | DisplayFormat::Floats => 4,
+            DisplayFormat::Qwords | DisplayFormat::Pointers | DisplayFormat::PointerSymbols | DisplayFormat::Doubles => 8,
+            DisplayFormat::UnicodeString => 16,
+        }
+    }
+
+    fn default_count(self) -> u64 {
+        match self {
+            DisplayFormat::PointerSymbols => 16,
+            DisplayFormat::Ascii | DisplayFormat::Unicode => DEFAULT_STRING_LENGTH,
+            DisplayFormat::UnicodeString => 1,
+            // The tables show 8 lines of 16 bytes, like WinDbg
+            _ => 128 / self.element_size(),
+        }
+    }
+
+    fn per_line(self) -> u64 {
+        match self {
+            DisplayFormat::PointerSymbols => 1,
+            _ => 16 / self.element_size(),
+        }
+    }
+
+    fn has_ascii_column(self) -> bool {
+        matches!(self, DisplayFormat::Bytes | DisplayFormat::DwordsAscii)
+    }
+
+    // Every element in a column is the same width, whether or not it could be read
+    fn column_width(self) -> usize {
+        match self {
+            DisplayFormat::Floats => 16,
+            DisplayFormat::Doubles => 24,
+            _ => self.element_size() as usize * 2,
+        }
+    }
+
+    fn format_element(self, bytes: Option<&[u8]>) -> String {
+        let width = self.column_width();
+        let bytes = match bytes {
+            Some(bytes) => bytes,
+            None => return format!("{:>width$}", "?".repeat(std::cmp::min(width, 16))),
+        };
+        match self {
+            DisplayFormat::Floats => format!("{:>width$.prec$e}", f32::from_le_bytes(bytes.try_into().unwrap()), prec = 6),
+            DisplayFormat::Doubles => format!("{:>width$.prec$e}", f64::from_le_bytes(bytes.try_into().unwrap()), prec = 15),
+            _ => {
+                let mut value = [0u8; 8];
+                value[..bytes.len()].copy_from_slice(bytes);
+                format!("{:0width$X}", u64::from_le_bytes(value))
+            }
+        }
+    }
+}
+
+// Shows count elements starting at the address, or the default amount for the format if there's no count. Returns
+// the address that a repeated command should continue from.
+pub fn display_memory(format: DisplayFormat, address: u64, count: Option<u64>, memory_source: &dyn MemorySource, process: &mut Process) -> u64 {
+    let (lines, next_address) = format_memory(format, address, count, memory_source, process);
+    for line in lines {
+        println!("{}", line);
+    }
+    next_address
+}
+
+// Nothing past the end of the address space can be shown, so the range stops there. It also stops at the most that can
+// be read at once, since a count that big is almost certainly a mistake.
+fn element_count(format: DisplayFormat, address: u64, count: Option<u64>) -> u64 {
+    let elements_left = ((u64::MAX - address) as u128 + 1) / format.element_size() as u128;
+    let elements_left = std::cmp::min(elements_left, (MAX_READ_SIZE / format.element_size()) as u128);
+    std::cmp::min(count.unwrap_or(format.default_count()) as u128, elements_left) as u64
+}
+
+fn format_memory(format: DisplayFormat, address: u64, count: Option<u64>, memory_source: &dyn MemorySource, process: &mut Process) -> (Vec<String>, u64) {
+    let count = element_count(format, address, count);
+    match format {
+        DisplayFormat::Ascii => format_string(address, count, false, memory_source),
+        DisplayFormat::Unicode => format_string(address, count, true, memory_source),
+        DisplayFormat::UnicodeString => {
+            let lines = (0..count).map(|index| format_unicode_string(address + index * 16, memory_source)).collect();
+            (lines, address.wrapping_add(count * 16))
+        }
+        _ => format_table(format, address, count, memory_source, process),
+    }
+}
+
+fn format_table(format: DisplayFormat, address: u64, count: u64, memory_source: &dyn MemorySource, process: &mut Process) -> (Vec<String>, u64) {
+    let element_size = format.element_size();
+    let per_line = format.per_line();
+    // Unreadable bytes are None, so they show up as question marks
+    let data = memory_source.read_memory(address, (count * element_size) as usize).unwrap_or_default();
+    let element = |index: u64| -> Option<Vec<u8>> {
+        let start = (index * element_size) as usize;
+        data.get(start..start + element_size as usize)?.iter().copied().collect()
+    };
+
+    let mut lines = Vec::new();
+    for line_start in (0..count).step_by(per_line as usize) {
+        let line_count = std::cmp::min(per_line, count - line_start);
+        let mut line = format!("{:016X} ", address + line_start * element_size);
+        for index in line_start..line_start + line_count {
+            // Like WinDbg, db has a dash in the middle of each line
+            let separator = if matches!(format, DisplayFormat::Bytes) && index - line_start == 8 { '-' } else { ' ' };
+            line += &format!("{}{}", separator, format.format_element(element(index).as_deref()));
+        }
+
+        if format.has_ascii_column() {
+            let padding = (per_line - line_count) as usize * (format.column_width() + 1);
+            let line_bytes = (line_start * element_size) as usize..((line_start + line_count) * element_size) as usize;
+            let ascii: String = data.get(line_bytes).unwrap_or_default().iter().map(|b| match b {
+                Some(b) if (0x20..0x7f).contains(b) => *b as char,
+                Some(_) => '.',
+                None => '?',
+            }).collect();
+            line += &format!("{:padding$}  {}", "", ascii);
+        }
+
+        if matches!(format, DisplayFormat::PointerSymbols) {
+            let symbol = element(line_start).and_then(|bytes| name_resolution::resolve_address_to_name(u64::from_le_bytes(bytes.try_into().unwrap()), process));
+            if let Some(symbol) = symbol {
+                line += &format!(" {}", symbol);
+            }
+        }
+        lines.push(line);
+    }
+
+    (lines, address.wrapping_add(count * element_size))
+}
+
+// Strings end at a null terminator, at the first character that can't be read, or after max_count characters
+fn format_string(address: u64, max_count: u64, is_wide: bool, memory_source: &dyn MemorySource) -> (Vec<String>, u64) {
+    let char_size: u64 = if is_wide { 2 } else { 1 };
+    let data = memory_source.read_raw_memory(address, (max_count * char_size) as usize);
+    let chars: Vec<u16> = data.chunks_exact(char_size as usize).map(|c| if is_wide { u16::from_le_bytes([c[0], c[1]]) } else { c[0] as u16 }).collect();
+    let length = chars.iter().position(|c| *c == 0);
+    let text = &chars[..length.unwrap_or(chars.len())];
+
+    let line = if chars.is_empty() {
+        format!("{:016X}  ????", address)
+    } else if is_wide {
+        format!("{:016X}  \"{}\"", address, String::from_utf16_lossy(text))
+    } else {
+        // Anything that isn't plain ASCII is shown as a dot, like WinDbg
+        let text: String = text.iter().map(|c| if (0x20..0x7f).contains(c) { *c as u8 as char } else { '.' }).collect();
+        format!("{:016X}  \"{}\"", address, text)
+    };
+
+    // The next string starts after the terminator
+    (vec![line], address.wrapping_add((text.len() as u64 + length.map_or(0, |_| 1)) * char_size))
+}
+
+// A UNICODE_STRING is a length in bytes, a maximum length, and then a pointer to the characters, which don't have to
+// be null terminated
+fn format_unicode_string(address: u64, memory_source: &dyn MemorySource) -> String {
+    let header = memory_source.read_raw_memory(address, 16);
+    if header.len() < 16 {
+        return format!("{:016X}  ????", address);
+    }
+    let length = u16::from_le_bytes([header[0], header[1]]) as usize;
+    let buffer = u64::from_le_bytes(header[8..16].try_into().unwrap());
+    let data = memory_source.read_raw_memory(buffer, length);
+    let chars: Vec<u16> = data.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+    if data.len() < length {
+        format!("{:016X}  \"{}\" (could not read all of the string at {:016X})", address, String::from_utf16_lossy(&chars), buffer)
+    } else {
+        format!("{:016X}  \"{}\"", address, String::from_utf16_lossy(&chars))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_target::FakeMemory;
+
+    fn format(format: DisplayFormat, address: u64, count: Option<u64>, memory: &FakeMemory) -> (Vec<String>, u64) {
+        format_memory(format, address, count, memory, &mut Process::new())
+    }
+
+    #[test]
+    fn unreadable_elements_are_question_marks() {
+        let mut memory = FakeMemory::default();
+        memory.add_region(0x1000, b"ABCDEF");
+        let (lines, next) = format(DisplayFormat::Bytes, 0x1000, Some(10), &memory);
+        assert_eq!(lines, ["0000000000001000  41 42 43 44 45 46 ?? ??-?? ??                    ABCDEF????"]);
+        assert_eq!(next, 0x100a);
+
+        // An element that's only partly readable can't be shown at all
+        let (lines, next) = format(DisplayFormat::Dwords, 0x1000, Some(2), &memory);
+        assert_eq!(lines, ["0000000000001000  44434241 ????????"]);
+        assert_eq!(next, 0x1008);
+        let (lines, _) = format(DisplayFormat::Doubles, 0x1000, Some(1), &memory);
+        assert_eq!(lines, ["0000000000001000          ????????????????"]);
+    }
+
+    #[test]
+    fn range_stops_at_end_of_address_space() {
+        let mut memory = FakeMemory::default();
+        memory.add_region(u64::MAX - 0xf, &[0x11; 0x10]);
+        let (lines, next) = format(DisplayFormat::Qwords, u64::MAX - 0xf, None, &memory);
+        assert_eq!(lines, ["FFFFFFFFFFFFFFF0  1111111111111111 1111111111111111"]);
+        assert_eq!(next, 0);
+
+        let (lines, next) = format(DisplayFormat::UnicodeString, u64::MAX - 0xf, Some(4), &memory);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(next, 0);
+    }
+
+    #[test]
+    fn huge_counts_are_limited_to_the_biggest_read() {
+        assert_eq!(element_count(DisplayFormat::Qwords, 0, Some(u64::MAX)), MAX_READ_SIZE / 8);
+        assert_eq!(element_count(DisplayFormat::Unicode, 0x1000, Some(u64::MAX / 2)), MAX_READ_SIZE / 2);
+        assert_eq!(element_count(DisplayFormat::UnicodeString, u64::MAX - 0x1f, Some(u64::MAX)), 2);
+        assert_eq!(element_count(DisplayFormat::Bytes, 0x1000, None), 0x80);
+    }
+
+    #[test]
+    fn next_string_starts_after_the_terminator() {
+        let mut memory = FakeMemory::default();
+        memory.add_region(0x1000, b"ab\0cd\x01");
+        let (lines, next) = format(DisplayFormat::Ascii, 0x1000, None, &memory);
+        assert_eq!(lines, ["0000000000001000  \"ab\""]);
+        assert_eq!(next, 0x1003);
+
+        // Without a terminator, the string ends where memory can't be read or at the count
+        let (lines, next) = format(DisplayFormat::Ascii, 0x1003, None, &memory);
+        assert_eq!(lines, ["0000000000001003  \"cd.\""]);
+        assert_eq!(next, 0x1006);
+        assert_eq!(format(DisplayFormat::Ascii, 0x1003, Some(2), &memory).1, 0x1005);
+        let (lines, next) = format(DisplayFormat::Ascii, 0x2000, None, &memory);
+        assert_eq!(lines, ["0000000000002000  ????"]);
+        assert_eq!(next, 0x2000);
+
+        let mut memory = FakeMemory::default();
+        memory.add_region(0x1000, &[b'h', 0, b'i', 0, 0, 0, b'x', 0]);
+        let (lines, next) = format(DisplayFormat::Unicode, 0x1000, None, &memory);
+        assert_eq!(lines, ["0000000000001000  \"hi\""]);
+        assert_eq!(next, 0x1006);
+    }
+
+    #[test]
+    fn truncated_unicode_strings_are_flagged() {
+        let mut memory = FakeMemory::default();
+        // Two UNICODE_STRINGs with a length of 8 bytes, but the second buffer only has 4 bytes that can be read
+        memory.add_region(0x1000, &[8, 0, 8, 0, 0, 0, 0, 0, 0x00, 0x20, 0, 0, 0, 0, 0, 0]);
+        memory.add_region(0x1010, &[8, 0, 8, 0, 0, 0, 0, 0, 0x00, 0x30, 0, 0, 0, 0, 0, 0]);
+        memory.add_region(0x1020, &[8, 0]);
+        memory.add_region(0x2000, &[b'a', 0, b'b', 0, b'c', 0, b'd', 0]);
+        memory.add_region(0x3000, &[b'a', 0, b'b', 0]);
+
+        let (lines, next) = format(DisplayFormat::UnicodeString, 0x1000, Some(3), &memory);
+        assert_eq!(lines, [
+            "0000000000001000  \"abcd\"",
+            "0000000000001010  \"ab\" (could not read all of the string at 0000000000003000)",
+            "0000000000001020  ????",
+        ]);
+        assert_eq!(next, 0x1030);
+    }
+}
@@ -0,0 +1,247 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::event::{DebugEvent, EventContext};
+use crate::memory::MemorySource;
+use crate::registers::RegisterContext;
+use crate::target::{ContinueStatus, DebugTarget, MemoryRegion, MemoryRegionInfo};
+
+// A range of the target's address space that was saved in the dump file, or in another file that it refers to
+pub struct DumpMemoryRange {
+    pub address: u64,
+    pub size: u64,
+    pub file_index: usize,
+    pub file_offset: u64,
+}
+
+pub struct DumpMemorySource {
+    files: Vec<RefCell<File>>,
+    // Sorted by address, and not overlapping
+    ranges: Vec<DumpMemoryRange>,
+    // Bytes that the debugger has changed. The file is never written to, so these only last as long as the session.
+    overlay: BTreeMap<u64, u8>,
+}
+
+impl DumpMemorySource {
+    pub fn new(files: Vec<File>, mut ranges: Vec<DumpMemoryRange>) -> DumpMemorySource {
+        ranges.sort_by_key(|r| r.address);
+        DumpMemorySource { files: files.into_iter().map(RefCell::new).collect(), ranges, overlay: BTreeMap::new() }
+    }
+
+    // Only memory that was saved in the dump can be changed, since there's nothing to read anywhere else. Writing
+    // stops at the first byte that isn't in the dump.
+    pub fn write_memory(&mut self, address: u64, data: &[u8]) -> usize {
+        let mut written = 0;
+        for (offset, byte) in data.iter().enumerate() {
+            let Some(byte_address) = address.checked_add(offset as u64).filter(|a| self.find_range(*a).is_some()) else {
+                break;
+            };
+            self.overlay.insert(byte_address, *byte);
+            written += 1;
+        }
+        written
+    }
+
+    fn find_range(&self, address: u64) -> Option<&DumpMemoryRange> {
+        let idx = self.ranges.partition_point(|r| r.address <= address);
+        let range = self.ranges.get(idx.checked_sub(1)?)?;
+        (address - range.address < range.size).then_some(range)
+    }
+
+    fn read_file(&self, file_index: usize, offset: u64, len: usize) -> Vec<u8> {
+        let mut file = self.files[file_index].borrow_mut();
+        let mut data = Vec::with_capacity(len);
+        if file.seek(SeekFrom::Start(offset)).is_ok() {
+            // A truncated dump will just give us less data than we asked for
+            let _ = (&mut *file).take(len as u64).read_to_end(&mut data);
+        }
+        data
+    }
+}
+
+impl MemorySource for DumpMemorySource {
+    fn read_memory(&self, address: u64, len: usize) -> Result<Vec<Option<u8>>, &'static str> {
+        let mut data: Vec<Option<u8>> = Vec::with_capacity(len);
+
+        while data.len() < len {
+            let len_left = len - data.len();
+            // Nothing past the end of the address space is in the dump
+            let Some(cur_address) = address.checked_add(data.len() as u64) else {
+                data.extend(std::iter::repeat_n(None, len_left));
+                break;
+            };
+            match self.find_range(cur_address) {
+                Some(range) => {
+                    let range_offset = cur_address - range.address;
+                    let chunk_len = std::cmp::min(len_left as u64, range.size - range_offset) as usize;
+                    // The file offset comes from the dump, so a range that runs past the end of any file isn't there
+                    let chunk = match range.file_offset.checked_add(range_offset) {
+                        Some(file_offset) => self.read_file(range.file_index, file_offset, chunk_len),
+                        None => Vec::new(),
+                    };
+                    let read_len = chunk.len();
+                    data.extend(chunk.into_iter().map(Some));
+                    data.extend(std::iter::repeat_n(None, chunk_len - read_len));
+                }
+                None => {
+                    // Skip ahead to the next range that was saved, if there is one
+                    let next_range = self.ranges.iter().map(|r| r.address).find(|a| *a > cur_address);
+                    let gap = next_range.map_or(len_left as u64, |a| std::cmp::min(len_left as u64, a - cur_address));
+                    data.extend(std::iter::repeat_n(None, gap as usize));
+                }
+            }
+        }
+
+        let end = address.saturating_add(len as u64);
+        for (byte_address, byte) in self.overlay.range(address..end) {
+            data[(byte_address - address) as usize] = Some(*byte);
+        }
+
+        Ok(data)
+    }
+
+    fn read_raw_memory(&self, address: u64, len: usize) -> Vec<u8> {
+        match self.read_memory(address, len) {
+            Ok(data) => data.into_iter().map_while(|b| b).collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+// A target that was saved to a file, such as a minidump or an image file. Nothing can run, so all of the events that
+// describe the process are queued up front, followed by the event that the dump was captured for.
+pub struct DumpTarget {
+    memory: DumpMemorySource,
+    threads: Vec<(u32, RegisterContext)>,
+    events: VecDeque<(EventContext, DebugEvent)>,
+    // Only some dumps describe the whole address space, and only dumps of Windows processes have TEBs
+    memory_map: Option<Vec<MemoryRegionInfo>>,
+    thread_tebs: HashMap<u32, u64>,
+}
+
+impl DumpTarget {
+    // The first module is assumed to be the executable. The event thread is the thread that the stop event applies
+    // to, or the first thread if there isn't one.
+    pub fn new(memory: DumpMemorySource, process_id: u32, threads: Vec<(u32, RegisterContext)>, modules: Vec<(u64, Option<String>)>, stop_event: Option<(u32, DebugEvent)>) -> Result<DumpTarget, &'static str> {
+        let first_thread = threads.first().map(|(thread_id, _)| *thread_id).ok_or("Dump does not contain any threads")?;
+        let event_thread = stop_event.as_ref().map_or(first_thread, |(thread_id, _)| *thread_id);
+        let ctx = EventContext { process_id, thread_id: event_thread };
+
+        let mut events = VecDeque::new();
+        let mut modules = modules.into_iter();
+        match modules.next() {
+            Some((exe_base, exe_name)) => events.push_back((ctx, DebugEvent::CreateProcess { exe_name, exe_base })),
+            // Raw memory images don't have any modules, but the thread still needs to be reported
+            None => events.push_back((ctx, DebugEvent::CreateThread { thread_id: event_thread })),
+        }
+        for (module_base, module_name) in modules {
+            events.push_back((ctx, DebugEvent::LoadModule { module_name, module_base }));
+        }
+        for (thread_id, _) in threads.iter().filter(|(thread_id, _)| *thread_id != event_thread) {
+            events.push_back((ctx, DebugEvent::CreateThread { thread_id: *thread_id }));
+        }
+        let stop_event = stop_event.map_or(DebugEvent::Other("Dump does not contain an exception".to_string()), |(_, event)| event);
+        events.push_back((ctx, stop_event));
+
+        Ok(DumpTarget { memory, threads, events, memory_map: None, thread_tebs: HashMap::new() })
+    }
+
+    pub fn set_memory_map(&mut self, memory_map: Vec<MemoryRegionInfo>) {
+        self.memory_map = Some(memory_map);
+    }
+
+    pub fn set_thread_tebs(&mut self, thread_tebs: HashMap<u32, u64>) {
+        self.thread_tebs = thread_tebs;
+    }
+}
+
+impl DebugTarget for DumpTarget {
+    fn wait_for_event(&mut self) -> Result<(EventContext, DebugEvent), &'static str> {
+        self.events.pop_front().ok_or("Dump files can't be resumed")
+    }
+
+    fn memory_source(&self) -> &dyn MemorySource {
+        &self.memory
+    }
+
+    fn write_memory(&mut self, address: u64, data: &[u8]) -> Result<usize, &'static str> {
+        match self.memory.write_memory(address, data) {
+            0 if !data.is_empty() => Err("Memory is not in the dump"),
+            written => Ok(written),
+        }
+    }
+
+    fn get_thread_context(&self, thread_id: u32) -> Result<RegisterContext, &'static str> {
+        self.threads.iter().find(|(t, _)| *t == thread_id).map(|(_, context)| *context).ok_or("Thread is not in the dump")
+    }
+
+    fn set_thread_context(&mut self, _thread_id: u32, _context: &RegisterContext) -> Result<(), &'static str> {
+        Err("Dump files are read only")
+    }
+
+    fn continue_event(&mut self, _event_context: &EventContext, _status: ContinueStatus) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    fn memory_regions(&self) -> Result<Vec<MemoryRegion>, &'static str> {
+        Ok(self.memory.ranges.iter().map(|r| MemoryRegion { address: r.address, size: r.size }).collect())
+    }
+
+    fn memory_map(&self) -> Result<Vec<MemoryRegionInfo>, &'static str> {
+        // Without a memory info list, all we know is which memory was saved
+        match &self.memory_map {
+            Some(memory_map) => Ok(memory_map.clone()),
+            None => Ok(self.memory_regions()?.iter().map(MemoryRegionInfo::committed).collect()),
+        }
+    }
+
+    fn thread_environment_block(&self, thread_id: u32) -> Option<u64> {
+        self.thread_tebs.get(&thread_id).copied()
+    }
+
+    fn is_live(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_target::temp_file_path;
+
+    fn source_with_ranges(name: &str, ranges: Vec<DumpMemoryRange>) -> (DumpMemorySource, String) {
+        let path = temp_file_path(name);
+        std::fs::write(&path, (0..0x100).map(|i| i as u8).collect::<Vec<u8>>()).unwrap();
+        (DumpMemorySource::new(vec![File::open(&path).unwrap()], ranges), path)
+    }
+
+    #[test]
+    fn read_at_end_of_address_space() {
+        let address = u64::MAX - 0xf;
+        let (mut source, path) = source_with_ranges("end.bin", vec![DumpMemoryRange { address, size: 0x10, file_index: 0, file_offset: 0 }]);
+
+        let data = source.read_memory(address, 0x20).unwrap();
+        assert_eq!(data[..0x10], (0..0x10).map(Some).collect::<Vec<_>>());
+        assert_eq!(data[0x10..], [None; 0x10]);
+        assert_eq!(source.write_memory(address, &[0xaa; 0x20]), 0x10);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn range_with_huge_file_offset_is_unreadable() {
+        let ranges = vec![
+            DumpMemoryRange { address: 0x1000, size: 0x10, file_index: 0, file_offset: u64::MAX - 4 },
+            DumpMemoryRange { address: 0x1010, size: 0x10, file_index: 0, file_offset: 0x20 },
+        ];
+        let (source, path) = source_with_ranges("offset.bin", ranges);
+
+        let data = source.read_memory(0x1008, 0x18).unwrap();
+        assert_eq!(data[..0x8], [None; 0x8]);
+        assert_eq!(data[0x8..], (0x20..0x30).map(Some).collect::<Vec<_>>());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,257 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use gimli::{AttributeValue, EndianSlice, LineProgramHeader, RunTimeEndian, Unit};
+use object::{BinaryFormat, Object, ObjectSection, ObjectSegment};
+
+type Reader<'a> = EndianSlice<'a, RunTimeEndian>;
+type Dwarf<'a> = gimli::Dwarf<Reader<'a>>;
+
+struct Function {
+    low: u64,
+    high: u64,
+    name: String,
+}
+
+#[derive(Clone, Copy)]
+struct LineRow {
+    address: u64,
+    file: usize,
+    line: u32,
+    is_stmt: bool,
+    // Marks the first address after a contiguous run of code, so it doesn't map to any line
+    end_sequence: bool,
+}
+
+// The parts of the DWARF debug info that we use for symbols and source lines. Everything is pulled out of the file when
+// the module is loaded, so we don't need to keep the file data around. All addresses here are the link-time addresses,
+// so they need the load bias added to match addresses in the process.
+pub struct DwarfInfo {
+    load_bias: u64,
+    functions: Vec<Function>,
+    files: Vec<String>,
+    lines: Vec<LineRow>,
+}
+
+impl DwarfInfo {
+    // Loads the DWARF info for an ELF or PE (e.g. MinGW) image file that is loaded at module_address. Returns None if
+    // the file doesn't have any debug info.
+    pub fn load(path: &str, module_address: u64) -> Result<Option<DwarfInfo>> {
+        let data = std::fs::read(path)?;
+        let file = object::File::parse(&*data)?;
+
+        let load_bias = load_bias(&file, module_address);
+
+        if file.section_by_name(".debug_info").is_some() {
+            return DwarfInfo::from_object(&file, load_bias).map(Some);
+        }
+
+        // Linux distributions usually ship the debug info separately, in a file named after the build ID
+        if let Ok(Some(build_id)) = file.build_id() {
+            if let Some((first, rest)) = build_id.split_first() {
+                let hex: String = rest.iter().map(|b| format!("{:02x}", b)).collect();
+                let debug_path = format!("/usr/lib/debug/.build-id/{:02x}/{}.debug", first, hex);
+                if let Ok(debug_data) = std::fs::read(debug_path) {
+                    let debug_file = object::File::parse(&*debug_data)?;
+                    return DwarfInfo::from_object(&debug_file, load_bias).map(Some);
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn from_object(file: &object::File, load_bias: u64) -> Result<DwarfInfo> {
+        let endian = if file.is_little_endian() { RunTimeEndian::Little } else { RunTimeEndian::Big };
+        let sections = gimli::DwarfSections::load(|id| -> Result<Cow<[u8]>> {
+            match file.section_by_name(id.name()) {
+                Some(section) => Ok(section.uncompressed_data()?),
+                None => Ok(Cow::Borrowed(&[])),
+            }
+        })?;
+        let dwarf = sections.borrow(|section| EndianSlice::new(section, endian));
+
+        let mut info = DwarfInfo { load_bias, functions: Vec::new(), files: Vec::new(), lines: Vec::new() };
+        let mut file_ids: HashMap<String, usize> = HashMap::new();
+
+        let mut units = dwarf.units();
+        while let Some(header) = units.next()? {
+            let unit = dwarf.unit(header)?;
+            info.read_functions(&dwarf, &unit)?;
+            info.read_lines(&dwarf, &unit, &mut file_ids)?;
+        }
+
+        info.functions.sort_by_key(|f| f.low);
+        // End markers go before any row that starts at the same address, so they don't hide the start of the next run
+        info.lines.sort_by_key(|r| (r.address, !r.end_sequence));
+
+        Ok(info)
+    }
+
+    fn read_functions(&mut self, dwarf: &Dwarf, unit: &Unit<Reader>) -> Result<()> {
+        let mut entries = unit.entries();
+        while let Some((_, entry)) = entries.next_dfs()? {
+            if entry.tag() != gimli::DW_TAG_subprogram {
+                continue;
+            }
+
+            let name = match function_name(dwarf, unit, entry)? {
+                Some(name) => name,
+                None => continue,
+            };
+
+            let mut ranges = dwarf.die_ranges(unit, entry)?;
+            while let Some(range) = ranges.next()? {
+                // Functions that were discarded by the linker end up at address 0
+                if range.begin != 0 && range.begin < range.end {
+                    self.functions.push(Function { low: range.begin, high: range.end, name: name.clone() });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn read_lines(&mut self, dwarf: &Dwarf, unit: &Unit<Reader>, file_ids: &mut HashMap<String, usize>) -> Result<()> {
+        let program = match unit.line_program.clone() {
+            Some(program) => program,
+            None => return Ok(()),
+        };
+
+        // The file indices are per unit, so map them to our own list of files
+        let mut unit_files: HashMap<u64, usize> = HashMap::new();
+        let mut sequence: Vec<LineRow> = Vec::new();
+        let mut rows = program.rows();
+        while let Some((header, row)) = rows.next_row()? {
+            let file = match unit_files.get(&row.file_index()) {
+                Some(&file) => file,
+                None => {
+                    let path = file_path(dwarf, unit, header, row.file_index()).unwrap_or_default();
+                    let next_id = self.files.len();
+                    let file = *file_ids.entry(path.clone()).or_insert(next_id);
+                    if file == next_id {
+                        self.files.push(path);
+                    }
+                    unit_files.insert(row.file_index(), file);
+                    file
+                }
+            };
+
+            sequence.push(LineRow {
+                address: row.address(),
+                file,
+                line: row.line().map_or(0, |l| l.get() as u32),
+                is_stmt: row.is_stmt(),
+                end_sequence: row.end_sequence(),
+            });
+
+            if row.end_sequence() {
+                // Like functions, sequences for code that the linker discarded start at address 0
+                if sequence.first().is_some_and(|r| r.address != 0) {
+                    self.lines.append(&mut sequence);
+                }
+                sequence.clear();
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the name and start address of the function that contains the address
+    pub fn find_function(&self, address: u64) -> Option<(&str, u64)> {
+        let address = address.wrapping_sub(self.load_bias);
+        self.functions.iter()
+            .filter(|f| f.low <= address && address < f.high)
+            .max_by_key(|f| f.low)
+            .map(|f| (f.name.as_str(), f.low.wrapping_add(self.load_bias)))
+    }
+
+    // Returns the name and start address of every function
+    pub fn functions(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.functions.iter().map(|f| (f.name.as_str(), f.low.wrapping_add(self.load_bias)))
+    }
+
+    pub fn find_function_by_name(&self, name: &str) -> Option<u64> {
+        self.functions.iter().find(|f| f.name == name).map(|f| f.low.wrapping_add(self.load_bias))
+    }
+
+    pub fn find_line(&self, address: u64) -> Option<(&str, u32)> {
+        let address = address.wrapping_sub(self.load_bias);
+        let index = self.lines.partition_point(|r| r.address <= address);
+        let row = self.lines.get(index.checked_sub(1)?)?;
+        if row.end_sequence {
+            return None;
+        }
+        Some((self.files[row.file].as_str(), row.line))
+    }
+
+    // Finds the lowest address for a line. If the line doesn't have any code, this uses the next line that does.
+    pub fn find_line_address(&self, src_file: &str, src_line: u32) -> Option<u64> {
+        let mut best: Option<&LineRow> = None;
+        for row in self.lines.iter() {
+            if row.end_sequence || !row.is_stmt || row.line < src_line || !file_matches(&self.files[row.file], src_file) {
+                continue;
+            }
+            let is_better = match best {
+                None => true,
+                Some(best) => (row.line, row.address) < (best.line, best.address),
+            };
+            if is_better {
+                best = Some(row);
+            }
+        }
+        best.map(|r| r.address.wrapping_add(self.load_bias))
+    }
+}
+
+// The difference between the addresses the image was linked at and where it's loaded. PE addresses in DWARF include the
+// preferred image base. ELF addresses are relative to the address the first segment was linked at, which is usually 0
+// for shared objects and position independent executables.
+pub fn load_bias(file: &object::File, module_address: u64) -> u64 {
+    let link_base = match file.format() {
+        BinaryFormat::Pe => file.relative_address_base(),
+        _ => file.segments().map(|s| s.address() & !0xfff).min().unwrap_or(0),
+    };
+    module_address.wrapping_sub(link_base)
+}
+
+fn function_name(dwarf: &Dwarf, unit: &Unit<Reader>, entry: &gimli::DebuggingInformationEntry<Reader>) -> Result<Option<String>> {
+    if let Some(name) = entry.attr_value(gimli::DW_AT_name)? {
+        return Ok(Some(dwarf.attr_string(unit, name)?.to_string_lossy().into_owned()));
+    }
+
+    // Out of line definitions and concrete instances of inlined functions get their name from another entry
+    for attr in [gimli::DW_AT_specification, gimli::DW_AT_abstract_origin] {
+        if let Some(AttributeValue::UnitRef(offset)) = entry.attr_value(attr)? {
+            let origin = unit.entry(offset)?;
+            return function_name(dwarf, unit, &origin);
+        }
+    }
+    Ok(None)
+}
+
+fn file_path(dwarf: &Dwarf, unit: &Unit<Reader>, header: &LineProgramHeader<Reader>, file_index: u64) -> Option<String> {
+    let file = header.file(file_index)?;
+    // Each part of the path is relative to the one before it, unless it's absolute
+    let mut path = PathBuf::new();
+    if let Some(comp_dir) = &unit.comp_dir {
+        path.push(comp_dir.to_string_lossy().as_ref());
+    }
+    if let Some(directory) = file.directory(header) {
+        path.push(dwarf.attr_string(unit, directory).ok()?.to_string_lossy().as_ref());
+    }
+    path.push(dwarf.attr_string(unit, file.path_name()).ok()?.to_string_lossy().as_ref());
+    Some(path.to_string_lossy().into_owned())
+}
+
+// DWARF usually has full paths, so we let the user give just the end of the path
+fn file_matches(path: &str, src_file: &str) -> bool {
+    if path == src_file {
+        return true;
+    }
+    match path.strip_suffix(src_file) {
+        Some(rest) => rest.ends_with('/') || rest.ends_with('\\'),
+        None => false,
+    }
+}
+
@@ -0,0 +1,168 @@
+use crate::target::DebugTarget;
+
+// The edit commands either change all of the memory or report an error, so a short write counts as a failure. Some
+// of the bytes before the failure will still have been changed.
+fn write_all(target: &mut dyn DebugTarget, address: u64, data: &[u8]) -> Result<(), String> {
+    let written = target.write_memory(address, data).map_err(|e| e.to_string())?;
+    if written < data.len() {
+        return Err(format!("Memory access error at {:#018x}", address + written as u64));
+    }
+    Ok(())
+}
+
+// eb, ew, ed and eq write each value as an element of the given size
+pub fn enter_values(target: &mut dyn DebugTarget, address: u64, values: &[u64], element_size: usize) -> Result<(), String> {
+    let mut data = Vec::with_capacity(values.len() * element_size);
+    for value in values {
+        if element_size < 8 && *value >> (element_size * 8) != 0 {
+            return Err(format!("{:#x} is too large for a {} byte value", value, element_size));
+        }
+        data.extend_from_slice(&value.to_le_bytes()[..element_size]);
+    }
+    write_all(target, address, &data)
+}
+
+// Like WinDbg, ea and eu don't add a null terminator
+pub fn enter_string(target: &mut dyn DebugTarget, address: u64, text: &str, is_wide: bool) -> Result<(), String> {
+    let data: Vec<u8> = if is_wide {
+        text.encode_utf16().flat_map(|c| c.to_le_bytes()).collect()
+    } else {
+        text.as_bytes().to_vec()
+    };
+    write_all(target, address, &data)
+}
+
+// Repeats the pattern over the range, cutting it short at the end if it doesn't fit evenly
+pub fn fill(target: &mut dyn DebugTarget, address: u64, len: u64, pattern: &[u64]) -> Result<(), String> {
+    let pattern = pattern.iter().map(|b| u8::try_from(*b).map_err(|_| format!("{:#x} is too large for a byte", b))).collect::<Result<Vec<u8>, String>>()?;
+    let data: Vec<u8> = pattern.iter().copied().cycle().take(len as usize).collect();
+    write_all(target, address, &data)
+}
+
+// The whole source range is read before anything is written, so it's fine for the ranges to overlap
+pub fn move_memory(target: &mut dyn DebugTarget, source: u64, len: u64, destination: u64) -> Result<(), String> {
+    let data = target.memory_source().read_raw_memory(source, len as usize);
+    if data.len() < len as usize {
+        return Err(format!("Memory access error at {:#018x}", source + data.len() as u64));
+    }
+    write_all(target, destination, &data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::grammar::{self, CommandExpr, EvalExpr, RangeExpr};
+    use crate::fake_target::FakeTarget;
+
+    const ADDRESS: u64 = 0x2000;
+
+    // 0x10 writable bytes, with nothing mapped after them
+    fn target() -> FakeTarget {
+        let mut target = FakeTarget::new(0x1000, 0x1010);
+        target.add_memory(ADDRESS, &[0xff; 0x10]);
+        target
+    }
+
+    fn read(target: &FakeTarget, len: usize) -> Vec<u8> {
+        target.memory_source().read_raw_memory(ADDRESS, len)
+    }
+
+    fn number(expr: &EvalExpr) -> u64 {
+        match expr {
+            EvalExpr::Number(value) => *value,
+            _ => panic!("Not a number"),
+        }
+    }
+
+    // Runs an edit command the way the command loop does, for commands that only use numbers
+    fn run(target: &mut FakeTarget, command: &str) -> Result<(), String> {
+        match grammar::parse(command).unwrap() {
+            CommandExpr::EnterValues(element_size, address, values) => {
+                enter_values(target, number(&address), &values.iter().map(number).collect::<Vec<u64>>(), element_size)
+            }
+            CommandExpr::Fill(_, RangeExpr::Count(address, len), pattern) => {
+                fill(target, number(&address), len, &pattern.iter().map(number).collect::<Vec<u64>>())
+            }
+            _ => panic!("Not an edit command"),
+        }
+    }
+
+    #[test]
+    fn commands_pick_the_element_size() {
+        let mut target = target();
+        run(&mut target, "eb 0x2000 1 2").unwrap();
+        assert_eq!(read(&target, 4), [1, 2, 0xff, 0xff]);
+        run(&mut target, "ed 0x2000 0x11223344 5").unwrap();
+        assert_eq!(read(&target, 9), [0x44, 0x33, 0x22, 0x11, 5, 0, 0, 0, 0xff]);
+        run(&mut target, "eq 0x2008 0x0102030405060708").unwrap();
+        assert_eq!(read(&target, 0x10)[8..], [8, 7, 6, 5, 4, 3, 2, 1]);
+        assert!(run(&mut target, "eb 0x2000 0x100").is_err());
+
+        run(&mut target, "f 0x2000 L5 0xaa 0xbb").unwrap();
+        assert_eq!(read(&target, 6), [0xaa, 0xbb, 0xaa, 0xbb, 0xaa, 0]);
+    }
+
+    #[test]
+    fn values_are_written_at_their_element_size() {
+        let mut target = target();
+        enter_values(&mut target, ADDRESS, &[1, 2], 1).unwrap();
+        assert_eq!(read(&target, 4), [1, 2, 0xff, 0xff]);
+        enter_values(&mut target, ADDRESS, &[0x1234], 2).unwrap();
+        assert_eq!(read(&target, 4), [0x34, 0x12, 0xff, 0xff]);
+        enter_values(&mut target, ADDRESS, &[0x1122_3344, 5], 4).unwrap();
+        assert_eq!(read(&target, 9), [0x44, 0x33, 0x22, 0x11, 5, 0, 0, 0, 0xff]);
+        enter_values(&mut target, ADDRESS + 8, &[u64::MAX], 8).unwrap();
+        assert_eq!(read(&target, 0x10)[8..], [0xff; 8]);
+    }
+
+    #[test]
+    fn values_too_large_for_the_element_are_rejected() {
+        let mut target = target();
+        assert!(enter_values(&mut target, ADDRESS, &[1, 0x100], 1).is_err());
+        assert!(enter_values(&mut target, ADDRESS, &[0x1_0000], 2).is_err());
+        assert!(enter_values(&mut target, ADDRESS, &[0x1_0000_0000], 4).is_err());
+        // Nothing is written unless every value fits
+        assert_eq!(read(&target, 4), [0xff; 4]);
+    }
+
+    #[test]
+    fn fill_pattern_wraps_and_is_cut_short() {
+        let mut target = target();
+        fill(&mut target, ADDRESS, 7, &[1, 2, 3]).unwrap();
+        assert_eq!(read(&target, 8), [1, 2, 3, 1, 2, 3, 1, 0xff]);
+        fill(&mut target, ADDRESS, 2, &[4, 5, 6]).unwrap();
+        assert_eq!(read(&target, 4), [4, 5, 3, 1]);
+        assert!(fill(&mut target, ADDRESS, 4, &[1, 0x100]).is_err());
+        assert_eq!(read(&target, 4), [4, 5, 3, 1]);
+    }
+
+    #[test]
+    fn strings_are_written_without_a_terminator() {
+        let mut target = target();
+        enter_string(&mut target, ADDRESS, "ab", false).unwrap();
+        assert_eq!(read(&target, 3), [b'a', b'b', 0xff]);
+        enter_string(&mut target, ADDRESS, "\u{20AC}c", true).unwrap();
+        assert_eq!(read(&target, 5), [0xac, 0x20, b'c', 0, 0xff]);
+    }
+
+    #[test]
+    fn partial_writes_are_errors() {
+        let mut target = target();
+        let error = enter_values(&mut target, ADDRESS + 0xc, &[1, 2], 4).unwrap_err();
+        assert_eq!(error, "Memory access error at 0x0000000000002010");
+        // The bytes before the failure have still been written
+        assert_eq!(read(&target, 0x10)[0xc..], [1, 0, 0, 0]);
+
+        assert!(fill(&mut target, ADDRESS + 0xe, 4, &[0]).is_err());
+        assert_eq!(read(&target, 0x10)[0xe..], [0, 0]);
+        assert!(move_memory(&mut target, ADDRESS + 8, 0x10, ADDRESS).is_err());
+    }
+
+    #[test]
+    fn overlapping_moves_copy_the_original_bytes() {
+        let mut target = target();
+        enter_values(&mut target, ADDRESS, &[1, 2, 3, 4], 1).unwrap();
+        move_memory(&mut target, ADDRESS, 4, ADDRESS + 2).unwrap();
+        assert_eq!(read(&target, 7), [1, 2, 1, 2, 3, 4, 0xff]);
+    }
+}
@@ -0,0 +1,385 @@
+use crate::memory::{self, impl_pod, MemorySource, Pod};
+use crate::module::{Export, ExportTarget};
+
+pub const ELF_MAGIC: &[u8] = b"\x7fELF";
+
+const ELFCLASS64: u8 = 2;
+const EM_X86_64: u16 = 62;
+pub const ET_EXEC: u16 = 2;
+pub const ET_CORE: u16 = 4;
+
+pub const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+pub const PT_NOTE: u32 = 4;
+
+const DT_NULL: i64 = 0;
+const DT_HASH: i64 = 4;
+const DT_STRTAB: i64 = 5;
+const DT_SYMTAB: i64 = 6;
+const DT_STRSZ: i64 = 10;
+const DT_SONAME: i64 = 14;
+const DT_GNU_HASH: i64 = 0x6ffffef5;
+
+const SHN_UNDEF: u16 = 0;
+const STB_GLOBAL: u8 = 1;
+const STB_WEAK: u8 = 2;
+const STB_GNU_UNIQUE: u8 = 10;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_GNU_IFUNC: u8 = 10;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+#[allow(non_camel_case_types)]
+pub struct Elf64_Ehdr {
+    pub e_ident: [u8; 16],
+    pub e_type: u16,
+    pub e_machine: u16,
+    pub e_version: u32,
+    pub e_entry: u64,
+    pub e_phoff: u64,
+    pub e_shoff: u64,
+    pub e_flags: u32,
+    pub e_ehsize: u16,
+    pub e_phentsize: u16,
+    pub e_phnum: u16,
+    pub e_shentsize: u16,
+    pub e_shnum: u16,
+    pub e_shstrndx: u16,
+}
+
+impl_pod!(Elf64_Ehdr {
+    e_ident: [u8; 16], e_type: u16, e_machine: u16, e_version: u32, e_entry: u64, e_phoff: u64, e_shoff: u64,
+    e_flags: u32, e_ehsize: u16, e_phentsize: u16, e_phnum: u16, e_shentsize: u16, e_shnum: u16, e_shstrndx: u16,
+});
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+#[allow(non_camel_case_types)]
+pub struct Elf64_Phdr {
+    pub p_type: u32,
+    pub p_flags: u32,
+    pub p_offset: u64,
+    pub p_vaddr: u64,
+    pub p_paddr: u64,
+    pub p_filesz: u64,
+    pub p_memsz: u64,
+    pub p_align: u64,
+}
+
+impl_pod!(Elf64_Phdr {
+    p_type: u32, p_flags: u32, p_offset: u64, p_vaddr: u64, p_paddr: u64, p_filesz: u64, p_memsz: u64, p_align: u64,
+});
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+#[allow(non_camel_case_types)]
+struct Elf64_Dyn {
+    d_tag: i64,
+    d_val: u64,
+}
+
+impl_pod!(Elf64_Dyn { d_tag: i64, d_val: u64 });
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+#[allow(non_camel_case_types)]
+struct Elf64_Sym {
+    st_name: u32,
+    st_info: u8,
+    st_other: u8,
+    st_shndx: u16,
+    st_value: u64,
+    st_size: u64,
+}
+
+impl_pod!(Elf64_Sym { st_name: u32, st_info: u8, st_other: u8, st_shndx: u16, st_value: u64, st_size: u64 });
+
+// What we learn about an ELF image from its program headers and dynamic section
+pub struct ElfImage {
+    pub size: u64,
+    pub exports: Vec<Export>,
+    pub soname: Option<String>,
+}
+
+pub fn read_elf_header(memory_source: &dyn MemorySource, address: u64) -> Result<Elf64_Ehdr, &'static str> {
+    let header: Elf64_Ehdr = memory::read_memory_data(memory_source, address)?;
+    if &header.e_ident[0..4] != ELF_MAGIC {
+        return Err("Not an ELF image");
+    }
+    if header.e_ident[4] != ELFCLASS64 || header.e_machine != EM_X86_64 {
+        return Err("Unsupported machine architecture for module");
+    }
+    Ok(header)
+}
+
+pub fn read_program_headers(memory_source: &dyn MemorySource, header_address: u64, header: &Elf64_Ehdr) -> Result<Vec<Elf64_Phdr>, &'static str> {
+    // The program headers are almost always in the first page, which means they're mapped along with the ELF header.
+    Ok(memory::read_memory_full_array::<Elf64_Phdr>(memory_source, header_address.wrapping_add(header.e_phoff), header.e_phnum as usize)?)
+}
+
+// Reads an ELF image that has been mapped at module_address, the way the loader would have laid it out.
+pub fn read_elf_image(module_address: u64, memory_source: &dyn MemorySource) -> Result<ElfImage, &'static str> {
+    let header = read_elf_header(memory_source, module_address)?;
+    let program_headers = read_program_headers(memory_source, module_address, &header)?;
+
+    let loads: Vec<&Elf64_Phdr> = program_headers.iter().filter(|p| p.p_type == PT_LOAD).collect();
+    let image_start = loads.iter().map(|p| p.p_vaddr & !(PAGE_SIZE - 1)).min().ok_or("ELF image has no loadable segments")?;
+    let mut image_end = image_start;
+    for p in loads.iter() {
+        image_end = std::cmp::max(image_end, p.p_vaddr.checked_add(p.p_memsz).ok_or("ELF image has an invalid segment")?);
+    }
+    let size = (image_end - image_start).checked_add(PAGE_SIZE - 1).ok_or("ELF image has an invalid segment")? & !(PAGE_SIZE - 1);
+
+    // Executables are loaded at the address they were linked at, everything else is relative to where it was mapped.
+    // The bias can be negative, so it's added with wrapping arithmetic.
+    let load_bias = if header.e_type == ET_EXEC { 0 } else { module_address.wrapping_sub(image_start) };
+
+    let mut exports = Vec::new();
+    let mut soname = None;
+    if let Some(dynamic) = program_headers.iter().find(|p| p.p_type == PT_DYNAMIC) {
+        let entries = memory::read_memory_array::<Elf64_Dyn>(memory_source, load_bias.wrapping_add(dynamic.p_vaddr), (dynamic.p_memsz as usize) / Elf64_Dyn::SIZE)?;
+        let find_entry = |tag: i64| entries.iter().take_while(|d| d.d_tag != DT_NULL).find(|d| d.d_tag == tag).map(|d| d.d_val);
+
+        // The dynamic loader relocates the pointers in the dynamic section in place, but only once it gets around to
+        // processing the module (and never for the vDSO). Anything that's below the image must still be unrelocated.
+        let to_address = |ptr: u64| if ptr < module_address { ptr.wrapping_add(load_bias) } else { ptr };
+
+        if let (Some(symtab), Some(strtab)) = (find_entry(DT_SYMTAB), find_entry(DT_STRTAB)) {
+            let symtab = to_address(symtab);
+            let strtab = to_address(strtab);
+            let strsz = find_entry(DT_STRSZ).unwrap_or(0) as usize;
+            let string_table = memory_source.read_raw_memory(strtab, strsz);
+
+            let symbol_count = if let Some(hash) = find_entry(DT_GNU_HASH) {
+                gnu_hash_symbol_count(memory_source, to_address(hash))?
+            } else if let Some(hash) = find_entry(DT_HASH) {
+                // The second word is nchain, which is the same as the number of symbols
+                memory::read_memory_data::<u32>(memory_source, to_address(hash).wrapping_add(4))? as usize
+            } else {
+                0
+            };
+
+            let symbols = memory::read_memory_full_array::<Elf64_Sym>(memory_source, symtab, symbol_count)?;
+            for (index, symbol) in symbols.iter().enumerate() {
+                let binding = symbol.st_info >> 4;
+                let symbol_type = symbol.st_info & 0xf;
+                let is_global = matches!(binding, STB_GLOBAL | STB_WEAK | STB_GNU_UNIQUE);
+                let is_code_or_data = matches!(symbol_type, STT_FUNC | STT_OBJECT | STT_GNU_IFUNC);
+                if symbol.st_shndx == SHN_UNDEF || !is_global || !is_code_or_data {
+                    continue;
+                }
+
+                let name = read_table_string(&string_table, symbol.st_name as usize);
+                // ELF doesn't have ordinals, but the symbol index serves the same purpose
+                exports.push(Export { name, ordinal: index as u32, target: ExportTarget::RVA(load_bias.wrapping_add(symbol.st_value)) });
+            }
+
+            if let Some(soname_offset) = find_entry(DT_SONAME) {
+                soname = read_table_string(&string_table, soname_offset as usize);
+            }
+        }
+    }
+
+    Ok(ElfImage { size, exports, soname })
+}
+
+fn read_table_string(string_table: &[u8], offset: usize) -> Option<String> {
+    let bytes = string_table.get(offset..)?;
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    Some(String::from_utf8_lossy(&bytes[..end]).to_string())
+}
+
+// DT_GNU_HASH doesn't record the number of symbols, so we have to find the end of the longest hash chain. Symbols
+// before symoffset aren't in the hash table at all.
+fn gnu_hash_symbol_count(memory_source: &dyn MemorySource, hash_address: u64) -> Result<usize, &'static str> {
+    let header = memory::read_memory_full_array::<u32>(memory_source, hash_address, 4)?;
+    let (bucket_count, symoffset, bloom_size) = (header[0] as u64, header[1], header[2] as u64);
+    let buckets_address = hash_address.wrapping_add(16 + bloom_size * 8);
+    let buckets = memory::read_memory_full_array::<u32>(memory_source, buckets_address, bucket_count as usize)?;
+    let chains_address = buckets_address.wrapping_add(bucket_count * 4);
+
+    let last_bucket = match buckets.iter().max() {
+        Some(&max) if max >= symoffset => max,
+        _ => return Ok(symoffset as usize),
+    };
+
+    let mut index = last_bucket;
+    loop {
+        let chain = memory::read_memory_data::<u32>(memory_source, chains_address.wrapping_add(((index - symoffset) as u64) * 4))?;
+        // The low bit marks the end of a chain
+        if chain & 1 == 1 {
+            break;
+        }
+        index = index.checked_add(1).ok_or("GNU hash chain does not end")?;
+    }
+
+    Ok(index as usize + 1)
+}
+
+// A loaded segment of an ELF file. Anything past the file size is zero-filled, like .bss.
+struct FileSegment {
+    address: u64,
+    memory_size: u64,
+    file_offset: u64,
+    file_size: u64,
+}
+
+// Presents an ELF file on disk as if it had been mapped by the loader at a given base address, so that the same code
+// can read modules out of either process memory or a file.
+pub struct ElfFileView {
+    data: Vec<u8>,
+    segments: Vec<FileSegment>,
+}
+
+impl ElfFileView {
+    pub fn open(path: &str, base_address: u64) -> Result<ElfFileView, &'static str> {
+        let data = std::fs::read(path).map_err(|_| "Could not read file")?;
+        let whole_file = FileSegment { address: 0, memory_size: data.len() as u64, file_offset: 0, file_size: data.len() as u64 };
+        let raw = ElfFileView { segments: vec![whole_file], data };
+
+        // Read the headers using file offsets first, then we can figure out where everything would be mapped
+        let header = read_elf_header(&raw, 0)?;
+        let program_headers = read_program_headers(&raw, 0, &header)?;
+        let image_start = program_headers.iter().filter(|p| p.p_type == PT_LOAD).map(|p| p.p_vaddr & !(PAGE_SIZE - 1)).min().ok_or("ELF image has no loadable segments")?;
+        let load_bias = if header.e_type == ET_EXEC { 0 } else { base_address.wrapping_sub(image_start) };
+
+        let segments = program_headers.iter().filter(|p| p.p_type == PT_LOAD).map(|p| {
+            // Segments are mapped with page granularity, so the bytes before the segment on the same page are visible
+            let page_delta = p.p_vaddr & (PAGE_SIZE - 1);
+            Some(FileSegment {
+                address: load_bias.wrapping_add(p.p_vaddr - page_delta),
+                memory_size: p.p_memsz.checked_add(page_delta)?,
+                file_offset: p.p_offset.checked_sub(page_delta)?,
+                file_size: p.p_filesz.checked_add(page_delta)?,
+            })
+        }).collect::<Option<Vec<FileSegment>>>().ok_or("ELF file has an invalid segment")?;
+
+        Ok(ElfFileView { data: raw.data, segments })
+    }
+
+    // Copies as much of the segment as the read covers, starting segment_offset bytes in
+    fn read_segment(&self, segment: &FileSegment, segment_offset: u64, len: usize, data: &mut Vec<Option<u8>>) {
+        let len = std::cmp::min(len as u64, segment.memory_size - segment_offset) as usize;
+        let file_len = std::cmp::min(len as u64, segment.file_size.saturating_sub(segment_offset)) as usize;
+        let file_bytes = segment.file_offset.checked_add(segment_offset)
+            .and_then(|offset| usize::try_from(offset).ok())
+            .and_then(|offset| self.data.get(offset..))
+            .unwrap_or_default();
+        let read_len = std::cmp::min(file_len, file_bytes.len());
+
+        data.extend(file_bytes[..read_len].iter().map(|b| Some(*b)));
+        // Anything that should be in the file but is past the end of it can't be read
+        data.extend(std::iter::repeat_n(None, file_len - read_len));
+        // The rest is the zero-filled part of the segment (e.g. .bss)
+        data.extend(std::iter::repeat_n(Some(0), len - file_len));
+    }
+}
+
+impl MemorySource for ElfFileView {
+    fn read_memory(&self, address: u64, len: usize) -> Result<Vec<Option<u8>>, &'static str> {
+        let mut data: Vec<Option<u8>> = Vec::with_capacity(len);
+        while data.len() < len {
+            let len_left = len - data.len();
+            let Some(cur_address) = address.checked_add(data.len() as u64) else {
+                data.extend(std::iter::repeat_n(None, len_left));
+                break;
+            };
+            match self.segments.iter().find(|s| s.address <= cur_address && cur_address - s.address < s.memory_size) {
+                Some(segment) => self.read_segment(segment, cur_address - segment.address, len_left, &mut data),
+                None => {
+                    // Skip ahead to the next segment, if there is one
+                    let next_segment = self.segments.iter().map(|s| s.address).filter(|a| *a > cur_address).min();
+                    let gap = next_segment.map_or(len_left as u64, |a| std::cmp::min(len_left as u64, a - cur_address));
+                    data.extend(std::iter::repeat_n(None, gap as usize));
+                }
+            }
+        }
+        Ok(data)
+    }
+
+    fn read_raw_memory(&self, address: u64, len: usize) -> Vec<u8> {
+        match self.read_memory(address, len) {
+            Ok(data) => data.into_iter().map_while(|b| b).collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_target::temp_file_path;
+
+    const BASE_ADDRESS: u64 = 0x7000_0000;
+    const ET_DYN: u16 = 3;
+
+    // A shared object with a segment for the headers, and a data segment that isn't page aligned and has a .bss
+    fn build_elf(segments: &[Elf64_Phdr]) -> Vec<u8> {
+        let mut e_ident = [0u8; 16];
+        e_ident[..4].copy_from_slice(ELF_MAGIC);
+        e_ident[4] = ELFCLASS64;
+        let header = Elf64_Ehdr { e_ident, e_type: ET_DYN, e_machine: EM_X86_64, e_phoff: Elf64_Ehdr::SIZE as u64, e_phnum: segments.len() as u16, ..Default::default() };
+        let mut data = memory::encode(&header);
+        segments.iter().for_each(|p| p.encode(&mut data));
+        data.resize(0x1000, 0);
+        data.extend((0..0x100).map(|i| i as u8));
+        data
+    }
+
+    fn load(p_vaddr: u64, p_offset: u64, p_filesz: u64, p_memsz: u64) -> Elf64_Phdr {
+        Elf64_Phdr { p_type: PT_LOAD, p_offset, p_vaddr, p_filesz, p_memsz, ..Default::default() }
+    }
+
+    fn open_view(name: &str, base_address: u64, segments: &[Elf64_Phdr]) -> Result<ElfFileView, &'static str> {
+        let path = temp_file_path(name);
+        std::fs::write(&path, build_elf(segments)).unwrap();
+        let view = ElfFileView::open(&path, base_address);
+        std::fs::remove_file(&path).unwrap();
+        view
+    }
+
+    #[test]
+    fn file_view_maps_segments() {
+        let view = open_view("view.so", BASE_ADDRESS, &[load(0, 0, 0x100, 0x100), load(0x2010, 0x1010, 0x80, 0x200)]).unwrap();
+        assert_eq!(view.read_raw_memory(BASE_ADDRESS, 4), ELF_MAGIC);
+
+        // The start of the page before the segment, then the segment, then its .bss, then nothing
+        let data = view.read_memory(BASE_ADDRESS + 0x2000, 0x300).unwrap();
+        let expected: Vec<Option<u8>> = (0..0x90).map(Some)
+            .chain(std::iter::repeat_n(Some(0), 0x180))
+            .chain(std::iter::repeat_n(None, 0xf0))
+            .collect();
+        assert_eq!(data, expected);
+
+        // Reads that start between segments pick up at the next one
+        let data = view.read_memory(BASE_ADDRESS + 0xff, 0x1f10).unwrap();
+        assert_eq!(data[0], Some(0));
+        assert!(data[1..0x1f01].iter().all(|b| b.is_none()));
+        assert_eq!(data[0x1f01..], (0..0xf).map(Some).collect::<Vec<_>>());
+
+        let image = read_elf_image(BASE_ADDRESS, &view).unwrap();
+        assert_eq!(image.size, 0x3000);
+    }
+
+    #[test]
+    fn segment_before_its_page_offset_is_rejected() {
+        assert!(open_view("offset.so", BASE_ADDRESS, &[load(0, 0, 0x100, 0x100), load(0x2010, 0, 0x80, 0x200)]).is_err());
+    }
+
+    #[test]
+    fn segment_past_end_of_address_space_is_rejected() {
+        let view = open_view("overflow.so", BASE_ADDRESS, &[load(0, 0, 0x100, 0x100), load(0xffff_ffff_ffff_f000, 0x1000, 0x80, 0x2000)]).unwrap();
+        assert!(read_elf_image(BASE_ADDRESS, &view).is_err());
+    }
+
+    // The load bias is negative when a module is mapped below the address it was linked at
+    #[test]
+    fn image_mapped_below_its_link_address() {
+        let view = open_view("low.so", 0x1000, &[load(0x10000, 0, 0x100, 0x100)]).unwrap();
+        assert_eq!(view.read_raw_memory(0x1000, 4), ELF_MAGIC);
+        assert_eq!(read_elf_image(0x1000, &view).unwrap().size, 0x1000);
+    }
+}
@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+use std::fs::File;
+
+use windows_sys::Win32::Foundation::{EXCEPTION_ACCESS_VIOLATION, EXCEPTION_BREAKPOINT, EXCEPTION_ILLEGAL_INSTRUCTION, EXCEPTION_INT_DIVIDE_BY_ZERO, STATUS_STACK_BUFFER_OVERRUN};
+
+use crate::dump_target::{DumpMemoryRange, DumpMemorySource, DumpTarget};
+use crate::elf::{self, ELF_MAGIC};
+use crate::event::DebugEvent;
+use crate::memory::MemorySource;
+use crate::registers::RegisterContext;
+
+const NT_PRSTATUS: u32 = 1;
+const NT_FPREGSET: u32 = 2;
+const NT_PRPSINFO: u32 = 3;
+const NT_AUXV: u32 = 6;
+const NT_FILE: u32 = 0x46494C45;
+
+// Offsets into struct elf_prstatus and struct elf_prpsinfo for x64
+const PRSTATUS_CURSIG_OFFSET: usize = 12;
+const PRSTATUS_PID_OFFSET: usize = 32;
+const PRSTATUS_REG_OFFSET: usize = 112;
+const PRSTATUS_REG_COUNT: usize = 27;
+const PRPSINFO_PID_OFFSET: usize = 24;
+
+const AT_PHDR: u64 = 3;
+const AT_SYSINFO_EHDR: u64 = 33;
+
+const SIGILL: u16 = 4;
+const SIGTRAP: u16 = 5;
+const SIGABRT: u16 = 6;
+const SIGBUS: u16 = 7;
+const SIGFPE: u16 = 8;
+const SIGSEGV: u16 = 11;
+
+// A file mapping from the NT_FILE note
+struct FileMapping {
+    start: u64,
+    end: u64,
+    file_offset: u64,
+    path: String,
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    data.get(offset..offset + 8).map_or(0, |b| u64::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    data.get(offset..offset + 4).map_or(0, |b| u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+// Splits a PT_NOTE segment into (type, descriptor) pairs. The names and descriptors are each padded to 4 bytes.
+fn parse_notes(data: &[u8]) -> Vec<(u32, &[u8])> {
+    let mut notes = Vec::new();
+    let mut offset = 0;
+    while offset + 12 <= data.len() {
+        let name_size = read_u32(data, offset) as usize;
+        let desc_size = read_u32(data, offset + 4) as usize;
+        let note_type = read_u32(data, offset + 8);
+        let desc_start = offset + 12 + ((name_size + 3) & !3);
+        let desc_end = desc_start + desc_size;
+        if desc_end > data.len() {
+            break;
+        }
+        notes.push((note_type, &data[desc_start..desc_end]));
+        offset = (desc_end + 3) & !3;
+    }
+    notes
+}
+
+// The registers are saved as a user_regs_struct
+fn registers_from_prstatus(prstatus: &[u8]) -> RegisterContext {
+    let regs: Vec<u64> = (0..PRSTATUS_REG_COUNT).map(|i| read_u64(prstatus, PRSTATUS_REG_OFFSET + i * 8)).collect();
+    RegisterContext {
+        r15: regs[0],
+        r14: regs[1],
+        r13: regs[2],
+        r12: regs[3],
+        rbp: regs[4],
+        rbx: regs[5],
+        r11: regs[6],
+        r10: regs[7],
+        r9: regs[8],
+        r8: regs[9],
+        rax: regs[10],
+        rcx: regs[11],
+        rdx: regs[12],
+        rsi: regs[13],
+        rdi: regs[14],
+        // regs[15] is orig_rax
+        rip: regs[16],
+        cs: regs[17] as u16,
+        eflags: regs[18] as u32,
+        rsp: regs[19],
+        ss: regs[20] as u16,
+        // regs[21] and regs[22] are fs_base and gs_base
+        ds: regs[23] as u16,
+        es: regs[24] as u16,
+        fs: regs[25] as u16,
+        gs: regs[26] as u16,
+        ..Default::default()
+    }
+}
+
+// The NT_FILE note is a count and page size, then (start, end, page offset) for each mapping, then all of the paths
+fn parse_file_note(desc: &[u8]) -> Result<Vec<FileMapping>, &'static str> {
+    let count = read_u64(desc, 0);
+    let page_size = read_u64(desc, 8);
+    // Every mapping needs its 24 bytes to be in the note, which keeps the count from being anything at all
+    if count > (desc.len().saturating_sub(16) / 24) as u64 {
+        return Err("Core file has an invalid NT_FILE note");
+    }
+    let count = count as usize;
+    let mut paths = desc.get(16 + count * 24..).unwrap_or_default().split(|&c| c == 0);
+    (0..count).map(|i| {
+        let entry = 16 + i * 24;
+        Ok(FileMapping {
+            start: read_u64(desc, entry),
+            end: read_u64(desc, entry + 8),
+            file_offset: read_u64(desc, entry + 16).checked_mul(page_size).ok_or("Core file has an invalid NT_FILE note")?,
+            path: String::from_utf8_lossy(paths.next().unwrap_or_default()).to_string(),
+        })
+    }).collect()
+}
+
+fn signal_to_event(signal: u16) -> DebugEvent {
+    let exception_code = match signal {
+        SIGSEGV | SIGBUS => EXCEPTION_ACCESS_VIOLATION,
+        SIGILL => EXCEPTION_ILLEGAL_INSTRUCTION,
+        SIGFPE => EXCEPTION_INT_DIVIDE_BY_ZERO,
+        SIGTRAP => EXCEPTION_BREAKPOINT,
+        // abort() on Windows ends in a fail fast, which is reported with this code
+        SIGABRT => STATUS_STACK_BUFFER_OVERRUN,
+        _ => return DebugEvent::Other(format!("Process was terminated by signal {}", signal)),
+    };
+    DebugEvent::Exception { first_chance: false, exception_code }
+}
+
+// Opens a Linux core file. The kernel doesn't save the contents of file backed mappings that haven't been written to,
+// such as the code of every module, so those are read from the original files if they're still around.
+pub fn open_core_file(path: &str) -> Result<DumpTarget, &'static str> {
+    let file = File::open(path).map_err(|_| "Could not open core file")?;
+    let file_size = file.metadata().map_err(|_| "Could not open core file")?.len();
+
+    // Read the headers using file offsets first, then we can figure out where everything was mapped
+    let raw = DumpMemorySource::new(vec![file.try_clone().map_err(|_| "Could not open core file")?], vec![DumpMemoryRange { address: 0, size: file_size, file_index: 0, file_offset: 0 }]);
+    let header = elf::read_elf_header(&raw, 0)?;
+    if header.e_type != elf::ET_CORE {
+        return Err("ELF file is not a core file");
+    }
+    let program_headers = elf::read_program_headers(&raw, 0, &header)?;
+
+    let mut process_id = 0;
+    let mut threads = Vec::new();
+    let mut signal = None;
+    let mut auxv = Vec::new();
+    let mut mappings = Vec::new();
+    for note_segment in program_headers.iter().filter(|p| p.p_type == elf::PT_NOTE) {
+        let data = raw.read_raw_memory(note_segment.p_offset, note_segment.p_filesz as usize);
+        for (note_type, desc) in parse_notes(&data) {
+            match note_type {
+                NT_PRSTATUS => {
+                    let thread_id = read_u32(desc, PRSTATUS_PID_OFFSET);
+                    // The thread that caught the signal comes first
+                    signal.get_or_insert((thread_id, read_u32(desc, PRSTATUS_CURSIG_OFFSET) as u16));
+                    threads.push((thread_id, registers_from_prstatus(desc)));
+                }
+                NT_FPREGSET => {
+                    // This belongs to the thread from the NT_PRSTATUS before it, and is in the FXSAVE layout
+                    if let Some((_, context)) = threads.last_mut() {
+                        let len = std::cmp::min(desc.len(), context.fx_save.len());
+                        context.fx_save[..len].copy_from_slice(&desc[..len]);
+                    }
+                }
+                NT_PRPSINFO => process_id = read_u32(desc, PRPSINFO_PID_OFFSET),
+                NT_AUXV => auxv = desc.chunks_exact(16).map(|entry| (read_u64(entry, 0), read_u64(entry, 8))).collect(),
+                NT_FILE => mappings = parse_file_note(desc)?,
+                _ => {}
+            }
+        }
+    }
+
+    let mut files = vec![file];
+    let mut file_indexes: HashMap<&str, Option<usize>> = HashMap::new();
+    let mut ranges = Vec::new();
+    for segment in program_headers.iter().filter(|p| p.p_type == elf::PT_LOAD) {
+        if segment.p_filesz > 0 {
+            ranges.push(DumpMemoryRange { address: segment.p_vaddr, size: segment.p_filesz, file_index: 0, file_offset: segment.p_offset });
+        }
+
+        // Whatever wasn't saved in the core file might still be in the file that was mapped there
+        let missing_start = segment.p_vaddr.checked_add(segment.p_filesz).ok_or("Core file has an invalid segment")?;
+        let missing_end = segment.p_vaddr.checked_add(segment.p_memsz).ok_or("Core file has an invalid segment")?;
+        if missing_end <= missing_start {
+            continue;
+        }
+        for mapping in mappings.iter().filter(|m| m.start < missing_end && m.end > missing_start) {
+            let file_index = *file_indexes.entry(&mapping.path).or_insert_with(|| {
+                let mapped_file = File::open(&mapping.path).ok()?;
+                files.push(mapped_file);
+                Some(files.len() - 1)
+            });
+            if let Some(file_index) = file_index {
+                let start = std::cmp::max(mapping.start, missing_start);
+                let end = std::cmp::min(mapping.end, missing_end);
+                let file_offset = mapping.file_offset.checked_add(start - mapping.start).ok_or("Core file has an invalid NT_FILE note")?;
+                ranges.push(DumpMemoryRange { address: start, size: end - start, file_index, file_offset });
+            }
+        }
+    }
+    let memory = DumpMemorySource::new(files, ranges);
+
+    // The executable is the file that the program headers are in. The vDSO isn't a file, but it's in the core file.
+    let find_auxv = |entry_type: u64| auxv.iter().find(|(t, _)| *t == entry_type).map(|(_, value)| *value);
+    let exe_path = find_auxv(AT_PHDR).and_then(|phdr| mappings.iter().find(|m| m.start <= phdr && phdr < m.end)).map(|m| m.path.clone());
+    let is_image = |m: &&FileMapping| m.file_offset == 0 && memory.read_raw_memory(m.start, 4) == ELF_MAGIC;
+    let mut modules: Vec<(u64, Option<String>)> = Vec::new();
+    if let Some(exe) = mappings.iter().filter(is_image).find(|m| Some(&m.path) == exe_path.as_ref()) {
+        modules.push((exe.start, Some(exe.path.clone())));
+    }
+    if let Some(vdso) = find_auxv(AT_SYSINFO_EHDR) {
+        modules.push((vdso, Some("[vdso]".to_string())));
+    }
+    for mapping in mappings.iter().filter(is_image).filter(|m| Some(&m.path) != exe_path.as_ref()) {
+        modules.push((mapping.start, Some(mapping.path.clone())));
+    }
+
+    let stop_event = signal.map(|(thread_id, signal)| (thread_id, signal_to_event(signal)));
+    DumpTarget::new(memory, process_id, threads, modules, stop_event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_note(count: u64, page_size: u64, mappings: &[(u64, u64, u64)], paths: &[&str]) -> Vec<u8> {
+        let mut desc = Vec::new();
+        desc.extend_from_slice(&count.to_le_bytes());
+        desc.extend_from_slice(&page_size.to_le_bytes());
+        for (start, end, page_offset) in mappings {
+            desc.extend_from_slice(&start.to_le_bytes());
+            desc.extend_from_slice(&end.to_le_bytes());
+            desc.extend_from_slice(&page_offset.to_le_bytes());
+        }
+        for path in paths {
+            desc.extend_from_slice(path.as_bytes());
+            desc.push(0);
+        }
+        desc
+    }
+
+    #[test]
+    fn file_note_is_parsed() {
+        let desc = file_note(2, 0x1000, &[(0x400000, 0x401000, 0), (0x401000, 0x403000, 1)], &["/bin/a", "/bin/b"]);
+        let mappings = parse_file_note(&desc).unwrap();
+        assert_eq!(mappings.len(), 2);
+        assert_eq!((mappings[1].start, mappings[1].end, mappings[1].file_offset), (0x401000, 0x403000, 0x1000));
+        assert_eq!(mappings[1].path, "/bin/b");
+    }
+
+    #[test]
+    fn file_note_with_too_many_mappings_is_rejected() {
+        let desc = file_note(u64::MAX / 8, 0x1000, &[(0x400000, 0x401000, 0)], &["/bin/a"]);
+        assert!(parse_file_note(&desc).is_err());
+    }
+
+    #[test]
+    fn file_note_with_huge_offset_is_rejected() {
+        let desc = file_note(1, 0x1000, &[(0x400000, 0x401000, u64::MAX / 0x100)], &["/bin/a"]);
+        assert!(parse_file_note(&desc).is_err());
+    }
+
+    #[test]
+    fn abort_is_an_exception() {
+        assert!(matches!(signal_to_event(SIGABRT), DebugEvent::Exception { first_chance: false, exception_code: STATUS_STACK_BUFFER_OVERRUN }));
+        assert!(matches!(signal_to_event(SIGSEGV), DebugEvent::Exception { first_chance: false, exception_code: EXCEPTION_ACCESS_VIOLATION }));
+    }
+}
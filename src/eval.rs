@@ -1,14 +1,12 @@
 use crate::command::grammar::EvalExpr;
 use crate::process::Process;
 use crate::name_resolution::resolve_name_to_address;
-use crate::registers::get_register;
+use crate::registers::{get_register, RegisterContext};
 use crate::source::resolve_source_line_to_address;
-use windows_sys::Win32::System::Diagnostics::Debug::CONTEXT;
 
 pub struct EvalContext<'a> {
     pub process: &'a mut Process,
-    // TODO: This should really be an abstraction on top of the context
-    pub register_context: &'a CONTEXT,
+    pub register_context: &'a RegisterContext,
 }
 
 pub fn evaluate_expression(expr: EvalExpr, context: &mut EvalContext) -> Result<u64, anyhow::Error> {
@@ -16,8 +14,8 @@ pub fn evaluate_expression(expr: EvalExpr, context: &mut EvalContext) -> Result<
         EvalExpr::Number(x) => Ok(x),
         EvalExpr::Add(x, _, y) => Ok(evaluate_expression(*x, context)? + evaluate_expression(*y, context)?),
         EvalExpr::Symbol(sym) => {
-            if sym.starts_with('@') {
-                if let Ok(val) = get_register(context.register_context, &sym[1..]) {
+            if let Some(reg_name) = sym.strip_prefix('@') {
+                if let Ok(val) = get_register(context.register_context, reg_name) {
                     return Ok(val);
                 }
             }
@@ -0,0 +1,174 @@
+use std::fmt;
+
+use windows_sys::Win32::Foundation::EXCEPTION_SINGLE_STEP;
+
+use crate::breakpoint::BreakpointManager;
+use crate::event::{DebugEvent, EventContext};
+use crate::process::Process;
+use crate::target::{ContinueStatus, DebugTarget};
+
+// A module that the target loaded, and whether we could make sense of it
+pub struct LoadedModule {
+    pub address: u64,
+    // The name of the module, or the name the target gave if it couldn't be loaded
+    pub name: String,
+    pub error: Option<&'static str>,
+}
+
+// The same way the command line debugger shows it
+impl fmt::Display for LoadedModule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.error {
+            Some(e) => write!(f, "LoadDll: {:X}   {} (could not load module: {})", self.address, self.name, e),
+            None => write!(f, "LoadDll: {:X}   {}", self.address, self.name),
+        }
+    }
+}
+
+// A debug event, after the pump has kept the process up to date with it
+pub enum Event {
+    // The single step that the target was resumed with has finished
+    Step,
+    Breakpoint(u32),
+    // Any other exception. It goes back to the target when it resumes, unless continue_status is changed first.
+    Exception { first_chance: bool, exception_code: i32 },
+    // The process started with its main module and its first thread
+    ProcessCreated(LoadedModule),
+    ModuleLoaded(LoadedModule),
+    ThreadCreated(u32),
+    ThreadExited(u32),
+    Output(String),
+    Message(String),
+    Exited,
+}
+
+// The part of driving a target that's the same for every machine interface: keeping track of the modules and threads,
+// telling our own steps and breakpoints apart from the target's exceptions, and putting the breakpoints back each time
+// the target resumes. The front end decides which events to stop for, and what to tell its client about them.
+pub struct EventPump {
+    pub process: Process,
+    pub breakpoints: BreakpointManager,
+    // The event that the target is stopped for, and how to continue from it
+    pub event_context: EventContext,
+    pub continue_status: ContinueStatus,
+    expect_step_exception: bool,
+}
+
+impl EventPump {
+    pub fn new() -> EventPump {
+        EventPump {
+            process: Process::new(),
+            breakpoints: BreakpointManager::new(),
+            event_context: EventContext { process_id: 0, thread_id: 0 },
+            continue_status: ContinueStatus::Handled,
+            expect_step_exception: false,
+        }
+    }
+
+    fn load_module(&mut self, target: &dyn DebugTarget, address: u64, module_name: Option<String>) -> LoadedModule {
+        match self.process.add_module(address, module_name.clone(), target.memory_source()) {
+            Ok(module) => LoadedModule { address, name: module.name.clone(), error: None },
+            Err(e) => LoadedModule { address, name: module_name.unwrap_or_default(), error: Some(e) },
+        }
+    }
+
+    pub fn next_event(&mut self, target: &mut dyn DebugTarget) -> Result<Event, &'static str> {
+        let (event_context, debug_event) = target.wait_for_event()?;
+        self.event_context = event_context;
+        self.continue_status = ContinueStatus::Handled;
+
+        let event = match debug_event {
+            DebugEvent::Exception { first_chance, exception_code } => {
+                // A thread that has just exited may not have a context any more
+                let ctx = target.get_thread_context(event_context.thread_id).unwrap_or_default();
+                if self.expect_step_exception && exception_code == EXCEPTION_SINGLE_STEP {
+                    self.expect_step_exception = false;
+                    Event::Step
+                } else if let Some(id) = self.breakpoints.was_breakpoint_hit(&ctx) {
+                    Event::Breakpoint(id)
+                } else {
+                    self.continue_status = ContinueStatus::NotHandled;
+                    Event::Exception { first_chance, exception_code }
+                }
+            }
+            DebugEvent::CreateProcess { exe_name, exe_base } => {
+                self.process.add_thread(event_context.thread_id);
+                Event::ProcessCreated(self.load_module(target, exe_base, exe_name))
+            }
+            DebugEvent::CreateThread { thread_id } => {
+                self.process.add_thread(thread_id);
+                Event::ThreadCreated(thread_id)
+            }
+            DebugEvent::ExitThread { thread_id } => {
+                self.process.remove_thread(thread_id);
+                Event::ThreadExited(thread_id)
+            }
+            DebugEvent::LoadModule { module_name, module_base } => Event::ModuleLoaded(self.load_module(target, module_base, module_name)),
+            DebugEvent::OutputDebugString(output) => Event::Output(output),
+            DebugEvent::Other(msg) => Event::Message(msg),
+            DebugEvent::ExitProcess => Event::Exited,
+        };
+        Ok(event)
+    }
+
+    // Lets the target carry on from the event it stopped for, or just the given thread for a single instruction
+    pub fn resume(&mut self, target: &mut dyn DebugTarget, step_thread: Option<u32>) -> Result<(), &'static str> {
+        // Events can come in before the step happens, like the modules that are reported when a process starts, so
+        // the step is expected until it turns up
+        if let Some(thread_id) = step_thread {
+            target.step_thread(thread_id)?;
+            self.expect_step_exception = true;
+        }
+
+        let event_context = self.event_context;
+        let status = self.continue_status;
+        self.continue_status = ContinueStatus::Handled;
+        // There's nothing to put breakpoints in for a dump, which only goes on to its next recorded event
+        if target.is_live() {
+            self.breakpoints.apply_breakpoints(&mut self.process, event_context.thread_id, target);
+        }
+        target.continue_event(&event_context, status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_target::{FakeTarget, THREAD_ID};
+
+    const ENTRY: u64 = 0x1000;
+
+    fn start() -> (EventPump, FakeTarget) {
+        let mut target = FakeTarget::new(ENTRY, ENTRY + 0x10);
+        target.add_memory(ENTRY, &[0x90; 0x10]);
+        let mut pump = EventPump::new();
+        assert!(matches!(pump.next_event(&mut target), Ok(Event::ProcessCreated(LoadedModule { address: ENTRY, .. }))));
+        assert_eq!(pump.process.iterate_threads().collect::<Vec<_>>(), vec![&THREAD_ID]);
+        pump.resume(&mut target, None).unwrap();
+        assert!(matches!(pump.next_event(&mut target), Ok(Event::Exception { .. })));
+        (pump, target)
+    }
+
+    #[test]
+    fn steps_and_breakpoints_are_not_exceptions() {
+        let (mut pump, mut target) = start();
+        let id = pump.breakpoints.add_breakpoint(ENTRY + 4).unwrap();
+
+        pump.resume(&mut target, Some(THREAD_ID)).unwrap();
+        assert!(matches!(pump.next_event(&mut target), Ok(Event::Step)));
+        assert_eq!(target.get_thread_context(THREAD_ID).unwrap().rip, ENTRY + 1);
+
+        pump.resume(&mut target, None).unwrap();
+        assert!(matches!(pump.next_event(&mut target), Ok(Event::Breakpoint(hit)) if hit == id));
+        assert_eq!(target.get_thread_context(THREAD_ID).unwrap().rip, ENTRY + 4);
+
+        pump.resume(&mut target, None).unwrap();
+        assert!(matches!(pump.next_event(&mut target), Ok(Event::Exited)));
+    }
+
+    #[test]
+    fn exceptions_go_back_to_the_target() {
+        let (pump, _) = start();
+        assert!(matches!(pump.continue_status, ContinueStatus::NotHandled));
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::VecDeque;
+
+use windows_sys::Win32::Foundation::{EXCEPTION_BREAKPOINT, EXCEPTION_SINGLE_STEP};
+
+use crate::event::{DebugEvent, EventContext};
+use crate::memory::MemorySource;
+use crate::registers::RegisterContext;
+use crate::target::{ContinueStatus, DebugTarget, MemoryRegion};
+
+pub const PROCESS_ID: u32 = 0x100;
+pub const THREAD_ID: u32 = 0x200;
+
+const TRAP_FLAG: u32 = 1 << 8;
+const RESUME_FLAG: u32 = 1 << 16;
+const DR6_SINGLE_STEP: u64 = 1 << 14;
+
+// A path in the temp directory for a test to write a file to. Tests run in parallel, so each one needs its own name.
+pub fn temp_file_path(name: &str) -> String {
+    std::env::temp_dir().join(format!("dbgrs-test-{}-{}", std::process::id(), name)).to_string_lossy().to_string()
+}
+
+// Memory made of a few separate blocks of bytes. Anything outside of them can't be read.
+#[derive(Default)]
+pub struct FakeMemory {
+    regions: Vec<(u64, Vec<u8>)>,
+}
+
+impl FakeMemory {
+    pub fn add_region(&mut self, address: u64, data: &[u8]) {
+        self.regions.push((address, data.to_vec()));
+    }
+
+    fn byte_at(&self, address: u64) -> Option<u8> {
+        self.regions.iter()
+            .find(|(start, data)| address >= *start && address - *start < data.len() as u64)
+            .map(|(start, data)| data[(address - start) as usize])
+    }
+
+    fn write_byte(&mut self, address: u64, value: u8) -> bool {
+        match self.regions.iter_mut().find(|(start, data)| address >= *start && address - *start < data.len() as u64) {
+            Some((start, data)) => {
+                data[(address - *start) as usize] = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Returns how many bytes were written before the first one that isn't mapped
+    pub fn write(&mut self, address: u64, data: &[u8]) -> usize {
+        data.iter().enumerate().take_while(|(offset, b)| self.write_byte(address.wrapping_add(*offset as u64), **b)).count()
+    }
+}
+
+impl MemorySource for FakeMemory {
+    fn read_memory(&self, address: u64, len: usize) -> Result<Vec<Option<u8>>, &'static str> {
+        Ok((0..len as u64).map(|offset| self.byte_at(address.wrapping_add(offset))).collect())
+    }
+
+    fn read_raw_memory(&self, address: u64, len: usize) -> Vec<u8> {
+        (0..len as u64).map_while(|offset| self.byte_at(address.wrapping_add(offset))).collect()
+    }
+}
+
+// A target that lives entirely in memory, so the engine can be tested without a real process. It has one thread that
+// runs a program of one byte instructions from its entry point up to an end address, where the process exits. The
+// trap flag and the debug registers work the way they do on the CPU, so breakpoints and stepping behave like they
+// would on a live target.
+pub struct FakeTarget {
+    memory: FakeMemory,
+    context: RegisterContext,
+    end_address: u64,
+    events: VecDeque<DebugEvent>,
+    running: bool,
+    exited: bool,
+}
+
+impl FakeTarget {
+    // Like a real process, it starts with a create process event and then stops at an initial breakpoint
+    pub fn new(entry_point: u64, end_address: u64) -> FakeTarget {
+        let context = RegisterContext { rip: entry_point, ..Default::default() };
+        let events = VecDeque::from([
+            DebugEvent::CreateProcess { exe_name: Some("fake".to_string()), exe_base: entry_point },
+            DebugEvent::Exception { first_chance: true, exception_code: EXCEPTION_BREAKPOINT },
+        ]);
+        FakeTarget { memory: FakeMemory::default(), context, end_address, events, running: false, exited: false }
+    }
+
+    pub fn add_memory(&mut self, address: u64, data: &[u8]) {
+        self.memory.add_region(address, data);
+    }
+
+    fn event_context(&self) -> EventContext {
+        EventContext { process_id: PROCESS_ID, thread_id: THREAD_ID }
+    }
+
+    fn breakpoint_at(&self, address: u64) -> Option<usize> {
+        let ctx = &self.context;
+        [ctx.dr0, ctx.dr1, ctx.dr2, ctx.dr3].iter().enumerate()
+            .find(|(idx, dr)| ctx.dr7 & (1 << (idx * 2)) != 0 && **dr == address)
+            .map(|(idx, _)| idx)
+    }
+
+    // Runs until a breakpoint, the end of a single step, or the end of the program
+    fn run(&mut self) -> DebugEvent {
+        let single_step = self.context.eflags & TRAP_FLAG != 0;
+        // The resume flag only skips the breakpoint check on the first instruction
+        let mut skip_breakpoints = self.context.eflags & RESUME_FLAG != 0;
+        self.context.eflags &= !(TRAP_FLAG | RESUME_FLAG);
+
+        loop {
+            if !skip_breakpoints {
+                if let Some(idx) = self.breakpoint_at(self.context.rip) {
+                    self.context.dr6 |= 1 << idx;
+                    return DebugEvent::Exception { first_chance: true, exception_code: EXCEPTION_SINGLE_STEP };
+                }
+            }
+            skip_breakpoints = false;
+
+            if self.context.rip >= self.end_address {
+                self.exited = true;
+                return DebugEvent::ExitProcess;
+            }
+            self.context.rip += 1;
+
+            if single_step {
+                self.context.dr6 |= DR6_SINGLE_STEP;
+                return DebugEvent::Exception { first_chance: true, exception_code: EXCEPTION_SINGLE_STEP };
+            }
+        }
+    }
+}
+
+impl DebugTarget for FakeTarget {
+    fn wait_for_event(&mut self) -> Result<(EventContext, DebugEvent), &'static str> {
+        if let Some(event) = self.events.pop_front() {
+            return Ok((self.event_context(), event));
+        }
+        if self.exited {
+            return Err("The process has exited");
+        }
+        if !self.running {
+            return Err("The target was not continued");
+        }
+        self.running = false;
+        let event = self.run();
+        Ok((self.event_context(), event))
+    }
+
+    fn memory_source(&self) -> &dyn MemorySource {
+        &self.memory
+    }
+
+    fn write_memory(&mut self, address: u64, data: &[u8]) -> Result<usize, &'static str> {
+        Ok(self.memory.write(address, data))
+    }
+
+    fn get_thread_context(&self, thread_id: u32) -> Result<RegisterContext, &'static str> {
+        if thread_id != THREAD_ID || self.exited {
+            return Err("No such thread");
+        }
+        Ok(self.context)
+    }
+
+    fn set_thread_context(&mut self, thread_id: u32, context: &RegisterContext) -> Result<(), &'static str> {
+        if thread_id != THREAD_ID || self.exited {
+            return Err("No such thread");
+        }
+        self.context = *context;
+        Ok(())
+    }
+
+    // DR6 is cleared each time the thread runs, like the ptrace target does, so a stale hit is never reported
+    fn continue_event(&mut self, _event_context: &EventContext, _status: ContinueStatus) -> Result<(), &'static str> {
+        if self.exited {
+            return Err("The process has exited");
+        }
+        self.context.dr6 = 0;
+        self.running = true;
+        Ok(())
+    }
+
+    fn memory_regions(&self) -> Result<Vec<MemoryRegion>, &'static str> {
+        Ok(self.memory.regions.iter().map(|(address, data)| MemoryRegion { address: *address, size: data.len() as u64 }).collect())
+    }
+}
@@ -0,0 +1,434 @@
+use std::net::TcpListener;
+
+use windows_sys::Win32::Foundation::{DBG_CONTROL_C, EXCEPTION_ACCESS_VIOLATION, EXCEPTION_ILLEGAL_INSTRUCTION, EXCEPTION_INT_DIVIDE_BY_ZERO};
+
+use crate::command::grammar::{self, CommandExpr};
+use crate::elf;
+use crate::eval;
+use crate::event_pump::{Event, EventPump};
+use crate::name_resolution;
+use crate::registers::RegisterContext;
+use crate::rsp::{self, RspConnection};
+use crate::stack;
+use crate::target::{ContinueStatus, DebugTarget};
+
+const PACKET_SIZE: usize = 0x4000;
+
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+// We only describe the architecture, and let the front end use its built in register layout for it. That's the same
+// layout that rsp::registers_to_block produces.
+const TARGET_XML: &str = r#"<?xml version="1.0"?><!DOCTYPE target SYSTEM "gdb-target.dtd"><target version="1.0"><architecture>i386:x86-64</architecture></target>"#;
+
+// Serves the GDB Remote Serial Protocol for a target that dbgrs controls, so that gdb (or anything else that speaks
+// the protocol) can be used as the front end. The target is driven the same way main_debugger_loop drives it, with
+// breakpoints going through the BreakpointManager and symbols available through "monitor" commands.
+struct GdbServer<'a> {
+    target: &'a mut dyn DebugTarget,
+    connection: RspConnection,
+    pump: EventPump,
+    stop_reply: String,
+    // The thread that g/G/p/P packets apply to
+    selected_thread: u32,
+}
+
+fn exception_to_signal(exception_code: i32) -> u8 {
+    match exception_code {
+        EXCEPTION_ACCESS_VIOLATION => rsp::GDB_SIGSEGV,
+        EXCEPTION_ILLEGAL_INSTRUCTION => rsp::GDB_SIGILL,
+        EXCEPTION_INT_DIVIDE_BY_ZERO => rsp::GDB_SIGFPE,
+        DBG_CONTROL_C => rsp::GDB_SIGINT,
+        _ => rsp::GDB_SIGTRAP,
+    }
+}
+
+// Parses "addr,length" as used by the m, M and Z packets
+fn parse_address_length(text: &[u8]) -> Option<(u64, u64)> {
+    let comma = text.iter().position(|&c| c == b',')?;
+    let address = rsp::parse_hex_u64(&text[..comma]).ok()?;
+    let length = rsp::parse_hex_u64(&text[comma + 1..]).ok()?;
+    Some((address, length))
+}
+
+// Produces the reply to a qXfer read, which is a window into the whole object starting with 'm' if there's more to
+// read or 'l' if this is the last of it. The window comes from the client, so it can't be trusted to be sensible.
+fn xfer_reply(object: &[u8], window: &[u8]) -> Vec<u8> {
+    let Some((offset, length)) = parse_address_length(window) else {
+        return b"E01".to_vec();
+    };
+    let Some(start) = usize::try_from(offset).ok().filter(|start| *start <= object.len()) else {
+        return b"E01".to_vec();
+    };
+    let end = std::cmp::min(start.saturating_add(length as usize), object.len());
+    let mut reply = vec![if end < object.len() { b'm' } else { b'l' }];
+    reply.extend_from_slice(&object[start..end]);
+    reply
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;").replace('"', "&quot;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+impl GdbServer<'_> {
+    // Runs the target until something happens that the front end needs to know about. Everything else, like modules
+    // loading and threads starting, is handled the same way the command line debugger would handle it.
+    fn run_until_stop(&mut self) -> Result<bool, &'static str> {
+        loop {
+            let event = self.pump.next_event(self.target)?;
+            let thread_id = self.pump.event_context.thread_id;
+            let stop_reply = match event {
+                Event::Step => Some(format!("T{:02x}thread:{:x};", rsp::GDB_SIGTRAP, thread_id)),
+                Event::Breakpoint(_) => Some(format!("T{:02x}thread:{:x};hwbreak:;", rsp::GDB_SIGTRAP, thread_id)),
+                Event::Exception { exception_code, .. } => Some(format!("T{:02x}thread:{:x};", exception_to_signal(exception_code), thread_id)),
+                Event::ProcessCreated(module) | Event::ModuleLoaded(module) => {
+                    println!("{}", module);
+                    None
+                }
+                Event::ThreadCreated(_) | Event::ThreadExited(_) => None,
+                Event::Output(output) => {
+                    // The front end shows these on its console, and we can send them while the target is running
+                    self.connection.send_str(&format!("O{}", rsp::encode_hex(output.as_bytes())))?;
+                    None
+                }
+                Event::Message(msg) => {
+                    println!("{}", msg);
+                    None
+                }
+                Event::Exited => {
+                    self.stop_reply = "W00".to_string();
+                    return Ok(false);
+                }
+            };
+            if let Some(stop_reply) = stop_reply {
+                self.selected_thread = thread_id;
+                self.stop_reply = stop_reply;
+                return Ok(true);
+            }
+            self.pump.resume(self.target, None)?;
+        }
+    }
+
+    fn resume(&mut self, step_thread: Option<u32>, signal: u8) -> Result<(), &'static str> {
+        // The front end decides whether the exception should go back to the target, by passing the signal back
+        if signal == 0 {
+            self.pump.continue_status = ContinueStatus::Handled;
+        }
+        self.pump.resume(self.target, step_thread)
+    }
+
+    fn thread_for(&self, thread_id: u32) -> u32 {
+        // 0 and -1 mean any thread, so use the thread that the target stopped for
+        if thread_id == 0 || thread_id == u32::MAX {
+            self.pump.event_context.thread_id
+        } else {
+            thread_id
+        }
+    }
+
+    fn read_registers(&self) -> Result<Vec<u8>, &'static str> {
+        let context = self.target.get_thread_context(self.selected_thread)?;
+        let mut block = Vec::new();
+        rsp::registers_to_block(&context, &mut block);
+        Ok(block)
+    }
+
+    fn write_registers(&mut self, block: &[u8]) -> Result<(), &'static str> {
+        // The debug registers aren't in the block, so start with the current context to keep them
+        let mut context: RegisterContext = self.target.get_thread_context(self.selected_thread)?;
+        rsp::registers_from_block(block, &mut context)?;
+        self.target.set_thread_context(self.selected_thread, &context)
+    }
+
+    fn thread_list(&self) -> String {
+        let threads: Vec<String> = self.pump.process.iterate_threads().map(|t| format!("{:x}", t)).collect();
+        format!("m{}", threads.join(","))
+    }
+
+    // The Windows style library list, where the segment address is the start of the first section
+    fn library_list(&self) -> String {
+        let mut xml = String::from("<library-list>");
+        for module in self.pump.process.iterate_modules().filter(|m| m.is_pe()) {
+            xml.push_str(&format!(r#"<library name="{}"><segment address="0x{:x}"/></library>"#, escape_xml(&module.name), module.address + 0x1000));
+        }
+        xml.push_str("</library-list>");
+        xml
+    }
+
+    // The SVR4 library list, which is what front ends expect for ELF targets. Our ELF modules are all linked at zero
+    // (the main executable isn't in this list), so the load bias is the same as the base address.
+    fn svr4_library_list(&self) -> String {
+        let mut xml = String::from(r#"<library-list-svr4 version="1.0">"#);
+        for module in self.pump.process.iterate_modules().filter(|m| !m.is_pe()).skip(1) {
+            xml.push_str(&format!(r#"<library name="{}" lm="0x0" l_addr="0x{:x}" l_ld="0x0"/>"#, escape_xml(&module.name), module.address));
+        }
+        xml.push_str("</library-list-svr4>");
+        xml
+    }
+
+    // Front ends find where a position independent executable was loaded from the auxiliary vector. We don't have
+    // the real one, but we can rebuild the parts that matter from the ELF header of the executable.
+    fn auxiliary_vector(&self) -> Vec<u8> {
+        let mut auxv = Vec::new();
+        let exe_base = match self.pump.process.iterate_modules().next() {
+            Some(module) if !module.is_pe() => module.address,
+            _ => return auxv,
+        };
+        let memory_source = self.target.memory_source();
+        if let Ok(header) = elf::read_elf_header(memory_source, exe_base) {
+            // Executables that aren't position independent have absolute addresses in their headers
+            let bias = if header.e_type == elf::ET_EXEC { 0 } else { exe_base };
+            let entries = [
+                (AT_PHDR, exe_base + header.e_phoff),
+                (AT_PHENT, header.e_phentsize as u64),
+                (AT_PHNUM, header.e_phnum as u64),
+                (AT_PAGESZ, 0x1000),
+                (AT_ENTRY, header.e_entry + bias),
+                (AT_NULL, 0),
+            ];
+            for (key, value) in entries {
+                auxv.extend_from_slice(&key.to_le_bytes());
+                auxv.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        auxv
+    }
+
+    // "monitor" commands from the front end, which give it access to our symbols and unwinder
+    fn monitor_command(&mut self, command: &str) -> String {
+        let cmd = match grammar::parse(command) {
+            Ok(cmd) => cmd,
+            Err(_) => return format!("Could not parse command: {}\n", command),
+        };
+        let ctx = match self.target.get_thread_context(self.selected_thread) {
+            Ok(ctx) => ctx,
+            Err(e) => return format!("Could not get thread context: {}\n", e),
+        };
+        let mut eval_context = eval::EvalContext { process: &mut self.pump.process, register_context: &ctx };
+
+        match cmd {
+            CommandExpr::StackWalk(_) => {
+                let mut output = String::from(" #   RSP              Call Site\n");
+                let mut context = ctx;
+                let mut frame_number = 0;
+                loop {
+                    let call_site = name_resolution::resolve_address_to_name(context.rip, &mut self.pump.process).unwrap_or_else(|| format!("0x{:X}", context.rip));
+                    output.push_str(&format!("{:02X} 0x{:016X} {}\n", frame_number, context.rsp, call_site));
+                    match stack::unwind_context(&mut self.pump.process, context, self.target.memory_source()) {
+                        Ok(Some(unwound_context)) => context = unwound_context,
+                        _ => break,
+                    }
+                    frame_number += 1;
+                }
+                output
+            }
+            CommandExpr::ListNearest(_, expr) => match eval::evaluate_expression(*expr, &mut eval_context) {
+                Ok(address) => match name_resolution::resolve_address_to_name(address, &mut self.pump.process) {
+                    Some(sym) => format!("{}\n", sym),
+                    None => "No symbol found\n".to_string(),
+                },
+                Err(e) => format!("Could not evaluate expression: {}\n", e),
+            },
+            CommandExpr::Evaluate(_, expr) => match eval::evaluate_expression(*expr, &mut eval_context) {
+                Ok(value) => format!(" = 0x{:X}\n", value),
+                Err(e) => format!("Could not evaluate expression: {}\n", e),
+            },
+            _ => "Only k, ln and ? are supported as monitor commands\n".to_string(),
+        }
+    }
+
+    // Handles a packet while the target is stopped. Returns false when the session is over.
+    fn handle_packet(&mut self, packet: &[u8]) -> Result<bool, &'static str> {
+        let reply: Vec<u8> = match packet {
+            b"?" => self.stop_reply.clone().into_bytes(),
+            p if p.starts_with(b"qSupported") => {
+                format!("PacketSize={:x};QStartNoAckMode+;swbreak+;hwbreak+;vContSupported+;qXfer:features:read+;qXfer:libraries:read+;qXfer:libraries-svr4:read+;qXfer:exec-file:read+;qXfer:auxv:read+", PACKET_SIZE).into_bytes()
+            }
+            b"QStartNoAckMode" => {
+                self.connection.send_str("OK")?;
+                self.connection.no_ack = true;
+                return Ok(true);
+            }
+            b"qAttached" => b"1".to_vec(),
+            b"qC" => format!("QC{:x}", self.pump.event_context.thread_id).into_bytes(),
+            b"qfThreadInfo" => self.thread_list().into_bytes(),
+            b"qsThreadInfo" => b"l".to_vec(),
+            p if p.starts_with(b"qXfer:features:read:target.xml:") => xfer_reply(TARGET_XML.as_bytes(), &p[31..]),
+            p if p.starts_with(b"qXfer:libraries:read::") => xfer_reply(self.library_list().as_bytes(), &p[22..]),
+            p if p.starts_with(b"qXfer:libraries-svr4:read::") => xfer_reply(self.svr4_library_list().as_bytes(), &p[27..]),
+            p if p.starts_with(b"qXfer:exec-file:read:") => {
+                // The annex is the process ID, but we only ever have the one process
+                let window = p[21..].splitn(2, |&c| c == b':').nth(1).unwrap_or_default();
+                let exe_name = self.pump.process.iterate_modules().next().map(|m| m.name.clone()).unwrap_or_default();
+                xfer_reply(exe_name.as_bytes(), window)
+            }
+            p if p.starts_with(b"qXfer:auxv:read::") => xfer_reply(&self.auxiliary_vector(), &p[17..]),
+            p if p.starts_with(b"qRcmd,") => {
+                let command = String::from_utf8_lossy(&rsp::decode_hex(&p[6..])?).to_string();
+                rsp::encode_hex(self.monitor_command(command.trim()).as_bytes()).into_bytes()
+            }
+            p if p.starts_with(b"Hg") || p.starts_with(b"Hc") => {
+                // We only need to track the thread for registers, since vCont says which threads to step
+                if p[1] == b'g' {
+                    let thread_id = if &p[2..] == b"-1" { 0 } else { rsp::parse_hex_u64(&p[2..]).unwrap_or(0) as u32 };
+                    self.selected_thread = self.thread_for(thread_id);
+                }
+                b"OK".to_vec()
+            }
+            p if p.starts_with(b"T") => {
+                let thread_id = rsp::parse_hex_u64(&p[1..]).unwrap_or(0) as u32;
+                if self.pump.process.iterate_threads().any(|t| *t == thread_id) { b"OK".to_vec() } else { b"E01".to_vec() }
+            }
+            b"g" => match self.read_registers() {
+                Ok(block) => rsp::encode_hex(&block).into_bytes(),
+                Err(_) => b"E01".to_vec(),
+            },
+            p if p.starts_with(b"G") => {
+                let block = rsp::decode_hex(&p[1..])?;
+                if self.write_registers(&block).is_ok() { b"OK".to_vec() } else { b"E01".to_vec() }
+            }
+            p if p.starts_with(b"p") => {
+                let register = rsp::parse_hex_u64(&p[1..]).ok().and_then(|r| rsp::register_offset(r as usize));
+                match (register, self.read_registers()) {
+                    (Some((offset, size)), Ok(block)) => rsp::encode_hex(&block[offset..offset + size]).into_bytes(),
+                    _ => b"E01".to_vec(),
+                }
+            }
+            p if p.starts_with(b"P") => {
+                let equals = p.iter().position(|&c| c == b'=').ok_or("Invalid P packet")?;
+                let register = rsp::parse_hex_u64(&p[1..equals]).ok().and_then(|r| rsp::register_offset(r as usize));
+                let value = rsp::decode_hex(&p[equals + 1..])?;
+                match (register, self.read_registers()) {
+                    (Some((offset, size)), Ok(mut block)) if value.len() == size => {
+                        block[offset..offset + size].copy_from_slice(&value);
+                        if self.write_registers(&block).is_ok() { b"OK".to_vec() } else { b"E01".to_vec() }
+                    }
+                    _ => b"E01".to_vec(),
+                }
+            }
+            p if p.starts_with(b"m") => match parse_address_length(&p[1..]) {
+                Some((address, length)) => {
+                    let length = std::cmp::min(length as usize, (PACKET_SIZE - 4) / 2);
+                    let data = self.target.memory_source().read_raw_memory(address, length);
+                    if data.is_empty() && length > 0 { b"E14".to_vec() } else { rsp::encode_hex(&data).into_bytes() }
+                }
+                None => b"E01".to_vec(),
+            },
+            p if p.starts_with(b"M") => {
+                let colon = p.iter().position(|&c| c == b':').ok_or("Invalid M packet")?;
+                match parse_address_length(&p[1..colon]) {
+                    Some((address, _)) => {
+                        let data = rsp::decode_hex(&p[colon + 1..])?;
+                        match self.target.write_memory(address, &data) {
+                            Ok(written) if written == data.len() => b"OK".to_vec(),
+                            _ => b"E14".to_vec(),
+                        }
+                    }
+                    None => b"E01".to_vec(),
+                }
+            }
+            // Software and hardware breakpoints both end up as hardware breakpoints in the BreakpointManager
+            p if p.starts_with(b"Z0,") || p.starts_with(b"Z1,") => match parse_address_length(&p[3..]) {
+                Some((address, _)) if self.pump.breakpoints.find_breakpoint(address).is_some() => b"OK".to_vec(),
+                Some((address, _)) => match self.pump.breakpoints.add_breakpoint(address) {
+                    Ok(_) => b"OK".to_vec(),
+                    Err(_) => b"E0c".to_vec(),
+                },
+                None => b"E01".to_vec(),
+            },
+            p if p.starts_with(b"z0,") || p.starts_with(b"z1,") => {
+                if let Some(id) = parse_address_length(&p[3..]).and_then(|(address, _)| self.pump.breakpoints.find_breakpoint(address)) {
+                    self.pump.breakpoints.clear_breakpoint(id);
+                }
+                b"OK".to_vec()
+            }
+            b"vCont?" => b"vCont;c;C;s;S".to_vec(),
+            p if p.starts_with(b"vCont;") => {
+                let mut step_thread = None;
+                let mut signal = 0;
+                for action in p[6..].split(|&c| c == b';') {
+                    let (action, thread) = match action.iter().position(|&c| c == b':') {
+                        Some(colon) => (&action[..colon], rsp::parse_hex_u64(&action[colon + 1..]).ok().map(|t| t as u32)),
+                        None => (action, None),
+                    };
+                    let thread_id = self.thread_for(thread.unwrap_or(0));
+                    match action.first() {
+                        Some(b's') | Some(b'S') if step_thread.is_none() => step_thread = Some(thread_id),
+                        _ => {}
+                    }
+                    if matches!(action.first(), Some(b'C') | Some(b'S')) && thread_id == self.pump.event_context.thread_id {
+                        signal = rsp::parse_hex_u64(&action[1..]).unwrap_or(0) as u8;
+                    }
+                }
+                self.resume(step_thread, signal)?;
+                return self.report_stop();
+            }
+            p if matches!(p.first(), Some(b'c') | Some(b's') | Some(b'C') | Some(b'S')) => {
+                let step_thread = matches!(p[0], b's' | b'S').then_some(self.pump.event_context.thread_id);
+                let signal = if matches!(p[0], b'C' | b'S') { rsp::parse_hex_u64(&p[1..3.min(p.len())]).unwrap_or(0) as u8 } else { 0 };
+                self.resume(step_thread, signal)?;
+                return self.report_stop();
+            }
+            b"k" => return Ok(false),
+            p if p.starts_with(b"vKill") => {
+                self.connection.send_str("OK")?;
+                return Ok(false);
+            }
+            p if p.first() == Some(&b'D') => {
+                self.pump.breakpoints.remove_all_breakpoints(&self.pump.process, self.target);
+                let event_context = self.pump.event_context;
+                if self.target.detach(&event_context, ContinueStatus::Handled).is_ok() {
+                    self.connection.send_str("OK")?;
+                    return Ok(false);
+                }
+                b"E01".to_vec()
+            }
+            // An empty reply tells the front end that we don't support the packet
+            _ => Vec::new(),
+        };
+
+        self.connection.send_packet(&reply)?;
+        Ok(true)
+    }
+
+    fn report_stop(&mut self) -> Result<bool, &'static str> {
+        let stopped = self.run_until_stop()?;
+        self.connection.send_str(&self.stop_reply.clone())?;
+        Ok(stopped)
+    }
+}
+
+// Accepts a single connection from a front end and serves it until the target exits or the front end goes away
+pub fn run_server(target: &mut dyn DebugTarget, address: &str) -> Result<(), &'static str> {
+    let listener = TcpListener::bind(address).map_err(|_| "Could not listen for connections")?;
+    println!("Waiting for a GDB connection on {}", address);
+    let (stream, _) = listener.accept().map_err(|_| "Could not accept connection")?;
+
+    let mut server = GdbServer {
+        target,
+        connection: RspConnection::new(stream),
+        pump: EventPump::new(),
+        stop_reply: String::new(),
+        selected_thread: 0,
+    };
+    println!("Front end connected");
+
+    // Get the target to its initial breakpoint before the front end starts asking about it
+    if !server.run_until_stop()? {
+        return Err("Target exited before it could be debugged");
+    }
+
+    loop {
+        let packet = match server.connection.read_packet() {
+            Ok(packet) => packet,
+            Err(_) => return Ok(()),
+        };
+        if !server.handle_packet(&packet)? {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::fs::File;
+
+use windows::Win32::System::Diagnostics::Debug::{IMAGE_FILE_HEADER, IMAGE_NT_HEADERS64, IMAGE_SECTION_HEADER};
+use windows::Win32::System::SystemServices::{IMAGE_DOS_HEADER, IMAGE_DOS_SIGNATURE};
+
+use crate::dump_target::{DumpMemoryRange, DumpMemorySource, DumpTarget};
+use crate::event::DebugEvent;
+use crate::memory::{self, Pod};
+use crate::registers::RegisterContext;
+
+// Maps a PE file the way the loader would, with each section at its virtual address. Nothing is relocated, so if the
+// image is mapped somewhere other than its preferred base then any absolute addresses in it will be wrong.
+pub fn open_pe_image(path: &str, base_address: Option<u64>) -> Result<DumpTarget, &'static str> {
+    let file = File::open(path).map_err(|_| "Could not open image file")?;
+    let file_size = file.metadata().map_err(|_| "Could not open image file")?.len();
+
+    // The headers are read using file offsets, since nothing has been mapped yet
+    let raw = DumpMemorySource::new(vec![file.try_clone().map_err(|_| "Could not open image file")?], vec![DumpMemoryRange { address: 0, size: file_size, file_index: 0, file_offset: 0 }]);
+    let dos_header: IMAGE_DOS_HEADER = memory::read_memory_data(&raw, 0)?;
+    if dos_header.e_magic != IMAGE_DOS_SIGNATURE {
+        return Err("Not a PE image");
+    }
+    let pe_header_offset = dos_header.e_lfanew as u64;
+    let pe_header: IMAGE_NT_HEADERS64 = memory::read_memory_data(&raw, pe_header_offset)?;
+    let base_address = base_address.unwrap_or(pe_header.OptionalHeader.ImageBase);
+
+    // Everything is at an offset from the base address, and all of it has to fit below the end of the address space
+    let image_address = |offset: u64, size: u64| base_address.checked_add(offset).filter(|a| a.checked_add(size).is_some()).ok_or("Image does not fit at that base address");
+    image_address(0, pe_header.OptionalHeader.SizeOfImage as u64)?;
+
+    // The section table comes right after the optional header, which can vary in size
+    let section_table_offset = pe_header_offset.wrapping_add(4 + IMAGE_FILE_HEADER::SIZE as u64 + pe_header.FileHeader.SizeOfOptionalHeader as u64);
+    let sections: Vec<IMAGE_SECTION_HEADER> = memory::read_memory_full_array(&raw, section_table_offset, pe_header.FileHeader.NumberOfSections as usize)?;
+
+    let headers_size = pe_header.OptionalHeader.SizeOfHeaders as u64;
+    let mut ranges = vec![DumpMemoryRange { address: image_address(0, headers_size)?, size: headers_size, file_index: 0, file_offset: 0 }];
+    for section in sections.iter() {
+        // The loader zero fills anything past the raw data, but we'll just leave it unreadable
+        let virtual_size = unsafe { section.Misc.VirtualSize };
+        let size = if virtual_size == 0 { section.SizeOfRawData } else { std::cmp::min(virtual_size, section.SizeOfRawData) };
+        if size != 0 {
+            let address = image_address(section.VirtualAddress as u64, size as u64)?;
+            ranges.push(DumpMemoryRange { address, size: size as u64, file_index: 0, file_offset: section.PointerToRawData as u64 });
+        }
+    }
+
+    let entry_point = image_address(pe_header.OptionalHeader.AddressOfEntryPoint as u64, 0)?;
+    let stop_event = DebugEvent::Other(format!("Image mapped at {:X}", base_address));
+    let context = RegisterContext { rip: entry_point, ..Default::default() };
+    DumpTarget::new(DumpMemorySource::new(vec![file], ranges), 0, vec![(0, context)], vec![(base_address, Some(path.to_string()))], Some((0, stop_event)))
+}
+
+// Maps the whole file at base_address, for code that isn't in any image format like shellcode or firmware
+pub fn open_raw_image(path: &str, base_address: u64) -> Result<DumpTarget, &'static str> {
+    let file = File::open(path).map_err(|_| "Could not open image file")?;
+    let file_size = file.metadata().map_err(|_| "Could not open image file")?.len();
+
+    let end_address = base_address.checked_add(file_size).ok_or("Image does not fit at that base address")?;
+
+    let ranges = vec![DumpMemoryRange { address: base_address, size: file_size, file_index: 0, file_offset: 0 }];
+    let stop_event = DebugEvent::Other(format!("Raw image mapped at {:X}-{:X}", base_address, end_address));
+    let context = RegisterContext { rip: base_address, ..Default::default() };
+    DumpTarget::new(DumpMemorySource::new(vec![file], ranges), 0, vec![(0, context)], Vec::new(), Some((0, stop_event)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_target::temp_file_path;
+    use crate::target::DebugTarget;
+    use windows::Win32::System::Diagnostics::Debug::{IMAGE_NT_OPTIONAL_HDR64_MAGIC, IMAGE_OPTIONAL_HEADER64, IMAGE_SECTION_HEADER_0};
+    use windows::Win32::System::SystemInformation::IMAGE_FILE_MACHINE_AMD64;
+    use windows::Win32::System::SystemServices::IMAGE_NT_SIGNATURE;
+
+    fn with_file<R>(name: &str, data: &[u8], f: impl FnOnce(&str) -> R) -> R {
+        let path = temp_file_path(name);
+        std::fs::write(&path, data).unwrap();
+        let result = f(&path);
+        std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[test]
+    fn raw_image_is_mapped_at_base() {
+        let data: Vec<u8> = (0..0x100).map(|i| i as u8).collect();
+        let target = with_file("raw.bin", &data, |path| open_raw_image(path, 0x1000)).unwrap();
+        assert_eq!(target.memory_source().read_raw_memory(0x1000, 0x100), data);
+        assert_eq!(target.get_thread_context(0).unwrap().rip, 0x1000);
+    }
+
+    #[test]
+    fn raw_image_past_end_of_address_space_is_rejected() {
+        assert!(with_file("raw-end.bin", &[0; 0x100], |path| open_raw_image(path, u64::MAX - 0x10)).is_err());
+    }
+
+    // A PE file with just the headers and one section, with its code at 0x1000 in memory and 0x200 in the file
+    fn build_pe(image_base: u64) -> Vec<u8> {
+        let dos_header = IMAGE_DOS_HEADER { e_magic: IMAGE_DOS_SIGNATURE, e_lfanew: 0x40, ..Default::default() };
+        let mut pe_header = IMAGE_NT_HEADERS64 { Signature: IMAGE_NT_SIGNATURE, ..Default::default() };
+        pe_header.FileHeader.Machine = IMAGE_FILE_MACHINE_AMD64;
+        pe_header.FileHeader.NumberOfSections = 1;
+        pe_header.FileHeader.SizeOfOptionalHeader = IMAGE_OPTIONAL_HEADER64::SIZE as u16;
+        pe_header.OptionalHeader.Magic = IMAGE_NT_OPTIONAL_HDR64_MAGIC;
+        pe_header.OptionalHeader.ImageBase = image_base;
+        pe_header.OptionalHeader.AddressOfEntryPoint = 0x1000;
+        pe_header.OptionalHeader.SizeOfImage = 0x2000;
+        pe_header.OptionalHeader.SizeOfHeaders = 0x200;
+        let section = IMAGE_SECTION_HEADER {
+            Name: *b".text\0\0\0", Misc: IMAGE_SECTION_HEADER_0 { VirtualSize: 0x10 }, VirtualAddress: 0x1000,
+            SizeOfRawData: 0x200, PointerToRawData: 0x200, ..Default::default()
+        };
+
+        let mut data = memory::encode(&dos_header);
+        data.resize(0x40, 0);
+        pe_header.encode(&mut data);
+        section.encode(&mut data);
+        data.resize(0x200, 0);
+        data.extend((0..0x200).map(|i| i as u8));
+        data
+    }
+
+    #[test]
+    fn pe_image_is_mapped_at_base() {
+        let target = with_file("image.exe", &build_pe(0x1_4000_0000), |path| open_pe_image(path, None)).unwrap();
+        assert_eq!(target.memory_source().read_raw_memory(0x1_4000_0000, 2), b"MZ");
+        assert_eq!(target.memory_source().read_raw_memory(0x1_4000_1000, 4), [0, 1, 2, 3]);
+        assert_eq!(target.get_thread_context(0).unwrap().rip, 0x1_4000_1000);
+    }
+
+    #[test]
+    fn pe_image_past_end_of_address_space_is_rejected() {
+        let pe = build_pe(u64::MAX - 0xfff);
+        assert!(with_file("image-end.exe", &pe, |path| open_pe_image(path, None)).is_err());
+        // The headers fit, but the section doesn't
+        assert!(with_file("image-end.exe", &pe, |path| open_pe_image(path, Some(u64::MAX - 0x1fff))).is_err());
+    }
+}
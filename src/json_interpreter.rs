@@ -0,0 +1,342 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+
+use serde_json::{json, Map, Value};
+use crate::breakpoint::BreakpointManager;
+use crate::command::grammar::{self, CommandExpr};
+use crate::dap;
+use crate::eval;
+use crate::event_pump::{Event, EventPump, LoadedModule};
+use crate::name_resolution;
+use crate::process::Process;
+use crate::registers::{self, RegisterContext};
+use crate::source;
+use crate::stack;
+use crate::target::DebugTarget;
+use crate::unassemble;
+
+// Error codes from the JSON-RPC spec, and one of our own for when the debugger can't do what was asked
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const DEBUGGER_ERROR: i64 = -32000;
+
+// The code and message of a JSON-RPC error
+type RequestError = (i64, String);
+
+fn invalid_params(message: &str) -> RequestError {
+    (INVALID_PARAMS, message.to_string())
+}
+
+fn debugger_error(message: String) -> RequestError {
+    (DEBUGGER_ERROR, message)
+}
+
+// 64-bit values are sent as hex strings, since plenty of JSON parsers would lose precision on them as numbers
+fn hex(value: u64) -> Value {
+    json!(format!("{:#x}", value))
+}
+
+// Adds the module, symbol, and offset of an address to a result, for as much of that as is known
+fn add_symbol(result: &mut Value, address: u64, process: &mut Process) {
+    if let Some((module, symbol, offset)) = name_resolution::resolve_address_to_symbol(address, process) {
+        result["module"] = json!(module);
+        result["symbol"] = json!(symbol);
+        result["offset"] = hex(offset);
+    } else if let Some(module) = process.get_containing_module(address) {
+        result["module"] = json!(module.name);
+        result["offset"] = hex(address - module.address);
+    }
+}
+
+// Addresses can be given as numbers, or as expressions in the same syntax as the command line
+fn evaluate_param(value: &Value, process: &mut Process, ctx: &RegisterContext) -> Result<u64, RequestError> {
+    let expression = match value {
+        Value::Number(number) => return number.as_u64().ok_or_else(|| invalid_params("Numbers must be unsigned integers")),
+        Value::String(expression) => expression,
+        _ => return Err(invalid_params("Expected a number or an expression")),
+    };
+    let expr = match grammar::parse(&format!("? {}", expression)) {
+        Ok(CommandExpr::Evaluate(_, expr)) => expr,
+        _ => return Err((INVALID_PARAMS, format!("Could not parse expression: {}", expression))),
+    };
+    let mut eval_context = eval::EvalContext { process, register_context: ctx };
+    eval::evaluate_expression(*expr, &mut eval_context).map_err(|e| debugger_error(format!("Could not evaluate expression: {}", e)))
+}
+
+// Handles the requests that only look at the state of the target or the debugger, without letting the target run
+fn handle_query(method: &str, params: &Value, target: &dyn DebugTarget, process: &mut Process, breakpoints: &mut BreakpointManager, ctx: &RegisterContext, next_unassemble_address: &mut u64) -> Result<Value, RequestError> {
+    match method {
+        "registers" => {
+            let names: Vec<&str> = match params["names"].as_array() {
+                Some(names) => names.iter().filter_map(|name| name.as_str()).collect(),
+                None => registers::REGISTER_NAMES.to_vec(),
+            };
+            let mut values = Map::new();
+            for name in names {
+                let value = registers::get_register(ctx, name).map_err(|_| (INVALID_PARAMS, format!("Unrecognized register name: {}", name)))?;
+                values.insert(name.to_lowercase(), hex(value));
+            }
+            Ok(Value::Object(values))
+        }
+        "stack" => {
+            let mut context = *ctx;
+            let mut frames = Vec::new();
+            loop {
+                let mut frame = json!({ "frame": frames.len(), "rsp": hex(context.rsp), "rip": hex(context.rip) });
+                add_symbol(&mut frame, context.rip, process);
+                frames.push(frame);
+                match stack::unwind_context(process, context, target.memory_source()) {
+                    Ok(Some(unwound_context)) => context = unwound_context,
+                    _ => break,
+                }
+            }
+            Ok(json!({ "frames": frames }))
+        }
+        "setBreakpoint" => {
+            let address = evaluate_param(&params["address"], process, ctx)?;
+            let id = breakpoints.add_breakpoint(address).map_err(|e| debugger_error(format!("Could not set breakpoint: {}", e)))?;
+            Ok(json!({ "id": id, "address": hex(address) }))
+        }
+        "clearBreakpoint" => {
+            let id = params["id"].as_u64().ok_or_else(|| invalid_params("Expected a breakpoint id"))?;
+            breakpoints.clear_breakpoint(id as u32);
+            Ok(json!({}))
+        }
+        "breakpoints" => {
+            let list: Vec<Value> = breakpoints.iterate_breakpoints().map(|(id, address)| {
+                let mut breakpoint = json!({ "id": id, "address": hex(address) });
+                add_symbol(&mut breakpoint, address, process);
+                breakpoint
+            }).collect();
+            Ok(json!({ "breakpoints": list }))
+        }
+        "disassemble" => {
+            // Like u, carry on from where the last one stopped if no address is given
+            let address = match params.get("address") {
+                Some(address) => evaluate_param(address, process, ctx)?,
+                None => *next_unassemble_address,
+            };
+            let count = params["count"].as_u64().unwrap_or(16) as usize;
+            let instructions = unassemble::disassemble(target.memory_source(), address, count);
+            let last = instructions.last().ok_or_else(|| debugger_error(format!("Failed to read memory at {:#x}", address)))?;
+            *next_unassemble_address = last.address + last.bytes.len() as u64;
+            let lines: Vec<Value> = instructions.iter().map(|i| json!({ "address": hex(i.address), "bytes": i.bytes, "text": i.text })).collect();
+            Ok(json!({ "instructions": lines }))
+        }
+        "readMemory" => {
+            let address = evaluate_param(&params["address"], process, ctx)?;
+            let length = params["length"].as_u64().unwrap_or(16) as usize;
+            // Bytes that couldn't be read are null
+            let bytes = target.memory_source().read_memory(address, length).map_err(|e| debugger_error(format!("Could not read memory: {}", e)))?;
+            Ok(json!({ "address": hex(address), "bytes": bytes }))
+        }
+        "evaluate" => {
+            let value = evaluate_param(&params["expression"], process, ctx)?;
+            Ok(json!({ "value": hex(value) }))
+        }
+        "symbol" => {
+            let address = evaluate_param(&params["address"], process, ctx)?;
+            let mut result = json!({ "address": hex(address) });
+            add_symbol(&mut result, address, process);
+            if result.get("symbol").is_none() {
+                return Err(debugger_error("No symbol found".to_string()));
+            }
+            Ok(result)
+        }
+        "symbols" => {
+            let pattern = params["pattern"].as_str().ok_or_else(|| invalid_params("Expected a pattern"))?;
+            let symbols = name_resolution::find_matching_symbols(pattern, process).map_err(|e| debugger_error(format!("Could not search symbols: {}", e)))?;
+            let list: Vec<Value> = symbols.into_iter().map(|(address, name)| json!({ "address": hex(address), "name": name })).collect();
+            Ok(json!({ "symbols": list }))
+        }
+        "sourceLine" => {
+            let address = evaluate_param(&params["address"], process, ctx)?;
+            let (file, line) = source::resolve_address_to_source_line(address, process).map_err(|e| debugger_error(format!("Couldn't look up source: {}", e)))?;
+            Ok(json!({ "file": file, "line": line }))
+        }
+        "threads" => Ok(json!({ "threads": process.iterate_threads().collect::<Vec<_>>() })),
+        "modules" => {
+            let modules: Vec<Value> = process.iterate_modules().map(|m| json!({ "name": m.name, "address": hex(m.address), "size": hex(m.size) })).collect();
+            Ok(json!({ "modules": modules }))
+        }
+        _ => Err((METHOD_NOT_FOUND, format!("Unknown method: {}", method))),
+    }
+}
+
+// Drives the target the same way as the command line, but takes JSON-RPC 2.0 requests on stdin instead of commands
+// and answers them with structured results on stdout, one message per line. Debug events are sent as notifications
+// as they happen, followed by a "stopped" notification whenever the debugger is waiting for requests.
+pub struct JsonInterpreter {
+    reader: BufReader<File>,
+    writer: File,
+}
+
+impl JsonInterpreter {
+    pub fn new() -> Result<JsonInterpreter, &'static str> {
+        let (input, output) = dap::take_stdio()?;
+        Ok(JsonInterpreter { reader: BufReader::new(input), writer: output })
+    }
+
+    fn send(&mut self, message: Value) {
+        // If the client has gone away there's nobody to tell, and the next read will find out anyway
+        let _ = writeln!(self.writer, "{}", message).and_then(|_| self.writer.flush());
+    }
+
+    fn notify(&mut self, method: &str, params: Value) {
+        self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }));
+    }
+
+    // Requests without an id are notifications, which don't get a response
+    fn respond(&mut self, id: &Option<Value>, result: Result<Value, RequestError>) {
+        let Some(id) = id else {
+            return;
+        };
+        match result {
+            Ok(result) => self.send(json!({ "jsonrpc": "2.0", "id": id, "result": result })),
+            Err((code, message)) => self.send(json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })),
+        }
+    }
+
+    // Returns the id, method, and params of the next request. None means the client went away.
+    fn read_request(&mut self) -> Option<(Option<Value>, String, Value)> {
+        loop {
+            let mut line = String::new();
+            match self.reader.read_line(&mut line) {
+                Ok(0) | Err(_) => return None,
+                Ok(_) if line.trim().is_empty() => continue,
+                Ok(_) => {}
+            }
+            let request: Value = match serde_json::from_str(&line) {
+                Ok(request) => request,
+                Err(_) => {
+                    self.respond(&Some(Value::Null), Err((PARSE_ERROR, "Could not parse request".to_string())));
+                    continue;
+                }
+            };
+            let id = request.get("id").cloned();
+            match request["method"].as_str() {
+                Some(method) => return Some((id, method.to_string(), request["params"].clone())),
+                None => self.respond(&Some(id.unwrap_or_default()), Err((INVALID_REQUEST, "Request has no method".to_string()))),
+            }
+        }
+    }
+
+    fn module_loaded(&mut self, module: LoadedModule) {
+        let mut params = json!({ "address": hex(module.address), "name": module.name });
+        if let Some(e) = module.error {
+            params["error"] = json!(e);
+        }
+        self.notify("loadModule", params);
+    }
+
+    // Returns the process to attach to next, just like main_debugger_loop
+    pub fn debugger_loop(&mut self, target: &mut dyn DebugTarget) -> Option<u32> {
+        let mut pump = EventPump::new();
+
+        loop {
+            let event = match pump.next_event(target) {
+                Ok(event) => event,
+                Err(e) => {
+                    self.notify("error", json!({ "message": format!("Failed to wait for debug event: {}", e) }));
+                    return None;
+                }
+            };
+            let event_context = pump.event_context;
+            // A thread that has just exited may not have a context any more
+            let ctx = target.get_thread_context(event_context.thread_id).unwrap_or_default();
+
+            let is_exit = matches!(event, Event::Exited);
+            let is_setup_event = matches!(event, Event::ProcessCreated(_) | Event::ThreadCreated(_) | Event::ModuleLoaded(_));
+            match event {
+                Event::Step => {}
+                Event::Breakpoint(id) => self.notify("breakpointHit", json!({ "threadId": event_context.thread_id, "id": id })),
+                Event::Exception { first_chance, exception_code } => {
+                    self.notify("exception", json!({ "threadId": event_context.thread_id, "code": format!("{:#x}", exception_code), "firstChance": first_chance }));
+                }
+                Event::ProcessCreated(module) | Event::ModuleLoaded(module) => self.module_loaded(module),
+                Event::ThreadCreated(thread_id) => self.notify("threadCreated", json!({ "threadId": thread_id })),
+                Event::ThreadExited(thread_id) => self.notify("threadExited", json!({ "threadId": thread_id })),
+                Event::Output(debug_string) => self.notify("debugOutput", json!({ "text": debug_string })),
+                Event::Message(msg) => self.notify("message", json!({ "text": msg })),
+                Event::Exited => self.notify("exitProcess", json!({})),
+            }
+
+            let mut next_unassemble_address = ctx.rip;
+            // There's nothing to see at each module or thread of a dump file, just the event it was captured for
+            let mut continue_execution = is_setup_event && !target.is_live();
+            let mut resumed = false;
+
+            if !continue_execution {
+                let mut stopped = json!({ "processId": event_context.process_id, "threadId": event_context.thread_id, "rip": hex(ctx.rip) });
+                add_symbol(&mut stopped, ctx.rip, &mut pump.process);
+                self.notify("stopped", stopped);
+            }
+
+            while !continue_execution {
+                // Losing the client is the same as quitting
+                let (id, method, params) = self.read_request()?;
+                let result = match method.as_str() {
+                    "go" | "step" if !target.is_live() => Err(debugger_error("The target is not running".to_string())),
+                    // The process is already gone, so there's nothing to resume
+                    "go" if is_exit => {
+                        continue_execution = true;
+                        Ok(json!({}))
+                    }
+                    "go" | "step" => match pump.resume(target, (method == "step").then_some(event_context.thread_id)) {
+                        Ok(()) => {
+                            resumed = true;
+                            continue_execution = true;
+                            Ok(json!({}))
+                        }
+                        Err(e) => Err(debugger_error(format!("Could not resume the target: {}", e))),
+                    },
+                    "attach" | "detach" => {
+                        let process_id = params["processId"].as_u64();
+                        if method == "attach" && process_id.is_none() {
+                            Err(invalid_params("Expected a processId"))
+                        } else if method == "detach" && !target.is_live() {
+                            Err(debugger_error("The target is not running".to_string()))
+                        } else {
+                            // We can only debug one process at a time, so attaching lets go of this one first
+                            let detached = if target.is_live() {
+                                pump.breakpoints.remove_all_breakpoints(&pump.process, target);
+                                target.detach(&event_context, pump.continue_status)
+                            } else {
+                                Ok(())
+                            };
+                            match detached {
+                                Ok(()) => {
+                                    self.respond(&id, Ok(json!({})));
+                                    return process_id.map(|p| p as u32);
+                                }
+                                Err(e) => Err(debugger_error(format!("Could not detach: {}", e))),
+                            }
+                        }
+                    }
+                    "quit" => {
+                        // The process will be terminated since we didn't detach.
+                        self.respond(&id, Ok(json!({})));
+                        return None;
+                    }
+                    _ => handle_query(&method, &params, target, &mut pump.process, &mut pump.breakpoints, &ctx, &mut next_unassemble_address),
+                };
+                self.respond(&id, result);
+            }
+
+            if is_exit {
+                break;
+            }
+
+            if !resumed {
+                if let Err(e) = pump.resume(target, None) {
+                    self.notify("error", json!({ "message": format!("Failed to continue: {}", e) }));
+                    return None;
+                }
+            }
+        }
+
+        None
+    }
+}
@@ -1,40 +1,81 @@
 use event::DebugEvent;
 use memory::MemorySource;
-use windows_sys::{
-    Win32::Foundation::*,
-    Win32::System::Environment::*,
-    Win32::System::{Diagnostics::Debug::*, Threading::*},
-};
+use windows_sys::Win32::Foundation::EXCEPTION_SINGLE_STEP;
 
-use std::{fs::File, io::{self, BufRead}, mem::MaybeUninit, ptr::null, cmp::{max, min}};
+use std::{fs::File, io::{self, BufRead, Read}, cmp::{max, min}};
 
 mod command;
 mod eval;
 mod memory;
+mod memory_cache;
 mod process;
 mod registers;
 mod stack;
+mod cfi;
+mod dwarf;
+mod elf;
 mod module;
 mod name_resolution;
 mod event;
 mod breakpoint;
+#[cfg(windows)]
 mod util;
 mod unassemble;
 mod source;
+mod target;
+#[cfg(windows)]
+mod win32_target;
+#[cfg(target_os = "linux")]
+mod ptrace_target;
+mod rsp;
+mod rsp_target;
+mod dump_target;
+mod minidump;
+mod elf_core;
+mod image_target;
+mod trace;
+mod remote;
+mod dap;
+mod event_pump;
+mod json_interpreter;
+mod gdb_server;
+mod script;
+mod edit;
+mod display;
+mod search;
+mod address_map;
+mod snapshot;
+#[cfg(test)]
+mod fake_target;
 
 use process::Process;
-use command::grammar::{CommandExpr, EvalExpr};
+use command::grammar::{CommandExpr, DisplayRange, EvalExpr, RangeExpr, SearchValue};
 use breakpoint::BreakpointManager;
-use util::*;
 use source::resolve_address_to_source_line;
+use target::{ContinueStatus, DebugTarget};
 
-const TRAP_FLAG: u32 = 1 << 8;
+// The most memory that a single range can cover, which is the same as WinDbg's limit
+const MAX_RANGE_SIZE: u64 = 256 * 1024 * 1024;
 
 fn show_usage(error_message: &str) {
     println!("Error: {msg}", msg = error_message);
     println!("Usage: DbgRs <Command Line>");
+    println!("       DbgRs -p <Process ID>");
+    println!("       DbgRs --remote <host:port>");
+    println!("       DbgRs --gdbserver <[host:]port> <Command Line>");
+    println!("       DbgRs --server <[host:]port> <Target Arguments>");
+    println!("       DbgRs --connect <host:port>");
+    println!("       DbgRs --dap");
+    println!("       DbgRs --interpreter=json <Target Arguments>");
+    println!("       DbgRs [-c <Commands>] [-cf <Script File>] [--batch] <Target Arguments>");
+    println!("       DbgRs -o <Command Line>");
+    println!("       DbgRs -z <Minidump or Core File>");
+    println!("       DbgRs --image <PE File or Raw Binary> [Base Address]");
+    println!("       DbgRs --replay <Trace File>");
+    println!("       DbgRs --record <Trace File> <Target Arguments>");
 }
 
+#[cfg(windows)]
 unsafe fn wcslen(ptr: *const u16) -> usize {
     let mut len = 0;
     while *ptr.add(len) != 0 {
@@ -47,7 +88,10 @@ unsafe fn wcslen(ptr: *const u16) -> usize {
 // command line options such as attaching to processes.
 // Q: Why not just convert to UTF8?
 // A: There can be cases where this is lossy, and we want to make sure we can debug as close as possible to normal execution.
-fn parse_command_line() -> Result<Vec<u16>, &'static str> {
+#[cfg(windows)]
+fn parse_command_line(skip_args: usize) -> Result<Vec<u16>, &'static str> {
+    use windows_sys::Win32::System::Environment::GetCommandLineW;
+
     let cmd_line = unsafe {
         // As far as I can tell, standard rust command line argument parsing won't preserve spaces. So we'll call
         // the win32 api directly and then parse it out.
@@ -56,61 +100,116 @@ fn parse_command_line() -> Result<Vec<u16>, &'static str> {
         std::slice::from_raw_parts(p, len + 1)
     };
 
-    let mut cmd_line_iter = cmd_line.iter().copied();
+    let mut cmd_line_iter = cmd_line.iter().copied().peekable();
 
-    let first = cmd_line_iter.next().ok_or("Command line was empty")?;
+    // Skip the debugger itself, along with any of our own options that come before the target command line.
+    for _ in 0..=skip_args {
+        let first = cmd_line_iter.next().ok_or("Command line was empty")?;
 
-    // If the first character is a quote, we need to find a matching end quote. Otherwise, the first space.
-    let end_char = (if first == '"' as u16 { '"' } else { ' ' }) as u16;
+        // If the first character is a quote, we need to find a matching end quote. Otherwise, the first space.
+        let end_char = (if first == '"' as u16 { '"' } else { ' ' }) as u16;
 
-    loop {
-        let next = cmd_line_iter.next().ok_or("No arguments found")?;
-        if next == end_char {
-            break;
+        loop {
+            let next = cmd_line_iter.next().ok_or("No arguments found")?;
+            if next == end_char {
+                break;
+            }
         }
-    }
 
-    // Now we need to skip any whitespace
-    let cmd_line_iter = cmd_line_iter.skip_while(|x| x == &(' ' as u16));
+        // Now we need to skip any whitespace
+        while cmd_line_iter.next_if_eq(&(' ' as u16)).is_some() {}
+    }
 
     Ok(cmd_line_iter.collect())
 }
 
 fn load_module_at_address(process: &mut Process, memory_source: &dyn MemorySource, base_address: u64, module_name: Option<String>) {
-    let module = process.add_module(base_address, module_name, memory_source).unwrap();
+    match process.add_module(base_address, module_name.clone(), memory_source) {
+        Ok(module) => println!("LoadDll: {:X}   {}", base_address, module.name),
+        Err(e) => println!("LoadDll: {:X}   {} (could not load module: {})", base_address, module_name.unwrap_or_default(), e),
+    }
+}
+
+// Everything we keep track of for each process being debugged. There's only more than one when child processes are
+// being debugged too.
+struct DebuggedProcess {
+    process_id: u32,
+    process: Process,
+    breakpoints: BreakpointManager,
+    snapshots: snapshot::Snapshots,
+}
+
+impl DebuggedProcess {
+    fn new(process_id: u32) -> DebuggedProcess {
+        DebuggedProcess { process_id, process: Process::new(), breakpoints: BreakpointManager::new(), snapshots: snapshot::Snapshots::default() }
+    }
+}
 
-    println!("LoadDll: {:X}   {}", base_address, module.name);
+// Like WinDbg, the current process is marked with a . and the one that reported the event with a #
+fn list_processes(processes: &[DebuggedProcess], current_index: usize, event_index: usize) {
+    for (index, debugged) in processes.iter().enumerate() {
+        let marker = if index == current_index { '.' } else if index == event_index { '#' } else { ' ' };
+        let name = debugged.process.iterate_modules().next().map_or("", |module| module.name.as_str());
+        println!("{} {:2} id: {:x} {}", marker, index, debugged.process_id, name);
+    }
 }
 
-fn main_debugger_loop(process: HANDLE) {
+// Returns the process to attach to next, if the user asked to switch to one
+fn main_debugger_loop(target: &mut dyn DebugTarget, commands: &mut command::CommandReader) -> Option<u32> {
     let mut expect_step_exception = false;
-    let mem_source = memory::make_live_memory_source(process);
-    let mut process = Process::new();
-    let mut breakpoints = BreakpointManager::new();
+    let mut processes: Vec<DebuggedProcess> = Vec::new();
+    let mut scripts = script::ScriptEngine::new();
 
     let mut source_search_paths = Vec::new();
+    // The d commands carry on from where the last one left off, and a plain d uses the same format again
+    let mut display_format = display::DisplayFormat::Bytes;
+    let mut next_display_address = 0;
 
     loop {
-        let (event_context, debug_event) = event::wait_for_next_debug_event(mem_source.as_ref());
+        let (event_context, debug_event) = match target.wait_for_event() {
+            Ok(event) => event,
+            Err(e) => {
+                println!("Failed to wait for debug event: {}", e);
+                return None;
+            }
+        };
+        let mem_source = target.memory_source();
 
         // The thread context will be needed to determine what to do with some events
-        let thread = AutoClosedHandle(unsafe {
-            OpenThread(
-                THREAD_GET_CONTEXT | THREAD_SET_CONTEXT,
-                FALSE,
-                event_context.thread_id,
-            )
-        });
+        let mut ctx = match target.get_thread_context(event_context.thread_id) {
+            Ok(ctx) => ctx,
+            Err(e) => {
+                // A thread that has just exited may not have a context any more
+                if !matches!(debug_event, DebugEvent::ExitThread { .. } | DebugEvent::ExitProcess) {
+                    println!("Could not get thread context of thread {:x}: {}", event_context.thread_id, e);
+                }
+                Default::default()
+            }
+        };
 
-        let mut ctx: AlignedContext = unsafe { std::mem::zeroed() };
-        ctx.context.ContextFlags = CONTEXT_ALL;
-        let ret = unsafe { GetThreadContext(thread.handle(), &mut ctx.context) };
-        if ret == 0 {
-            panic!("GetThreadContext failed");
+        // A Linux process that runs a new program gets a new address space, so it starts over as a new process
+        if matches!(debug_event, DebugEvent::CreateProcess { .. }) {
+            processes.retain(|p| p.process_id != event_context.process_id);
         }
+        let event_index = match processes.iter().position(|p| p.process_id == event_context.process_id) {
+            Some(index) => index,
+            None => {
+                processes.push(DebuggedProcess::new(event_context.process_id));
+                processes.len() - 1
+            }
+        };
+        let is_last_process = processes.len() == 1;
+        let DebuggedProcess { process, breakpoints, .. } = &mut processes[event_index];
 
-        let mut continue_status = DBG_CONTINUE;
+        let mut continue_status = ContinueStatus::Handled;
         let mut is_exit = false;
+        let mut process_exited = false;
+        let mut hit_breakpoint = false;
+        let exception_code = match debug_event {
+            DebugEvent::Exception { exception_code, .. } => Some(exception_code),
+            _ => None,
+        };
+        let is_setup_event = matches!(debug_event, DebugEvent::CreateProcess { .. } | DebugEvent::CreateThread { .. } | DebugEvent::LoadModule { .. });
         match debug_event {
             DebugEvent::Exception { first_chance, exception_code } => {
                 let chance_string = if first_chance {
@@ -121,17 +220,18 @@ fn main_debugger_loop(process: HANDLE) {
 
                 if expect_step_exception && exception_code == EXCEPTION_SINGLE_STEP {
                     expect_step_exception = false;
-                    continue_status = DBG_CONTINUE;
-                } else if let Some(bp_index) = breakpoints.was_breakpoint_hit(&ctx.context) {
+                    continue_status = ContinueStatus::Handled;
+                } else if let Some(bp_index) = breakpoints.was_breakpoint_hit(&ctx) {
                     println!("Breakpoint {} hit", bp_index);
-                    continue_status = DBG_CONTINUE;
+                    continue_status = ContinueStatus::Handled;
+                    hit_breakpoint = true;
                 } else {
                     println!("Exception code {:x} ({})", exception_code, chance_string);
-                    continue_status = DBG_EXCEPTION_NOT_HANDLED;
+                    continue_status = ContinueStatus::NotHandled;
                 }
             },
             DebugEvent::CreateProcess { exe_name, exe_base } => {
-                load_module_at_address(&mut process, mem_source.as_ref(), exe_base, exe_name);
+                load_module_at_address(process, mem_source, exe_base, exe_name);
                 process.add_thread(event_context.thread_id);
             },
             DebugEvent::CreateThread { thread_id } => {
@@ -143,32 +243,83 @@ fn main_debugger_loop(process: HANDLE) {
                 println!("Thread exited: {:x}", thread_id);
             },
             DebugEvent::LoadModule { module_name, module_base } => {
-                load_module_at_address(&mut process, mem_source.as_ref(), module_base, module_name);
+                load_module_at_address(process, mem_source, module_base, module_name);
             },
             DebugEvent::OutputDebugString(debug_string) => println!("DebugOut: {}", debug_string),
             DebugEvent::Other(msg) => println!("{}", msg),
             DebugEvent::ExitProcess => {
-                is_exit = true;
+                // The session only ends once every process has exited
+                process_exited = true;
+                is_exit = is_last_process;
                 println!("ExitProcess");
             },
         }
 
-        let mut next_unassemble_address = ctx.context.Rip;
-        let mut continue_execution = false;
+        // Breakpoints set by scripts can have a callback that decides whether to stop
+        let script_continue = hit_breakpoint && !scripts.breakpoint_hit(ctx.rip, &mut script::ScriptSession {
+            target: &mut *target,
+            process_id: event_context.process_id,
+            process,
+            breakpoints,
+            thread_id: event_context.thread_id,
+            context: &ctx,
+        });
+
+        let mut next_unassemble_address = ctx.rip;
+        // The | command can switch to another process to look at, which doesn't change where the target resumes
+        let mut current_index = event_index;
+        let mut thread_id = event_context.thread_id;
+        // There's nothing to see at each module or thread of a dump file, just the event it was captured for, which is
+        // always a break since it's the only stop there is. Batch mode only stops for exceptions and the end of the
+        // process, since nobody is there to look at the rest.
+        let is_final_stop = !target.is_live() && !is_setup_event;
+        let is_break = (exception_code.is_some() && !script_continue) || is_exit || is_final_stop;
+        let mut continue_execution = (is_setup_event && !target.is_live()) || (commands.is_batch() && !is_break) || script_continue;
+        if is_break {
+            commands.break_in(is_exit);
+        }
+
+        // Watched snapshots show what changed since the last time the debugger stopped here
+        if !continue_execution {
+            let DebuggedProcess { process, snapshots, .. } = &mut processes[event_index];
+            snapshots.report_watched(target.memory_source(), process);
+        }
 
         while !continue_execution {
+            // The process ID is only worth showing when there's more than one
+            let thread_prefix = if processes.len() > 1 {
+                format!("[{:X}:{:X}]", processes[current_index].process_id, thread_id)
+            } else {
+                format!("[{:X}]", thread_id)
+            };
+            let DebuggedProcess { process_id, process, breakpoints, snapshots } = &mut processes[current_index];
+            let process_id = *process_id;
 
-            if let Some(sym) = name_resolution::resolve_address_to_name(ctx.context.Rip, &mut process) {
-                println!("[{:X}] {}", event_context.thread_id, sym);
+            macro_rules! script_session {
+                () => {
+                    script::ScriptSession { target: &mut *target, process_id, process: &mut *process, breakpoints: &mut *breakpoints, thread_id, context: &ctx }
+                };
+            }
+
+            if let Some(sym) = name_resolution::resolve_address_to_name(ctx.rip, process) {
+                println!("{} {}", thread_prefix, sym);
             } else {
-                println!("[{:X}] {:#018x}", event_context.thread_id, ctx.context.Rip);
+                println!("{} {:#018x}", thread_prefix, ctx.rip);
             }
 
-            let cmd = command::read_command();
+            let cmd = match commands.read_command() {
+                Some(cmd) => cmd,
+                // The end of a batch script carries on as if it ended with g, until the target exits
+                None if commands.is_batch() && !is_exit && target.is_live() => {
+                    continue_execution = true;
+                    continue;
+                }
+                None => return None,
+            };
 
 
             let mut eval_expr = |expr: Box<EvalExpr>| -> Option<u64> {
-                let mut eval_context = eval::EvalContext{ process: &mut process, register_context: &ctx.context };
+                let mut eval_context = eval::EvalContext{ process: &mut *process, register_context: &ctx };
                 let result = eval::evaluate_expression(*expr, &mut eval_context);
                 match result {
                     Ok(val) => Some(val),
@@ -179,32 +330,133 @@ fn main_debugger_loop(process: HANDLE) {
                 }
             };
 
+            // Ranges count elements of the command's size, so "ed rsp L4" covers 16 bytes. The result is the start
+            // address and the number of elements.
+            let mut eval_range = |range: RangeExpr, element_size: u64| -> Option<(u64, u64)> {
+                let (start, count) = match range {
+                    RangeExpr::Count(start, count) => (eval_expr(start)?, count),
+                    RangeExpr::Length(start, _, count) => (eval_expr(start)?, eval_expr(count)?),
+                    RangeExpr::Span(start, end) => {
+                        let (start, end) = (eval_expr(start)?, eval_expr(end)?);
+                        if end < start {
+                            println!("The end of the range is before the start");
+                            return None;
+                        }
+                        (start, (end - start) / element_size + 1)
+                    }
+                };
+                // A typo in a range can easily ask for more memory than there is, so this has a limit like WinDbg does
+                if count.saturating_mul(element_size) > MAX_RANGE_SIZE {
+                    println!("Range is too large");
+                    return None;
+                }
+                Some((start, count))
+            };
+
             match cmd {
                 CommandExpr::StepInto(_) => {
-                    ctx.context.EFlags |= TRAP_FLAG;
-                    let ret = unsafe { SetThreadContext(thread.handle(), &ctx.context) };
-                    if ret == 0 {
-                        panic!("SetThreadContext failed");
+                    if !target.is_live() {
+                        println!("The target is not running");
+                        continue;
+                    }
+                    if let Err(e) = target.step_thread(thread_id) {
+                        println!("Could not step thread: {}", e);
+                        continue;
                     }
                     expect_step_exception = true;
                     continue_execution = true;
                 }
                 CommandExpr::Go(_) => {
+                    if !target.is_live() {
+                        println!("The target is not running");
+                        continue;
+                    }
                     continue_execution = true;
                 }
                 CommandExpr::DisplayRegisters(_) => {
-                    registers::display_all(&ctx.context);
+                    registers::display_all(&ctx);
                 }
                 CommandExpr::DisplaySpecificRegister(_, reg) => {
-                    registers::display_named(&ctx.context, &reg);
+                    registers::display_named(&ctx, &reg);
+                }
+                CommandExpr::DisplayMemory(command, range) => {
+                    display_format = display::DisplayFormat::from_command(&command).unwrap_or(display_format);
+                    let range = match range {
+                        DisplayRange::Range(range) => eval_range(range, display_format.element_size()).map(|(address, count)| (address, Some(count))),
+                        DisplayRange::Address(expr) => eval_expr(expr).map(|address| (address, None)),
+                    };
+                    if let Some((address, count)) = range {
+                        next_display_address = display::display_memory(display_format, address, count, target.memory_source(), process);
+                    }
+                }
+                CommandExpr::DisplayMemoryContinue(command) => {
+                    display_format = display::DisplayFormat::from_command(&command).unwrap_or(display_format);
+                    next_display_address = display::display_memory(display_format, next_display_address, None, target.memory_source(), process);
+                }
+                CommandExpr::EnterValues(element_size, expr, values) => {
+                    if let Some(address) = eval_expr(expr) {
+                        if let Some(values) = values.into_iter().map(|v| eval_expr(Box::new(v))).collect::<Option<Vec<u64>>>() {
+                            if let Err(e) = edit::enter_values(target, address, &values, element_size) {
+                                println!("Could not write memory: {}", e);
+                            }
+                        }
+                    }
                 }
-                CommandExpr::DisplayBytes(_, expr) => {
+                CommandExpr::EnterString(is_wide, expr, text) => {
                     if let Some(address) = eval_expr(expr) {
-                        let bytes = mem_source.read_raw_memory(address, 16);
-                        for byte in bytes {
-                            print!("{:02X} ", byte);
+                        if let Err(e) = edit::enter_string(target, address, &text, is_wide) {
+                            println!("Could not write memory: {}", e);
+                        }
+                    }
+                }
+                CommandExpr::Fill(_, range, pattern) => {
+                    if let Some((address, len)) = eval_range(range, 1) {
+                        if let Some(pattern) = pattern.into_iter().map(|v| eval_expr(Box::new(v))).collect::<Option<Vec<u64>>>() {
+                            if let Err(e) = edit::fill(target, address, len, &pattern) {
+                                println!("Could not write memory: {}", e);
+                            }
+                        }
+                    }
+                }
+                CommandExpr::Move(_, range, destination) => {
+                    if let Some((address, len)) = eval_range(range, 1) {
+                        if let Some(destination) = eval_expr(destination) {
+                            if let Err(e) = edit::move_memory(target, address, len, destination) {
+                                println!("Could not write memory: {}", e);
+                            }
+                        }
+                    }
+                }
+                CommandExpr::SearchValues(_, element_size, range, values) => {
+                    if let Some((address, count)) = eval_range(range, element_size as u64) {
+                        let values = values.into_iter().map(|v| match v {
+                            SearchValue::Value(expr) => eval_expr(expr).map(Some),
+                            SearchValue::Wildcard(_) => Some(None),
+                        }).collect::<Option<Vec<Option<u64>>>>();
+                        if let Some(values) = values {
+                            match search::value_pattern(&values, element_size) {
+                                Ok(pattern) => {
+                                    let format = match element_size {
+                                        1 => display::DisplayFormat::Bytes,
+                                        2 => display::DisplayFormat::Words,
+                                        4 => display::DisplayFormat::Dwords,
+                                        _ => display::DisplayFormat::Qwords,
+                                    };
+                                    for found in search::search_memory(target.memory_source(), address, count * element_size as u64, &pattern) {
+                                        display::display_memory(format, found, Some(16 / element_size as u64), target.memory_source(), process);
+                                    }
+                                }
+                                Err(e) => println!("Could not search memory: {}", e),
+                            }
+                        }
+                    }
+                }
+                CommandExpr::SearchString(_, is_wide, range, text) => {
+                    if let Some((address, len)) = eval_range(range, 1) {
+                        let pattern = search::string_pattern(&text, is_wide);
+                        for found in search::search_memory(target.memory_source(), address, len, &pattern) {
+                            display::display_memory(display::DisplayFormat::Bytes, found, Some(16), target.memory_source(), process);
                         }
-                        println!();
                     }
                 }
                 CommandExpr::Evaluate(_, expr) => {
@@ -214,7 +466,7 @@ fn main_debugger_loop(process: HANDLE) {
                 }
                 CommandExpr::ListNearest(_, expr) => {
                     if let Some(val) = eval_expr(expr) {
-                        if let Some(sym) = name_resolution::resolve_address_to_name(val, &mut process) {
+                        if let Some(sym) = name_resolution::resolve_address_to_name(val, process) {
                             println!("{}", sym);
                         } else {
                             println!("No symbol found");
@@ -223,15 +475,15 @@ fn main_debugger_loop(process: HANDLE) {
                 }
                 CommandExpr::Unassemble(_, expr) => {
                     if let Some(addr) = eval_expr(expr) {
-                        next_unassemble_address = unassemble::unassemble(mem_source.as_ref(), addr, 16);
+                        next_unassemble_address = unassemble::unassemble(target.memory_source(), addr, 16);
                     }
                 }
                 CommandExpr::UnassembleContinue(_) => {
-                    next_unassemble_address = unassemble::unassemble(mem_source.as_ref(), next_unassemble_address, 16);
+                    next_unassemble_address = unassemble::unassemble(target.memory_source(), next_unassemble_address, 16);
                 }
                 CommandExpr::ListSource(_, expr) => {
                     if let Some(val) = eval_expr(expr) {
-                        match resolve_address_to_source_line(val, &mut process) {
+                        match resolve_address_to_source_line(val, process) {
                             Ok((file_name, line_number)) => {
                                 println!("LSA: {}:{}", file_name, line_number);
                                 if let Ok(file_name) = source::find_source_file_match(&file_name, &source_search_paths) {
@@ -261,39 +513,176 @@ fn main_debugger_loop(process: HANDLE) {
                     source_search_paths.clear();
                     source_search_paths.extend(path.split(';').map(|s| s.to_string()));
                 }
+                CommandExpr::ExamineSymbols(_, pattern) => {
+                    match name_resolution::find_matching_symbols(&pattern, process) {
+                        Ok(symbols) => {
+                            for (address, name) in symbols {
+                                println!("{:016X} {}", address, name);
+                            }
+                        }
+                        Err(e) => println!("Could not search symbols: {}", e),
+                    }
+                }
+                CommandExpr::DisplayFunctionEntry(_, expr) => {
+                    if let Some(address) = eval_expr(expr) {
+                        if let Err(e) = stack::display_function_entry(process, address, target.memory_source()) {
+                            println!("Could not display function entry: {}", e);
+                        }
+                    }
+                }
+                CommandExpr::WriteDump(_, args) => {
+                    // Like WinDbg, /ma saves all of the memory. Otherwise it's just enough to walk the stacks.
+                    let (full_memory, path) = match args.strip_prefix("/ma") {
+                        Some(path) => (true, path.trim()),
+                        None => (false, args.strip_prefix("/m").unwrap_or(&args).trim()),
+                    };
+                    if path.is_empty() {
+                        println!("Usage: .dump [/ma] <path>");
+                    } else {
+                        // The exception only belongs in the dump of the process that it happened in
+                        let exception_code = exception_code.filter(|_| current_index == event_index);
+                        match minidump::write_minidump(path, full_memory, target, process, thread_id, exception_code) {
+                            Ok(()) => println!("Dump written to {}", path),
+                            Err(e) => println!("Could not write dump: {}", e),
+                        }
+                    }
+                }
                 CommandExpr::SetBreakpoint(_, expr) => {
                     if let Some(addr) = eval_expr(expr) {
-                        breakpoints.add_breakpoint(addr);
+                        // Scripts that run at every stop set the same breakpoints each time
+                        if let Some(id) = breakpoints.find_breakpoint(addr) {
+                            println!("Breakpoint {} is already set at {:#018x}", id, addr);
+                        } else if let Err(e) = breakpoints.add_breakpoint(addr) {
+                            println!("Could not set breakpoint: {}", e);
+                        }
                     }
                 }
                 CommandExpr::ListBreakpoints(_) => {
-                    breakpoints.list_breakpoints(&mut process);
+                    breakpoints.list_breakpoints(process);
                 }
                 CommandExpr::ClearBreakpoint(_, expr) => {
                     if let Some(id) = eval_expr(expr) {
                         breakpoints.clear_breakpoint(id as u32);
+                        scripts.retain_breakpoints(process_id, breakpoints);
                     }
                 }
                 CommandExpr::StackWalk(_) => {
-                    let mut context = ctx.context.clone();
+                    let mut context = ctx;
                     println!(" #   RSP              Call Site");
                     let mut frame_number = 0;
                     loop {
-                        if let Some(sym) = name_resolution::resolve_address_to_name(context.Rip, &mut process) {
-                            println!("{:02X} 0x{:016X} {}", frame_number, context.Rsp, sym);
+                        if let Some(sym) = name_resolution::resolve_address_to_name(context.rip, process) {
+                            println!("{:02X} 0x{:016X} {}", frame_number, context.rsp, sym);
                         } else {
-                            println!("{:02X} 0x{:016X} 0x{:X}", frame_number, context.Rsp, context.Rip);
+                            println!("{:02X} 0x{:016X} 0x{:X}", frame_number, context.rsp, context.rip);
                         }
-                        match stack::unwind_context(&mut process, context, mem_source.as_ref()) {
+                        match stack::unwind_context(process, context, target.memory_source()) {
                             Ok(Some(unwound_context)) => context = unwound_context,
                             _ => break
                         }
                         frame_number += 1;
                     }
                 }
+                CommandExpr::Attach(_, expr) => {
+                    if let Some(process_id) = eval_expr(expr) {
+                        // We can only debug one process at a time, so let go of this one first
+                        if target.is_live() {
+                            for debugged in processes.iter_mut() {
+                                debugged.breakpoints.remove_all_breakpoints(&debugged.process, target);
+                            }
+                            if let Err(e) = target.detach(&event_context, continue_status) {
+                                println!("Could not detach: {}", e);
+                                continue;
+                            }
+                        }
+                        return Some(process_id as u32);
+                    }
+                }
+                CommandExpr::Detach(_) => {
+                    if !target.is_live() {
+                        println!("The target is not running");
+                        continue;
+                    }
+                    for debugged in processes.iter_mut() {
+                        debugged.breakpoints.remove_all_breakpoints(&debugged.process, target);
+                    }
+                    match target.detach(&event_context, continue_status) {
+                        Ok(()) => {
+                            println!("Detached");
+                            return None;
+                        }
+                        Err(e) => println!("Could not detach: {}", e),
+                    }
+                }
+                CommandExpr::RunScript(_, path) => {
+                    if let Err(e) = commands.run_script(&path) {
+                        println!("Could not run script {}: {}", path, e);
+                    }
+                }
+                CommandExpr::LoadScript(_, path) => {
+                    if let Err(e) = scripts.run_file(&path, &mut script_session!()) {
+                        println!("Script {} failed: {}", path, e);
+                    }
+                }
+                CommandExpr::ScriptCommand(name, args) => {
+                    if let Err(e) = scripts.run_command(&name, &args.unwrap_or_default(), &mut script_session!()) {
+                        println!("!{} failed: {}", name, e);
+                    }
+                }
+                CommandExpr::AddressMap(_) => {
+                    address_map::display_address_map(target, process);
+                }
+                CommandExpr::AddressInfo(_, expr) => {
+                    if let Some(address) = eval_expr(expr) {
+                        address_map::display_address_info(target, process, address);
+                    }
+                }
+                CommandExpr::ListSnapshots(_) => {
+                    snapshots.list();
+                }
+                CommandExpr::TakeSnapshot(_, watched, name, range) => {
+                    if let Some((address, len)) = eval_range(range, 1) {
+                        snapshots.take(&name, address, len, watched.is_some(), target.memory_source());
+                    }
+                }
+                CommandExpr::DeleteSnapshot(_, _, name) => {
+                    if let Err(e) = snapshots.remove(&name) {
+                        println!("{}", e);
+                    }
+                }
+                CommandExpr::DiffSnapshot(_, name) => {
+                    if let Err(e) = snapshots.diff(&name, target.memory_source(), process) {
+                        println!("{}", e);
+                    }
+                }
+                CommandExpr::ListProcesses(_) => {
+                    list_processes(&processes, current_index, event_index);
+                }
+                CommandExpr::SwitchProcess(index) => {
+                    let index = index as usize;
+                    let Some(debugged) = processes.get(index) else {
+                        println!("No process {}", index);
+                        continue;
+                    };
+                    if index != current_index {
+                        if let Err(e) = target.switch_process(debugged.process_id) {
+                            println!("Could not switch to process {:x}: {}", debugged.process_id, e);
+                            continue;
+                        }
+                    }
+                    // Other processes are looked at from their first thread, since none of their threads have an event
+                    thread_id = match debugged.process.iterate_threads().next() {
+                        _ if index == event_index => event_context.thread_id,
+                        Some(first_thread) => *first_thread,
+                        None => 0,
+                    };
+                    ctx = target.get_thread_context(thread_id).unwrap_or_default();
+                    next_unassemble_address = ctx.rip;
+                    current_index = index;
+                }
                 CommandExpr::Quit(_) => {
                     // The process will be terminated since we didn't detach.
-                    return;
+                    return None;
                 }
             }
         }
@@ -302,62 +691,304 @@ fn main_debugger_loop(process: HANDLE) {
             break;
         }
 
-        breakpoints.apply_breakpoints(&mut process, event_context.thread_id, mem_source.as_ref());
+        if process_exited {
+            processes.retain(|p| p.process_id != event_context.process_id);
+        }
+
+        if target.is_live() {
+            for debugged in processes.iter_mut() {
+                debugged.breakpoints.apply_breakpoints(&mut debugged.process, event_context.thread_id, target);
+            }
+        }
 
-        unsafe {
-            ContinueDebugEvent(
-                event_context.process_id,
-                event_context.thread_id,
-                continue_status,
-            );
+        if let Err(e) = target.continue_event(&event_context, continue_status) {
+            println!("Failed to continue: {}", e);
+            return None;
         }
     }
-}
 
-fn main() {
-    let target_command_line_result = parse_command_line();
+    None
+}
 
-    let mut command_line_buffer = match target_command_line_result {
-        Ok(i) => i,
-        Err(msg) => {
-            show_usage(msg);
-            return;
-        }
-    };
+#[cfg(windows)]
+fn launch_target(skip_args: usize, debug_children: bool) -> Result<Box<dyn DebugTarget>, &'static str> {
+    let mut command_line_buffer = parse_command_line(skip_args)?;
 
     println!(
         "Command line was: '{str}'",
         str = String::from_utf16_lossy(&command_line_buffer)
     );
 
-    let mut si: STARTUPINFOEXW = unsafe { std::mem::zeroed() };
-    si.StartupInfo.cb = std::mem::size_of::<STARTUPINFOEXW>() as u32;
-    let mut pi: MaybeUninit<PROCESS_INFORMATION> = MaybeUninit::uninit();
-    let ret = unsafe {
-        CreateProcessW(
-            null(),
-            command_line_buffer.as_mut_ptr(),
-            null(),
-            null(),
-            FALSE,
-            DEBUG_ONLY_THIS_PROCESS | CREATE_NEW_CONSOLE,
-            null(),
-            null(),
-            &mut si.StartupInfo,
-            pi.as_mut_ptr(),
-        )
+    Ok(Box::new(win32_target::Win32Target::launch(&mut command_line_buffer, debug_children)?))
+}
+
+#[cfg(target_os = "linux")]
+fn launch_target(skip_args: usize, debug_children: bool) -> Result<Box<dyn DebugTarget>, &'static str> {
+    // Unlike Windows, the arguments are already split up by the time we get them, so there's nothing to preserve.
+    let args: Vec<String> = std::env::args().skip(1 + skip_args).collect();
+    if args.is_empty() {
+        return Err("No arguments found");
+    }
+
+    println!("Command line was: '{str}'", str = args.join(" "));
+
+    launch_program(&args, debug_children)
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+fn launch_target(_skip_args: usize, _debug_children: bool) -> Result<Box<dyn DebugTarget>, &'static str> {
+    Err("Launching processes is not supported on this platform")
+}
+
+// Launches a program from arguments that have already been split up, like the ones a debug adapter client sends
+#[cfg(windows)]
+fn launch_program(args: &[String], debug_children: bool) -> Result<Box<dyn DebugTarget>, &'static str> {
+    // Only the simple cases of quoting are handled, which is arguments with spaces in them
+    let quoted: Vec<String> = args.iter().map(|arg| if arg.contains(' ') { format!("\"{}\"", arg) } else { arg.clone() }).collect();
+    let mut command_line_buffer: Vec<u16> = quoted.join(" ").encode_utf16().chain(std::iter::once(0)).collect();
+    Ok(Box::new(win32_target::Win32Target::launch(&mut command_line_buffer, debug_children)?))
+}
+
+#[cfg(target_os = "linux")]
+fn launch_program(args: &[String], debug_children: bool) -> Result<Box<dyn DebugTarget>, &'static str> {
+    Ok(Box::new(ptrace_target::PtraceTarget::launch(args, debug_children)?))
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+fn launch_program(_args: &[String], _debug_children: bool) -> Result<Box<dyn DebugTarget>, &'static str> {
+    Err("Launching processes is not supported on this platform")
+}
+
+#[cfg(windows)]
+fn attach_target(process_id: u32) -> Result<Box<dyn DebugTarget>, &'static str> {
+    println!("Attaching to process {}", process_id);
+    Ok(Box::new(win32_target::Win32Target::attach(process_id)?))
+}
+
+#[cfg(target_os = "linux")]
+fn attach_target(process_id: u32) -> Result<Box<dyn DebugTarget>, &'static str> {
+    println!("Attaching to process {}", process_id);
+    Ok(Box::new(ptrace_target::PtraceTarget::attach(process_id as i32)?))
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+fn attach_target(_process_id: u32) -> Result<Box<dyn DebugTarget>, &'static str> {
+    Err("Attaching to processes is not supported on this platform")
+}
+
+fn connect_remote(address: Option<&String>) -> Result<Box<dyn DebugTarget>, &'static str> {
+    let address = address.ok_or("No remote address specified")?;
+    println!("Connecting to {}", address);
+    Ok(Box::new(rsp_target::RspTarget::connect(address)?))
+}
+
+fn open_dump(path: Option<&String>) -> Result<Box<dyn DebugTarget>, &'static str> {
+    let path = path.ok_or("No dump file specified")?;
+    println!("Loading dump file {}", path);
+
+    // Linux core files are ELF files, anything else should be a minidump
+    let mut magic = [0u8; 4];
+    File::open(path).and_then(|mut f| f.read_exact(&mut magic)).map_err(|_| "Could not read dump file")?;
+    if magic == elf::ELF_MAGIC {
+        Ok(Box::new(elf_core::open_core_file(path)?))
+    } else {
+        Ok(Box::new(minidump::open_minidump(path)?))
+    }
+}
+
+fn open_image(path: Option<&String>, base_address: Option<&String>) -> Result<Box<dyn DebugTarget>, &'static str> {
+    let path = path.ok_or("No image file specified")?;
+    let base_address = match base_address {
+        Some(text) => Some(u64::from_str_radix(text.trim_start_matches("0x"), 16).map_err(|_| "Invalid base address")?),
+        None => None,
     };
+    println!("Loading image file {}", path);
+
+    // Anything that isn't a PE file is mapped as is, which only makes sense if we know where it goes
+    let mut magic = [0u8; 2];
+    File::open(path).and_then(|mut f| f.read_exact(&mut magic)).map_err(|_| "Could not read image file")?;
+    if magic == *b"MZ" {
+        Ok(Box::new(image_target::open_pe_image(path, base_address)?))
+    } else {
+        let base_address = base_address.ok_or("A base address is required for raw images")?;
+        Ok(Box::new(image_target::open_raw_image(path, base_address)?))
+    }
+}
+
+fn run_gdb_server(address: Option<&String>) -> Result<(), &'static str> {
+    let address = address.ok_or("No port specified")?;
+    // A bare port number only listens locally, since the protocol has no authentication
+    let address = if address.contains(':') { address.clone() } else { format!("127.0.0.1:{}", address) };
+    let mut target = launch_target(2, false)?;
+    gdb_server::run_server(target.as_mut(), &address)
+}
 
-    if ret == 0 {
-        panic!("CreateProcessW failed");
+fn connect_server(address: Option<&String>, process_id: Option<u32>) -> Result<Box<dyn DebugTarget>, &'static str> {
+    let address = address.ok_or("No server address specified")?;
+    match process_id {
+        Some(process_id) => println!("Connecting to {} to attach to process {}", address, process_id),
+        None => println!("Connecting to {}", address),
     }
+    Ok(Box::new(remote::RemoteTarget::connect(address, process_id)?))
+}
 
-    let pi = unsafe { pi.assume_init() };
+fn run_dbgrs_server(args: &[String]) -> Result<(), &'static str> {
+    let address = args.get(2).ok_or("No port specified")?;
+    // Like the GDB server, a bare port number only listens locally since anyone who connects can control the target
+    let address = if address.contains(':') { address.clone() } else { format!("127.0.0.1:{}", address) };
+    let target = open_target(args, 3, false)?;
+    remote::run_server(target, &address, &attach_target)
+}
 
-    unsafe { CloseHandle(pi.hThread) };
+// Opens whatever target the arguments starting at first_arg describe, which is a new process by default
+fn open_target(args: &[String], first_arg: usize, debug_children: bool) -> Result<Box<dyn DebugTarget>, &'static str> {
+    match args.get(first_arg).map(|a| a.as_str()) {
+        Some("-p") => match args.get(first_arg + 1).map(|pid| pid.parse::<u32>()) {
+            Some(Ok(process_id)) => attach_target(process_id),
+            Some(Err(_)) => Err("Invalid process ID"),
+            None => Err("No process ID specified"),
+        },
+        Some("--remote") => connect_remote(args.get(first_arg + 1)),
+        Some("--connect") => connect_server(args.get(first_arg + 1), None),
+        Some("-z") => open_dump(args.get(first_arg + 1)),
+        Some("--image") => open_image(args.get(first_arg + 1), args.get(first_arg + 2)),
+        Some("--replay") => {
+            let path = args.get(first_arg + 1).ok_or("No trace file specified")?;
+            println!("Replaying trace file {}", path);
+            Ok(Box::new(trace::ReplayTarget::open(path)?))
+        }
+        _ => launch_target(first_arg - 1, debug_children),
+    }
+}
+
+// Options for the debugger itself come before the arguments that describe the target. Returns where those start.
+fn parse_debugger_options(args: &[String], mut first_arg: usize, recording: &mut Option<trace::TraceWriter>, commands: &mut command::CommandReader, debug_children: &mut bool) -> Result<usize, &'static str> {
+    loop {
+        match args.get(first_arg).map(|a| a.as_str()) {
+            // Recording wraps whatever target the rest of the arguments describe
+            Some("--record") => {
+                let path = args.get(first_arg + 1).ok_or("No trace file specified")?;
+                *recording = Some(trace::TraceWriter::create(path)?);
+                first_arg += 2;
+            }
+            Some("-c") => {
+                commands.add_startup_commands(args.get(first_arg + 1).ok_or("No commands specified")?);
+                first_arg += 2;
+            }
+            Some("-cf") => {
+                commands.add_startup_script(args.get(first_arg + 1).ok_or("No script file specified")?)?;
+                first_arg += 2;
+            }
+            Some("--batch") => {
+                commands.set_batch();
+                first_arg += 1;
+            }
+            // Like WinDbg, -o debugs the processes that the target starts as well
+            Some("-o") => {
+                *debug_children = true;
+                first_arg += 1;
+            }
+            _ => return Ok(first_arg),
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(|a| a.as_str()) == Some("--gdbserver") {
+        if let Err(msg) = run_gdb_server(args.get(2)) {
+            show_usage(msg);
+        }
+        return;
+    }
+    if args.get(1).map(|a| a.as_str()) == Some("--dap") {
+        if let Err(msg) = dap::run_server(&|args: &[String]| launch_program(args, false), &attach_target) {
+            eprintln!("Error: {}", msg);
+        }
+        return;
+    }
+    if args.get(1).map(|a| a.as_str()) == Some("--server") {
+        if let Err(msg) = run_dbgrs_server(&args) {
+            show_usage(msg);
+        }
+        return;
+    }
+
+    // The JSON interpreter takes over stdin and stdout before anything else, so that the only thing on stdout is the
+    // protocol
+    let (mut interpreter, first_arg) = match args.get(1).map(|a| a.as_str()) {
+        Some("--interpreter=json") => match json_interpreter::JsonInterpreter::new() {
+            Ok(interpreter) => (Some(interpreter), 2),
+            Err(msg) => {
+                show_usage(msg);
+                return;
+            }
+        },
+        _ => (None, 1),
+    };
 
-    // Later, we'll need to pass in a process handle.
-    main_debugger_loop(pi.hProcess);
+    let mut recording = None;
+    let mut commands = command::CommandReader::new();
+    let mut debug_children = false;
+    let first_arg = match parse_debugger_options(&args, first_arg, &mut recording, &mut commands, &mut debug_children) {
+        // The JSON interpreter takes its commands from the client instead
+        Ok(_) if interpreter.is_some() && commands.has_script() => {
+            show_usage("Scripts can't be used with the JSON interpreter");
+            return;
+        }
+        // It only keeps track of one process at a time
+        Ok(_) if interpreter.is_some() && debug_children => {
+            show_usage("Child processes can't be debugged with the JSON interpreter");
+            return;
+        }
+        Ok(first_arg) => first_arg,
+        Err(msg) => {
+            show_usage(msg);
+            return;
+        }
+    };
+    let replaying = args.get(first_arg).map(|a| a.as_str()) == Some("--replay");
+    // Attaching from a front end happens on the server, so the new process is on the same machine as the last one
+    let server_address = match args.get(first_arg).map(|a| a.as_str()) {
+        Some("--connect") => args.get(first_arg + 1),
+        _ => None,
+    };
 
-    unsafe { CloseHandle(pi.hProcess) };
+    let record = |target: Box<dyn DebugTarget>| -> Box<dyn DebugTarget> {
+        match &recording {
+            Some(trace) => Box::new(trace::RecordingTarget::new(target, trace.clone())),
+            None => target,
+        }
+    };
+
+    let mut target = match open_target(&args, first_arg, debug_children) {
+        Ok(t) => record(t),
+        Err(msg) => {
+            show_usage(msg);
+            return;
+        }
+    };
+
+    let mut debugger_loop = |target: &mut dyn DebugTarget| match &mut interpreter {
+        Some(interpreter) => interpreter.debugger_loop(target),
+        None => main_debugger_loop(target, &mut commands),
+    };
+
+    while let Some(process_id) = debugger_loop(target.as_mut()) {
+        // The trace already has the events for the process that was attached to next
+        if replaying {
+            continue;
+        }
+        let attached = match server_address {
+            Some(_) => connect_server(server_address, Some(process_id)),
+            None => attach_target(process_id),
+        };
+        target = match attached {
+            Ok(t) => record(t),
+            Err(e) => {
+                println!("Could not attach to process {}: {}", process_id, e);
+                return;
+            }
+        };
+    }
 }
@@ -1,5 +1,4 @@
-use core::ffi::c_void;
-use windows_sys::{Win32::Foundation, Win32::System::Diagnostics::Debug::*};
+use std::fmt;
 
 pub trait MemorySource {
     // Read up to "len" bytes, and return Option<u8> to represent what bytes are available in the range
@@ -8,48 +7,181 @@ pub trait MemorySource {
     fn read_raw_memory(&self, address: u64, len: usize) -> Vec<u8>;
 }
 
-pub fn read_memory_array<T: Sized + Default>(
+// Counts in the target's data structures can be anything if the data is corrupt, so we refuse to read more than this
+// in one go instead of trying to allocate whatever was asked for
+pub const MAX_READ_SIZE: u64 = 256 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryError {
+    // The first address that couldn't be read, and how many bytes from there were still needed
+    Unreadable { address: u64, len: u64 },
+    // The read was too big to try, which usually means the count came from corrupt data
+    TooLarge { address: u64, len: u64 },
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MemoryError::Unreadable { address, len } => write!(f, "Could not read {:#x} bytes at {:#018x}", len, address),
+            MemoryError::TooLarge { address, len } => write!(f, "Refusing to read {:#x} bytes at {:#018x}", len, address),
+        }
+    }
+}
+
+// Most of the debugger reports errors as static strings, so this lets ? work everywhere. The address is lost, but
+// anything that wants it can use the MemoryError directly.
+impl From<MemoryError> for &'static str {
+    fn from(error: MemoryError) -> &'static str {
+        match error {
+            MemoryError::Unreadable { .. } => "Could not read memory",
+            MemoryError::TooLarge { .. } => "Memory read is too large",
+        }
+    }
+}
+
+// Plain old data that can be decoded from the target's memory, or from a file. Each type reads its fields one at a time
+// as little endian values, so any bytes at all decode to something, and padding and alignment don't matter. Encoding
+// writes the fields back out the same way, for the files that we write ourselves.
+pub trait Pod: Sized {
+    // How many bytes the type takes up in the target. This is the sum of the fields, which isn't necessarily the same
+    // as size_of::<Self>().
+    const SIZE: usize;
+    fn decode(decoder: &mut Decoder) -> Self;
+    fn encode(&self, data: &mut Vec<u8>);
+}
+
+// Hands out the bytes of one value in order. The caller always provides exactly SIZE bytes, so running off the end
+// means a Pod impl is reading more fields than its SIZE says it has, and we just give it zeros.
+pub struct Decoder<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl Decoder<'_> {
+    pub fn read<T: Pod>(&mut self) -> T {
+        T::decode(self)
+    }
+
+    pub fn bytes<const N: usize>(&mut self) -> [u8; N] {
+        let bytes = self.data.get(self.offset..self.offset + N).and_then(|b| b.try_into().ok()).unwrap_or([0; N]);
+        self.offset += N;
+        bytes
+    }
+}
+
+macro_rules! impl_pod_for_int {
+    ($($t:ty),+) => {
+        $(
+            impl Pod for $t {
+                const SIZE: usize = std::mem::size_of::<$t>();
+                fn decode(decoder: &mut Decoder) -> Self {
+                    <$t>::from_le_bytes(decoder.bytes())
+                }
+                fn encode(&self, data: &mut Vec<u8>) {
+                    data.extend_from_slice(&self.to_le_bytes());
+                }
+            }
+        )+
+    };
+}
+
+impl_pod_for_int!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+impl<T: Pod, const N: usize> Pod for [T; N] {
+    const SIZE: usize = T::SIZE * N;
+    fn decode(decoder: &mut Decoder) -> Self {
+        std::array::from_fn(|_| decoder.read())
+    }
+    fn encode(&self, data: &mut Vec<u8>) {
+        self.iter().for_each(|value| value.encode(data));
+    }
+}
+
+// Implements Pod for a struct by decoding or encoding each field in order. The fields have to be listed in the same
+// order as the target lays them out, with nothing left out, since that's what SIZE is worked out from.
+macro_rules! impl_pod {
+    ($name:ty { $($field:ident: $t:ty),+ $(,)? }) => {
+        impl $crate::memory::Pod for $name {
+            const SIZE: usize = 0 $(+ <$t as $crate::memory::Pod>::SIZE)+;
+            fn decode(decoder: &mut $crate::memory::Decoder) -> Self {
+                Self { $($field: decoder.read::<$t>()),+ }
+            }
+            fn encode(&self, data: &mut Vec<u8>) {
+                // Each field is copied out first, since some of the structs are packed
+                $($crate::memory::Pod::encode(&{ self.$field }, data);)+
+            }
+        }
+    };
+}
+pub(crate) use impl_pod;
+
+// Decodes a value from the start of a buffer, or None if the buffer is too small
+pub fn decode<T: Pod>(data: &[u8]) -> Option<T> {
+    let data = data.get(..T::SIZE)?;
+    Some(Decoder { data, offset: 0 }.read())
+}
+
+pub fn encode<T: Pod>(value: &T) -> Vec<u8> {
+    let mut data = Vec::with_capacity(T::SIZE);
+    value.encode(&mut data);
+    data
+}
+
+pub fn read_memory_array<T: Pod>(
     source: &dyn MemorySource,
     address: u64,
     max_count: usize,
-) -> Result<Vec<T>, &'static str> {
-    let element_size = ::core::mem::size_of::<T>();
-    let max_bytes = max_count * element_size;
-    let raw_bytes = source.read_raw_memory(address, max_bytes);
-    let mut data: Vec<T> = Vec::new();
-    let mut offset: usize = 0;
-    while offset + element_size <= raw_bytes.len() {
-        let mut item: T = T::default();
-        let dst: *mut u8 = unsafe { std::mem::transmute(&mut item) };
-        let src = &raw_bytes[offset] as *const u8;
-        unsafe { std::ptr::copy_nonoverlapping(src, dst, element_size) };
-        data.push(item);
-        offset += element_size;
+) -> Result<Vec<T>, MemoryError> {
+    let max_bytes = (max_count as u64).saturating_mul(T::SIZE as u64);
+    if max_bytes > MAX_READ_SIZE {
+        return Err(MemoryError::TooLarge { address, len: max_bytes });
     }
-
-    Ok(data)
+    let raw_bytes = source.read_raw_memory(address, max_bytes as usize);
+    Ok(raw_bytes.chunks_exact(T::SIZE).filter_map(decode).collect())
 }
 
-pub fn read_memory_full_array<T: Sized + Default>(
+pub fn read_memory_full_array<T: Pod>(
     source: &dyn MemorySource,
     address: u64,
     count: usize,
-) -> Result<Vec<T>, &'static str> {
+) -> Result<Vec<T>, MemoryError> {
     let arr = read_memory_array(source, address, count)?;
 
     if arr.len() != count {
-        Err("Could not read all items")
+        let bytes_read = (arr.len() * T::SIZE) as u64;
+        Err(MemoryError::Unreadable { address: address.wrapping_add(bytes_read), len: (count * T::SIZE) as u64 - bytes_read })
     } else {
         Ok(arr)
     }
 }
 
-pub fn read_memory_data<T: Sized + Default + Copy>(
+pub fn read_memory_data<T: Pod>(
     source: &dyn MemorySource,
     address: u64,
-) -> Result<T, &'static str> {
-    let data = read_memory_array::<T>(source, address, 1)?;
-    Ok(data[0])
+) -> Result<T, MemoryError> {
+    let mut data = read_memory_full_array::<T>(source, address, 1)?;
+    Ok(data.remove(0))
+}
+
+// The characters 0x80 to 0x9F in Windows-1252, which is the ANSI code page on most Windows machines. The rest of it is
+// the same as Latin-1, and the five holes are mapped to the C1 controls, like MultiByteToWideChar does.
+const WINDOWS_1252_HIGH: [char; 32] = [
+    '\u{20AC}', '\u{0081}', '\u{201A}', '\u{0192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{02C6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', '\u{008D}', '\u{017D}', '\u{008F}',
+    '\u{0090}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{02DC}', '\u{2122}', '\u{0161}', '\u{203A}', '\u{0153}', '\u{009D}', '\u{017E}', '\u{0178}',
+];
+
+// Narrow strings are UTF-8 on Linux, and in the ANSI code page on Windows. Valid UTF-8 is very unlikely to be
+// anything else, so we use that if we can and fall back to Windows-1252, which can decode any bytes at all.
+pub fn decode_narrow_string(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(_) => bytes.iter().map(|&b| match b {
+            0x80..=0x9F => WINDOWS_1252_HIGH[(b - 0x80) as usize],
+            _ => b as char,
+        }).collect(),
+    }
 }
 
 pub fn read_memory_string(
@@ -57,7 +189,7 @@ pub fn read_memory_string(
     address: u64,
     max_count: usize,
     is_wide: bool,
-) -> Result<String, &'static str> {
+) -> Result<String, MemoryError> {
     let result: String = if is_wide {
         let mut words = read_memory_array::<u16>(source, address, max_count)?;
         let null_pos = words.iter().position(|&v| v == 0);
@@ -71,90 +203,87 @@ pub fn read_memory_string(
         if let Some(null_pos) = null_pos {
             bytes.truncate(null_pos);
         }
-        // TODO: This is not quite right. Technically most strings read here are encoded as ASCII.
-        String::from_utf8(bytes).unwrap()
+        decode_narrow_string(&bytes)
     };
     Ok(result)
 }
 
+#[cfg_attr(not(windows), allow(dead_code))]
 pub fn read_memory_string_indirect(
     source: &dyn MemorySource,
     address: u64,
     max_count: usize,
     is_wide: bool,
-) -> Result<String, &'static str> {
+) -> Result<String, MemoryError> {
     let string_address = read_memory_data::<u64>(source, address)?;
     read_memory_string(source, string_address, max_count, is_wide)
 }
 
-struct LiveMemorySource {
-    hprocess: Foundation::HANDLE,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_target::FakeMemory;
 
-pub fn make_live_memory_source(hprocess: Foundation::HANDLE) -> Box<dyn MemorySource> {
-    Box::new(LiveMemorySource { hprocess })
-}
-
-impl MemorySource for LiveMemorySource {
-    fn read_memory(&self, address: u64, len: usize) -> Result<Vec<Option<u8>>, &'static str> {
-        let mut buffer: Vec<u8> = vec![0; len];
-        let mut data: Vec<Option<u8>> = vec![None; len];
-        let mut offset: usize = 0;
-
-        while offset < len {
-            let mut bytes_read: usize = 0;
-            let len_left = len - offset;
-            let cur_address = address + (offset as u64);
+    // Packed, so the fields aren't aligned and SIZE is the same as size_of
+    #[repr(C, packed)]
+    #[derive(Default, Clone, Copy, Debug, PartialEq)]
+    struct Header {
+        kind: u8,
+        flags: u32,
+        count: u16,
+        tag: [u8; 3],
+        address: u64,
+    }
 
-            let result = unsafe {
-                ReadProcessMemory(
-                    self.hprocess,
-                    cur_address as *const c_void,
-                    buffer.as_mut_ptr() as *mut c_void,
-                    len_left,
-                    &mut bytes_read as *mut usize,
-                )
-            };
+    impl_pod!(Header { kind: u8, flags: u32, count: u16, tag: [u8; 3], address: u64 });
 
-            if result == 0 {
-                return Err("ReadProcessMemory failed");
-            };
+    #[test]
+    fn pod_round_trips_in_field_order() {
+        let header = Header { kind: 1, flags: 0x0504_0302, count: 0x0706, tag: *b"abc", address: 0x1122_3344_5566_7788 };
+        let data = encode(&header);
+        assert_eq!(Header::SIZE, std::mem::size_of::<Header>());
+        assert_eq!(data, [1, 2, 3, 4, 5, 6, 7, b'a', b'b', b'c', 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11]);
+        assert_eq!(decode::<Header>(&data), Some(header));
 
-            for index in 0..bytes_read {
-                let data_index = offset + index;
-                data[data_index] = Some(buffer[index]);
-            }
+        // Anything after the value is ignored, and too little is nothing at all
+        let mut longer = data.clone();
+        longer.push(0xff);
+        assert_eq!(decode::<Header>(&longer), Some(header));
+        assert_eq!(decode::<Header>(&data[..Header::SIZE - 1]), None);
+    }
 
-            if bytes_read > 0 {
-                offset += bytes_read;
-            } else {
-                offset += 1;
-            }
-        }
+    #[test]
+    fn short_reads_are_unreadable() {
+        let mut memory = FakeMemory::default();
+        memory.add_region(0x1000, &[1, 0, 0, 0, 2, 0]);
 
-        Ok(data)
+        assert_eq!(read_memory_full_array::<u32>(&memory, 0x1000, 1), Ok(vec![1]));
+        // The error picks up at the first element that couldn't be read
+        assert_eq!(read_memory_full_array::<u32>(&memory, 0x1000, 2), Err(MemoryError::Unreadable { address: 0x1004, len: 4 }));
+        assert_eq!(read_memory_data::<u64>(&memory, 0x1000), Err(MemoryError::Unreadable { address: 0x1000, len: 8 }));
+        assert_eq!(read_memory_data::<u16>(&memory, 0x2000), Err(MemoryError::Unreadable { address: 0x2000, len: 2 }));
+        // Partial reads are fine when we only want as much as there is
+        assert_eq!(read_memory_array::<u32>(&memory, 0x1000, 2), Ok(vec![1]));
     }
 
-    fn read_raw_memory(&self, address: u64, len: usize) -> Vec<u8> {
-        let mut buffer: Vec<u8> = vec![0; len];
-        let mut bytes_read: usize = 0;
-
-        let result = unsafe {
-            ReadProcessMemory(
-                self.hprocess,
-                address as *const c_void,
-                buffer.as_mut_ptr() as *mut c_void,
-                len,
-                &mut bytes_read as *mut usize,
-            )
-        };
-
-        if result == 0 {
-            bytes_read = 0;
-        }
+    #[test]
+    fn oversized_reads_are_refused() {
+        let memory = FakeMemory::default();
+        let max_count = (MAX_READ_SIZE / 8) as usize;
+        assert_eq!(read_memory_array::<u64>(&memory, 0x1000, max_count), Ok(vec![]));
+        assert_eq!(read_memory_array::<u64>(&memory, 0x1000, max_count + 1), Err(MemoryError::TooLarge { address: 0x1000, len: MAX_READ_SIZE + 8 }));
+        // Counts that don't even fit in the address space are still refused rather than wrapping around
+        assert_eq!(read_memory_full_array::<u32>(&memory, 0x1000, usize::MAX), Err(MemoryError::TooLarge { address: 0x1000, len: u64::MAX }));
+    }
 
-        buffer.truncate(bytes_read);
+    #[test]
+    fn narrow_strings_fall_back_to_windows_1252() {
+        assert_eq!(decode_narrow_string("h\u{e9}llo \u{20AC}".as_bytes()), "h\u{e9}llo \u{20AC}");
+        // 0x81 is one of the holes in Windows-1252
+        assert_eq!(decode_narrow_string(&[0x80, b'A', 0xe9, 0x81, 0x93, 0x9f]), "\u{20AC}A\u{e9}\u{81}\u{201C}\u{178}");
 
-        buffer
+        let mut memory = FakeMemory::default();
+        memory.add_region(0x1000, &[b'c', 0xe0, b'f', 0xe9, 0, b'x']);
+        assert_eq!(read_memory_string(&memory, 0x1000, 0x10, false), Ok("c\u{e0}f\u{e9}".to_string()));
     }
 }
@@ -0,0 +1,189 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::memory::MemorySource;
+
+const PAGE_SIZE: u64 = 0x1000;
+// Reads bigger than this (like a search over a big range) go straight to the target, so they don't push out
+// everything else that's cached
+const MAX_CACHED_READ_PAGES: u64 = 16;
+// The cache is emptied once it gets this big, which is 16MB of target memory
+const MAX_CACHED_PAGES: usize = 4096;
+
+// Reading the memory of a live target means a syscall each time, and commands like k and the d* commands do a lot
+// of small reads from the same few pages. This keeps whole pages from the source, including the ones that couldn't
+// be read, so each page is only fetched once while the target is stopped. Anything that changes the target's memory
+// has to call invalidate, and the targets do that whenever the target runs or memory is written.
+pub struct CachedMemorySource<S: MemorySource> {
+    source: S,
+    // Bytes that couldn't be read are None, so a page that can't be read at all is all None
+    pages: RefCell<HashMap<u64, Vec<Option<u8>>>>,
+}
+
+impl<S: MemorySource> CachedMemorySource<S> {
+    pub fn new(source: S) -> CachedMemorySource<S> {
+        CachedMemorySource { source, pages: RefCell::new(HashMap::new()) }
+    }
+
+    pub fn source(&self) -> &S {
+        &self.source
+    }
+
+    pub fn invalidate(&self) {
+        self.pages.borrow_mut().clear();
+    }
+
+    pub fn invalidate_range(&self, address: u64, len: usize) {
+        if len == 0 {
+            return;
+        }
+        let first_page = address & !(PAGE_SIZE - 1);
+        let last_page = address.saturating_add(len as u64 - 1) & !(PAGE_SIZE - 1);
+        let mut pages = self.pages.borrow_mut();
+        pages.retain(|page, _| *page < first_page || *page > last_page);
+    }
+
+    fn with_page<R>(&self, page: u64, f: impl FnOnce(&[Option<u8>]) -> R) -> R {
+        let mut pages = self.pages.borrow_mut();
+        if !pages.contains_key(&page) {
+            if pages.len() >= MAX_CACHED_PAGES {
+                pages.clear();
+            }
+            let mut data = self.source.read_memory(page, PAGE_SIZE as usize).unwrap_or_default();
+            data.resize(PAGE_SIZE as usize, None);
+            pages.insert(page, data);
+        }
+        f(&pages[&page])
+    }
+}
+
+impl<S: MemorySource> MemorySource for CachedMemorySource<S> {
+    fn read_memory(&self, address: u64, len: usize) -> Result<Vec<Option<u8>>, &'static str> {
+        if len as u64 > MAX_CACHED_READ_PAGES * PAGE_SIZE {
+            return self.source.read_memory(address, len);
+        }
+
+        let mut data: Vec<Option<u8>> = Vec::with_capacity(len);
+        while data.len() < len {
+            let cur_address = address.wrapping_add(data.len() as u64);
+            let page = cur_address & !(PAGE_SIZE - 1);
+            let page_offset = (cur_address - page) as usize;
+            let chunk_len = std::cmp::min(PAGE_SIZE as usize - page_offset, len - data.len());
+            self.with_page(page, |bytes| data.extend_from_slice(&bytes[page_offset..page_offset + chunk_len]));
+        }
+        Ok(data)
+    }
+
+    fn read_raw_memory(&self, address: u64, len: usize) -> Vec<u8> {
+        if len as u64 > MAX_CACHED_READ_PAGES * PAGE_SIZE {
+            return self.source.read_raw_memory(address, len);
+        }
+
+        let data = self.read_memory(address, len).unwrap_or_default();
+        data.iter().map_while(|b| *b).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_target::FakeMemory;
+    use std::cell::Cell;
+
+    // Counts the reads that get through to the memory underneath
+    #[derive(Default)]
+    struct CountingMemory {
+        memory: RefCell<FakeMemory>,
+        reads: Cell<usize>,
+    }
+
+    impl MemorySource for CountingMemory {
+        fn read_memory(&self, address: u64, len: usize) -> Result<Vec<Option<u8>>, &'static str> {
+            self.reads.set(self.reads.get() + 1);
+            self.memory.borrow().read_memory(address, len)
+        }
+
+        fn read_raw_memory(&self, address: u64, len: usize) -> Vec<u8> {
+            self.reads.set(self.reads.get() + 1);
+            self.memory.borrow().read_raw_memory(address, len)
+        }
+    }
+
+    fn cache_with(address: u64, data: &[u8]) -> CachedMemorySource<CountingMemory> {
+        let source = CountingMemory::default();
+        source.memory.borrow_mut().add_region(address, data);
+        CachedMemorySource::new(source)
+    }
+
+    #[test]
+    fn each_page_is_read_once() {
+        let cache = cache_with(0x1000, &[0xaa; 0x1000]);
+        assert_eq!(cache.read_memory(0x1010, 4).unwrap(), [Some(0xaa); 4]);
+        assert_eq!(cache.read_raw_memory(0x1ff0, 8), [0xaa; 8]);
+        assert_eq!(cache.read_memory(0x1000, 0x1000).unwrap().len(), 0x1000);
+        assert_eq!(cache.source().reads.get(), 1);
+    }
+
+    #[test]
+    fn unreadable_pages_are_cached() {
+        let cache = cache_with(0x1000, &[0xaa; 0x10]);
+        assert_eq!(cache.read_memory(0x5000, 4).unwrap(), [None; 4]);
+        assert_eq!(cache.read_memory(0x5100, 4).unwrap(), [None; 4]);
+        assert!(cache.read_raw_memory(0x5000, 4).is_empty());
+        assert_eq!(cache.source().reads.get(), 1);
+
+        // The part of a page that can't be read stays unreadable, and raw reads stop there
+        assert_eq!(cache.read_memory(0x100e, 4).unwrap(), [Some(0xaa), Some(0xaa), None, None]);
+        assert_eq!(cache.read_raw_memory(0x100e, 4), [0xaa, 0xaa]);
+        assert_eq!(cache.source().reads.get(), 2);
+    }
+
+    #[test]
+    fn written_pages_are_read_again_after_invalidating() {
+        let cache = cache_with(0x1000, &[0; 0x2000]);
+        assert_eq!(cache.read_raw_memory(0x1ffe, 4), [0; 4]);
+        assert_eq!(cache.source().reads.get(), 2);
+
+        // Only the page that was written to is fetched again
+        cache.source().memory.borrow_mut().write(0x1ffe, &[1, 2]);
+        assert_eq!(cache.read_raw_memory(0x1ffe, 4), [0; 4]);
+        cache.invalidate_range(0x1ffe, 2);
+        assert_eq!(cache.read_raw_memory(0x1ffe, 4), [1, 2, 0, 0]);
+        assert_eq!(cache.source().reads.get(), 3);
+
+        cache.invalidate();
+        cache.read_raw_memory(0x1ffe, 4);
+        assert_eq!(cache.source().reads.get(), 5);
+    }
+
+    #[test]
+    fn reads_that_span_pages_are_put_together() {
+        let data: Vec<u8> = (0..0x3000).map(|i| (i / 0x1000) as u8 + 1).collect();
+        let cache = cache_with(0x1000, &data);
+        let read = cache.read_raw_memory(0x1ff0, 0x1020);
+        assert_eq!(read.len(), 0x1020);
+        assert_eq!(read[..0x10], [1; 0x10]);
+        assert_eq!(read[0x10..0x1010], [2; 0x1000]);
+        assert_eq!(read[0x1010..], [3; 0x10]);
+        assert_eq!(cache.source().reads.get(), 3);
+
+        // The end of the address space is as far as a read can go
+        let cache = cache_with(u64::MAX - 0xf, &[0xbb; 0x10]);
+        assert_eq!(cache.read_raw_memory(u64::MAX - 0xf, 0x10), [0xbb; 0x10]);
+    }
+
+    #[test]
+    fn big_reads_bypass_the_cache() {
+        let len = (MAX_CACHED_READ_PAGES * PAGE_SIZE) as usize;
+        let cache = cache_with(0x1000, &vec![0xcc; len + 1]);
+        assert_eq!(cache.read_memory(0x1000, len + 1).unwrap(), vec![Some(0xcc); len + 1]);
+        assert_eq!(cache.read_raw_memory(0x1000, len + 1).len(), len + 1);
+        assert_eq!(cache.source().reads.get(), 2);
+        assert!(cache.pages.borrow().is_empty());
+
+        // A read of the biggest size that's still cached goes a page at a time
+        cache.read_memory(0x1000, len).unwrap();
+        assert_eq!(cache.source().reads.get(), 2 + MAX_CACHED_READ_PAGES as usize);
+        assert_eq!(cache.pages.borrow().len(), MAX_CACHED_READ_PAGES as usize);
+    }
+}
@@ -0,0 +1,708 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use std::collections::HashMap;
+
+use windows_sys::Win32::Storage::FileSystem::VS_FIXEDFILEINFO;
+use windows_sys::Win32::System::Diagnostics::Debug::{
+    ExceptionStream, Memory64ListStream, MemoryInfoListStream, MemoryListStream, MiniDumpNormal, MiniDumpWithFullMemory,
+    MiniDumpWithFullMemoryInfo, ModuleListStream, SystemInfoStream, ThreadListStream, CONTEXT, CONTEXT_0,
+    CPU_INFORMATION, CPU_INFORMATION_1, M128A, MINIDUMP_DIRECTORY, MINIDUMP_EXCEPTION, MINIDUMP_EXCEPTION_STREAM,
+    MINIDUMP_HEADER, MINIDUMP_HEADER_0, MINIDUMP_LOCATION_DESCRIPTOR, MINIDUMP_MEMORY_DESCRIPTOR,
+    MINIDUMP_MEMORY_DESCRIPTOR64, MINIDUMP_MEMORY_INFO, MINIDUMP_MEMORY_INFO_LIST, MINIDUMP_MODULE, MINIDUMP_STREAM_TYPE,
+    MINIDUMP_SYSTEM_INFO, MINIDUMP_SYSTEM_INFO_0, MINIDUMP_SYSTEM_INFO_1, MINIDUMP_THREAD, MINIDUMP_VERSION, XSAVE_FORMAT,
+};
+
+use crate::dump_target::{DumpMemoryRange, DumpMemorySource, DumpTarget};
+use crate::event::DebugEvent;
+use crate::memory::{self, impl_pod, Decoder, MemorySource, Pod};
+use crate::process::Process;
+use crate::registers::RegisterContext;
+use crate::target::{DebugTarget, MemoryRegion, MemoryRegionInfo};
+
+// "MDMP"
+pub const MINIDUMP_SIGNATURE: u32 = 0x504D444D;
+// "RSDS", the CodeView record that points to a PDB
+const CV_SIGNATURE_RSDS: u32 = 0x53445352;
+
+const PROCESSOR_ARCHITECTURE_AMD64: u16 = 9;
+const VER_PLATFORM_WIN32_NT: u32 = 2;
+// Minidumps have no platform ID for Linux, so we use the same one as Breakpad
+const PLATFORM_ID_LINUX: u32 = 0x8201;
+
+const PAGE_SIZE: u64 = 0x1000;
+// Dumps without /ma save this much of each stack, and this much code on either side of each instruction pointer so
+// that it can still be disassembled.
+const STACK_SAVE_SIZE: u64 = 0x10000;
+const CODE_SAVE_SIZE: u64 = 0x100;
+const WRITE_CHUNK_SIZE: u64 = 0x100000;
+
+// The minidump structures are decoded field by field, since some of them are packed and a few have unions in them. For
+// the unions, we only ever look at one of the members, which is all that we need to read and write.
+impl_pod!(MINIDUMP_LOCATION_DESCRIPTOR { DataSize: u32, Rva: u32 });
+impl_pod!(MINIDUMP_DIRECTORY { StreamType: u32, Location: MINIDUMP_LOCATION_DESCRIPTOR });
+impl_pod!(MINIDUMP_MEMORY_DESCRIPTOR { StartOfMemoryRange: u64, Memory: MINIDUMP_LOCATION_DESCRIPTOR });
+impl_pod!(MINIDUMP_MEMORY_DESCRIPTOR64 { StartOfMemoryRange: u64, DataSize: u64 });
+impl_pod!(MINIDUMP_MEMORY_INFO_LIST { SizeOfHeader: u32, SizeOfEntry: u32, NumberOfEntries: u64 });
+
+impl_pod!(MINIDUMP_HEADER {
+    Signature: u32, Version: u32, NumberOfStreams: u32, StreamDirectoryRva: u32, CheckSum: u32,
+    Anonymous: MINIDUMP_HEADER_0, Flags: u64,
+});
+
+impl Pod for MINIDUMP_HEADER_0 {
+    const SIZE: usize = 4;
+    fn decode(decoder: &mut Decoder) -> Self {
+        MINIDUMP_HEADER_0 { TimeDateStamp: decoder.read() }
+    }
+    fn encode(&self, data: &mut Vec<u8>) {
+        unsafe { self.TimeDateStamp }.encode(data);
+    }
+}
+
+impl_pod!(MINIDUMP_THREAD {
+    ThreadId: u32, SuspendCount: u32, PriorityClass: u32, Priority: u32, Teb: u64, Stack: MINIDUMP_MEMORY_DESCRIPTOR,
+    ThreadContext: MINIDUMP_LOCATION_DESCRIPTOR,
+});
+
+impl_pod!(VS_FIXEDFILEINFO {
+    dwSignature: u32, dwStrucVersion: u32, dwFileVersionMS: u32, dwFileVersionLS: u32, dwProductVersionMS: u32,
+    dwProductVersionLS: u32, dwFileFlagsMask: u32, dwFileFlags: u32, dwFileOS: i32, dwFileType: i32,
+    dwFileSubtype: i32, dwFileDateMS: u32, dwFileDateLS: u32,
+});
+
+impl_pod!(MINIDUMP_MODULE {
+    BaseOfImage: u64, SizeOfImage: u32, CheckSum: u32, TimeDateStamp: u32, ModuleNameRva: u32,
+    VersionInfo: VS_FIXEDFILEINFO, CvRecord: MINIDUMP_LOCATION_DESCRIPTOR, MiscRecord: MINIDUMP_LOCATION_DESCRIPTOR,
+    Reserved0: u64, Reserved1: u64,
+});
+
+impl_pod!(MINIDUMP_MEMORY_INFO {
+    BaseAddress: u64, AllocationBase: u64, AllocationProtect: u32, __alignment1: u32, RegionSize: u64, State: u32,
+    Protect: u32, Type: u32, __alignment2: u32,
+});
+
+impl_pod!(MINIDUMP_EXCEPTION {
+    ExceptionCode: u32, ExceptionFlags: u32, ExceptionRecord: u64, ExceptionAddress: u64, NumberParameters: u32,
+    __unusedAlignment: u32, ExceptionInformation: [u64; 15],
+});
+
+impl_pod!(MINIDUMP_EXCEPTION_STREAM {
+    ThreadId: u32, __alignment: u32, ExceptionRecord: MINIDUMP_EXCEPTION, ThreadContext: MINIDUMP_LOCATION_DESCRIPTOR,
+});
+
+impl_pod!(MINIDUMP_SYSTEM_INFO {
+    ProcessorArchitecture: u16, ProcessorLevel: u16, ProcessorRevision: u16, Anonymous1: MINIDUMP_SYSTEM_INFO_0,
+    MajorVersion: u32, MinorVersion: u32, BuildNumber: u32, PlatformId: u32, CSDVersionRva: u32,
+    Anonymous2: MINIDUMP_SYSTEM_INFO_1, Cpu: CPU_INFORMATION,
+});
+
+// The processor count and product type
+impl Pod for MINIDUMP_SYSTEM_INFO_0 {
+    const SIZE: usize = 2;
+    fn decode(decoder: &mut Decoder) -> Self {
+        MINIDUMP_SYSTEM_INFO_0 { Reserved0: decoder.read() }
+    }
+    fn encode(&self, data: &mut Vec<u8>) {
+        unsafe { self.Reserved0 }.encode(data);
+    }
+}
+
+// The suite mask
+impl Pod for MINIDUMP_SYSTEM_INFO_1 {
+    const SIZE: usize = 4;
+    fn decode(decoder: &mut Decoder) -> Self {
+        MINIDUMP_SYSTEM_INFO_1 { Reserved1: decoder.read() }
+    }
+    fn encode(&self, data: &mut Vec<u8>) {
+        unsafe { self.Reserved1 }.encode(data);
+    }
+}
+
+impl_pod!(CPU_INFORMATION_1 {
+    VendorId: [u32; 3], VersionInformation: u32, FeatureInformation: u32, AMDExtendedCpuFeatures: u32,
+});
+
+// The x86 member, which is the bigger of the two
+impl Pod for CPU_INFORMATION {
+    const SIZE: usize = CPU_INFORMATION_1::SIZE;
+    fn decode(decoder: &mut Decoder) -> Self {
+        CPU_INFORMATION { X86CpuInfo: decoder.read() }
+    }
+    fn encode(&self, data: &mut Vec<u8>) {
+        unsafe { self.X86CpuInfo }.encode(data);
+    }
+}
+
+impl_pod!(M128A { Low: u64, High: i64 });
+
+impl_pod!(XSAVE_FORMAT {
+    ControlWord: u16, StatusWord: u16, TagWord: u8, Reserved1: u8, ErrorOpcode: u16, ErrorOffset: u32,
+    ErrorSelector: u16, Reserved2: u16, DataOffset: u32, DataSelector: u16, Reserved3: u16, MxCsr: u32,
+    MxCsr_Mask: u32, FloatRegisters: [M128A; 8], XmmRegisters: [M128A; 16], Reserved4: [u8; 96],
+});
+
+// The floating point state, which is all that RegisterContext keeps
+impl Pod for CONTEXT_0 {
+    const SIZE: usize = XSAVE_FORMAT::SIZE;
+    fn decode(decoder: &mut Decoder) -> Self {
+        CONTEXT_0 { FltSave: decoder.read() }
+    }
+    fn encode(&self, data: &mut Vec<u8>) {
+        unsafe { self.FltSave }.encode(data);
+    }
+}
+
+impl_pod!(CONTEXT {
+    P1Home: u64, P2Home: u64, P3Home: u64, P4Home: u64, P5Home: u64, P6Home: u64, ContextFlags: u32, MxCsr: u32,
+    SegCs: u16, SegDs: u16, SegEs: u16, SegFs: u16, SegGs: u16, SegSs: u16, EFlags: u32, Dr0: u64, Dr1: u64,
+    Dr2: u64, Dr3: u64, Dr6: u64, Dr7: u64, Rax: u64, Rcx: u64, Rdx: u64, Rbx: u64, Rsp: u64, Rbp: u64, Rsi: u64,
+    Rdi: u64, R8: u64, R9: u64, R10: u64, R11: u64, R12: u64, R13: u64, R14: u64, R15: u64, Rip: u64,
+    Anonymous: CONTEXT_0, VectorRegister: [M128A; 26], VectorControl: u64, DebugControl: u64, LastBranchToRip: u64,
+    LastBranchFromRip: u64, LastExceptionToRip: u64, LastExceptionFromRip: u64,
+});
+
+fn read_struct<T: Pod>(file: &mut File, offset: u64) -> Result<T, &'static str> {
+    let mut buffer = vec![0u8; T::SIZE];
+    file.seek(SeekFrom::Start(offset)).map_err(|_| "Could not seek in dump file")?;
+    file.read_exact(&mut buffer).map_err(|_| "Dump file is truncated")?;
+    memory::decode(&buffer).ok_or("Dump file is truncated")
+}
+
+// A MINIDUMP_STRING, which is a byte count followed by UTF-16 characters
+fn read_string(file: &mut File, rva: u64) -> Result<String, &'static str> {
+    let length: u32 = read_struct(file, rva)?;
+    // The length comes from the file, so don't trust it any further than the end of the file
+    let file_size = file.metadata().map_err(|_| "Could not read dump file")?.len();
+    if rva + 4 + length as u64 > file_size {
+        return Err("Dump file is truncated");
+    }
+    let mut buffer = vec![0u8; length as usize];
+    file.read_exact(&mut buffer).map_err(|_| "Dump file is truncated")?;
+    let chars: Vec<u16> = buffer.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+    Ok(String::from_utf16_lossy(&chars))
+}
+
+fn read_context(file: &mut File, location: MINIDUMP_LOCATION_DESCRIPTOR) -> Result<RegisterContext, &'static str> {
+    // Older writers may save a smaller context, so whatever isn't there is left as zero
+    let mut buffer = vec![0u8; CONTEXT::SIZE];
+    let len = std::cmp::min(location.DataSize as usize, buffer.len());
+    file.seek(SeekFrom::Start(location.Rva as u64)).map_err(|_| "Could not seek in dump file")?;
+    file.read_exact(&mut buffer[..len]).map_err(|_| "Dump file is truncated")?;
+    let context: CONTEXT = memory::decode(&buffer).ok_or("Dump file is truncated")?;
+    Ok(RegisterContext::from(&context))
+}
+
+// Reads a stream that is a count followed by an array of T, which is how most of the list streams are laid out
+fn read_list<T: Pod>(file: &mut File, rva: u64) -> Result<Vec<T>, &'static str> {
+    let count: u32 = read_struct(file, rva)?;
+    (0..count as u64).map(|i| read_struct(file, rva + 4 + i * T::SIZE as u64)).collect()
+}
+
+// The stream type constants from windows-sys are named like the C enum
+#[allow(non_upper_case_globals)]
+pub fn open_minidump(path: &str) -> Result<DumpTarget, &'static str> {
+    let mut file = File::open(path).map_err(|_| "Could not open dump file")?;
+    let header: MINIDUMP_HEADER = read_struct(&mut file, 0)?;
+    if header.Signature != MINIDUMP_SIGNATURE {
+        return Err("File is not a minidump");
+    }
+
+    let mut ranges = Vec::new();
+    let mut threads = Vec::new();
+    let mut modules = Vec::new();
+    let mut exception = None;
+    let mut memory_map = None;
+    let mut thread_tebs = HashMap::new();
+
+    for i in 0..header.NumberOfStreams as u64 {
+        let directory_rva = header.StreamDirectoryRva as u64 + i * MINIDUMP_DIRECTORY::SIZE as u64;
+        let directory: MINIDUMP_DIRECTORY = read_struct(&mut file, directory_rva)?;
+        let rva = directory.Location.Rva as u64;
+
+        match directory.StreamType as i32 {
+            ThreadListStream => {
+                for thread in read_list::<MINIDUMP_THREAD>(&mut file, rva)? {
+                    threads.push((thread.ThreadId, read_context(&mut file, thread.ThreadContext)?));
+                    if thread.Teb != 0 {
+                        thread_tebs.insert(thread.ThreadId, thread.Teb);
+                    }
+                }
+            }
+            ModuleListStream => {
+                for module in read_list::<MINIDUMP_MODULE>(&mut file, rva)? {
+                    modules.push((module.BaseOfImage, read_string(&mut file, module.ModuleNameRva as u64).ok()));
+                }
+            }
+            MemoryListStream => {
+                for descriptor in read_list::<MINIDUMP_MEMORY_DESCRIPTOR>(&mut file, rva)? {
+                    ranges.push(DumpMemoryRange { address: descriptor.StartOfMemoryRange, size: descriptor.Memory.DataSize as u64, file_index: 0, file_offset: descriptor.Memory.Rva as u64 });
+                }
+            }
+            Memory64ListStream => {
+                // Full memory dumps store the memory contiguously, starting at BaseRva, in the same order as the list
+                let count: u64 = read_struct(&mut file, rva)?;
+                let mut file_offset: u64 = read_struct(&mut file, rva + 8)?;
+                for i in 0..count {
+                    let descriptor: MINIDUMP_MEMORY_DESCRIPTOR64 = read_struct(&mut file, rva + 16 + i * MINIDUMP_MEMORY_DESCRIPTOR64::SIZE as u64)?;
+                    ranges.push(DumpMemoryRange { address: descriptor.StartOfMemoryRange, size: descriptor.DataSize, file_index: 0, file_offset });
+                    file_offset = file_offset.checked_add(descriptor.DataSize).ok_or("Memory list is too large")?;
+                }
+            }
+            MemoryInfoListStream => {
+                // The header says how big everything is, so that newer writers can add to the structures
+                let list: MINIDUMP_MEMORY_INFO_LIST = read_struct(&mut file, rva)?;
+                if (list.SizeOfEntry as usize) < MINIDUMP_MEMORY_INFO::SIZE {
+                    return Err("Memory info list is invalid");
+                }
+                let mut regions = Vec::new();
+                for i in 0..list.NumberOfEntries {
+                    let info_rva = i.checked_mul(list.SizeOfEntry as u64).and_then(|o| o.checked_add(rva + list.SizeOfHeader as u64)).ok_or("Memory info list is too large")?;
+                    let info: MINIDUMP_MEMORY_INFO = read_struct(&mut file, info_rva)?;
+                    regions.push(MemoryRegionInfo {
+                        address: info.BaseAddress,
+                        size: info.RegionSize,
+                        allocation_base: info.AllocationBase,
+                        state: info.State,
+                        protect: info.Protect,
+                        region_type: info.Type,
+                        name: None,
+                    });
+                }
+                memory_map = Some(regions);
+            }
+            ExceptionStream => {
+                let stream: MINIDUMP_EXCEPTION_STREAM = read_struct(&mut file, rva)?;
+                let context = read_context(&mut file, stream.ThreadContext)?;
+                exception = Some((stream.ThreadId, stream.ExceptionRecord.ExceptionCode as i32, context));
+            }
+            _ => {}
+        }
+    }
+
+    // The thread list has the context of the faulting thread at the point the dump was written, which is usually
+    // somewhere in an exception handler. The exception stream has the context at the point of the exception.
+    let stop_event = exception.map(|(thread_id, exception_code, context)| {
+        match threads.iter_mut().find(|(t, _)| *t == thread_id) {
+            Some(thread) => thread.1 = context,
+            None => threads.push((thread_id, context)),
+        }
+        (thread_id, DebugEvent::Exception { first_chance: false, exception_code })
+    });
+
+    let mut target = DumpTarget::new(DumpMemorySource::new(vec![file], ranges), 0, threads, modules, stop_event)?;
+    if let Some(memory_map) = memory_map {
+        target.set_memory_map(memory_map);
+    }
+    target.set_thread_tebs(thread_tebs);
+    Ok(target)
+}
+
+// Lays out everything except the memory contents, which can be much too large to hold on to and is written straight
+// to the file after this
+struct MinidumpBuilder {
+    buffer: Vec<u8>,
+}
+
+impl MinidumpBuilder {
+    fn append_bytes(&mut self, data: &[u8]) -> MINIDUMP_LOCATION_DESCRIPTOR {
+        // Nothing in a minidump needs more than 8 byte alignment
+        self.buffer.resize((self.buffer.len() + 7) & !7, 0);
+        let rva = self.buffer.len() as u32;
+        self.buffer.extend_from_slice(data);
+        MINIDUMP_LOCATION_DESCRIPTOR { DataSize: data.len() as u32, Rva: rva }
+    }
+
+    fn append<T: Pod>(&mut self, value: &T) -> MINIDUMP_LOCATION_DESCRIPTOR {
+        self.append_bytes(&memory::encode(value))
+    }
+
+    fn append_string(&mut self, text: &str) -> u32 {
+        let chars: Vec<u16> = text.encode_utf16().collect();
+        let mut data = ((chars.len() * 2) as u32).to_le_bytes().to_vec();
+        for c in chars.iter().chain(std::iter::once(&0)) {
+            data.extend_from_slice(&c.to_le_bytes());
+        }
+        self.append_bytes(&data).Rva
+    }
+
+    fn write_at(&mut self, offset: usize, data: &[u8]) {
+        self.buffer[offset..offset + data.len()].copy_from_slice(data);
+    }
+}
+
+// Narrows the ranges down to the bytes that can actually be read, since the memory list can only describe memory
+// that is in the file. The ranges need to be sorted.
+fn find_readable_runs(memory_source: &dyn MemorySource, ranges: &[MemoryRegion]) -> Vec<MemoryRegion> {
+    fn add_run(runs: &mut Vec<MemoryRegion>, address: u64, size: u64) {
+        match runs.last_mut() {
+            Some(run) if run.address + run.size == address => run.size += size,
+            _ => runs.push(MemoryRegion { address, size }),
+        }
+    }
+
+    let mut runs: Vec<MemoryRegion> = Vec::new();
+    for range in ranges {
+        let end = range.address.saturating_add(range.size);
+        // Ranges can overlap, such as the code around two threads that are in the same function
+        let mut address = std::cmp::max(range.address, runs.last().map_or(0, |r| r.address + r.size));
+        while address < end {
+            let chunk_len = std::cmp::min(end - address, PAGE_SIZE - address % PAGE_SIZE);
+            let data = memory_source.read_memory(address, chunk_len as usize).unwrap_or_default();
+            // Memory is readable a page at a time, so it's only worth looking at each byte when a page is partly readable
+            if data.len() as u64 == chunk_len && data.iter().all(|b| b.is_some()) {
+                add_run(&mut runs, address, chunk_len);
+            } else if data.iter().any(|b| b.is_some()) {
+                for (offset, _) in data.iter().enumerate().filter(|(_, b)| b.is_some()) {
+                    add_run(&mut runs, address + offset as u64, 1);
+                }
+            }
+            address += chunk_len;
+        }
+    }
+    runs
+}
+
+// The CodeView record that lets a debugger find the PDB for a module
+fn codeview_record(module: &crate::module::Module) -> Option<Vec<u8>> {
+    let pdb_info = module.pdb_info.as_ref()?;
+    let pdb_name = module.pdb_name.as_ref()?;
+    let mut record = CV_SIGNATURE_RSDS.to_le_bytes().to_vec();
+    record.extend_from_slice(&pdb_info.guid.data1.to_le_bytes());
+    record.extend_from_slice(&pdb_info.guid.data2.to_le_bytes());
+    record.extend_from_slice(&pdb_info.guid.data3.to_le_bytes());
+    record.extend_from_slice(&pdb_info.guid.data4);
+    record.extend_from_slice(&pdb_info.age.to_le_bytes());
+    record.extend_from_slice(pdb_name.as_bytes());
+    record.push(0);
+    Some(record)
+}
+
+// Writes a minidump of the target as it is now. The event thread and exception code describe the event that the
+// target is stopped for, which becomes the exception stream if it was an exception.
+pub fn write_minidump(path: &str, full_memory: bool, target: &dyn DebugTarget, process: &Process, event_thread: u32, exception_code: Option<i32>) -> Result<(), &'static str> {
+    let threads: Vec<(u32, RegisterContext)> = process.iterate_threads().filter_map(|t| target.get_thread_context(*t).ok().map(|ctx| (*t, ctx))).collect();
+
+    let regions = if full_memory {
+        match target.memory_regions() {
+            Ok(regions) => Some(regions),
+            Err(e) => {
+                println!("{}, so only modules and stacks will be saved", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let mut ranges = match regions {
+        Some(regions) => regions,
+        None => {
+            let mut ranges = Vec::new();
+            if full_memory {
+                ranges.extend(process.iterate_modules().map(|m| MemoryRegion { address: m.address, size: m.size }));
+            }
+            for (_, context) in threads.iter() {
+                ranges.push(MemoryRegion { address: context.rsp, size: STACK_SAVE_SIZE });
+                ranges.push(MemoryRegion { address: context.rip.saturating_sub(CODE_SAVE_SIZE), size: CODE_SAVE_SIZE * 2 });
+            }
+            ranges
+        }
+    };
+    ranges.sort_by_key(|r| r.address);
+    let runs = find_readable_runs(target.memory_source(), &ranges);
+
+    // Describing the whole address space lets !address work on the dump, when the target can do that
+    let memory_map = target.memory_map().unwrap_or_default();
+
+    let stream_count = 4 + exception_code.is_some() as usize + !memory_map.is_empty() as usize;
+    let mut builder = MinidumpBuilder { buffer: vec![0; MINIDUMP_HEADER::SIZE] };
+    let directory = builder.append_bytes(&vec![0; stream_count * MINIDUMP_DIRECTORY::SIZE]);
+    let mut streams: Vec<(MINIDUMP_STREAM_TYPE, MINIDUMP_LOCATION_DESCRIPTOR)> = Vec::new();
+
+    let mut system_info: MINIDUMP_SYSTEM_INFO = unsafe { std::mem::zeroed() };
+    system_info.ProcessorArchitecture = PROCESSOR_ARCHITECTURE_AMD64;
+    system_info.Anonymous1.Anonymous.NumberOfProcessors = std::thread::available_parallelism().map_or(1, |n| n.get().min(255) as u8);
+    system_info.PlatformId = if process.iterate_modules().next().is_none_or(|m| m.is_pe()) { VER_PLATFORM_WIN32_NT } else { PLATFORM_ID_LINUX };
+    system_info.CSDVersionRva = builder.append_string("");
+    streams.push((SystemInfoStream, builder.append(&system_info)));
+
+    let contexts: Vec<MINIDUMP_LOCATION_DESCRIPTOR> = threads.iter().map(|(_, context)| builder.append(&CONTEXT::from(context))).collect();
+    let mut minidump_threads: Vec<MINIDUMP_THREAD> = threads.iter().zip(contexts.iter()).map(|((thread_id, _), context)| {
+        let mut thread: MINIDUMP_THREAD = unsafe { std::mem::zeroed() };
+        thread.ThreadId = *thread_id;
+        thread.Teb = target.thread_environment_block(*thread_id).unwrap_or(0);
+        thread.ThreadContext = *context;
+        thread
+    }).collect();
+    // The thread list is written once the stacks are filled in, which is after we know where the memory is going to be
+    let thread_list = builder.append_bytes(&vec![0; 4 + threads.len() * MINIDUMP_THREAD::SIZE]);
+    streams.push((ThreadListStream, thread_list));
+
+    let mut module_list = (process.iterate_modules().count() as u32).to_le_bytes().to_vec();
+    for module in process.iterate_modules() {
+        let (checksum, timestamp) = module.image_checksum_and_timestamp();
+        let mut entry: MINIDUMP_MODULE = unsafe { std::mem::zeroed() };
+        entry.BaseOfImage = module.address;
+        entry.SizeOfImage = module.size as u32;
+        entry.CheckSum = checksum;
+        entry.TimeDateStamp = timestamp;
+        entry.ModuleNameRva = builder.append_string(&module.name);
+        if let Some(record) = codeview_record(module) {
+            entry.CvRecord = builder.append_bytes(&record);
+        }
+        entry.encode(&mut module_list);
+    }
+    streams.push((ModuleListStream, builder.append_bytes(&module_list)));
+
+    if !memory_map.is_empty() {
+        let header = MINIDUMP_MEMORY_INFO_LIST {
+            SizeOfHeader: MINIDUMP_MEMORY_INFO_LIST::SIZE as u32,
+            SizeOfEntry: MINIDUMP_MEMORY_INFO::SIZE as u32,
+            NumberOfEntries: memory_map.len() as u64,
+        };
+        let mut memory_info_list = memory::encode(&header);
+        for region in memory_map.iter() {
+            let mut info: MINIDUMP_MEMORY_INFO = unsafe { std::mem::zeroed() };
+            info.BaseAddress = region.address;
+            info.AllocationBase = region.allocation_base;
+            info.RegionSize = region.size;
+            info.State = region.state;
+            info.Protect = region.protect;
+            info.Type = region.region_type;
+            info.encode(&mut memory_info_list);
+        }
+        streams.push((MemoryInfoListStream, builder.append_bytes(&memory_info_list)));
+    }
+
+    if let Some(exception_code) = exception_code {
+        let mut stream: MINIDUMP_EXCEPTION_STREAM = unsafe { std::mem::zeroed() };
+        stream.ThreadId = event_thread;
+        stream.ExceptionRecord.ExceptionCode = exception_code as u32;
+        if let Some(idx) = threads.iter().position(|(t, _)| *t == event_thread) {
+            stream.ExceptionRecord.ExceptionAddress = threads[idx].1.rip;
+            stream.ThreadContext = contexts[idx];
+        }
+        streams.push((ExceptionStream, builder.append(&stream)));
+    }
+
+    // The memory goes at the end, which keeps everything else within reach of a 32-bit RVA. Full dumps use the 64-bit
+    // list, where the memory is contiguous. Otherwise each range has its own RVA.
+    let (list_size, descriptor_size) = if full_memory { (16, MINIDUMP_MEMORY_DESCRIPTOR64::SIZE) } else { (4, MINIDUMP_MEMORY_DESCRIPTOR::SIZE) };
+    let list_rva = (builder.buffer.len() + 7) & !7;
+    let data_rva = ((list_rva + list_size + runs.len() * descriptor_size + 7) & !7) as u64;
+    let mut run_rvas = Vec::new();
+    let mut memory_list = Vec::new();
+    if full_memory {
+        memory_list.extend_from_slice(&(runs.len() as u64).to_le_bytes());
+        memory_list.extend_from_slice(&data_rva.to_le_bytes());
+    } else {
+        memory_list.extend_from_slice(&(runs.len() as u32).to_le_bytes());
+    }
+    let mut rva = data_rva;
+    for run in runs.iter() {
+        if full_memory {
+            MINIDUMP_MEMORY_DESCRIPTOR64 { StartOfMemoryRange: run.address, DataSize: run.size }.encode(&mut memory_list);
+        } else {
+            let memory = MINIDUMP_LOCATION_DESCRIPTOR { DataSize: run.size as u32, Rva: rva as u32 };
+            MINIDUMP_MEMORY_DESCRIPTOR { StartOfMemoryRange: run.address, Memory: memory }.encode(&mut memory_list);
+        }
+        run_rvas.push(rva);
+        rva += run.size;
+    }
+    if !full_memory && rva > u32::MAX as u64 {
+        return Err("Too much memory to save without /ma");
+    }
+    let memory_list = builder.append_bytes(&memory_list);
+    streams.push((if full_memory { Memory64ListStream } else { MemoryListStream }, memory_list));
+    builder.buffer.resize(data_rva as usize, 0);
+
+    // Point each thread's stack at the memory that was saved for it, as long as it's within reach of a 32-bit RVA
+    for (thread, (_, context)) in minidump_threads.iter_mut().zip(threads.iter()) {
+        let run = runs.iter().position(|r| context.rsp >= r.address && context.rsp < r.address + r.size);
+        if let Some(run_idx) = run {
+            let run = &runs[run_idx];
+            let stack_rva = run_rvas[run_idx] + (context.rsp - run.address);
+            let stack_size = std::cmp::min(run.address + run.size - context.rsp, u32::MAX as u64);
+            if stack_rva <= u32::MAX as u64 {
+                let memory = MINIDUMP_LOCATION_DESCRIPTOR { DataSize: stack_size as u32, Rva: stack_rva as u32 };
+                thread.Stack = MINIDUMP_MEMORY_DESCRIPTOR { StartOfMemoryRange: context.rsp, Memory: memory };
+            }
+        }
+    }
+    let mut thread_list_data = (minidump_threads.len() as u32).to_le_bytes().to_vec();
+    minidump_threads.iter().for_each(|thread| thread.encode(&mut thread_list_data));
+    builder.write_at(thread_list.Rva as usize, &thread_list_data);
+
+    let mut directory_data = Vec::new();
+    for (stream_type, location) in streams.iter() {
+        MINIDUMP_DIRECTORY { StreamType: *stream_type as u32, Location: *location }.encode(&mut directory_data);
+    }
+    builder.write_at(directory.Rva as usize, &directory_data);
+
+    let mut header: MINIDUMP_HEADER = unsafe { std::mem::zeroed() };
+    header.Signature = MINIDUMP_SIGNATURE;
+    header.Version = MINIDUMP_VERSION;
+    header.NumberOfStreams = stream_count as u32;
+    header.StreamDirectoryRva = directory.Rva;
+    header.Anonymous.TimeDateStamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_secs() as u32);
+    let memory_flags = if full_memory { MiniDumpWithFullMemory } else { MiniDumpNormal };
+    let info_flags = if memory_map.is_empty() { 0 } else { MiniDumpWithFullMemoryInfo };
+    header.Flags = (memory_flags | info_flags) as u64;
+    builder.write_at(0, &memory::encode(&header));
+
+    let mut file = File::create(path).map_err(|_| "Could not create dump file")?;
+    file.write_all(&builder.buffer).map_err(|_| "Could not write dump file")?;
+    for run in runs.iter() {
+        let mut address = run.address;
+        while address < run.address + run.size {
+            let len = std::cmp::min(run.address + run.size - address, WRITE_CHUNK_SIZE) as usize;
+            // Memory that has become unreadable since we looked is written as zeroes, to keep the layout intact
+            let mut data = target.memory_source().read_raw_memory(address, len);
+            data.resize(len, 0);
+            file.write_all(&data).map_err(|_| "Could not write dump file")?;
+            address += len as u64;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use windows_sys::Win32::Foundation::EXCEPTION_ACCESS_VIOLATION;
+
+    use super::*;
+    use crate::fake_target::{temp_file_path, FakeTarget, THREAD_ID};
+
+    const CODE_START: u64 = 0x10000;
+    const STACK_START: u64 = 0x40000;
+
+    fn code() -> Vec<u8> {
+        (0..0x3000).map(|i| (i * 3) as u8).collect()
+    }
+
+    fn stack() -> Vec<u8> {
+        (0..0x100).map(|i| (0xff - i) as u8).collect()
+    }
+
+    fn stopped_target() -> (FakeTarget, Process) {
+        let mut target = FakeTarget::new(CODE_START + 0x10, CODE_START + 0x2000);
+        target.add_memory(CODE_START, &code());
+        target.add_memory(STACK_START, &stack());
+        let mut context = target.get_thread_context(THREAD_ID).unwrap();
+        context.rsp = STACK_START + 0x80;
+        context.rax = 0x1234;
+        context.fx_save[24] = 0x80;
+        target.set_thread_context(THREAD_ID, &context).unwrap();
+
+        let mut process = Process::new();
+        process.add_thread(THREAD_ID);
+        (target, process)
+    }
+
+    fn write_and_open(name: &str, full_memory: bool) -> DumpTarget {
+        let (target, process) = stopped_target();
+        let path = temp_file_path(name);
+        write_minidump(&path, full_memory, &target, &process, THREAD_ID, Some(EXCEPTION_ACCESS_VIOLATION)).unwrap();
+        let dump = open_minidump(&path);
+        std::fs::remove_file(&path).unwrap();
+        dump.unwrap()
+    }
+
+    #[test]
+    fn full_dump_round_trip() {
+        let mut dump = write_and_open("full.dmp", true);
+
+        assert!(matches!(dump.wait_for_event().unwrap().1, DebugEvent::CreateThread { thread_id: THREAD_ID }));
+        let (ctx, event) = dump.wait_for_event().unwrap();
+        assert_eq!(ctx.thread_id, THREAD_ID);
+        assert!(matches!(event, DebugEvent::Exception { first_chance: false, exception_code: EXCEPTION_ACCESS_VIOLATION }));
+
+        let context = dump.get_thread_context(THREAD_ID).unwrap();
+        assert_eq!(context.rip, CODE_START + 0x10);
+        assert_eq!(context.rsp, STACK_START + 0x80);
+        assert_eq!(context.rax, 0x1234);
+        assert_eq!(context.fx_save[24], 0x80);
+
+        // All of the memory is saved, and nothing else
+        assert_eq!(dump.memory_source().read_raw_memory(CODE_START, 0x3000), code());
+        assert_eq!(dump.memory_source().read_raw_memory(STACK_START, 0x100), stack());
+        assert_eq!(dump.memory_source().read_memory(STACK_START + 0xf0, 0x20).unwrap()[0x10..], [None; 0x10]);
+
+        let memory_map = dump.memory_map().unwrap();
+        let regions: Vec<(u64, u64)> = memory_map.iter().map(|r| (r.address, r.size)).collect();
+        assert_eq!(regions, [(CODE_START, 0x3000), (STACK_START, 0x100)]);
+    }
+
+    // Memory info is saved whenever the target can list its memory, which the fake target always can
+    #[test]
+    fn header_flags_describe_contents() {
+        for (name, full_memory, memory_flags) in [("flags-full.dmp", true, MiniDumpWithFullMemory), ("flags-small.dmp", false, MiniDumpNormal)] {
+            let (target, process) = stopped_target();
+            let path = temp_file_path(name);
+            write_minidump(&path, full_memory, &target, &process, THREAD_ID, None).unwrap();
+            let header: MINIDUMP_HEADER = memory::decode(&std::fs::read(&path).unwrap()).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!({ header.Flags }, (memory_flags | MiniDumpWithFullMemoryInfo) as u64);
+        }
+    }
+
+    #[test]
+    fn readable_runs_skip_unreadable_bytes() {
+        let (target, _) = stopped_target();
+        let ranges = [
+            // Overlapping ranges are only saved once, and a range that runs into the next page carries on the same run
+            MemoryRegion { address: CODE_START + 0x800, size: 0x1000 },
+            MemoryRegion { address: CODE_START + 0x1000, size: 0x1800 },
+            // The stack is only readable for part of its page
+            MemoryRegion { address: STACK_START - 0x10, size: 0x1000 },
+        ];
+        let runs: Vec<(u64, u64)> = find_readable_runs(target.memory_source(), &ranges).iter().map(|r| (r.address, r.size)).collect();
+        assert_eq!(runs, [(CODE_START + 0x800, 0x2000), (STACK_START, 0x100)]);
+    }
+
+    #[test]
+    fn small_dump_round_trip() {
+        let dump = write_and_open("small.dmp", false);
+
+        let context = dump.get_thread_context(THREAD_ID).unwrap();
+        assert_eq!(context.rip, CODE_START + 0x10);
+
+        // Only the code around the instruction pointer and the stack from the stack pointer up are saved
+        assert_eq!(dump.memory_source().read_raw_memory(CODE_START, 0x110), code()[..0x110]);
+        assert_eq!(dump.memory_source().read_memory(CODE_START + 0x110, 1).unwrap(), [None]);
+        assert_eq!(dump.memory_source().read_raw_memory(STACK_START + 0x80, 0x100), stack()[0x80..]);
+        assert_eq!(dump.memory_source().read_memory(STACK_START, 1).unwrap(), [None]);
+    }
+
+    #[test]
+    fn string_longer_than_file_is_rejected() {
+        let path = temp_file_path("string.dmp");
+        std::fs::write(&path, [0xff, 0xff, 0xff, 0xff, b'a', 0]).unwrap();
+        let result = read_string(&mut File::open(&path).unwrap(), 0);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn memory_list_past_end_of_file_offsets_is_rejected() {
+        let (target, process) = stopped_target();
+        let path = temp_file_path("overflow.dmp");
+        write_minidump(&path, true, &target, &process, THREAD_ID, None).unwrap();
+
+        // Point the memory at the very end of the 64-bit file offsets, so that adding up the sizes overflows
+        let mut data = std::fs::read(&path).unwrap();
+        let header: MINIDUMP_HEADER = memory::decode(&data).unwrap();
+        let memory_list = (0..header.NumberOfStreams as usize)
+            .map(|i| memory::decode::<MINIDUMP_DIRECTORY>(&data[header.StreamDirectoryRva as usize + i * MINIDUMP_DIRECTORY::SIZE..]).unwrap())
+            .find(|d| d.StreamType == Memory64ListStream as u32)
+            .unwrap();
+        let base_rva = memory_list.Location.Rva as usize + 8;
+        data[base_rva..base_rva + 8].copy_from_slice(&(u64::MAX - 0x10).to_le_bytes());
+        std::fs::write(&path, &data).unwrap();
+
+        let result = open_minidump(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+}
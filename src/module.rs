@@ -1,5 +1,8 @@
+use crate::cfi::CallFrameInfo;
+use crate::dwarf::DwarfInfo;
+use crate::elf;
 use crate::memory::{*, self};
-use windows::Win32::System::SystemInformation::IMAGE_FILE_MACHINE_AMD64;
+use windows::Win32::System::SystemInformation::{IMAGE_FILE_MACHINE, IMAGE_FILE_MACHINE_AMD64};
 use windows::Win32::System::SystemServices::*;
 use windows::Win32::System::Diagnostics::Debug::{*, IMAGE_DATA_DIRECTORY};
 use pdb::{PDB, AddressMap};
@@ -14,7 +17,12 @@ pub struct Module {
     pub pdb_info: Option<PdbInfo>,
     pub pdb: Option<PDB<'static, File>>,
     pub address_map: Option<AddressMap<'static>>,
-    pe_header: IMAGE_NT_HEADERS64,
+    // ELF modules and PE modules built by GCC/MinGW have DWARF debug info instead of a PDB
+    pub dwarf: Option<DwarfInfo>,
+    // How to unwind through ELF modules, which don't have a function table like PE modules do
+    pub frame_info: Option<CallFrameInfo>,
+    // Only PE modules have this, ELF modules don't have an equivalent for most of the data directories
+    pe_header: Option<IMAGE_NT_HEADERS64>,
 }
 
 pub struct Export {
@@ -24,16 +32,17 @@ pub struct Export {
     pub target: ExportTarget,
 }
 
-impl ToString for Export {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for Export {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         if let Some(str) = &self.name {
-            str.to_string()
+            write!(f, "{}", str)
         } else {
-            format!("Ordinal{}", self.ordinal)
+            write!(f, "Ordinal{}", self.ordinal)
         }
     }
 }
 
+#[allow(clippy::upper_case_acronyms, dead_code)]
 pub enum ExportTarget {
     RVA(u64),
     Forwarder(String)
@@ -55,15 +64,135 @@ impl ::core::clone::Clone for PdbInfo {
     }
 }
 
+impl_pod!(PdbInfo { signature: u32, guid: windows::core::GUID, age: u32 });
+
+impl Pod for windows::core::GUID {
+    const SIZE: usize = 16;
+    fn decode(decoder: &mut Decoder) -> Self {
+        windows::core::GUID::from_values(decoder.read(), decoder.read(), decoder.read(), decoder.read())
+    }
+    fn encode(&self, data: &mut Vec<u8>) {
+        self.data1.encode(data);
+        self.data2.encode(data);
+        self.data3.encode(data);
+        self.data4.encode(data);
+    }
+}
+
+// The PE headers are decoded field by field, since some of them are packed and the section header has a union in it
+macro_rules! impl_pod_for_newtype {
+    ($($name:ident($t:ty)),+) => {
+        $(
+            impl Pod for $name {
+                const SIZE: usize = <$t as Pod>::SIZE;
+                fn decode(decoder: &mut Decoder) -> Self {
+                    $name(decoder.read())
+                }
+                fn encode(&self, data: &mut Vec<u8>) {
+                    self.0.encode(data);
+                }
+            }
+        )+
+    };
+}
+
+impl_pod_for_newtype!(
+    IMAGE_FILE_MACHINE(u16),
+    IMAGE_FILE_CHARACTERISTICS(u16),
+    IMAGE_OPTIONAL_HEADER_MAGIC(u16),
+    IMAGE_SUBSYSTEM(u16),
+    IMAGE_DLL_CHARACTERISTICS(u16),
+    IMAGE_SECTION_CHARACTERISTICS(u32),
+    IMAGE_DEBUG_TYPE(u32)
+);
+
+impl_pod!(IMAGE_DOS_HEADER {
+    e_magic: u16, e_cblp: u16, e_cp: u16, e_crlc: u16, e_cparhdr: u16, e_minalloc: u16, e_maxalloc: u16, e_ss: u16,
+    e_sp: u16, e_csum: u16, e_ip: u16, e_cs: u16, e_lfarlc: u16, e_ovno: u16, e_res: [u16; 4], e_oemid: u16,
+    e_oeminfo: u16, e_res2: [u16; 10], e_lfanew: i32,
+});
+
+impl_pod!(IMAGE_FILE_HEADER {
+    Machine: IMAGE_FILE_MACHINE, NumberOfSections: u16, TimeDateStamp: u32, PointerToSymbolTable: u32,
+    NumberOfSymbols: u32, SizeOfOptionalHeader: u16, Characteristics: IMAGE_FILE_CHARACTERISTICS,
+});
+
+impl_pod!(IMAGE_DATA_DIRECTORY { VirtualAddress: u32, Size: u32 });
+
+impl_pod!(IMAGE_OPTIONAL_HEADER64 {
+    Magic: IMAGE_OPTIONAL_HEADER_MAGIC, MajorLinkerVersion: u8, MinorLinkerVersion: u8, SizeOfCode: u32,
+    SizeOfInitializedData: u32, SizeOfUninitializedData: u32, AddressOfEntryPoint: u32, BaseOfCode: u32,
+    ImageBase: u64, SectionAlignment: u32, FileAlignment: u32, MajorOperatingSystemVersion: u16,
+    MinorOperatingSystemVersion: u16, MajorImageVersion: u16, MinorImageVersion: u16, MajorSubsystemVersion: u16,
+    MinorSubsystemVersion: u16, Win32VersionValue: u32, SizeOfImage: u32, SizeOfHeaders: u32, CheckSum: u32,
+    Subsystem: IMAGE_SUBSYSTEM, DllCharacteristics: IMAGE_DLL_CHARACTERISTICS, SizeOfStackReserve: u64,
+    SizeOfStackCommit: u64, SizeOfHeapReserve: u64, SizeOfHeapCommit: u64, LoaderFlags: u32,
+    NumberOfRvaAndSizes: u32, DataDirectory: [IMAGE_DATA_DIRECTORY; 16],
+});
+
+impl_pod!(IMAGE_NT_HEADERS64 { Signature: u32, FileHeader: IMAGE_FILE_HEADER, OptionalHeader: IMAGE_OPTIONAL_HEADER64 });
+
+impl Pod for IMAGE_SECTION_HEADER {
+    const SIZE: usize = 40;
+    fn decode(decoder: &mut Decoder) -> Self {
+        IMAGE_SECTION_HEADER {
+            Name: decoder.read(),
+            Misc: IMAGE_SECTION_HEADER_0 { VirtualSize: decoder.read() },
+            VirtualAddress: decoder.read(),
+            SizeOfRawData: decoder.read(),
+            PointerToRawData: decoder.read(),
+            PointerToRelocations: decoder.read(),
+            PointerToLinenumbers: decoder.read(),
+            NumberOfRelocations: decoder.read(),
+            NumberOfLinenumbers: decoder.read(),
+            Characteristics: decoder.read(),
+        }
+    }
+    fn encode(&self, data: &mut Vec<u8>) {
+        self.Name.encode(data);
+        // Both halves of the union are a u32
+        unsafe { self.Misc.VirtualSize }.encode(data);
+        self.VirtualAddress.encode(data);
+        self.SizeOfRawData.encode(data);
+        self.PointerToRawData.encode(data);
+        self.PointerToRelocations.encode(data);
+        self.PointerToLinenumbers.encode(data);
+        self.NumberOfRelocations.encode(data);
+        self.NumberOfLinenumbers.encode(data);
+        self.Characteristics.encode(data);
+    }
+}
+
+impl_pod!(IMAGE_DEBUG_DIRECTORY {
+    Characteristics: u32, TimeDateStamp: u32, MajorVersion: u16, MinorVersion: u16, Type: IMAGE_DEBUG_TYPE,
+    SizeOfData: u32, AddressOfRawData: u32, PointerToRawData: u32,
+});
+
+impl_pod!(IMAGE_EXPORT_DIRECTORY {
+    Characteristics: u32, TimeDateStamp: u32, MajorVersion: u16, MinorVersion: u16, Name: u32, Base: u32,
+    NumberOfFunctions: u32, NumberOfNames: u32, AddressOfFunctions: u32, AddressOfNames: u32,
+    AddressOfNameOrdinals: u32,
+});
+
+type DebugInfo = (Option<PdbInfo>, Option<String>, Option<PDB<'static, File>>);
+
 impl Module {
     pub fn from_memory_view(module_address: u64, module_name: Option<String>, memory_source: &dyn MemorySource) -> Result<Module, &'static str> {
 
+        let magic = memory_source.read_raw_memory(module_address, elf::ELF_MAGIC.len());
+        if magic == elf::ELF_MAGIC {
+            return Module::from_elf_view(module_address, module_name, memory_source);
+        }
+
         let dos_header: IMAGE_DOS_HEADER = memory::read_memory_data(memory_source, module_address)?;
+        if dos_header.e_magic != IMAGE_DOS_SIGNATURE {
+            return Err("Not a PE image");
+        }
 
         // NOTE: Do we trust that the headers are accurate, even if it means we could read outside the bounds of the
         //       module? For this debugger, we'll trust the data, but a real debugger should do sanity checks and 
         //       report discrepancies to the user in some way.
-        let pe_header_addr = module_address + dos_header.e_lfanew as u64;
+        let pe_header_addr = module_address.wrapping_add(dos_header.e_lfanew as i64 as u64);
 
         // NOTE: This should be IMAGE_NT_HEADERS32 for 32-bit modules, but the FileHeader lines up for both structures.
         let pe_header: IMAGE_NT_HEADERS64 = memory::read_memory_data(memory_source, pe_header_addr)?;
@@ -76,6 +205,11 @@ impl Module {
         let (pdb_info, pdb_name, mut pdb) = Module::read_debug_info(&pe_header, module_address, memory_source)?;
         let (exports, export_table_module_name) = Module::read_exports(&pe_header, module_address, memory_source)?;
 
+        let dwarf = match pdb {
+            None => module_name.as_deref().and_then(|path| DwarfInfo::load(path, module_address).ok().flatten()),
+            Some(_) => None,
+        };
+
         let module_name = module_name.or(export_table_module_name);
          let module_name = match module_name {
             Some(s) => s,
@@ -94,16 +228,62 @@ impl Module {
             pdb_name,
             pdb,
             address_map,
-            pe_header
+            dwarf,
+            frame_info: None,
+            pe_header: Some(pe_header)
         })
     }
 
+    // Loads an ELF module as if it were mapped at module_address, but reads the contents from the file on disk. This
+    // is useful when the module isn't in memory at all, or when parts of it aren't readable.
+    pub fn from_elf_file(module_address: u64, path: &str) -> Result<Module, &'static str> {
+        let file_view = elf::ElfFileView::open(path, module_address)?;
+        Module::from_elf_view(module_address, Some(path.to_string()), &file_view)
+    }
+
+    fn from_elf_view(module_address: u64, module_name: Option<String>, memory_source: &dyn MemorySource) -> Result<Module, &'static str> {
+        let image = elf::read_elf_image(module_address, memory_source)?;
+        let dwarf = module_name.as_deref().and_then(|path| DwarfInfo::load(path, module_address).ok().flatten());
+        let frame_info = module_name.as_deref().and_then(|path| CallFrameInfo::load(path, module_address).ok().flatten());
+
+        let module_name = match module_name.or(image.soname) {
+            Some(s) => s,
+            None => format!("module_{:X}", module_address),
+        };
+
+        Ok(Module {
+            name: module_name,
+            address: module_address,
+            size: image.size,
+            exports: image.exports,
+            pdb_info: None,
+            pdb_name: None,
+            pdb: None,
+            address_map: None,
+            dwarf,
+            frame_info,
+            pe_header: None,
+        })
+    }
+
+    pub fn is_pe(&self) -> bool {
+        self.pe_header.is_some()
+    }
+
+    // The checksum and timestamp from the PE header, which identify the exact build of the module
+    pub fn image_checksum_and_timestamp(&self) -> (u32, u32) {
+        match &self.pe_header {
+            Some(pe_header) => (pe_header.OptionalHeader.CheckSum, pe_header.FileHeader.TimeDateStamp),
+            None => (0, 0),
+        }
+    }
+
     pub fn contains_address(&self, address: u64) -> bool {
         let end = self.address + self.size;
         self.address <= address && address < end
     }
 
-    fn read_debug_info(pe_header: &IMAGE_NT_HEADERS64, module_address: u64, memory_source: &dyn MemorySource) -> Result<(Option<PdbInfo>, Option<String>, Option<PDB<'static, File>>), &'static str> {
+    fn read_debug_info(pe_header: &IMAGE_NT_HEADERS64, module_address: u64, memory_source: &dyn MemorySource) -> Result<DebugInfo, &'static str> {
         let mut pdb_info: Option<PdbInfo> = None;
         let mut pdb_name: Option<String> = None;
         let mut pdb: Option<PDB<File>> = None;
@@ -111,7 +291,7 @@ impl Module {
 
         let debug_table_info = pe_header.OptionalHeader.DataDirectory[IMAGE_DIRECTORY_ENTRY_DEBUG.0 as usize];
         if debug_table_info.VirtualAddress != 0 {
-            let dir_size = std::mem::size_of::<IMAGE_DEBUG_DIRECTORY>() as u64;
+            let dir_size = IMAGE_DEBUG_DIRECTORY::SIZE as u64;
             // We'll arbitrarily limit to 20 entries to keep it sane.
             let count: u64 = std::cmp::min(debug_table_info.Size as u64 / dir_size, 20);
             for dir_index in 0..count {
@@ -121,8 +301,8 @@ impl Module {
                     let pdb_info_address = debug_directory.AddressOfRawData as u64 + module_address;
                     pdb_info = Some(memory::read_memory_data(memory_source, pdb_info_address)?);
                     // We could check that pdb_info.signature is RSDS here.
-                    let pdb_name_address = pdb_info_address + std::mem::size_of::<PdbInfo>() as u64;
-                    let max_size = debug_directory.SizeOfData as usize - std::mem::size_of::<PdbInfo>();
+                    let pdb_name_address = pdb_info_address + PdbInfo::SIZE as u64;
+                    let max_size = (debug_directory.SizeOfData as usize).saturating_sub(PdbInfo::SIZE);
                     pdb_name = Some(memory::read_memory_string(memory_source, pdb_name_address, max_size, false)?);
 
                     let pdb_file = File::open(pdb_name.as_ref().unwrap());
@@ -140,7 +320,10 @@ impl Module {
     }
 
     pub fn get_data_directory(&self, entry: IMAGE_DIRECTORY_ENTRY) -> IMAGE_DATA_DIRECTORY {
-        self.pe_header.OptionalHeader.DataDirectory[entry.0 as usize]
+        match &self.pe_header {
+            Some(pe_header) => pe_header.OptionalHeader.DataDirectory[entry.0 as usize],
+            None => IMAGE_DATA_DIRECTORY::default(),
+        }
     }
 
     fn read_exports(pe_header: &IMAGE_NT_HEADERS64, module_address: u64, memory_source: &dyn MemorySource) -> Result<(Vec::<Export>, Option<String>), &'static str> {
@@ -7,14 +7,12 @@ use anyhow::anyhow;
 enum AddressMatch<'a> {
     None,
     Export(&'a Export),
-    Public(String)
+    Public(String),
+    Function(String),
 }
 impl AddressMatch<'_> {
     fn is_none(&self) -> bool {
-        match self {
-            AddressMatch::None => true,
-            _ => false
-        }
+        matches!(self, AddressMatch::None)
     }
 }
 
@@ -40,6 +38,69 @@ pub fn resolve_name_to_address(sym: &str, process: &mut Process) -> Result<u64,
     }
 }
 
+// Converts a pattern with * and ? wildcards into a regex that matches the whole string, ignoring case
+fn wildcard_regex(pattern: &str) -> Result<regex::Regex, anyhow::Error> {
+    let escaped = regex::escape(pattern).replace(r"\*", ".*").replace(r"\?", ".");
+    Ok(regex::RegexBuilder::new(&format!("^{}$", escaped)).case_insensitive(true).build()?)
+}
+
+// Finds all of the symbols that match a module!symbol pattern, where either part can have wildcards. A pattern
+// without a module searches every module.
+pub fn find_matching_symbols(pattern: &str, process: &mut Process) -> Result<Vec<(u64, String)>, anyhow::Error> {
+    let (module_pattern, symbol_pattern) = pattern.split_once('!').unwrap_or(("*", pattern));
+    let module_regex = wildcard_regex(module_pattern)?;
+    let symbol_regex = wildcard_regex(symbol_pattern)?;
+
+    let mut matches = Vec::new();
+    for module in process.iterate_modules_mut() {
+        let file_name = module.name.rsplit(['\\', '/']).next().unwrap_or(&module.name);
+        let file_name_noext = file_name.rsplit_once('.').map_or(file_name, |(noext, _)| noext);
+        if ![module.name.as_str(), file_name, file_name_noext].iter().any(|name| module_regex.is_match(name)) {
+            continue;
+        }
+
+        let mut module_matches = Vec::new();
+        for export in module.exports.iter() {
+            if let (Some(name), ExportTarget::RVA(export_addr)) = (&export.name, &export.target) {
+                if symbol_regex.is_match(name) {
+                    module_matches.push((*export_addr, name.clone()));
+                }
+            }
+        }
+
+        if let Some(pdb) = module.pdb.as_mut() {
+            let symbol_table = pdb.global_symbols()?;
+            let address_map = pdb.address_map()?;
+            let mut symbols = symbol_table.iter();
+            while let Some(symbol) = symbols.next()? {
+                if let Ok(SymbolData::Public(data)) = symbol.parse() {
+                    let name = data.name.to_string();
+                    if symbol_regex.is_match(&name) {
+                        if let Some(rva) = data.offset.to_rva(&address_map) {
+                            module_matches.push((module.address + rva.0 as u64, name.to_string()));
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some(dwarf) = module.dwarf.as_ref() {
+            for (name, address) in dwarf.functions() {
+                if symbol_regex.is_match(name) {
+                    module_matches.push((address, name.to_string()));
+                }
+            }
+        }
+
+        // The same function is often both exported and in the debug info
+        module_matches.sort();
+        module_matches.dedup();
+        matches.extend(module_matches.into_iter().map(|(address, name)| (address, format!("{}!{}", module.name, name))));
+    }
+
+    Ok(matches)
+}
+
 pub fn resolve_function_in_module(module: &mut Module, func: &str) -> Option<u64> {
     // We'll search exports first and private symbols next
     let export_resolution = resolve_export_in_module(module, func);
@@ -47,6 +108,11 @@ pub fn resolve_function_in_module(module: &mut Module, func: &str) -> Option<u64
         return export_resolution;
     }
 
+    let dwarf_resolution = module.dwarf.as_ref().and_then(|dwarf| dwarf.find_function_by_name(func));
+    if dwarf_resolution.is_some() {
+        return dwarf_resolution;
+    }
+
     resolve_symbol_name_in_module(module, func).unwrap_or(None)
 }
 
@@ -74,13 +140,11 @@ fn resolve_symbol_name_in_module(module: &mut Module, func: &str) -> Result<Opti
         let mi = pdb.module_info(&pdb_module)?.ok_or(anyhow!("Couldn't get module info"))?;
         let mut symbols = mi.symbols()?;
         while let Some(sym) = symbols.next()? {
-            if let Ok(parsed) = sym.parse() {
-                if let SymbolData::Procedure(proc_data) = parsed {
-                    if proc_data.name.to_string() == func {
-                        let rva = proc_data.offset.to_rva(address_map).ok_or(anyhow!("Couldn't convert procedure offset to RVA"))?;
-                        let address = module.address + rva.0 as u64;
-                        return Ok(Some(address));
-                    }
+            if let Ok(SymbolData::Procedure(proc_data)) = sym.parse() {
+                if proc_data.name.to_string() == func {
+                    let rva = proc_data.offset.to_rva(address_map).ok_or(anyhow!("Couldn't convert procedure offset to RVA"))?;
+                    let address = module.address + rva.0 as u64;
+                    return Ok(Some(address));
                 }
             }
         }
@@ -89,22 +153,18 @@ fn resolve_symbol_name_in_module(module: &mut Module, func: &str) -> Result<Opti
 }
 
 
-pub fn resolve_address_to_name(address: u64, process: &mut Process) -> Option<String> {
-    let module = match process.get_containing_module_mut(address) {
-        Some(module) => module,
-        None => return None
-    };
+// Finds the module an address is in, the closest symbol at or before it, and how far past that symbol it is
+pub fn resolve_address_to_symbol(address: u64, process: &mut Process) -> Option<(String, String, u64)> {
+    let module = process.get_containing_module_mut(address)?;
 
     let mut closest: AddressMatch = AddressMatch::None;
     let mut closest_addr: u64 = 0;
     // This could be faster if we were always in sorted order
     for export in module.exports.iter() {
         if let ExportTarget::RVA(export_addr) = export.target {
-            if export_addr <= address {
-                if closest.is_none() || closest_addr < export_addr {
-                    closest = AddressMatch::Export(export);
-                    closest_addr = export_addr;
-                }
+            if export_addr <= address && (closest.is_none() || closest_addr < export_addr) {
+                closest = AddressMatch::Export(export);
+                closest_addr = export_addr;
             }
         };
     }
@@ -131,25 +191,27 @@ pub fn resolve_address_to_name(address: u64, process: &mut Process) -> Option<St
         }
     }
 
-    if let AddressMatch::Export(closest) = closest {
-        let offset = address - closest_addr;
-        let sym_with_offset = if offset == 0 {
-            format!("{}!{}", &module.name, closest.to_string())
-        } else {
-            format!("{}!{}+0x{:X}", &module.name, closest.to_string(), offset)
-        };
-        return Some(sym_with_offset)
+    if let Some((name, function_addr)) = module.dwarf.as_ref().and_then(|dwarf| dwarf.find_function(address)) {
+        if closest.is_none() || closest_addr <= function_addr {
+            closest = AddressMatch::Function(name.to_string());
+            closest_addr = function_addr;
+        }
     }
 
-    if let AddressMatch::Public(closest) = closest {
-        let offset = address - closest_addr;
-        let sym_with_offset = if offset == 0 {
-            format!("{}!{}", &module.name, closest)
-        } else {
-            format!("{}!{}+0x{:X}", &module.name, closest, offset)
-        };
-        return Some(sym_with_offset)
-    }
-    
-    None
-}
\ No newline at end of file
+    let closest = match closest {
+        AddressMatch::Export(closest) => closest.to_string(),
+        AddressMatch::Public(closest) | AddressMatch::Function(closest) => closest,
+        AddressMatch::None => return None,
+    };
+    Some((module.name.clone(), closest, address - closest_addr))
+}
+
+pub fn resolve_address_to_name(address: u64, process: &mut Process) -> Option<String> {
+    let (module_name, symbol, offset) = resolve_address_to_symbol(address, process)?;
+    let sym_with_offset = if offset == 0 {
+        format!("{}!{}", module_name, symbol)
+    } else {
+        format!("{}!{}+0x{:X}", module_name, symbol, offset)
+    };
+    Some(sym_with_offset)
+}
@@ -1,5 +1,3 @@
-use windows_sys::Win32::Foundation;
-
 use crate::{module::Module, memory::MemorySource};
 
 pub struct Process {
@@ -13,7 +11,14 @@ impl Process {
     }
 
     pub fn add_module(&mut self, address: u64, name: Option<String>, memory_source: &dyn MemorySource) -> Result<&Module, &'static str> {
-        let module = Module::from_memory_view(address, name, memory_source)?;
+        let module = match Module::from_memory_view(address, name.clone(), memory_source) {
+            Ok(module) => module,
+            // If the module isn't readable in memory, an ELF module can still be loaded from the file it was mapped from
+            Err(e) => match name.as_deref().filter(|n| std::path::Path::new(n).is_file()) {
+                Some(path) => Module::from_elf_file(address, path).map_err(|_| e)?,
+                None => return Err(e),
+            },
+        };
         self.module_list.push(module);
         Ok(self.module_list.last().unwrap())
     }
@@ -30,24 +35,20 @@ impl Process {
         self.thread_list.iter()
     }
 
-    pub fn _get_containing_module(&self, address: u64) -> Option<&Module> {
-        for module in self.module_list.iter() {
-            if module.contains_address(address) {
-                return Some(&module);
-            }
-        };
+    pub fn iterate_modules(&self) -> core::slice::Iter<'_, Module> {
+        self.module_list.iter()
+    }
 
-        None
+    pub fn iterate_modules_mut(&mut self) -> core::slice::IterMut<'_, Module> {
+        self.module_list.iter_mut()
     }
 
-    pub fn get_containing_module_mut(&mut self, address: u64) -> Option<&mut Module> {
-        for module in self.module_list.iter_mut() {
-            if module.contains_address(address) {
-                return Some(module);
-            }
-        };
+    pub fn get_containing_module(&self, address: u64) -> Option<&Module> {
+        self.module_list.iter().find(|module| module.contains_address(address))
+    }
 
-        None
+    pub fn get_containing_module_mut(&mut self, address: u64) -> Option<&mut Module> {
+        self.module_list.iter_mut().find(|module| module.contains_address(address))
     }
 
     pub fn get_module_by_name_mut(&mut self, module_name: &str) -> Option<&mut Module> {
@@ -59,15 +60,14 @@ impl Process {
                 return Some(module);
             }
     
-            let trimmed = module.name.rsplitn(2, '\\').next().unwrap_or(&module.name);
+            let trimmed = module.name.rsplit(['\\', '/']).next().unwrap_or(&module.name);
             if potential_trimmed_match.is_none() && trimmed.to_lowercase() == module_name.to_lowercase() {
                 potential_trimmed_match = Some(module);
             } else if potential_trimmed_noext_match.is_none() {
-                let parts: Vec<&str> = trimmed.rsplitn(2, '.').collect();
-                let trimmed_noext = if parts.len() == 2 {
-                    parts[1]
-                } else {
-                    parts[0]
+                // Shared objects usually have a version after the extension (libc.so.6), so drop that too
+                let trimmed_noext = match trimmed.find(".so") {
+                    Some(pos) if trimmed[pos + 3..].is_empty() || trimmed[pos + 3..].starts_with('.') => &trimmed[..pos],
+                    _ => trimmed.rsplit_once('.').map_or(trimmed, |(noext, _)| noext),
                 };
                 if trimmed_noext.to_lowercase() == module_name.to_lowercase() {
                     potential_trimmed_noext_match = Some(module);
@@ -0,0 +1,776 @@
+use std::collections::{hash_map, HashMap, HashSet, VecDeque};
+use std::ffi::CString;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+
+use windows_sys::Win32::Foundation::{EXCEPTION_ACCESS_VIOLATION, EXCEPTION_BREAKPOINT, EXCEPTION_ILLEGAL_INSTRUCTION, EXCEPTION_INT_DIVIDE_BY_ZERO, EXCEPTION_SINGLE_STEP};
+use windows_sys::Win32::System::Memory::{
+    MEM_COMMIT, MEM_IMAGE, MEM_MAPPED, MEM_PRIVATE, PAGE_EXECUTE, PAGE_EXECUTE_READ, PAGE_EXECUTE_READWRITE, PAGE_NOACCESS,
+    PAGE_READONLY, PAGE_READWRITE,
+};
+
+use crate::event::{DebugEvent, EventContext};
+use crate::memory::MemorySource;
+use crate::memory_cache::CachedMemorySource;
+use crate::registers::RegisterContext;
+use crate::target::{ContinueStatus, DebugTarget, MemoryRegion, MemoryRegionInfo};
+
+// si_code values for SIGTRAP, from <asm-generic/siginfo.h>. These aren't exposed by the libc crate.
+const TRAP_BRKPT: i32 = 1;
+const TRAP_TRACE: i32 = 2;
+const TRAP_HWBKPT: i32 = 4;
+// An int3 instruction reports SIGTRAP with SI_KERNEL rather than TRAP_BRKPT on x64
+const SI_KERNEL: i32 = 0x80;
+
+const AT_ENTRY: u64 = 9;
+
+const DEBUG_REG_OFFSET: usize = std::mem::offset_of!(libc::user, u_debugreg);
+
+// Reads and writes go through /proc/<pid>/mem, which (unlike process_vm_readv) lets us see pages that aren't readable
+// by the target itself and write to read-only code pages, which is what a debugger wants.
+struct ProcMemorySource {
+    mem_file: File,
+}
+
+const PAGE_SIZE: u64 = 0x1000;
+
+impl ProcMemorySource {
+    fn read_into(&self, address: u64, buffer: &mut [u8]) -> usize {
+        let mut offset = 0;
+        while offset < buffer.len() {
+            match self.mem_file.read_at(&mut buffer[offset..], address + offset as u64) {
+                Ok(0) | Err(_) => break,
+                Ok(bytes_read) => offset += bytes_read,
+            }
+        }
+        offset
+    }
+}
+
+impl MemorySource for ProcMemorySource {
+    fn read_memory(&self, address: u64, len: usize) -> Result<Vec<Option<u8>>, &'static str> {
+        let mut data: Vec<Option<u8>> = vec![None; len];
+        let mut offset: usize = 0;
+
+        // Any failure will be for an entire page, so we read up to the end of each page at a time
+        while offset < len {
+            let cur_address = address + offset as u64;
+            let page_left = (PAGE_SIZE - (cur_address % PAGE_SIZE)) as usize;
+            let chunk_len = std::cmp::min(page_left, len - offset);
+            let mut buffer = vec![0u8; chunk_len];
+            let bytes_read = self.read_into(cur_address, &mut buffer);
+            for (index, byte) in buffer.iter().take(bytes_read).enumerate() {
+                data[offset + index] = Some(*byte);
+            }
+            offset += chunk_len;
+        }
+
+        Ok(data)
+    }
+
+    fn read_raw_memory(&self, address: u64, len: usize) -> Vec<u8> {
+        let mut buffer: Vec<u8> = vec![0; len];
+        let bytes_read = self.read_into(address, &mut buffer);
+        buffer.truncate(bytes_read);
+        buffer
+    }
+}
+
+// Everything we keep for each process. There's only more than one when child processes are being debugged too.
+struct TracedProcess {
+    memory_source: CachedMemorySource<ProcMemorySource>,
+    known_module_bases: HashSet<u64>,
+    // We put a temporary breakpoint on the entry point so that we stop once the dynamic loader has loaded everything,
+    // which is roughly where Windows gives us the initial breakpoint.
+    entry_breakpoint: Option<(u64, u8)>,
+    exited: bool,
+}
+
+impl TracedProcess {
+    fn open(pid: i32) -> Result<TracedProcess, &'static str> {
+        let mem_file = File::options().read(true).write(true).open(format!("/proc/{}/mem", pid)).map_err(|_| "Could not open process memory")?;
+        Ok(TracedProcess {
+            memory_source: CachedMemorySource::new(ProcMemorySource { mem_file }),
+            known_module_bases: HashSet::new(),
+            entry_breakpoint: None,
+            exited: false,
+        })
+    }
+
+    fn write_memory(&self, address: u64, data: &[u8]) -> Result<usize, &'static str> {
+        self.memory_source.invalidate_range(address, data.len());
+        self.memory_source.source().mem_file.write_at(data, address).map_err(|_| "Failed to write process memory")
+    }
+}
+
+pub struct PtraceTarget {
+    processes: HashMap<i32, TracedProcess>,
+    // Memory is read from this process, which is the one that reported the last event unless the debugger switched
+    current_pid: i32,
+    // All the threads we know about, and the process each one is in. We always stop every thread before reporting an
+    // event, to match the Windows model where the entire process is frozen while the debugger looks at an event.
+    threads: HashMap<i32, i32>,
+    // Stops that happened while we were stopping the rest of the threads. These get reported before we wait again.
+    pending_statuses: VecDeque<(i32, i32)>,
+    // Threads that have a SIGSTOP from us in flight that needs to be swallowed when it arrives.
+    pending_sigstops: HashSet<i32>,
+    // New threads whose initial SIGSTOP arrived before the clone event from the thread that created them.
+    early_new_threads: HashSet<i32>,
+    // Events for a single stop, which are handed out one at a time before the target is resumed.
+    queued_events: VecDeque<(EventContext, DebugEvent)>,
+    // The signal that caused the current stop, which is delivered to the thread if the exception isn't handled.
+    event_signal: Option<(i32, i32)>,
+    step_threads: HashSet<i32>,
+}
+
+fn ptrace(request: libc::c_uint, tid: i32, addr: u64, data: u64) -> Result<i64, &'static str> {
+    // PEEKUSER returns data through the return value, so errno is the only way to tell a failure apart from a value of -1
+    unsafe { *libc::__errno_location() = 0 };
+    let ret = unsafe { libc::ptrace(request, tid, addr as *mut libc::c_void, data as *mut libc::c_void) };
+    if ret == -1 && unsafe { *libc::__errno_location() } != 0 {
+        return Err("ptrace failed");
+    }
+    Ok(ret)
+}
+
+fn waitpid(tid: i32) -> Result<(i32, i32), &'static str> {
+    let mut status: i32 = 0;
+    let ret = unsafe { libc::waitpid(tid, &mut status, libc::__WALL) };
+    if ret == -1 {
+        return Err("waitpid failed");
+    }
+    Ok((ret, status))
+}
+
+// EXITKILL means the target goes away with us if we exit without detaching, which matches the Windows behavior. New
+// processes inherit these, so following forks gets the whole tree of processes that the target starts.
+fn trace_options(debug_children: bool) -> libc::c_int {
+    let options = libc::PTRACE_O_TRACECLONE | libc::PTRACE_O_EXITKILL;
+    if debug_children {
+        options | libc::PTRACE_O_TRACEFORK | libc::PTRACE_O_TRACEVFORK | libc::PTRACE_O_TRACEEXEC
+    } else {
+        options
+    }
+}
+
+// One line of /proc/<pid>/maps
+struct MemoryMapping {
+    start: u64,
+    end: u64,
+    offset: u64,
+    readable: bool,
+    // The permissions, like r-xp, where the last letter is p for private mappings or s for shared ones
+    perms: String,
+    path: String,
+}
+
+fn read_memory_maps(pid: i32) -> Vec<MemoryMapping> {
+    let mut maps = Vec::new();
+    let contents = std::fs::read_to_string(format!("/proc/{}/maps", pid)).unwrap_or_default();
+    for line in contents.lines() {
+        // e.g. 7f1c2a400000-7f1c2a428000 r--p 00000000 08:01 1234   /usr/lib/x86_64-linux-gnu/libc.so.6
+        let mut fields = line.split_whitespace();
+        let range = fields.next().unwrap_or("");
+        let perms = fields.next().unwrap_or("");
+        let offset = fields.next().unwrap_or("");
+        let _device = fields.next();
+        let _inode = fields.next();
+        let path = fields.collect::<Vec<&str>>().join(" ");
+        if let Some((start, end)) = range.split_once('-') {
+            if let (Ok(start), Ok(end), Ok(offset)) = (u64::from_str_radix(start, 16), u64::from_str_radix(end, 16), u64::from_str_radix(offset, 16)) {
+                maps.push(MemoryMapping { start, end, offset, readable: perms.starts_with('r'), perms: perms.to_string(), path });
+            }
+        }
+    }
+    maps
+}
+
+fn read_auxv_entry(pid: i32, entry_type: u64) -> Option<u64> {
+    let auxv = std::fs::read(format!("/proc/{}/auxv", pid)).ok()?;
+    for entry in auxv.chunks_exact(16) {
+        let key = u64::from_le_bytes(entry[0..8].try_into().unwrap());
+        let value = u64::from_le_bytes(entry[8..16].try_into().unwrap());
+        if key == entry_type {
+            return Some(value);
+        }
+    }
+    None
+}
+
+impl PtraceTarget {
+    pub fn launch(args: &[String], debug_children: bool) -> Result<PtraceTarget, &'static str> {
+        let program = CString::new(args.first().ok_or("No program specified")?.as_str()).map_err(|_| "Invalid program name")?;
+        let c_args: Vec<CString> = args.iter().map(|a| CString::new(a.as_str())).collect::<Result<_, _>>().map_err(|_| "Invalid argument")?;
+        let mut argv: Vec<*const libc::c_char> = c_args.iter().map(|a| a.as_ptr()).collect();
+        argv.push(std::ptr::null());
+
+        let pid = unsafe { libc::fork() };
+        if pid == -1 {
+            return Err("fork failed");
+        }
+
+        if pid == 0 {
+            // We're in the child, so only async-signal-safe calls until we exec.
+            unsafe {
+                libc::ptrace(libc::PTRACE_TRACEME, 0, std::ptr::null_mut::<libc::c_void>(), std::ptr::null_mut::<libc::c_void>());
+                libc::execvp(program.as_ptr(), argv.as_ptr());
+                libc::_exit(127);
+            }
+        }
+
+        // The child will stop with a SIGTRAP once the exec has happened
+        let (_, status) = waitpid(pid)?;
+        if !libc::WIFSTOPPED(status) || libc::WSTOPSIG(status) != libc::SIGTRAP {
+            return Err("Failed to launch process");
+        }
+
+        ptrace(libc::PTRACE_SETOPTIONS, pid, 0, trace_options(debug_children) as u64)?;
+
+        let mut target = PtraceTarget::new(pid)?;
+        target.threads.insert(pid, pid);
+
+        let ctx = EventContext { process_id: pid as u32, thread_id: pid as u32 };
+        let event = target.create_process_event(pid);
+        target.queued_events.push_back((ctx, event));
+        target.queue_new_modules(pid, pid);
+        target.set_entry_breakpoint(pid);
+
+        Ok(target)
+    }
+
+    fn new(pid: i32) -> Result<PtraceTarget, &'static str> {
+        Ok(PtraceTarget {
+            processes: HashMap::from([(pid, TracedProcess::open(pid)?)]),
+            current_pid: pid,
+            threads: HashMap::new(),
+            pending_statuses: VecDeque::new(),
+            pending_sigstops: HashSet::new(),
+            early_new_threads: HashSet::new(),
+            queued_events: VecDeque::new(),
+            event_signal: None,
+            step_threads: HashSet::new(),
+        })
+    }
+
+    // Works out where the main executable of a process is, and remembers it so that it isn't reported as a module too
+    fn create_process_event(&mut self, pid: i32) -> DebugEvent {
+        let exe_name = std::fs::read_link(format!("/proc/{}/exe", pid)).ok().map(|p| p.to_string_lossy().to_string());
+        let exe_base = exe_name.as_ref().and_then(|exe_name| {
+            read_memory_maps(pid).iter().filter(|m| m.path == *exe_name && m.offset == 0).map(|m| m.start).min()
+        }).unwrap_or(0);
+        if let Some(process) = self.processes.get_mut(&pid) {
+            process.known_module_bases.insert(exe_base);
+        }
+        DebugEvent::CreateProcess { exe_name, exe_base }
+    }
+
+    fn set_entry_breakpoint(&mut self, pid: i32) {
+        let (Some(entry), Some(process)) = (read_auxv_entry(pid, AT_ENTRY), self.processes.get_mut(&pid)) else {
+            return;
+        };
+        let original = process.memory_source.read_raw_memory(entry, 1);
+        if original.len() == 1 && process.write_memory(entry, &[0xCC]) == Ok(1) {
+            process.entry_breakpoint = Some((entry, original[0]));
+        }
+    }
+
+    fn event_context(&self, tid: i32) -> EventContext {
+        let pid = self.threads.get(&tid).copied().unwrap_or(tid);
+        EventContext { process_id: pid as u32, thread_id: tid as u32 }
+    }
+
+    fn all_exited(&self) -> bool {
+        self.processes.values().all(|p| p.exited)
+    }
+
+    // Attaches to every thread of a running process. There aren't any events for what's already there, so we make
+    // them up the way Windows does, and then report a breakpoint so that we break in.
+    pub fn attach(pid: i32) -> Result<PtraceTarget, &'static str> {
+        let mut target = PtraceTarget::new(pid)?;
+
+        // Threads can be created while we're attaching, so keep going until we've found all of them
+        loop {
+            let tids: Vec<i32> = std::fs::read_dir(format!("/proc/{}/task", pid))
+                .map_err(|_| "Process does not exist")?
+                .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+                .filter(|tid| !target.threads.contains_key(tid))
+                .collect();
+            if tids.is_empty() {
+                break;
+            }
+            for tid in tids {
+                if ptrace(libc::PTRACE_ATTACH, tid, 0, 0).is_err() {
+                    if tid == pid {
+                        return Err("Could not attach to process");
+                    }
+                    // The thread exited before we got to it
+                    continue;
+                }
+                let (_, status) = waitpid(tid)?;
+                ptrace(libc::PTRACE_SETOPTIONS, tid, 0, trace_options(false) as u64)?;
+                if !libc::WIFSTOPPED(status) || libc::WSTOPSIG(status) != libc::SIGSTOP {
+                    // Something else happened first, and our SIGSTOP is still on the way
+                    target.pending_statuses.push_back((tid, status));
+                    target.pending_sigstops.insert(tid);
+                }
+                target.threads.insert(tid, pid);
+            }
+        }
+
+        let ctx = EventContext { process_id: pid as u32, thread_id: pid as u32 };
+        let event = target.create_process_event(pid);
+        target.queued_events.push_back((ctx, event));
+        target.queue_new_modules(pid, pid);
+        for tid in target.threads.keys().filter(|tid| **tid != pid) {
+            target.queued_events.push_back((ctx, DebugEvent::CreateThread { thread_id: *tid as u32 }));
+        }
+        target.queued_events.push_back((ctx, DebugEvent::Exception { first_chance: true, exception_code: EXCEPTION_BREAKPOINT }));
+
+        Ok(target)
+    }
+
+    // Look for any images that have been mapped since we last looked, and queue up LoadModule events for them. There
+    // isn't an event for library loads, so we check whenever the target stops.
+    fn queue_new_modules(&mut self, pid: i32, thread_id: i32) {
+        let Some(process) = self.processes.get_mut(&pid) else {
+            return;
+        };
+        let maps = read_memory_maps(pid);
+        for MemoryMapping { start, offset, path, .. } in maps.iter() {
+            let is_image = *offset == 0 && (path.starts_with('/') || path == "[vdso]");
+            if !is_image || process.known_module_bases.contains(start) {
+                continue;
+            }
+
+            // Data files can be mapped too, so make sure this is actually an ELF image
+            if process.memory_source.read_raw_memory(*start, 4) != b"\x7fELF" {
+                continue;
+            }
+
+            process.known_module_bases.insert(*start);
+            let ctx = EventContext { process_id: pid as u32, thread_id: thread_id as u32 };
+            let module_name = Some(path.clone());
+            self.queued_events.push_back((ctx, DebugEvent::LoadModule { module_name, module_base: *start }));
+        }
+    }
+
+    fn next_status(&mut self) -> Result<(i32, i32), &'static str> {
+        match self.pending_statuses.pop_front() {
+            Some(status) => Ok(status),
+            None => waitpid(-1),
+        }
+    }
+
+    // Stop every thread except the one that reported an event, so the process is frozen while we look at it.
+    fn stop_other_threads(&mut self, event_tid: i32) {
+        let pending_tids: HashSet<i32> = self.pending_statuses.iter().map(|s| s.0).collect();
+        for (tid, pid) in self.threads.clone() {
+            if tid == event_tid || pending_tids.contains(&tid) {
+                continue;
+            }
+
+            unsafe { libc::syscall(libc::SYS_tgkill, pid, tid, libc::SIGSTOP) };
+            let status = match waitpid(tid) {
+                Ok((_, status)) => status,
+                Err(_) => continue,
+            };
+            if libc::WIFSTOPPED(status) && libc::WSTOPSIG(status) == libc::SIGSTOP && status >> 16 == 0 {
+                continue;
+            }
+
+            // Something else happened to the thread first. We'll report it later, and throw away our SIGSTOP
+            // when it eventually shows up.
+            self.pending_statuses.push_back((tid, status));
+            if libc::WIFSTOPPED(status) {
+                self.pending_sigstops.insert(tid);
+            }
+        }
+    }
+
+    fn translate_signal(&mut self, tid: i32, signal: i32) -> Result<Option<DebugEvent>, &'static str> {
+        let exception_code = match signal {
+            libc::SIGTRAP => {
+                let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+                ptrace(libc::PTRACE_GETSIGINFO, tid, 0, &mut info as *mut libc::siginfo_t as u64)?;
+                let mut regs = self.get_regs(tid)?;
+
+                let process = self.threads.get(&tid).and_then(|pid| self.processes.get_mut(pid));
+                if let Some((process, (entry, original))) = process.and_then(|p| p.entry_breakpoint.map(|bp| (p, bp))) {
+                    if info.si_code == SI_KERNEL && regs.rip == entry + 1 {
+                        // This is our entry point breakpoint, so put everything back the way it was.
+                        process.write_memory(entry, &[original])?;
+                        regs.rip = entry;
+                        ptrace(libc::PTRACE_SETREGS, tid, 0, &regs as *const libc::user_regs_struct as u64)?;
+                        process.entry_breakpoint = None;
+                        self.event_signal = None;
+                        return Ok(Some(DebugEvent::Exception { first_chance: true, exception_code: EXCEPTION_BREAKPOINT }));
+                    }
+                }
+
+                match info.si_code {
+                    TRAP_TRACE | TRAP_HWBKPT => EXCEPTION_SINGLE_STEP,
+                    TRAP_BRKPT | SI_KERNEL => EXCEPTION_BREAKPOINT,
+                    _ => EXCEPTION_BREAKPOINT,
+                }
+            }
+            libc::SIGSEGV | libc::SIGBUS => EXCEPTION_ACCESS_VIOLATION,
+            libc::SIGILL => EXCEPTION_ILLEGAL_INSTRUCTION,
+            libc::SIGFPE => EXCEPTION_INT_DIVIDE_BY_ZERO,
+            _ => {
+                // Not something that maps to an exception, so just let the target have it
+                ptrace(libc::PTRACE_CONT, tid, 0, signal as u64)?;
+                return Ok(None);
+            }
+        };
+
+        self.event_signal = Some((tid, signal));
+        Ok(Some(DebugEvent::Exception { first_chance: true, exception_code }))
+    }
+
+    // Turns a wait status into an event that should be reported, or None if it was handled internally
+    fn translate_status(&mut self, tid: i32, status: i32) -> Result<Option<(EventContext, DebugEvent)>, &'static str> {
+        let ctx = self.event_context(tid);
+        if libc::WIFEXITED(status) || libc::WIFSIGNALED(status) {
+            self.threads.remove(&tid);
+            self.pending_sigstops.remove(&tid);
+            if let Some(process) = self.processes.get_mut(&tid) {
+                process.exited = true;
+                return Ok(Some((ctx, DebugEvent::ExitProcess)));
+            }
+            return Ok(Some((ctx, DebugEvent::ExitThread { thread_id: tid as u32 })));
+        }
+
+        if !libc::WIFSTOPPED(status) {
+            return Ok(None);
+        }
+
+        let signal = libc::WSTOPSIG(status);
+        let ptrace_event = status >> 16;
+
+        if ptrace_event == libc::PTRACE_EVENT_CLONE {
+            let mut new_tid: libc::c_ulong = 0;
+            ptrace(libc::PTRACE_GETEVENTMSG, tid, 0, &mut new_tid as *mut libc::c_ulong as u64)?;
+            let new_tid = new_tid as i32;
+            if !self.early_new_threads.remove(&new_tid) {
+                // New threads start out with a SIGSTOP, which we'll swallow when it shows up
+                self.pending_sigstops.insert(new_tid);
+            }
+            self.threads.insert(new_tid, ctx.process_id as i32);
+            return Ok(Some((ctx, DebugEvent::CreateThread { thread_id: new_tid as u32 })));
+        }
+
+        if ptrace_event == libc::PTRACE_EVENT_FORK || ptrace_event == libc::PTRACE_EVENT_VFORK {
+            let mut new_pid: libc::c_ulong = 0;
+            ptrace(libc::PTRACE_GETEVENTMSG, tid, 0, &mut new_pid as *mut libc::c_ulong as u64)?;
+            let new_pid = new_pid as i32;
+            // The new process starts out with a SIGSTOP just like a new thread does
+            if !self.early_new_threads.remove(&new_pid) {
+                self.pending_sigstops.insert(new_pid);
+            }
+            self.threads.insert(new_pid, new_pid);
+            self.processes.insert(new_pid, TracedProcess::open(new_pid)?);
+            // The child is reported as the thread that stopped, so the modules it starts with are reported for it
+            let event = self.create_process_event(new_pid);
+            return Ok(Some((EventContext { process_id: new_pid as u32, thread_id: new_pid as u32 }, event)));
+        }
+
+        if ptrace_event == libc::PTRACE_EVENT_EXEC {
+            // The process has a whole new address space, so it gets reported as a new process with the same ID. The
+            // memory file still refers to the old one, so it has to be opened again.
+            let pid = ctx.process_id as i32;
+            self.processes.insert(pid, TracedProcess::open(pid)?);
+            let event = self.create_process_event(pid);
+            self.set_entry_breakpoint(pid);
+            return Ok(Some((ctx, event)));
+        }
+
+        if ptrace_event != 0 {
+            // Some other ptrace event we didn't ask for. Just keep going.
+            ptrace(libc::PTRACE_CONT, tid, 0, 0)?;
+            return Ok(None);
+        }
+
+        if signal == libc::SIGSTOP {
+            if self.pending_sigstops.remove(&tid) {
+                ptrace(libc::PTRACE_CONT, tid, 0, 0)?;
+                return Ok(None);
+            }
+            if let hash_map::Entry::Vacant(entry) = self.threads.entry(tid) {
+                // A new thread or process that we haven't seen the clone or fork event for yet. We find out which
+                // process it's in when that shows up.
+                entry.insert(tid);
+                self.early_new_threads.insert(tid);
+                ptrace(libc::PTRACE_CONT, tid, 0, 0)?;
+                return Ok(None);
+            }
+        }
+
+        Ok(self.translate_signal(tid, signal)?.map(|event| (ctx, event)))
+    }
+
+    // Waits for the next stop that needs to be reported, and queues up the events for it
+    fn queue_next_event(&mut self) -> Result<(), &'static str> {
+        if self.all_exited() {
+            return Err("Process has exited");
+        }
+
+        loop {
+            let (tid, status) = self.next_status()?;
+            if let Some((ctx, event)) = self.translate_status(tid, status)? {
+                if !self.all_exited() {
+                    self.stop_other_threads(tid);
+                }
+                // Modules that were loaded are reported before the event that noticed them, except for a new process
+                // where they have to come after it
+                let is_new_process = matches!(event, DebugEvent::CreateProcess { .. });
+                if is_new_process {
+                    self.queued_events.push_back((ctx, event));
+                    self.queue_new_modules(ctx.process_id as i32, ctx.thread_id as i32);
+                } else {
+                    if !matches!(event, DebugEvent::ExitProcess) {
+                        self.queue_new_modules(ctx.process_id as i32, ctx.thread_id as i32);
+                    }
+                    self.queued_events.push_back((ctx, event));
+                }
+                return Ok(());
+            }
+        }
+    }
+
+    fn current_process(&self) -> &TracedProcess {
+        // The process that reported the first event is always there, even after it has exited
+        &self.processes[&self.current_pid]
+    }
+
+    fn get_regs(&self, tid: i32) -> Result<libc::user_regs_struct, &'static str> {
+        let mut regs: libc::user_regs_struct = unsafe { std::mem::zeroed() };
+        ptrace(libc::PTRACE_GETREGS, tid, 0, &mut regs as *mut libc::user_regs_struct as u64)?;
+        Ok(regs)
+    }
+}
+
+impl DebugTarget for PtraceTarget {
+    fn wait_for_event(&mut self) -> Result<(EventContext, DebugEvent), &'static str> {
+        if self.queued_events.is_empty() {
+            self.queue_next_event()?;
+        }
+        let (ctx, event) = self.queued_events.pop_front().unwrap();
+        self.current_pid = ctx.process_id as i32;
+        Ok((ctx, event))
+    }
+
+    fn memory_source(&self) -> &dyn MemorySource {
+        &self.current_process().memory_source
+    }
+
+    fn write_memory(&mut self, address: u64, data: &[u8]) -> Result<usize, &'static str> {
+        self.current_process().write_memory(address, data)
+    }
+
+    fn memory_regions(&self) -> Result<Vec<MemoryRegion>, &'static str> {
+        let maps = read_memory_maps(self.current_pid);
+        Ok(maps.iter().filter(|m| m.readable).map(|m| MemoryRegion { address: m.start, size: m.end - m.start }).collect())
+    }
+
+    fn memory_map(&self) -> Result<Vec<MemoryRegionInfo>, &'static str> {
+        let maps = read_memory_maps(self.current_pid);
+        Ok(maps.into_iter().map(|m| {
+            let perm = |index: usize, letter: u8| m.perms.as_bytes().get(index) == Some(&letter);
+            // Windows has no write-only pages, so writable pages are always readable too
+            let protect = match (perm(0, b'r'), perm(1, b'w'), perm(2, b'x')) {
+                (false, false, false) => PAGE_NOACCESS,
+                (false, false, true) => PAGE_EXECUTE,
+                (true, false, false) => PAGE_READONLY,
+                (_, true, false) => PAGE_READWRITE,
+                (true, false, true) => PAGE_EXECUTE_READ,
+                (_, true, true) => PAGE_EXECUTE_READWRITE,
+            };
+            // The loader maps ELF files privately, so private file mappings are counted as images even though a
+            // program could map a data file the same way
+            let is_file = m.path.starts_with('/');
+            let region_type = match (is_file, perm(3, b's')) {
+                (true, false) => MEM_IMAGE,
+                (_, true) => MEM_MAPPED,
+                (false, false) => MEM_PRIVATE,
+            };
+            let name = (!m.path.is_empty()).then_some(m.path);
+            MemoryRegionInfo { address: m.start, size: m.end - m.start, allocation_base: m.start, state: MEM_COMMIT, protect, region_type, name }
+        }).collect())
+    }
+
+    fn get_thread_context(&self, thread_id: u32) -> Result<RegisterContext, &'static str> {
+        let tid = thread_id as i32;
+        let regs = self.get_regs(tid)?;
+        let mut context = RegisterContext {
+            rax: regs.rax,
+            rbx: regs.rbx,
+            rcx: regs.rcx,
+            rdx: regs.rdx,
+            rsi: regs.rsi,
+            rdi: regs.rdi,
+            rbp: regs.rbp,
+            rsp: regs.rsp,
+            r8: regs.r8,
+            r9: regs.r9,
+            r10: regs.r10,
+            r11: regs.r11,
+            r12: regs.r12,
+            r13: regs.r13,
+            r14: regs.r14,
+            r15: regs.r15,
+            rip: regs.rip,
+            eflags: regs.eflags as u32,
+            cs: regs.cs as u16,
+            ds: regs.ds as u16,
+            es: regs.es as u16,
+            fs: regs.fs as u16,
+            gs: regs.gs as u16,
+            ss: regs.ss as u16,
+            ..Default::default()
+        };
+
+        ptrace(libc::PTRACE_GETFPREGS, tid, 0, context.fx_save.as_mut_ptr() as u64)?;
+
+        let debug_regs: Vec<u64> = [0, 1, 2, 3, 6, 7].iter()
+            .map(|i| ptrace(libc::PTRACE_PEEKUSER, tid, (DEBUG_REG_OFFSET + i * 8) as u64, 0).map(|v| v as u64))
+            .collect::<Result<_, _>>()?;
+        context.dr0 = debug_regs[0];
+        context.dr1 = debug_regs[1];
+        context.dr2 = debug_regs[2];
+        context.dr3 = debug_regs[3];
+        context.dr6 = debug_regs[4];
+        context.dr7 = debug_regs[5];
+
+        Ok(context)
+    }
+
+    fn set_thread_context(&mut self, thread_id: u32, context: &RegisterContext) -> Result<(), &'static str> {
+        let tid = thread_id as i32;
+        // Start from the current registers so that anything we don't track (orig_rax, fs_base, etc.) is preserved
+        let mut regs = self.get_regs(tid)?;
+        regs.rax = context.rax;
+        regs.rbx = context.rbx;
+        regs.rcx = context.rcx;
+        regs.rdx = context.rdx;
+        regs.rsi = context.rsi;
+        regs.rdi = context.rdi;
+        regs.rbp = context.rbp;
+        regs.rsp = context.rsp;
+        regs.r8 = context.r8;
+        regs.r9 = context.r9;
+        regs.r10 = context.r10;
+        regs.r11 = context.r11;
+        regs.r12 = context.r12;
+        regs.r13 = context.r13;
+        regs.r14 = context.r14;
+        regs.r15 = context.r15;
+        regs.rip = context.rip;
+        regs.eflags = context.eflags as u64;
+        ptrace(libc::PTRACE_SETREGS, tid, 0, &regs as *const libc::user_regs_struct as u64)?;
+        ptrace(libc::PTRACE_SETFPREGS, tid, 0, context.fx_save.as_ptr() as u64)?;
+
+        // The address registers need to be valid before DR7 enables them
+        for (i, value) in [(0, context.dr0), (1, context.dr1), (2, context.dr2), (3, context.dr3), (7, context.dr7)] {
+            ptrace(libc::PTRACE_POKEUSER, tid, (DEBUG_REG_OFFSET + i * 8) as u64, value)?;
+        }
+
+        Ok(())
+    }
+
+    fn switch_process(&mut self, process_id: u32) -> Result<(), &'static str> {
+        if !self.processes.contains_key(&(process_id as i32)) {
+            return Err("Not debugging that process");
+        }
+        self.current_pid = process_id as i32;
+        Ok(())
+    }
+
+    fn step_thread(&mut self, thread_id: u32) -> Result<(), &'static str> {
+        self.step_threads.insert(thread_id as i32);
+        Ok(())
+    }
+
+    fn continue_event(&mut self, event_context: &EventContext, status: ContinueStatus) -> Result<(), &'static str> {
+        // If there are more events from the same stop, the target stays stopped until they have all been reported
+        if !self.queued_events.is_empty() || self.all_exited() {
+            return Ok(());
+        }
+
+        let event_tid = event_context.thread_id as i32;
+        let signal = match (self.event_signal.take(), status) {
+            (Some((tid, signal)), ContinueStatus::NotHandled) if tid == event_tid => signal,
+            _ => 0,
+        };
+
+        // Once the target runs, anything we've read from it could be out of date
+        for process in self.processes.values() {
+            process.memory_source.invalidate();
+        }
+
+        let pending_tids: HashSet<i32> = self.pending_statuses.iter().map(|s| s.0).collect();
+        for tid in self.threads.keys().copied().collect::<Vec<_>>() {
+            if pending_tids.contains(&tid) {
+                // This thread already has something to report, so leave it stopped until it has been reported
+                continue;
+            }
+            let signal = if tid == event_tid { signal } else { 0 };
+            // DR6 isn't cleared by the CPU, so clear it now so that the next stop doesn't see stale breakpoint hits
+            let _ = ptrace(libc::PTRACE_POKEUSER, tid, (DEBUG_REG_OFFSET + 6 * 8) as u64, 0);
+            let request = if self.step_threads.remove(&tid) { libc::PTRACE_SINGLESTEP } else { libc::PTRACE_CONT };
+            if ptrace(request, tid, 0, signal as u64).is_err() {
+                println!("Could not resume thread {:x}", tid);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn detach(&mut self, event_context: &EventContext, status: ContinueStatus) -> Result<(), &'static str> {
+        if self.all_exited() {
+            return Err("Process has exited");
+        }
+
+        // The entry point breakpoint would kill the process if it was hit without us
+        for process in self.processes.values_mut().filter(|p| !p.exited) {
+            if let Some((entry, original)) = process.entry_breakpoint.take() {
+                process.write_memory(entry, &[original])?;
+            }
+        }
+
+        let event_tid = event_context.thread_id as i32;
+        let signal = match (self.event_signal.take(), status) {
+            (Some((tid, signal)), ContinueStatus::NotHandled) if tid == event_tid => signal,
+            _ => 0,
+        };
+
+        for tid in self.threads.keys().copied().collect::<Vec<_>>() {
+            // A stop we haven't reported yet might be for a signal that the target should still get
+            let pending_signal = self.pending_statuses.iter()
+                .find(|(t, status)| *t == tid && libc::WIFSTOPPED(*status) && *status >> 16 == 0)
+                .map(|(_, status)| libc::WSTOPSIG(*status))
+                .filter(|s| *s != libc::SIGTRAP && *s != libc::SIGSTOP);
+            let signal = if tid == event_tid { signal } else { pending_signal.unwrap_or(0) };
+            let _ = ptrace(libc::PTRACE_POKEUSER, tid, (DEBUG_REG_OFFSET + 6 * 8) as u64, 0);
+            if ptrace(libc::PTRACE_DETACH, tid, 0, signal as u64).is_err() {
+                println!("Could not detach from thread {:x}", tid);
+            }
+        }
+
+        // Any SIGSTOP of ours that hasn't arrived yet would stop the process once we're gone
+        if !self.pending_sigstops.is_empty() {
+            for (pid, _) in self.processes.iter().filter(|(_, p)| !p.exited) {
+                unsafe { libc::kill(*pid, libc::SIGCONT) };
+            }
+        }
+
+        self.threads.clear();
+        self.pending_statuses.clear();
+        self.pending_sigstops.clear();
+        self.queued_events.clear();
+        for process in self.processes.values_mut() {
+            process.memory_source.invalidate();
+            process.exited = true;
+        }
+        Ok(())
+    }
+}
@@ -1,17 +1,170 @@
-use windows_sys::{
-    Win32::System::{Diagnostics::Debug::*},
-};
-
-pub fn display_all(context: &CONTEXT) {
-    println!("rax={:#018x} rbx={:#018x} rcx={:#018x}", context.Rax, context.Rbx, context.Rcx);
-    println!("rdx={:#018x} rsi={:#018x} rdi={:#018x}", context.Rdx, context.Rsi, context.Rdi);
-    println!("rip={:#018x} rsp={:#018x} rbp={:#018x}", context.Rip, context.Rsp, context.Rbp);
-    println!(" r8={:#018x}  r9={:#018x} r10={:#018x}", context.R8, context.R9, context.R10);
-    println!("r11={:#018x} r12={:#018x} r13={:#018x}", context.R11, context.R12, context.R13);
-    println!("r14={:#018x} r15={:#018x} eflags={:#010x}", context.R14, context.R15, context.EFlags);
+use windows_sys::Win32::System::Diagnostics::Debug::{CONTEXT, XSAVE_FORMAT};
+
+// Not sure why these are missing from windows_sys, but the definitions are in winnt.h
+pub const CONTEXT_AMD64: u32 = 0x00100000;
+pub const CONTEXT_CONTROL: u32 = CONTEXT_AMD64 | 0x00000001;
+pub const CONTEXT_INTEGER: u32 = CONTEXT_AMD64 | 0x00000002;
+pub const CONTEXT_SEGMENTS: u32 = CONTEXT_AMD64 | 0x00000004;
+pub const CONTEXT_FLOATING_POINT: u32 = CONTEXT_AMD64 | 0x00000008;
+pub const CONTEXT_DEBUG_REGISTERS: u32 = CONTEXT_AMD64 | 0x00000010;
+#[allow(dead_code)]
+pub const CONTEXT_FULL: u32 = CONTEXT_CONTROL | CONTEXT_INTEGER | CONTEXT_FLOATING_POINT;
+pub const CONTEXT_ALL: u32 = CONTEXT_CONTROL
+        | CONTEXT_INTEGER
+        | CONTEXT_SEGMENTS
+        | CONTEXT_FLOATING_POINT
+        | CONTEXT_DEBUG_REGISTERS;
+
+const FX_SAVE_SIZE: usize = 512;
+
+// An x64 register set that isn't tied to the thread context structure of any particular OS. Each debug target converts
+// between this and whatever its native representation is (CONTEXT, user_regs_struct, etc.)
+#[derive(Clone, Copy)]
+pub struct RegisterContext {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub eflags: u32,
+    pub cs: u16,
+    pub ds: u16,
+    pub es: u16,
+    pub fs: u16,
+    pub gs: u16,
+    pub ss: u16,
+    pub dr0: u64,
+    pub dr1: u64,
+    pub dr2: u64,
+    pub dr3: u64,
+    pub dr6: u64,
+    pub dr7: u64,
+    // The x87/MMX/SSE state, in the 512 byte layout used by the FXSAVE instruction. Both Windows (XSAVE_FORMAT) and
+    // Linux (user_fpregs_struct) use this same layout, so we just carry the raw bytes around.
+    pub fx_save: [u8; FX_SAVE_SIZE],
+}
+
+impl Default for RegisterContext {
+    fn default() -> Self {
+        RegisterContext {
+            rax: 0, rbx: 0, rcx: 0, rdx: 0, rsi: 0, rdi: 0, rbp: 0, rsp: 0,
+            r8: 0, r9: 0, r10: 0, r11: 0, r12: 0, r13: 0, r14: 0, r15: 0,
+            rip: 0, eflags: 0,
+            cs: 0, ds: 0, es: 0, fs: 0, gs: 0, ss: 0,
+            dr0: 0, dr1: 0, dr2: 0, dr3: 0, dr6: 0, dr7: 0,
+            fx_save: [0; FX_SAVE_SIZE],
+        }
+    }
+}
+
+impl From<&CONTEXT> for RegisterContext {
+    fn from(context: &CONTEXT) -> Self {
+        let fx_save: [u8; FX_SAVE_SIZE] = unsafe { std::mem::transmute(context.Anonymous.FltSave) };
+        RegisterContext {
+            rax: context.Rax,
+            rbx: context.Rbx,
+            rcx: context.Rcx,
+            rdx: context.Rdx,
+            rsi: context.Rsi,
+            rdi: context.Rdi,
+            rbp: context.Rbp,
+            rsp: context.Rsp,
+            r8: context.R8,
+            r9: context.R9,
+            r10: context.R10,
+            r11: context.R11,
+            r12: context.R12,
+            r13: context.R13,
+            r14: context.R14,
+            r15: context.R15,
+            rip: context.Rip,
+            eflags: context.EFlags,
+            cs: context.SegCs,
+            ds: context.SegDs,
+            es: context.SegEs,
+            fs: context.SegFs,
+            gs: context.SegGs,
+            ss: context.SegSs,
+            dr0: context.Dr0,
+            dr1: context.Dr1,
+            dr2: context.Dr2,
+            dr3: context.Dr3,
+            dr6: context.Dr6,
+            dr7: context.Dr7,
+            fx_save,
+        }
+    }
+}
+
+impl From<&RegisterContext> for CONTEXT {
+    fn from(context: &RegisterContext) -> Self {
+        let flt_save: XSAVE_FORMAT = unsafe { std::mem::transmute(context.fx_save) };
+        let mut native: CONTEXT = unsafe { std::mem::zeroed() };
+        // We carry everything covered by CONTEXT_ALL, so this is safe to use with SetThreadContext without losing state.
+        native.ContextFlags = CONTEXT_ALL;
+        native.Rax = context.rax;
+        native.Rbx = context.rbx;
+        native.Rcx = context.rcx;
+        native.Rdx = context.rdx;
+        native.Rsi = context.rsi;
+        native.Rdi = context.rdi;
+        native.Rbp = context.rbp;
+        native.Rsp = context.rsp;
+        native.R8 = context.r8;
+        native.R9 = context.r9;
+        native.R10 = context.r10;
+        native.R11 = context.r11;
+        native.R12 = context.r12;
+        native.R13 = context.r13;
+        native.R14 = context.r14;
+        native.R15 = context.r15;
+        native.Rip = context.rip;
+        native.EFlags = context.eflags;
+        native.SegCs = context.cs;
+        native.SegDs = context.ds;
+        native.SegEs = context.es;
+        native.SegFs = context.fs;
+        native.SegGs = context.gs;
+        native.SegSs = context.ss;
+        native.Dr0 = context.dr0;
+        native.Dr1 = context.dr1;
+        native.Dr2 = context.dr2;
+        native.Dr3 = context.dr3;
+        native.Dr6 = context.dr6;
+        native.Dr7 = context.dr7;
+        native.MxCsr = flt_save.MxCsr;
+        native.Anonymous.FltSave = flt_save;
+        native
+    }
+}
+
+// The general purpose registers, in the order the r command shows them
+pub const REGISTER_NAMES: [&str; 18] = [
+    "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rip", "rsp", "rbp", "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15", "eflags",
+];
+
+pub fn display_all(context: &RegisterContext) {
+    println!("rax={:#018x} rbx={:#018x} rcx={:#018x}", context.rax, context.rbx, context.rcx);
+    println!("rdx={:#018x} rsi={:#018x} rdi={:#018x}", context.rdx, context.rsi, context.rdi);
+    println!("rip={:#018x} rsp={:#018x} rbp={:#018x}", context.rip, context.rsp, context.rbp);
+    println!(" r8={:#018x}  r9={:#018x} r10={:#018x}", context.r8, context.r9, context.r10);
+    println!("r11={:#018x} r12={:#018x} r13={:#018x}", context.r11, context.r12, context.r13);
+    println!("r14={:#018x} r15={:#018x} eflags={:#010x}", context.r14, context.r15, context.eflags);
 }
 
-pub fn display_named(context: &CONTEXT, reg_name: &str) {
+pub fn display_named(context: &RegisterContext, reg_name: &str) {
     if let Ok(val) = get_register(context, reg_name) {
         println!("{}={:#018x}", reg_name.to_lowercase(), val);
     } else {
@@ -19,26 +172,26 @@ pub fn display_named(context: &CONTEXT, reg_name: &str) {
     }
 }
 
-pub fn get_register(context: &CONTEXT, reg_name: &str) -> Result<u64, String> {
+pub fn get_register(context: &RegisterContext, reg_name: &str) -> Result<u64, String> {
     let val = match reg_name.to_lowercase().as_str() {
-        "rax" => context.Rax,
-        "rbx" => context.Rbx,
-        "rcx" => context.Rcx,
-        "rdx" => context.Rdx,
-        "rsi" => context.Rsi,
-        "rdi" => context.Rdi,
-        "rip" => context.Rip,
-        "rsp" => context.Rsp,
-        "rbp" => context.Rbp,
-        "r8" => context.R8,
-        "r9" => context.R9,
-        "r10" => context.R10,
-        "r11" => context.R11,
-        "r12" => context.R12,
-        "r13" => context.R13,
-        "r14" => context.R14,
-        "r15" => context.R15,
-        "eflags" => context.EFlags as u64,
+        "rax" => context.rax,
+        "rbx" => context.rbx,
+        "rcx" => context.rcx,
+        "rdx" => context.rdx,
+        "rsi" => context.rsi,
+        "rdi" => context.rdi,
+        "rip" => context.rip,
+        "rsp" => context.rsp,
+        "rbp" => context.rbp,
+        "r8" => context.r8,
+        "r9" => context.r9,
+        "r10" => context.r10,
+        "r11" => context.r11,
+        "r12" => context.r12,
+        "r13" => context.r13,
+        "r14" => context.r14,
+        "r15" => context.r15,
+        "eflags" => context.eflags as u64,
         _ => return Err("Unrecognized register".to_string())
     };
     Ok(val)
@@ -0,0 +1,358 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::event::{DebugEvent, EventContext};
+use crate::memory::MemorySource;
+use crate::registers::RegisterContext;
+use crate::rsp::{decode_hex, encode_hex, parse_hex_u64};
+use crate::target::{ContinueStatus, DebugTarget, MemoryRegion};
+use crate::trace;
+
+// The protocol between a dbgrs front end and a dbgrs server. The front end sends one line for each DebugTarget call,
+// and the server answers with a line starting with "ok" followed by the result, or "error" followed by the hex encoded
+// message. Events, contexts, memory, and regions use the same encoding as trace files.
+const PROTOCOL_HEADER: &str = "dbgrs-remote 1";
+
+struct RemoteConnection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl RemoteConnection {
+    fn new(stream: TcpStream) -> Result<RemoteConnection, &'static str> {
+        // Every request waits for its answer, so Nagle only slows us down
+        let _ = stream.set_nodelay(true);
+        let writer = stream.try_clone().map_err(|_| "Could not set up connection")?;
+        Ok(RemoteConnection { reader: BufReader::new(stream), writer })
+    }
+
+    fn send_line(&mut self, line: &str) -> Result<(), &'static str> {
+        self.writer.write_all(format!("{}\n", line).as_bytes()).map_err(|_| "Lost connection to the remote side")
+    }
+
+    // None means the other side closed the connection
+    fn read_line(&mut self) -> Result<Option<String>, &'static str> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => Ok(None),
+            Ok(_) => Ok(Some(line.trim_end_matches(['\r', '\n']).to_string())),
+            Err(_) => Err("Lost connection to the remote side"),
+        }
+    }
+}
+
+fn parse_number(text: Option<&str>) -> Result<u64, &'static str> {
+    parse_hex_u64(text.ok_or("Request is missing a field")?.as_bytes())
+}
+
+fn encode_status(status: ContinueStatus) -> &'static str {
+    match status {
+        ContinueStatus::Handled => "handled",
+        ContinueStatus::NotHandled => "not_handled",
+    }
+}
+
+fn decode_status(text: Option<&str>) -> Result<ContinueStatus, &'static str> {
+    match text {
+        Some("handled") => Ok(ContinueStatus::Handled),
+        Some("not_handled") => Ok(ContinueStatus::NotHandled),
+        _ => Err("Invalid continue status"),
+    }
+}
+
+// A target that is being debugged by a dbgrs server on another machine
+pub struct RemoteTarget {
+    connection: RefCell<RemoteConnection>,
+    live: bool,
+    // The engine only deals in static error messages, so each distinct message from the server is leaked once and
+    // then reused
+    errors: RefCell<HashMap<String, &'static str>>,
+}
+
+impl RemoteTarget {
+    // Connects to a server, and asks it to attach to process_id first if there is one
+    pub fn connect(address: &str, process_id: Option<u32>) -> Result<RemoteTarget, &'static str> {
+        let stream = TcpStream::connect(address).map_err(|_| "Could not connect to server")?;
+        let mut connection = RemoteConnection::new(stream)?;
+        if connection.read_line()?.as_deref() != Some(PROTOCOL_HEADER) {
+            return Err("Not a dbgrs server");
+        }
+
+        let mut target = RemoteTarget { connection: RefCell::new(connection), live: true, errors: RefCell::new(HashMap::new()) };
+        if let Some(process_id) = process_id {
+            target.request(&format!("attach {:x}", process_id))?;
+        }
+        target.live = target.request("live")? == "1";
+        Ok(target)
+    }
+
+    // Sends a request and returns whatever came after the "ok"
+    fn request(&self, line: &str) -> Result<String, &'static str> {
+        let mut connection = self.connection.borrow_mut();
+        connection.send_line(line)?;
+        let reply = connection.read_line()?.ok_or("The server closed the connection")?;
+        if let Some(result) = reply.strip_prefix("ok") {
+            return Ok(result.trim_start().to_string());
+        }
+        let message = reply.strip_prefix("error ").ok_or("Invalid reply from server")?;
+        let message = String::from_utf8_lossy(&decode_hex(message.as_bytes())?).to_string();
+        let mut errors = self.errors.borrow_mut();
+        Err(*errors.entry(message).or_insert_with_key(|message| Box::leak(message.clone().into_boxed_str())))
+    }
+}
+
+impl MemorySource for RemoteTarget {
+    fn read_memory(&self, address: u64, len: usize) -> Result<Vec<Option<u8>>, &'static str> {
+        trace::decode_bytes(&self.request(&format!("read {:x} {:x}", address, len))?)
+    }
+
+    fn read_raw_memory(&self, address: u64, len: usize) -> Vec<u8> {
+        match self.request(&format!("read_raw {:x} {:x}", address, len)) {
+            Ok(data) => decode_hex(data.as_bytes()).unwrap_or_default(),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+impl DebugTarget for RemoteTarget {
+    fn wait_for_event(&mut self) -> Result<(EventContext, DebugEvent), &'static str> {
+        let reply = self.request("wait")?;
+        let fields: Vec<&str> = reply.split(' ').collect();
+        let ctx = EventContext { process_id: parse_number(fields.first().copied())? as u32, thread_id: parse_number(fields.get(1).copied())? as u32 };
+        Ok((ctx, trace::decode_event(fields.get(2..).unwrap_or_default())?))
+    }
+
+    fn memory_source(&self) -> &dyn MemorySource {
+        self
+    }
+
+    fn write_memory(&mut self, address: u64, data: &[u8]) -> Result<usize, &'static str> {
+        Ok(parse_number(Some(&self.request(&format!("write {:x} {}", address, encode_hex(data)))?))? as usize)
+    }
+
+    fn get_thread_context(&self, thread_id: u32) -> Result<RegisterContext, &'static str> {
+        let reply = self.request(&format!("context {:x}", thread_id))?;
+        let (values, fx_save) = reply.split_once(' ').ok_or("Invalid context from server")?;
+        trace::decode_context(values, fx_save)
+    }
+
+    fn set_thread_context(&mut self, thread_id: u32, context: &RegisterContext) -> Result<(), &'static str> {
+        self.request(&format!("set_context {:x} {}", thread_id, trace::encode_context(context))).map(|_| ())
+    }
+
+    fn step_thread(&mut self, thread_id: u32) -> Result<(), &'static str> {
+        self.request(&format!("step {:x}", thread_id)).map(|_| ())
+    }
+
+    fn continue_event(&mut self, event_context: &EventContext, status: ContinueStatus) -> Result<(), &'static str> {
+        self.request(&format!("continue {:x} {:x} {}", event_context.process_id, event_context.thread_id, encode_status(status))).map(|_| ())
+    }
+
+    fn detach(&mut self, event_context: &EventContext, status: ContinueStatus) -> Result<(), &'static str> {
+        self.request(&format!("detach {:x} {:x} {}", event_context.process_id, event_context.thread_id, encode_status(status))).map(|_| ())
+    }
+
+    fn memory_regions(&self) -> Result<Vec<MemoryRegion>, &'static str> {
+        let reply = self.request("regions")?;
+        trace::decode_regions(&reply.split(' ').collect::<Vec<&str>>())
+    }
+
+    fn is_live(&self) -> bool {
+        self.live
+    }
+}
+
+// Carries out a single request from the front end, and returns the text of the reply
+fn handle_request(target: &mut Option<Box<dyn DebugTarget>>, line: &str, attach: &dyn Fn(u32) -> Result<Box<dyn DebugTarget>, &'static str>) -> Result<String, &'static str> {
+    let mut fields = line.split(' ');
+    let request = fields.next().unwrap_or_default();
+    if request == "attach" {
+        *target = Some(attach(parse_number(fields.next())? as u32)?);
+        return Ok(String::new());
+    }
+
+    let target = target.as_mut().ok_or("The server isn't debugging anything")?;
+    Ok(match request {
+        "live" => (target.is_live() as u8).to_string(),
+        "wait" => {
+            let (ctx, event) = target.wait_for_event()?;
+            format!("{:x} {:x} {}", ctx.process_id, ctx.thread_id, trace::encode_event(&event))
+        }
+        "read" => {
+            let address = parse_number(fields.next())?;
+            trace::encode_bytes(&target.memory_source().read_memory(address, parse_number(fields.next())? as usize)?)
+        }
+        "read_raw" => {
+            let address = parse_number(fields.next())?;
+            encode_hex(&target.memory_source().read_raw_memory(address, parse_number(fields.next())? as usize))
+        }
+        "write" => {
+            let address = parse_number(fields.next())?;
+            let data = decode_hex(fields.next().unwrap_or_default().as_bytes())?;
+            format!("{:x}", target.write_memory(address, &data)?)
+        }
+        "context" => trace::encode_context(&target.get_thread_context(parse_number(fields.next())? as u32)?),
+        "set_context" => {
+            let thread_id = parse_number(fields.next())? as u32;
+            let context = trace::decode_context(fields.next().unwrap_or_default(), fields.next().unwrap_or_default())?;
+            target.set_thread_context(thread_id, &context)?;
+            String::new()
+        }
+        "step" => {
+            target.step_thread(parse_number(fields.next())? as u32)?;
+            String::new()
+        }
+        "continue" | "detach" => {
+            let ctx = EventContext { process_id: parse_number(fields.next())? as u32, thread_id: parse_number(fields.next())? as u32 };
+            let status = decode_status(fields.next())?;
+            if request == "continue" {
+                target.continue_event(&ctx, status)?;
+            } else {
+                target.detach(&ctx, status)?;
+            }
+            String::new()
+        }
+        "regions" => trace::encode_regions(&target.memory_regions()?),
+        _ => return Err("Unknown request"),
+    })
+}
+
+// Serves one front end until it goes away or detaches
+fn serve_connection(target: &mut Option<Box<dyn DebugTarget>>, connection: &mut RemoteConnection, attach: &dyn Fn(u32) -> Result<Box<dyn DebugTarget>, &'static str>) -> Result<(), &'static str> {
+    connection.send_line(PROTOCOL_HEADER)?;
+    while let Some(line) = connection.read_line()? {
+        let reply = handle_request(target, &line, attach);
+        match &reply {
+            Ok(result) => connection.send_line(format!("ok {}", result).trim_end())?,
+            Err(message) => connection.send_line(&format!("error {}", encode_hex(message.as_bytes())))?,
+        }
+
+        // Once the target is running on its own there's nothing more to do for it, but the front end might want to
+        // attach to something else
+        if line.starts_with("detach ") && reply.is_ok() {
+            *target = None;
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+// Runs the target on behalf of front ends that connect to address. The server keeps going for as long as there's a
+// reason for someone to connect again, which is after a detach or while serving something that isn't running.
+pub fn run_server(target: Box<dyn DebugTarget>, address: &str, attach: &dyn Fn(u32) -> Result<Box<dyn DebugTarget>, &'static str>) -> Result<(), &'static str> {
+    let listener = TcpListener::bind(address).map_err(|_| "Could not listen for connections")?;
+    let mut target = Some(target);
+    loop {
+        println!("Waiting for a dbgrs connection on {}", address);
+        let (stream, peer) = listener.accept().map_err(|_| "Could not accept connection")?;
+        println!("Front end connected from {}", peer);
+        let result = RemoteConnection::new(stream).and_then(|mut connection| serve_connection(&mut target, &mut connection, attach));
+        match result {
+            Ok(()) => println!("Front end disconnected"),
+            Err(msg) => println!("Front end disconnected: {}", msg),
+        }
+
+        if target.as_ref().is_some_and(|t| t.is_live()) {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::JoinHandle;
+
+    use windows_sys::Win32::Foundation::{EXCEPTION_BREAKPOINT, EXCEPTION_SINGLE_STEP};
+
+    use super::*;
+    use crate::fake_target::{FakeTarget, PROCESS_ID, THREAD_ID};
+
+    const ENTRY: u64 = 0x1000;
+    const DATA: u64 = 0x2000;
+
+    fn fake_target(entry_point: u64) -> Box<dyn DebugTarget> {
+        let mut target = FakeTarget::new(entry_point, entry_point + 0x10);
+        target.add_memory(entry_point, &[0x90; 0x10]);
+        target.add_memory(DATA, &[1, 2, 3, 4]);
+        Box::new(target)
+    }
+
+    // Serves one front end on another thread. Targets can't be sent between threads, so the fake target is made on
+    // the server's thread, and attaching makes one with its entry point at the process ID times 0x1000.
+    fn start_server(with_target: bool) -> (String, JoinHandle<Result<(), &'static str>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut target = with_target.then(|| fake_target(ENTRY));
+            let mut connection = RemoteConnection::new(stream)?;
+            serve_connection(&mut target, &mut connection, &|process_id| Ok(fake_target(process_id as u64 * 0x1000)))
+        });
+        (address, server)
+    }
+
+    #[test]
+    fn remote_target_drives_server_target() {
+        let (address, server) = start_server(true);
+        let mut target = RemoteTarget::connect(&address, None).unwrap();
+        assert!(target.is_live());
+
+        let (ctx, event) = target.wait_for_event().unwrap();
+        assert_eq!((ctx.process_id, ctx.thread_id), (PROCESS_ID, THREAD_ID));
+        assert!(matches!(event, DebugEvent::CreateProcess { exe_name: Some(name), exe_base: ENTRY } if name == "fake"));
+
+        // Bytes past the end of the data can't be read, and the raw read stops there
+        assert_eq!(target.read_memory(DATA, 6).unwrap(), vec![Some(1), Some(2), Some(3), Some(4), None, None]);
+        assert_eq!(target.read_raw_memory(DATA, 6), vec![1, 2, 3, 4]);
+        assert_eq!(target.write_memory(DATA + 2, &[0xaa, 0xbb, 0xcc]).unwrap(), 2);
+        assert_eq!(target.read_raw_memory(DATA, 4), vec![1, 2, 0xaa, 0xbb]);
+        let regions: Vec<(u64, u64)> = target.memory_regions().unwrap().iter().map(|r| (r.address, r.size)).collect();
+        assert_eq!(regions, vec![(ENTRY, 0x10), (DATA, 4)]);
+
+        let mut context = target.get_thread_context(THREAD_ID).unwrap();
+        assert_eq!(context.rip, ENTRY);
+        context.rax = 0x1234;
+        context.fx_save[0] = 0x56;
+        target.set_thread_context(THREAD_ID, &context).unwrap();
+        let context = target.get_thread_context(THREAD_ID).unwrap();
+        assert_eq!((context.rax, context.fx_save[0]), (0x1234, 0x56));
+
+        // The server's error messages come back as they are
+        assert_eq!(target.get_thread_context(THREAD_ID + 1).err(), Some("No such thread"));
+
+        target.continue_event(&ctx, ContinueStatus::Handled).unwrap();
+        let (ctx, event) = target.wait_for_event().unwrap();
+        assert!(matches!(event, DebugEvent::Exception { first_chance: true, exception_code: EXCEPTION_BREAKPOINT }));
+
+        target.step_thread(THREAD_ID).unwrap();
+        target.continue_event(&ctx, ContinueStatus::NotHandled).unwrap();
+        let (ctx, event) = target.wait_for_event().unwrap();
+        assert!(matches!(event, DebugEvent::Exception { exception_code: EXCEPTION_SINGLE_STEP, .. }));
+        assert_eq!(target.get_thread_context(THREAD_ID).unwrap().rip, ENTRY + 1);
+
+        target.continue_event(&ctx, ContinueStatus::Handled).unwrap();
+        assert!(matches!(target.wait_for_event().unwrap().1, DebugEvent::ExitProcess));
+
+        // The server is done once the front end goes away
+        drop(target);
+        assert_eq!(server.join().unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn remote_target_attaches_on_server() {
+        let (address, server) = start_server(false);
+        let mut target = RemoteTarget::connect(&address, Some(5)).unwrap();
+        assert!(matches!(target.wait_for_event().unwrap().1, DebugEvent::CreateProcess { exe_base: 0x5000, .. }));
+        drop(target);
+        assert_eq!(server.join().unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn server_without_target_reports_error() {
+        let (address, server) = start_server(false);
+        assert_eq!(RemoteTarget::connect(&address, None).err(), Some("The server isn't debugging anything"));
+        assert_eq!(server.join().unwrap(), Ok(()));
+    }
+}
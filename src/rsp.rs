@@ -0,0 +1,301 @@
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+use crate::registers::RegisterContext;
+
+// The pieces of the GDB Remote Serial Protocol that are shared by the client (RspTarget) and the stub server.
+// See https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+
+// The size of the register block in a 'g' packet for the default x86-64 target description: 17 general purpose
+// registers, eflags and 6 segment registers, 8 x87 registers, 8 x87 control registers, 16 xmm registers and mxcsr.
+// Stubs may send more than this (orig_rax, fs_base, ymm, etc.), which we carry around without interpreting.
+pub const AMD64_REGISTER_BLOCK_SIZE: usize = 17 * 8 + 7 * 4 + 8 * 10 + 8 * 4 + 16 * 16 + 4;
+
+// The offset of the x87 registers, which are the first thing in the block that isn't a general purpose register
+const X87_OFFSET: usize = 17 * 8 + 7 * 4;
+const X87_CONTROL_OFFSET: usize = X87_OFFSET + 8 * 10;
+const XMM_OFFSET: usize = X87_CONTROL_OFFSET + 8 * 4;
+const MXCSR_OFFSET: usize = XMM_OFFSET + 16 * 16;
+
+// Offsets within the FXSAVE layout used by RegisterContext::fx_save
+const FX_FCW: usize = 0;
+const FX_FSW: usize = 2;
+const FX_FTW: usize = 4;
+const FX_FOP: usize = 6;
+const FX_FIP: usize = 8;
+const FX_FCS: usize = 12;
+const FX_FDP: usize = 16;
+const FX_FDS: usize = 20;
+const FX_MXCSR: usize = 24;
+const FX_ST: usize = 32;
+const FX_XMM: usize = 160;
+
+// GDB's own signal numbers, which are what stop replies use regardless of the host OS
+pub const GDB_SIGINT: u8 = 2;
+pub const GDB_SIGILL: u8 = 4;
+pub const GDB_SIGTRAP: u8 = 5;
+pub const GDB_SIGFPE: u8 = 8;
+pub const GDB_SIGBUS: u8 = 10;
+pub const GDB_SIGSEGV: u8 = 11;
+
+pub struct RspConnection {
+    stream: TcpStream,
+    // Once QStartNoAckMode has been agreed on, neither side sends '+' or '-' any more
+    pub no_ack: bool,
+    buffer: VecDeque<u8>,
+}
+
+impl RspConnection {
+    pub fn new(stream: TcpStream) -> RspConnection {
+        // Packets are small and we always wait for a reply, so Nagle only slows us down
+        let _ = stream.set_nodelay(true);
+        RspConnection { stream, no_ack: false, buffer: VecDeque::new() }
+    }
+
+    pub fn send_packet(&mut self, data: &[u8]) -> Result<(), &'static str> {
+        let mut packet = Vec::with_capacity(data.len() + 4);
+        packet.push(b'$');
+        for &byte in data {
+            // These characters would be mistaken for framing, so they're escaped in packet data
+            if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+                packet.push(b'}');
+                packet.push(byte ^ 0x20);
+            } else {
+                packet.push(byte);
+            }
+        }
+        let checksum = packet[1..].iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+        packet.extend_from_slice(format!("#{:02x}", checksum).as_bytes());
+
+        loop {
+            self.stream.write_all(&packet).map_err(|_| "Failed to send packet")?;
+            if self.no_ack {
+                return Ok(());
+            }
+            match self.read_byte()? {
+                b'+' => return Ok(()),
+                // The other side wants the packet again
+                b'-' => continue,
+                // Anything else is probably the start of a packet, so keep it for the next read
+                other => {
+                    self.buffer.push_front(other);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    pub fn send_str(&mut self, data: &str) -> Result<(), &'static str> {
+        self.send_packet(data.as_bytes())
+    }
+
+    // Sends a packet and waits for the reply, which is how almost everything in the protocol works
+    pub fn request(&mut self, data: &str) -> Result<Vec<u8>, &'static str> {
+        self.send_str(data)?;
+        self.read_packet()
+    }
+
+    // Reads the next packet, without the framing, escapes, or run length encoding. A ^C interrupt from the other side
+    // comes back as a packet containing just 0x03.
+    pub fn read_packet(&mut self) -> Result<Vec<u8>, &'static str> {
+        loop {
+            let start = self.read_byte()?;
+            if start == 0x03 {
+                return Ok(vec![0x03]);
+            }
+            if start != b'$' {
+                // Stray acks, or garbage between packets
+                continue;
+            }
+
+            let mut raw = Vec::new();
+            loop {
+                let byte = self.read_byte()?;
+                if byte == b'#' {
+                    break;
+                }
+                raw.push(byte);
+            }
+            let checksum_text = [self.read_byte()?, self.read_byte()?];
+            let expected = std::str::from_utf8(&checksum_text).ok().and_then(|s| u8::from_str_radix(s, 16).ok());
+            let actual = raw.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+
+            if !self.no_ack {
+                if expected != Some(actual) {
+                    self.stream.write_all(b"-").map_err(|_| "Failed to send nack")?;
+                    continue;
+                }
+                self.stream.write_all(b"+").map_err(|_| "Failed to send ack")?;
+            }
+
+            return Ok(decode_packet_data(&raw));
+        }
+    }
+
+    fn read_byte(&mut self) -> Result<u8, &'static str> {
+        if self.buffer.is_empty() {
+            let mut chunk = [0u8; 4096];
+            let count = self.stream.read(&mut chunk).map_err(|_| "Failed to read from connection")?;
+            if count == 0 {
+                return Err("Connection closed");
+            }
+            self.buffer.extend(&chunk[..count]);
+        }
+        Ok(self.buffer.pop_front().unwrap())
+    }
+}
+
+fn decode_packet_data(raw: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(raw.len());
+    let mut index = 0;
+    while index < raw.len() {
+        match raw[index] {
+            b'}' if index + 1 < raw.len() => {
+                data.push(raw[index + 1] ^ 0x20);
+                index += 2;
+            }
+            // Run length encoding: "X*N" is X followed by N - 29 more copies of X
+            b'*' if index + 1 < raw.len() && !data.is_empty() => {
+                let repeat = raw[index + 1].saturating_sub(29) as usize;
+                let last = *data.last().unwrap();
+                data.extend(std::iter::repeat_n(last, repeat));
+                index += 2;
+            }
+            byte => {
+                data.push(byte);
+                index += 1;
+            }
+        }
+    }
+    data
+}
+
+pub fn encode_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Decodes hex bytes. Stubs send "xx" for register bytes that aren't available, which we treat as zero.
+pub fn decode_hex(text: &[u8]) -> Result<Vec<u8>, &'static str> {
+    text.chunks(2).map(|pair| {
+        if pair == b"xx" {
+            return Ok(0);
+        }
+        std::str::from_utf8(pair).ok().and_then(|s| u8::from_str_radix(s, 16).ok()).ok_or("Invalid hex data")
+    }).collect()
+}
+
+pub fn parse_hex_u64(text: &[u8]) -> Result<u64, &'static str> {
+    std::str::from_utf8(text).ok().and_then(|s| u64::from_str_radix(s, 16).ok()).ok_or("Invalid hex number")
+}
+
+// Register numbers in the default x86-64 register layout, which is what p/P packets use
+pub fn register_offset(register_number: usize) -> Option<(usize, usize)> {
+    match register_number {
+        0..=16 => Some((register_number * 8, 8)),
+        17..=23 => Some((17 * 8 + (register_number - 17) * 4, 4)),
+        24..=31 => Some((X87_OFFSET + (register_number - 24) * 10, 10)),
+        32..=39 => Some((X87_CONTROL_OFFSET + (register_number - 32) * 4, 4)),
+        40..=55 => Some((XMM_OFFSET + (register_number - 40) * 16, 16)),
+        56 => Some((MXCSR_OFFSET, 4)),
+        _ => None,
+    }
+}
+
+fn read_u64(block: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(block[offset..offset + 8].try_into().unwrap())
+}
+
+fn read_u32(block: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(block[offset..offset + 4].try_into().unwrap())
+}
+
+// Fills in a register context from a register block. Debug registers aren't part of the block, so they're left alone.
+pub fn registers_from_block(block: &[u8], context: &mut RegisterContext) -> Result<(), &'static str> {
+    if block.len() < AMD64_REGISTER_BLOCK_SIZE {
+        return Err("Register block is too small");
+    }
+
+    let general = [
+        &mut context.rax, &mut context.rbx, &mut context.rcx, &mut context.rdx,
+        &mut context.rsi, &mut context.rdi, &mut context.rbp, &mut context.rsp,
+        &mut context.r8, &mut context.r9, &mut context.r10, &mut context.r11,
+        &mut context.r12, &mut context.r13, &mut context.r14, &mut context.r15,
+        &mut context.rip,
+    ];
+    for (index, register) in general.into_iter().enumerate() {
+        *register = read_u64(block, index * 8);
+    }
+
+    context.eflags = read_u32(block, 17 * 8);
+    let segments = [&mut context.cs, &mut context.ss, &mut context.ds, &mut context.es, &mut context.fs, &mut context.gs];
+    for (index, register) in segments.into_iter().enumerate() {
+        *register = read_u32(block, 17 * 8 + (index + 1) * 4) as u16;
+    }
+
+    // The x87 state is sent as full 32-bit fields and a full tag word, but FXSAVE packs them
+    let fx = &mut context.fx_save;
+    let control = |index: usize| read_u32(block, X87_CONTROL_OFFSET + index * 4);
+    fx[FX_FCW..FX_FCW + 2].copy_from_slice(&(control(0) as u16).to_le_bytes());
+    fx[FX_FSW..FX_FSW + 2].copy_from_slice(&(control(1) as u16).to_le_bytes());
+    let full_tag = control(2);
+    // In the abridged tag, a set bit means the register is in use, which is any full tag value other than 3 (empty)
+    fx[FX_FTW] = (0..8).fold(0u8, |tag, i| if (full_tag >> (i * 2)) & 3 != 3 { tag | (1 << i) } else { tag });
+    fx[FX_FCS..FX_FCS + 2].copy_from_slice(&(control(3) as u16).to_le_bytes());
+    fx[FX_FIP..FX_FIP + 4].copy_from_slice(&control(4).to_le_bytes());
+    fx[FX_FDS..FX_FDS + 2].copy_from_slice(&(control(5) as u16).to_le_bytes());
+    fx[FX_FDP..FX_FDP + 4].copy_from_slice(&control(6).to_le_bytes());
+    fx[FX_FOP..FX_FOP + 2].copy_from_slice(&(control(7) as u16).to_le_bytes());
+    for i in 0..8 {
+        let src = X87_OFFSET + i * 10;
+        fx[FX_ST + i * 16..FX_ST + i * 16 + 10].copy_from_slice(&block[src..src + 10]);
+    }
+    fx[FX_XMM..FX_XMM + 256].copy_from_slice(&block[XMM_OFFSET..XMM_OFFSET + 256]);
+    fx[FX_MXCSR..FX_MXCSR + 4].copy_from_slice(&block[MXCSR_OFFSET..MXCSR_OFFSET + 4]);
+
+    Ok(())
+}
+
+// Writes a register context into a register block, leaving anything past the default layout as it was
+pub fn registers_to_block(context: &RegisterContext, block: &mut Vec<u8>) {
+    if block.len() < AMD64_REGISTER_BLOCK_SIZE {
+        block.resize(AMD64_REGISTER_BLOCK_SIZE, 0);
+    }
+
+    let general = [
+        context.rax, context.rbx, context.rcx, context.rdx, context.rsi, context.rdi, context.rbp, context.rsp,
+        context.r8, context.r9, context.r10, context.r11, context.r12, context.r13, context.r14, context.r15,
+        context.rip,
+    ];
+    for (index, value) in general.iter().enumerate() {
+        block[index * 8..index * 8 + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    let small = [
+        context.eflags, context.cs as u32, context.ss as u32, context.ds as u32,
+        context.es as u32, context.fs as u32, context.gs as u32,
+    ];
+    for (index, value) in small.iter().enumerate() {
+        block[17 * 8 + index * 4..17 * 8 + index * 4 + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    let fx = &context.fx_save;
+    let read_u16 = |offset: usize| u16::from_le_bytes(fx[offset..offset + 2].try_into().unwrap()) as u32;
+    // We can't tell the difference between valid, zero and special registers from the abridged tag, so anything in use
+    // is reported as valid (0) and everything else as empty (3)
+    let full_tag = (0..8).fold(0u32, |tag, i| if fx[FX_FTW] & (1 << i) == 0 { tag | (3 << (i * 2)) } else { tag });
+    let control = [
+        read_u16(FX_FCW), read_u16(FX_FSW), full_tag, read_u16(FX_FCS),
+        read_u32(fx, FX_FIP), read_u16(FX_FDS), read_u32(fx, FX_FDP), read_u16(FX_FOP),
+    ];
+    for (index, value) in control.iter().enumerate() {
+        let offset = X87_CONTROL_OFFSET + index * 4;
+        block[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+    for i in 0..8 {
+        let dst = X87_OFFSET + i * 10;
+        block[dst..dst + 10].copy_from_slice(&fx[FX_ST + i * 16..FX_ST + i * 16 + 10]);
+    }
+    block[XMM_OFFSET..XMM_OFFSET + 256].copy_from_slice(&fx[FX_XMM..FX_XMM + 256]);
+    block[MXCSR_OFFSET..MXCSR_OFFSET + 4].copy_from_slice(&fx[FX_MXCSR..FX_MXCSR + 4]);
+}
@@ -0,0 +1,761 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::TcpStream;
+
+use regex::Regex;
+use windows_sys::Win32::Foundation::{DBG_CONTROL_C, EXCEPTION_ACCESS_VIOLATION, EXCEPTION_BREAKPOINT, EXCEPTION_ILLEGAL_INSTRUCTION, EXCEPTION_INT_DIVIDE_BY_ZERO, EXCEPTION_SINGLE_STEP};
+
+use crate::event::{DebugEvent, EventContext};
+use crate::memory::MemorySource;
+use crate::registers::RegisterContext;
+use crate::rsp::{self, RspConnection};
+use crate::target::{ContinueStatus, DebugTarget};
+
+const AT_PHDR: u64 = 3;
+const PAGE_SIZE: u64 = 0x1000;
+// The number of registers in the default x86-64 layout, for stubs that only support p/P
+const REGISTER_COUNT: usize = 57;
+// The packet sizes below this leave no room for data once the command is counted, so a stub that asks for less than
+// this gets it anyway. Every stub we know of can take far bigger packets than this.
+const MIN_PACKET_SIZE: usize = 256;
+
+// The types of breakpoint that the Z and z packets take
+#[derive(Clone, Copy, PartialEq)]
+enum BreakpointType {
+    Software = 0,
+    Hardware = 1,
+    WriteWatch = 2,
+    // Debug registers can't express a read-only watchpoint, so nothing asks for this yet
+    #[allow(dead_code)]
+    ReadWatch = 3,
+    AccessWatch = 4,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct RemoteBreakpoint {
+    // Which debug register this came from, so we can report the hit in DR6
+    index: usize,
+    bp_type: BreakpointType,
+    address: u64,
+    kind: u64,
+}
+
+// A target on the other end of a GDB Remote Serial Protocol connection, like gdbserver or the QEMU gdbstub.
+//
+// The debugger engine sets breakpoints through the debug registers in the thread context, which the remote side doesn't
+// have. Instead, we keep track of the debug registers ourselves and turn them into Z/z packets, and then fill in DR6
+// when one of those breakpoints is hit.
+pub struct RspTarget {
+    connection: RefCell<RspConnection>,
+    packet_size: usize,
+    supports_vcont: bool,
+    supports_libraries: bool,
+    // Cleared when the stub turns out not to support Z1, after which we fall back to software breakpoints
+    supports_hardware_breakpoints: bool,
+    process_id: u32,
+    threads: Vec<u32>,
+    // The thread that g/G/p/P packets currently apply to, so we don't need to send Hg for every access
+    selected_thread: Cell<Option<u32>>,
+    known_module_bases: HashSet<u64>,
+    queued_events: VecDeque<(EventContext, DebugEvent)>,
+    // Console output arrives while the target is still running, and we can't talk to the target until it stops. So we
+    // hold on to the output and report it along with the next stop.
+    pending_output: Vec<String>,
+    // The signal for the current stop, which is passed back to the target if the exception isn't handled
+    event_signal: Option<(u32, u8)>,
+    step_threads: HashSet<u32>,
+    // DR0-DR3 and DR7. These are the same for every thread, since Z packets aren't per thread.
+    debug_registers: [u64; 5],
+    dr6: HashMap<u32, u64>,
+    breakpoints: Vec<RemoteBreakpoint>,
+    exited: bool,
+}
+
+fn parse_thread_id(text: &[u8]) -> Option<u32> {
+    // With the multiprocess extensions, thread IDs look like "p<pid>.<tid>"
+    let text = match text.iter().position(|&c| c == b'.') {
+        Some(pos) => &text[pos + 1..],
+        None => text,
+    };
+    rsp::parse_hex_u64(text).ok().map(|tid| tid as u32)
+}
+
+fn signal_to_exception(signal: u8) -> Option<i32> {
+    match signal {
+        rsp::GDB_SIGTRAP => Some(EXCEPTION_BREAKPOINT),
+        rsp::GDB_SIGINT => Some(DBG_CONTROL_C),
+        rsp::GDB_SIGSEGV | rsp::GDB_SIGBUS => Some(EXCEPTION_ACCESS_VIOLATION),
+        rsp::GDB_SIGILL => Some(EXCEPTION_ILLEGAL_INSTRUCTION),
+        rsp::GDB_SIGFPE => Some(EXCEPTION_INT_DIVIDE_BY_ZERO),
+        _ => None,
+    }
+}
+
+// Turns DR7 into the list of breakpoints it describes
+fn breakpoints_from_debug_registers(debug_registers: &[u64; 5]) -> Result<Vec<RemoteBreakpoint>, &'static str> {
+    let dr7 = debug_registers[4];
+    let mut breakpoints = Vec::new();
+    for (index, address) in debug_registers.iter().take(4).enumerate() {
+        if (dr7 >> (index * 2)) & 3 == 0 {
+            continue;
+        }
+        let rw = (dr7 >> (16 + index * 4)) & 3;
+        let len = match (dr7 >> (18 + index * 4)) & 3 {
+            0 => 1,
+            1 => 2,
+            2 => 8,
+            _ => 4,
+        };
+        let (bp_type, kind) = match rw {
+            // The kind for an execution breakpoint is the size of the breakpoint instruction, which is 1 on x86
+            0 => (BreakpointType::Hardware, 1),
+            1 => (BreakpointType::WriteWatch, len),
+            3 => (BreakpointType::AccessWatch, len),
+            _ => return Err("I/O breakpoints are not supported by remote targets"),
+        };
+        breakpoints.push(RemoteBreakpoint { index, bp_type, address: *address, kind });
+    }
+    Ok(breakpoints)
+}
+
+impl RspTarget {
+    pub fn connect(address: &str) -> Result<RspTarget, &'static str> {
+        let stream = TcpStream::connect(address).map_err(|_| "Could not connect to remote target")?;
+        let mut connection = RspConnection::new(stream);
+
+        let supported = connection.request("qSupported:swbreak+;hwbreak+;vContSupported+")?;
+        let supported = String::from_utf8_lossy(&supported).to_string();
+        let features: Vec<&str> = supported.split(';').collect();
+        let packet_size = features.iter()
+            .find_map(|f| f.strip_prefix("PacketSize="))
+            .and_then(|size| usize::from_str_radix(size, 16).ok())
+            .unwrap_or(0x400)
+            .max(MIN_PACKET_SIZE);
+
+        if features.contains(&"QStartNoAckMode+") && connection.request("QStartNoAckMode")? == b"OK" {
+            connection.no_ack = true;
+        }
+
+        let vcont = connection.request("vCont?")?;
+        let supports_vcont = vcont.starts_with(b"vCont") && vcont.windows(2).any(|w| w == b";s") && vcont.windows(2).any(|w| w == b";c");
+
+        let mut target = RspTarget {
+            connection: RefCell::new(connection),
+            packet_size,
+            supports_vcont,
+            supports_libraries: features.contains(&"qXfer:libraries-svr4:read+"),
+            supports_hardware_breakpoints: true,
+            process_id: 0,
+            threads: Vec::new(),
+            selected_thread: Cell::new(None),
+            known_module_bases: HashSet::new(),
+            queued_events: VecDeque::new(),
+            pending_output: Vec::new(),
+            event_signal: None,
+            step_threads: HashSet::new(),
+            debug_registers: [0; 5],
+            dr6: HashMap::new(),
+            breakpoints: Vec::new(),
+            exited: false,
+        };
+
+        // The reply to '?' is the reason the target is currently stopped, which we report like an initial breakpoint
+        let stop_reply = target.connection.get_mut().request("?")?;
+        if stop_reply.first() != Some(&b'T') && stop_reply.first() != Some(&b'S') {
+            return Err("Remote target is not stopped");
+        }
+        let stop_thread = match target.stop_reply_thread(&stop_reply) {
+            Some(thread_id) => thread_id,
+            None => target.current_thread()?,
+        };
+        target.process_id = stop_thread;
+
+        let ctx = EventContext { process_id: target.process_id, thread_id: stop_thread };
+        let exe_name = if features.contains(&"qXfer:exec-file:read+") {
+            target.read_xfer("exec-file", "").ok().map(|name| String::from_utf8_lossy(&name).to_string())
+        } else {
+            None
+        };
+        let exe_base = if features.contains(&"qXfer:auxv:read+") { target.find_exe_base() } else { None };
+        if let Some(exe_base) = exe_base {
+            target.known_module_bases.insert(exe_base);
+        }
+        target.queued_events.push_back((ctx, DebugEvent::CreateProcess { exe_name, exe_base: exe_base.unwrap_or(0) }));
+        target.threads.push(stop_thread);
+
+        target.queue_thread_changes(stop_thread)?;
+        target.queue_new_modules(stop_thread);
+        target.queued_events.push_back((ctx, DebugEvent::Exception { first_chance: true, exception_code: EXCEPTION_BREAKPOINT }));
+
+        Ok(target)
+    }
+
+    fn current_thread(&self) -> Result<u32, &'static str> {
+        let reply = self.connection.borrow_mut().request("qC")?;
+        match reply.strip_prefix(b"QC") {
+            Some(thread_id) => parse_thread_id(thread_id).ok_or("Invalid thread ID"),
+            None => self.threads.first().copied().ok_or("Could not determine the current thread"),
+        }
+    }
+
+    fn stop_reply_thread(&self, reply: &[u8]) -> Option<u32> {
+        reply.get(3..)?.split(|&c| c == b';').find_map(|field| field.strip_prefix(b"thread:")).and_then(parse_thread_id)
+    }
+
+    fn read_xfer(&self, object: &str, annex: &str) -> Result<Vec<u8>, &'static str> {
+        let mut data = Vec::new();
+        let chunk_size = self.packet_size - 16;
+        loop {
+            let request = format!("qXfer:{}:read:{}:{:x},{:x}", object, annex, data.len(), chunk_size);
+            let reply = self.connection.borrow_mut().request(&request)?;
+            match reply.split_first() {
+                Some((b'm', chunk)) => data.extend_from_slice(chunk),
+                Some((b'l', chunk)) => {
+                    data.extend_from_slice(chunk);
+                    return Ok(data);
+                }
+                _ => return Err("qXfer read failed"),
+            }
+        }
+    }
+
+    // The auxiliary vector tells us where the program headers of the executable are, which are right after the ELF
+    // header at the start of the image.
+    fn find_exe_base(&self) -> Option<u64> {
+        let auxv = self.read_xfer("auxv", "").ok()?;
+        let phdr = auxv.chunks_exact(16).find_map(|entry| {
+            let key = u64::from_le_bytes(entry[0..8].try_into().unwrap());
+            let value = u64::from_le_bytes(entry[8..16].try_into().unwrap());
+            (key == AT_PHDR).then_some(value)
+        })?;
+        let base = phdr & !(PAGE_SIZE - 1);
+        (self.read_raw_memory(base, 4) == b"\x7fELF").then_some(base)
+    }
+
+    fn read_thread_list(&self) -> Result<Vec<u32>, &'static str> {
+        let mut threads = Vec::new();
+        let mut reply = self.connection.borrow_mut().request("qfThreadInfo")?;
+        loop {
+            match reply.split_first() {
+                Some((b'm', list)) => threads.extend(list.split(|&c| c == b',').filter_map(parse_thread_id)),
+                Some((b'l', _)) => return Ok(threads),
+                // The stub doesn't support listing threads, so all we know about is the threads that have stopped
+                _ => return Ok(self.threads.clone()),
+            }
+            reply = self.connection.borrow_mut().request("qsThreadInfo")?;
+        }
+    }
+
+    // There aren't events for thread creation and exit by default, so we compare the thread list each time the target
+    // stops.
+    fn queue_thread_changes(&mut self, event_thread: u32) -> Result<(), &'static str> {
+        let threads = self.read_thread_list()?;
+        for thread_id in threads.iter().filter(|t| !self.threads.contains(t)) {
+            let ctx = EventContext { process_id: self.process_id, thread_id: *thread_id };
+            self.queued_events.push_back((ctx, DebugEvent::CreateThread { thread_id: *thread_id }));
+        }
+        for thread_id in self.threads.iter().filter(|t| !threads.contains(t)) {
+            let ctx = EventContext { process_id: self.process_id, thread_id: event_thread };
+            self.queued_events.push_back((ctx, DebugEvent::ExitThread { thread_id: *thread_id }));
+        }
+        self.threads = threads;
+        Ok(())
+    }
+
+    // The SVR4 library list is how gdbserver reports the shared objects that the dynamic loader has loaded
+    fn queue_new_modules(&mut self, thread_id: u32) {
+        if !self.supports_libraries {
+            return;
+        }
+        let libraries = match self.read_xfer("libraries-svr4", "") {
+            Ok(libraries) => String::from_utf8_lossy(&libraries).to_string(),
+            Err(_) => return,
+        };
+
+        let library_re = Regex::new(r"<library\s[^>]*>").unwrap();
+        let name_re = Regex::new(r#"name="([^"]*)""#).unwrap();
+        let base_re = Regex::new(r#"l_addr="0x([0-9a-fA-F]+)""#).unwrap();
+        for library in library_re.find_iter(&libraries) {
+            let name = name_re.captures(library.as_str()).map(|c| c[1].to_string()).filter(|n| !n.is_empty());
+            let base = base_re.captures(library.as_str()).and_then(|c| u64::from_str_radix(&c[1], 16).ok());
+            let (name, base) = match (name, base) {
+                (Some(name), Some(base)) => (name, base),
+                _ => continue,
+            };
+
+            // l_addr is the load bias, which is only the base address for modules that were linked at 0. That's almost
+            // every shared object, but check for an ELF header to be sure.
+            if self.known_module_bases.contains(&base) || self.read_raw_memory(base, 4) != b"\x7fELF" {
+                continue;
+            }
+
+            self.known_module_bases.insert(base);
+            let ctx = EventContext { process_id: self.process_id, thread_id };
+            self.queued_events.push_back((ctx, DebugEvent::LoadModule { module_name: Some(name), module_base: base }));
+        }
+    }
+
+    fn queue_output(&mut self, ctx: EventContext) {
+        for output in self.pending_output.drain(..) {
+            self.queued_events.push_back((ctx, DebugEvent::OutputDebugString(output)));
+        }
+    }
+
+    fn select_thread(&self, thread_id: u32) -> Result<(), &'static str> {
+        if self.exited {
+            return Err("Process has exited");
+        }
+        if self.selected_thread.get() == Some(thread_id) {
+            return Ok(());
+        }
+        let reply = self.connection.borrow_mut().request(&format!("Hg{:x}", thread_id))?;
+        if reply != b"OK" {
+            return Err("Could not select thread");
+        }
+        self.selected_thread.set(Some(thread_id));
+        Ok(())
+    }
+
+    fn read_register_block(&self, thread_id: u32) -> Result<Vec<u8>, &'static str> {
+        self.select_thread(thread_id)?;
+        let reply = self.connection.borrow_mut().request("g")?;
+        if !reply.is_empty() && reply[0] != b'E' {
+            return rsp::decode_hex(&reply);
+        }
+
+        // Some stubs only support reading registers one at a time
+        let mut block = vec![0u8; rsp::AMD64_REGISTER_BLOCK_SIZE];
+        for register_number in 0..REGISTER_COUNT {
+            let (offset, size) = rsp::register_offset(register_number).unwrap();
+            let reply = self.connection.borrow_mut().request(&format!("p{:x}", register_number))?;
+            let value = rsp::decode_hex(&reply)?;
+            if value.len() != size {
+                return Err("Could not read registers");
+            }
+            block[offset..offset + size].copy_from_slice(&value);
+        }
+        Ok(block)
+    }
+
+    fn write_register_block(&self, thread_id: u32, block: &[u8]) -> Result<(), &'static str> {
+        self.select_thread(thread_id)?;
+        let reply = self.connection.borrow_mut().request(&format!("G{}", rsp::encode_hex(block)))?;
+        if reply == b"OK" {
+            return Ok(());
+        }
+
+        for register_number in 0..REGISTER_COUNT {
+            let (offset, size) = rsp::register_offset(register_number).unwrap();
+            let request = format!("P{:x}={}", register_number, rsp::encode_hex(&block[offset..offset + size]));
+            if self.connection.borrow_mut().request(&request)? != b"OK" {
+                return Err("Could not write registers");
+            }
+        }
+        Ok(())
+    }
+
+    fn send_breakpoint(&mut self, insert: bool, breakpoint: &RemoteBreakpoint) -> Result<Vec<u8>, &'static str> {
+        let packet = format!("{}{},{:x},{:x}", if insert { 'Z' } else { 'z' }, breakpoint.bp_type as u8, breakpoint.address, breakpoint.kind);
+        self.connection.get_mut().request(&packet)
+    }
+
+    // Brings the breakpoints on the remote side in line with the debug registers
+    fn sync_breakpoints(&mut self) -> Result<(), &'static str> {
+        let mut wanted = breakpoints_from_debug_registers(&self.debug_registers)?;
+        if !self.supports_hardware_breakpoints {
+            for breakpoint in wanted.iter_mut().filter(|b| b.bp_type == BreakpointType::Hardware) {
+                breakpoint.bp_type = BreakpointType::Software;
+            }
+        }
+
+        for breakpoint in self.breakpoints.clone().iter().filter(|b| !wanted.contains(b)) {
+            self.send_breakpoint(false, breakpoint)?;
+            self.breakpoints.retain(|b| b != breakpoint);
+        }
+
+        for mut breakpoint in wanted {
+            if self.breakpoints.contains(&breakpoint) {
+                continue;
+            }
+            let mut reply = self.send_breakpoint(true, &breakpoint)?;
+            if reply.is_empty() && breakpoint.bp_type == BreakpointType::Hardware {
+                // An empty reply means the packet isn't supported, so use a software breakpoint instead
+                self.supports_hardware_breakpoints = false;
+                breakpoint.bp_type = BreakpointType::Software;
+                reply = self.send_breakpoint(true, &breakpoint)?;
+            }
+            if reply != b"OK" {
+                return Err("Remote target could not set breakpoint");
+            }
+            self.breakpoints.push(breakpoint);
+        }
+        Ok(())
+    }
+
+    // Works out which of our breakpoints caused a stop, and returns the exception code to report for it
+    fn translate_trap(&mut self, thread_id: u32, reply: &[u8]) -> Result<i32, &'static str> {
+        let stepped = self.step_threads.contains(&thread_id);
+
+        let mut watch_address = None;
+        for field in reply.get(3..).unwrap_or_default().split(|&c| c == b';') {
+            if let Some((key, value)) = field.iter().position(|&c| c == b':').map(|pos| (&field[..pos], &field[pos + 1..])) {
+                if matches!(key, b"watch" | b"rwatch" | b"awatch") {
+                    watch_address = rsp::parse_hex_u64(value).ok();
+                }
+            }
+        }
+
+        let hit = match watch_address {
+            Some(address) => self.breakpoints.iter().find(|b| b.address <= address && address < b.address + b.kind && b.bp_type != BreakpointType::Hardware && b.bp_type != BreakpointType::Software),
+            None => {
+                let rip = self.get_thread_context(thread_id)?.rip;
+                self.breakpoints.iter().find(|b| b.address == rip && matches!(b.bp_type, BreakpointType::Hardware | BreakpointType::Software))
+            }
+        };
+
+        if let Some(breakpoint) = hit {
+            self.dr6.insert(thread_id, 1 << breakpoint.index);
+            // Hardware breakpoints are reported as single step exceptions on Windows, so we do the same
+            return Ok(EXCEPTION_SINGLE_STEP);
+        }
+
+        Ok(if stepped { EXCEPTION_SINGLE_STEP } else { EXCEPTION_BREAKPOINT })
+    }
+
+    fn resume(&mut self, event_thread: u32, signal: u8) -> Result<(), &'static str> {
+        let signal_action = |action: char| if signal != 0 { format!("{}{:02x}", action.to_ascii_uppercase(), signal) } else { action.to_string() };
+
+        if self.supports_vcont {
+            let mut packet = String::from("vCont");
+            for thread_id in self.step_threads.iter() {
+                let action = if *thread_id == event_thread { signal_action('s') } else { "s".to_string() };
+                packet.push_str(&format!(";{}:{:x}", action, thread_id));
+            }
+            if signal != 0 && !self.step_threads.contains(&event_thread) {
+                packet.push_str(&format!(";{}:{:x}", signal_action('c'), event_thread));
+            }
+            packet.push_str(";c");
+            return self.connection.get_mut().send_str(&packet);
+        }
+
+        // Without vCont, we can only step one thread and everything else will run
+        let (thread_id, action) = match self.step_threads.iter().next() {
+            Some(thread_id) => (*thread_id, 's'),
+            None => (event_thread, 'c'),
+        };
+        let reply = self.connection.get_mut().request(&format!("Hc{:x}", thread_id))?;
+        if reply != b"OK" {
+            return Err("Could not select thread to resume");
+        }
+        self.connection.get_mut().send_str(&signal_action(action))
+    }
+}
+
+impl MemorySource for RspTarget {
+    fn read_memory(&self, address: u64, len: usize) -> Result<Vec<Option<u8>>, &'static str> {
+        let mut data: Vec<Option<u8>> = vec![None; len];
+        let mut offset: usize = 0;
+
+        // Failures are for an entire page, so we don't let a read cross the end of a page
+        while offset < len {
+            let cur_address = address.wrapping_add(offset as u64);
+            let page_left = (PAGE_SIZE - (cur_address % PAGE_SIZE)) as usize;
+            let chunk_len = std::cmp::min(page_left, len - offset);
+            let bytes = self.read_raw_memory(cur_address, chunk_len);
+            for (index, byte) in bytes.iter().enumerate() {
+                data[offset + index] = Some(*byte);
+            }
+            offset += chunk_len;
+        }
+
+        Ok(data)
+    }
+
+    fn read_raw_memory(&self, address: u64, len: usize) -> Vec<u8> {
+        let max_chunk = (self.packet_size - 4) / 2;
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            let chunk_len = std::cmp::min(max_chunk, len - data.len());
+            let request = format!("m{:x},{:x}", address.wrapping_add(data.len() as u64), chunk_len);
+            let chunk = match self.connection.borrow_mut().request(&request) {
+                Ok(reply) if !reply.is_empty() && reply[0] != b'E' => rsp::decode_hex(&reply).unwrap_or_default(),
+                _ => break,
+            };
+            data.extend_from_slice(&chunk);
+            // A short read means the rest isn't readable
+            if chunk.len() < chunk_len {
+                break;
+            }
+        }
+        data
+    }
+}
+
+impl DebugTarget for RspTarget {
+    fn wait_for_event(&mut self) -> Result<(EventContext, DebugEvent), &'static str> {
+        if let Some(event) = self.queued_events.pop_front() {
+            return Ok(event);
+        }
+
+        if self.exited {
+            return Err("Process has exited");
+        }
+
+        loop {
+            let reply = self.connection.get_mut().read_packet()?;
+            match reply.first() {
+                Some(b'O') => {
+                    let output = rsp::decode_hex(&reply[1..])?;
+                    self.pending_output.push(String::from_utf8_lossy(&output).to_string());
+                }
+                Some(b'W') | Some(b'X') => {
+                    self.exited = true;
+                    let ctx = EventContext { process_id: self.process_id, thread_id: self.process_id };
+                    self.queue_output(ctx);
+                    self.queued_events.push_back((ctx, DebugEvent::ExitProcess));
+                    return Ok(self.queued_events.pop_front().unwrap());
+                }
+                Some(b'T') | Some(b'S') => {
+                    self.selected_thread.set(None);
+                    let signal = rsp::parse_hex_u64(reply.get(1..3).ok_or("Invalid stop reply")?)? as u8;
+                    let thread_id = match self.stop_reply_thread(&reply) {
+                        Some(thread_id) => thread_id,
+                        None => self.current_thread()?,
+                    };
+
+                    let ctx = EventContext { process_id: self.process_id, thread_id };
+                    self.queue_output(ctx);
+                    self.queue_thread_changes(thread_id)?;
+                    self.queue_new_modules(thread_id);
+
+                    let exception_code = match signal_to_exception(signal) {
+                        Some(EXCEPTION_BREAKPOINT) => self.translate_trap(thread_id, &reply)?,
+                        Some(exception_code) => exception_code,
+                        None => {
+                            // Not something that maps to an exception, so just let the target have it
+                            self.resume(thread_id, signal)?;
+                            continue;
+                        }
+                    };
+
+                    // Stepping only lasts until the next stop, even for threads that didn't get to finish their step
+                    self.step_threads.clear();
+                    self.event_signal = Some((thread_id, signal));
+                    self.queued_events.push_back((ctx, DebugEvent::Exception { first_chance: true, exception_code }));
+                    return Ok(self.queued_events.pop_front().unwrap());
+                }
+                _ => return Err("Unexpected packet from remote target"),
+            }
+        }
+    }
+
+    fn memory_source(&self) -> &dyn MemorySource {
+        self
+    }
+
+    fn write_memory(&mut self, address: u64, data: &[u8]) -> Result<usize, &'static str> {
+        let max_chunk = (self.packet_size - 32) / 2;
+        let mut written = 0;
+        for chunk in data.chunks(max_chunk) {
+            let request = format!("M{:x},{:x}:{}", address.wrapping_add(written as u64), chunk.len(), rsp::encode_hex(chunk));
+            if self.connection.get_mut().request(&request)? != b"OK" {
+                break;
+            }
+            written += chunk.len();
+        }
+
+        if written == 0 && !data.is_empty() {
+            return Err("Failed to write remote memory");
+        }
+        Ok(written)
+    }
+
+    fn get_thread_context(&self, thread_id: u32) -> Result<RegisterContext, &'static str> {
+        let block = self.read_register_block(thread_id)?;
+        let mut context = RegisterContext::default();
+        rsp::registers_from_block(&block, &mut context)?;
+        context.dr0 = self.debug_registers[0];
+        context.dr1 = self.debug_registers[1];
+        context.dr2 = self.debug_registers[2];
+        context.dr3 = self.debug_registers[3];
+        context.dr7 = self.debug_registers[4];
+        context.dr6 = self.dr6.get(&thread_id).copied().unwrap_or(0);
+        Ok(context)
+    }
+
+    fn set_thread_context(&mut self, thread_id: u32, context: &RegisterContext) -> Result<(), &'static str> {
+        self.debug_registers = [context.dr0, context.dr1, context.dr2, context.dr3, context.dr7];
+        self.sync_breakpoints()?;
+
+        // Start from the current block so that registers outside the default layout are preserved
+        let mut block = self.read_register_block(thread_id)?;
+        let original = block.clone();
+        rsp::registers_to_block(context, &mut block);
+        if block != original {
+            self.write_register_block(thread_id, &block)?;
+        }
+        Ok(())
+    }
+
+    fn step_thread(&mut self, thread_id: u32) -> Result<(), &'static str> {
+        self.step_threads.insert(thread_id);
+        Ok(())
+    }
+
+    fn continue_event(&mut self, event_context: &EventContext, status: ContinueStatus) -> Result<(), &'static str> {
+        // If there are more events from the same stop, the target stays stopped until they have all been reported
+        if !self.queued_events.is_empty() || self.exited {
+            return Ok(());
+        }
+
+        let signal = match (self.event_signal.take(), status) {
+            (Some((thread_id, signal)), ContinueStatus::NotHandled) if thread_id == event_context.thread_id => signal,
+            _ => 0,
+        };
+
+        self.dr6.clear();
+        self.resume(event_context.thread_id, signal)
+    }
+
+    fn detach(&mut self, _event_context: &EventContext, _status: ContinueStatus) -> Result<(), &'static str> {
+        if self.exited {
+            return Err("Process has exited");
+        }
+        if self.connection.get_mut().request("D")? != b"OK" {
+            return Err("Remote target could not detach");
+        }
+        // The remote side lets the process go, so there's nothing to kill when we disconnect
+        self.exited = true;
+        Ok(())
+    }
+}
+
+impl Drop for RspTarget {
+    fn drop(&mut self) {
+        // Like the other targets, the debuggee doesn't outlive the debugger unless we detach
+        if !self.exited {
+            let _ = self.connection.get_mut().send_str("k");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use super::*;
+
+    const MEMORY_START: u64 = 0x10000;
+    const MEMORY_SIZE: usize = 0x2000;
+
+    // Just enough of a gdbserver to connect to and read and write memory. It has one stopped thread and a block of
+    // memory, and keeps every packet it was sent so the tests can look at them.
+    fn start_stub(packet_size: usize) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let stub_requests = requests.clone();
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut connection = RspConnection::new(stream);
+            let mut memory: Vec<u8> = (0..MEMORY_SIZE).map(|i| i as u8).collect();
+            while let Ok(packet) = connection.read_packet() {
+                let packet = String::from_utf8_lossy(&packet).to_string();
+                stub_requests.lock().unwrap().push(packet.clone());
+
+                let reply = if packet.starts_with("qSupported") {
+                    format!("PacketSize={:x}", packet_size)
+                } else if packet == "?" {
+                    "T05thread:1;".to_string()
+                } else if packet == "qfThreadInfo" {
+                    "m1".to_string()
+                } else if packet == "qsThreadInfo" {
+                    "l".to_string()
+                } else if packet == "k" {
+                    break;
+                } else if let Some(args) = packet.strip_prefix('m') {
+                    let (address, len) = args.split_once(',').unwrap();
+                    let start = u64::from_str_radix(address, 16).unwrap().wrapping_sub(MEMORY_START) as usize;
+                    let len = usize::from_str_radix(len, 16).unwrap();
+                    match memory.get(start..) {
+                        Some(rest) if !rest.is_empty() => rsp::encode_hex(&rest[..std::cmp::min(len, rest.len())]),
+                        _ => "E14".to_string(),
+                    }
+                } else if let Some(args) = packet.strip_prefix('M') {
+                    let (location, data) = args.split_once(':').unwrap();
+                    let start = u64::from_str_radix(location.split(',').next().unwrap(), 16).unwrap().wrapping_sub(MEMORY_START) as usize;
+                    let data = rsp::decode_hex(data.as_bytes()).unwrap();
+                    match memory.get_mut(start..start + data.len()) {
+                        Some(range) => {
+                            range.copy_from_slice(&data);
+                            "OK".to_string()
+                        }
+                        None => "E14".to_string(),
+                    }
+                } else {
+                    String::new()
+                };
+                if connection.send_str(&reply).is_err() {
+                    break;
+                }
+            }
+        });
+
+        (address, requests)
+    }
+
+    fn memory_requests(requests: &Mutex<Vec<String>>, command: char) -> Vec<usize> {
+        requests.lock().unwrap().iter()
+            .filter_map(|r| r.strip_prefix(command))
+            .map(|args| usize::from_str_radix(args.split([',', ':']).nth(1).unwrap(), 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn connect_reports_initial_stop() {
+        let (address, _) = start_stub(0x400);
+        let mut target = RspTarget::connect(&address).unwrap();
+        assert!(matches!(target.wait_for_event().unwrap().1, DebugEvent::CreateProcess { .. }));
+        let (ctx, event) = target.wait_for_event().unwrap();
+        assert_eq!(ctx.thread_id, 1);
+        assert!(matches!(event, DebugEvent::Exception { exception_code: EXCEPTION_BREAKPOINT, .. }));
+    }
+
+    #[test]
+    fn read_memory_in_chunks() {
+        let (address, requests) = start_stub(0x40);
+        let target = RspTarget::connect(&address).unwrap();
+        let expected: Vec<u8> = (0..0x300).map(|i| i as u8).collect();
+        assert_eq!(target.read_raw_memory(MEMORY_START, 0x300), expected);
+        assert!(memory_requests(&requests, 'm').iter().all(|len| *len <= (MIN_PACKET_SIZE - 4) / 2));
+    }
+
+    #[test]
+    fn read_memory_stops_at_unreadable_memory() {
+        let (address, _) = start_stub(0x400);
+        let target = RspTarget::connect(&address).unwrap();
+        let end = MEMORY_START + MEMORY_SIZE as u64;
+        let data = target.read_memory(end - 0x10, 0x20).unwrap();
+        assert!(data[..0x10].iter().all(|b| b.is_some()));
+        assert!(data[0x10..].iter().all(|b| b.is_none()));
+        assert_eq!(target.read_raw_memory(end - 0x10, 0x20).len(), 0x10);
+    }
+
+    // A packet size this small would leave no room for any data, which used to mean underflow or no progress at all
+    #[test]
+    fn tiny_packet_size_is_raised() {
+        for packet_size in [0, 4, 5, 16, 32] {
+            let (address, requests) = start_stub(packet_size);
+            let mut target = RspTarget::connect(&address).unwrap();
+
+            let data: Vec<u8> = (0..0x200).map(|i| (i * 7) as u8).collect();
+            assert_eq!(target.write_memory(MEMORY_START + 0x100, &data).unwrap(), data.len());
+            assert_eq!(target.read_raw_memory(MEMORY_START + 0x100, data.len()), data);
+            assert!(memory_requests(&requests, 'M').iter().all(|len| *len > 0));
+            assert!(memory_requests(&requests, 'm').iter().all(|len| *len > 0));
+        }
+    }
+}
@@ -0,0 +1,235 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use rhai::{Array, Blob, Dynamic, Engine, EvalAltResult, FnPtr, Map, Scope, AST, INT};
+
+use crate::breakpoint::BreakpointManager;
+use crate::memory::{self, Pod};
+use crate::name_resolution;
+use crate::process::Process;
+use crate::registers::{self, RegisterContext};
+use crate::stack;
+use crate::target::DebugTarget;
+
+// Scripts are written in Rhai (https://rhai.rs). Numbers in Rhai are signed 64-bit, so addresses above 0x7fff... show
+// up as negative numbers in scripts, but they still have the right bits in them.
+
+// The parts of the debugger that a script can see while it runs. This only exists while the target is stopped.
+pub struct ScriptSession<'a> {
+    pub target: &'a mut dyn DebugTarget,
+    pub process_id: u32,
+    pub process: &'a mut Process,
+    pub breakpoints: &'a mut BreakpointManager,
+    pub thread_id: u32,
+    pub context: &'a RegisterContext,
+}
+
+thread_local! {
+    // Functions registered with the engine have to be 'static, so they can't hold on to the session. Instead it's
+    // stashed here for as long as a script is running, and it's null the rest of the time.
+    static SESSION: Cell<*mut ScriptSession<'static>> = const { Cell::new(std::ptr::null_mut()) };
+}
+
+fn with_session<T>(f: impl FnOnce(&mut ScriptSession) -> Result<T, Box<EvalAltResult>>) -> Result<T, Box<EvalAltResult>> {
+    let session = SESSION.with(|s| s.get());
+    if session.is_null() {
+        return Err("The target is not stopped".into());
+    }
+    // The pointer is only set while enter_session has the session borrowed, and none of the functions that use it
+    // call back into the script, so this is the only reference to it.
+    f(unsafe { &mut *session })
+}
+
+fn enter_session<R>(session: &mut ScriptSession, f: impl FnOnce() -> R) -> R {
+    let ptr: *mut ScriptSession<'static> = (session as *mut ScriptSession).cast();
+    let previous = SESSION.with(|s| s.replace(ptr));
+    let result = f();
+    SESSION.with(|s| s.set(previous));
+    result
+}
+
+// Commands and breakpoint callbacks that scripts have set up, which outlive the script that registered them
+#[derive(Default)]
+struct ScriptState {
+    commands: HashMap<String, FnPtr>,
+    // Each process has its own breakpoints, so these are keyed by process ID and address
+    breakpoint_callbacks: HashMap<(u32, u64), FnPtr>,
+}
+
+pub struct ScriptEngine {
+    engine: Engine,
+    // The functions from every script that has been run, since commands and callbacks can refer to any of them
+    functions: AST,
+    state: Rc<RefCell<ScriptState>>,
+}
+
+fn read_data<T: Pod>(address: INT) -> Result<T, Box<EvalAltResult>> {
+    with_session(|s| {
+        memory::read_memory_data::<T>(s.target.memory_source(), address as u64).map_err(|e| e.to_string().into())
+    })
+}
+
+// Reads up to max_count characters, stopping at a null terminator
+fn read_string(address: INT, max_count: INT, is_wide: bool) -> Result<String, Box<EvalAltResult>> {
+    with_session(|s| {
+        memory::read_memory_string(s.target.memory_source(), address as u64, max_count as usize, is_wide).map_err(|e| e.to_string().into())
+    })
+}
+
+fn walk_stack(session: &mut ScriptSession, context: RegisterContext) -> Array {
+    let mut frames = Array::new();
+    let mut context = context;
+    loop {
+        let mut frame = Map::new();
+        frame.insert("rip".into(), (context.rip as INT).into());
+        frame.insert("rsp".into(), (context.rsp as INT).into());
+        frame.insert("symbol".into(), match name_resolution::resolve_address_to_name(context.rip, session.process) {
+            Some(sym) => sym.into(),
+            None => Dynamic::UNIT,
+        });
+        frames.push(frame.into());
+        match stack::unwind_context(session.process, context, session.target.memory_source()) {
+            Ok(Some(unwound_context)) => context = unwound_context,
+            _ => break,
+        }
+    }
+    frames
+}
+
+fn add_breakpoint(state: &Rc<RefCell<ScriptState>>, address: INT, callback: Option<FnPtr>) -> Result<INT, Box<EvalAltResult>> {
+    let address = address as u64;
+    let (process_id, id) = with_session(|s| match s.breakpoints.find_breakpoint(address) {
+        Some(id) => Ok((s.process_id, id)),
+        None => Ok((s.process_id, s.breakpoints.add_breakpoint(address)?)),
+    })?;
+    let mut state = state.borrow_mut();
+    match callback {
+        Some(callback) => state.breakpoint_callbacks.insert((process_id, address), callback),
+        None => state.breakpoint_callbacks.remove(&(process_id, address)),
+    };
+    Ok(id as INT)
+}
+
+fn register_api(engine: &mut Engine, state: &Rc<RefCell<ScriptState>>) {
+    engine.register_fn("read_bytes", |address: INT, len: INT| -> Result<Blob, Box<EvalAltResult>> {
+        with_session(|s| Ok(s.target.memory_source().read_raw_memory(address as u64, len as usize)))
+    });
+    engine.register_fn("read_u8", |address: INT| read_data::<u8>(address).map(|v| v as INT));
+    engine.register_fn("read_u16", |address: INT| read_data::<u16>(address).map(|v| v as INT));
+    engine.register_fn("read_u32", |address: INT| read_data::<u32>(address).map(|v| v as INT));
+    engine.register_fn("read_u64", |address: INT| read_data::<u64>(address).map(|v| v as INT));
+    engine.register_fn("read_pointer", |address: INT| read_data::<u64>(address).map(|v| v as INT));
+    engine.register_fn("read_string", |address: INT, max_count: INT| read_string(address, max_count, false));
+    engine.register_fn("read_wstring", |address: INT, max_count: INT| read_string(address, max_count, true));
+
+    engine.register_fn("reg", |name: &str| -> Result<INT, Box<EvalAltResult>> {
+        with_session(|s| Ok(registers::get_register(s.context, name)? as INT))
+    });
+    engine.register_fn("registers", || -> Result<Map, Box<EvalAltResult>> {
+        with_session(|s| {
+            let mut regs = Map::new();
+            for name in registers::REGISTER_NAMES {
+                regs.insert(name.into(), (registers::get_register(s.context, name)? as INT).into());
+            }
+            Ok(regs)
+        })
+    });
+
+    engine.register_fn("thread_id", || with_session(|s| Ok(s.thread_id as INT)));
+    engine.register_fn("threads", || -> Result<Array, Box<EvalAltResult>> {
+        with_session(|s| Ok(s.process.iterate_threads().map(|t| (*t as INT).into()).collect()))
+    });
+    engine.register_fn("modules", || -> Result<Array, Box<EvalAltResult>> {
+        with_session(|s| {
+            Ok(s.process.iterate_modules().map(|module| {
+                let mut m = Map::new();
+                m.insert("name".into(), module.name.clone().into());
+                m.insert("base".into(), (module.address as INT).into());
+                m.insert("size".into(), (module.size as INT).into());
+                m.into()
+            }).collect())
+        })
+    });
+
+    engine.register_fn("resolve", |name: &str| -> Result<INT, Box<EvalAltResult>> {
+        with_session(|s| match name_resolution::resolve_name_to_address(name, s.process) {
+            Ok(address) => Ok(address as INT),
+            Err(e) => Err(e.to_string().into()),
+        })
+    });
+    engine.register_fn("symbol", |address: INT| -> Result<Dynamic, Box<EvalAltResult>> {
+        with_session(|s| Ok(match name_resolution::resolve_address_to_name(address as u64, s.process) {
+            Some(sym) => sym.into(),
+            None => Dynamic::UNIT,
+        }))
+    });
+    engine.register_fn("stack", || -> Result<Array, Box<EvalAltResult>> {
+        with_session(|s| {
+            let context = *s.context;
+            Ok(walk_stack(s, context))
+        })
+    });
+    engine.register_fn("stack", |thread_id: INT| -> Result<Array, Box<EvalAltResult>> {
+        with_session(|s| {
+            let context = s.target.get_thread_context(thread_id as u32)?;
+            Ok(walk_stack(s, context))
+        })
+    });
+
+    let bp_state = state.clone();
+    engine.register_fn("bp", move |address: INT| add_breakpoint(&bp_state, address, None));
+    let bp_state = state.clone();
+    engine.register_fn("bp", move |address: INT, callback: FnPtr| add_breakpoint(&bp_state, address, Some(callback)));
+
+    let command_state = state.clone();
+    engine.register_fn("register_command", move |name: &str, callback: FnPtr| {
+        command_state.borrow_mut().commands.insert(name.to_string(), callback);
+    });
+}
+
+impl ScriptEngine {
+    pub fn new() -> ScriptEngine {
+        let mut engine = Engine::new();
+        let state = Rc::new(RefCell::new(ScriptState::default()));
+        register_api(&mut engine, &state);
+        ScriptEngine { engine, functions: AST::empty(), state }
+    }
+
+    pub fn run_file(&mut self, path: &str, session: &mut ScriptSession) -> Result<(), String> {
+        let ast = self.engine.compile_file(path.into()).map_err(|e| e.to_string())?;
+        self.functions.combine(ast.clone_functions_only());
+        enter_session(session, || self.engine.run_ast_with_scope(&mut Scope::new(), &ast)).map_err(|e| e.to_string())
+    }
+
+    // Runs a command that a script registered. The callback gets the rest of the command line as a string.
+    pub fn run_command(&mut self, name: &str, args: &str, session: &mut ScriptSession) -> Result<(), String> {
+        let callback = self.state.borrow().commands.get(name).cloned().ok_or("No script has registered this command")?;
+        enter_session(session, || callback.call::<Dynamic>(&self.engine, &self.functions, (args.to_string(),)))
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    // Returns whether the debugger should stop for a breakpoint at the address. The callback for the breakpoint can
+    // return false to keep going, and breakpoints without a callback always stop.
+    pub fn breakpoint_hit(&mut self, address: u64, session: &mut ScriptSession) -> bool {
+        let callback = match self.state.borrow().breakpoint_callbacks.get(&(session.process_id, address)) {
+            Some(callback) => callback.clone(),
+            None => return true,
+        };
+        match enter_session(session, || callback.call::<Dynamic>(&self.engine, &self.functions, ())) {
+            Ok(result) => result.as_bool().unwrap_or(true),
+            Err(e) => {
+                println!("Breakpoint callback failed: {}", e);
+                true
+            }
+        }
+    }
+
+    // Forgets the callbacks for breakpoints in the process that have been cleared
+    pub fn retain_breakpoints(&mut self, process_id: u32, breakpoints: &BreakpointManager) {
+        self.state.borrow_mut().breakpoint_callbacks.retain(|(callback_process_id, address), _| {
+            *callback_process_id != process_id || breakpoints.find_breakpoint(*address).is_some()
+        });
+    }
+}
@@ -0,0 +1,131 @@
+use crate::memory::MemorySource;
+
+// Memory is searched this much at a time, so that a big range doesn't need to be read all at once
+const SEARCH_CHUNK_SIZE: u64 = 0x10000;
+
+// A pattern is a list of bytes, where None is a wildcard that matches any byte that can be read. Each value becomes
+// element_size little endian bytes, and a wildcard value covers the whole element.
+pub fn value_pattern(values: &[Option<u64>], element_size: usize) -> Result<Vec<Option<u8>>, String> {
+    let mut pattern = Vec::with_capacity(values.len() * element_size);
+    for value in values {
+        match value {
+            Some(value) => {
+                if element_size < 8 && *value >> (element_size * 8) != 0 {
+                    return Err(format!("{:#x} is too large for a {} byte value", value, element_size));
+                }
+                pattern.extend(value.to_le_bytes()[..element_size].iter().map(|b| Some(*b)));
+            }
+            None => pattern.extend(std::iter::repeat_n(None, element_size)),
+        }
+    }
+    Ok(pattern)
+}
+
+pub fn string_pattern(text: &str, is_wide: bool) -> Vec<Option<u8>> {
+    if is_wide {
+        text.encode_utf16().flat_map(|c| c.to_le_bytes()).map(Some).collect()
+    } else {
+        text.bytes().map(Some).collect()
+    }
+}
+
+fn matches_at(data: &[Option<u8>], pattern: &[Option<u8>]) -> bool {
+    data.iter().zip(pattern).all(|(byte, expected)| match (byte, expected) {
+        (None, _) => false,
+        (Some(_), None) => true,
+        (Some(byte), Some(expected)) => byte == expected,
+    })
+}
+
+// Finds every address in the range where the whole pattern matches. Memory that can't be read never matches, so
+// unmapped pages in the middle of the range are just skipped over.
+pub fn search_memory(memory_source: &dyn MemorySource, address: u64, len: u64, pattern: &[Option<u8>]) -> Vec<u64> {
+    let mut results = Vec::new();
+    // Nothing past the end of the address space can match, so the range stops there
+    let len = std::cmp::min(len as u128, (u64::MAX - address) as u128 + 1) as u64;
+    let pattern_len = pattern.len() as u64;
+    if pattern_len == 0 || pattern_len > len {
+        return results;
+    }
+
+    // Each chunk is read with enough extra bytes to find matches that start in it and end in the next one. Every
+    // start is at most address + len - pattern_len, so the addresses can't overflow.
+    let last_start = len - pattern_len;
+    let mut offset = 0;
+    loop {
+        let starts = std::cmp::min(SEARCH_CHUNK_SIZE, last_start - offset + 1);
+        let chunk_address = address + offset;
+        let data = memory_source.read_memory(chunk_address, (starts + pattern_len - 1) as usize).unwrap_or_default();
+        for start in 0..starts as usize {
+            if let Some(window) = data.get(start..start + pattern.len()) {
+                if matches_at(window, pattern) {
+                    results.push(chunk_address + start as u64);
+                }
+            }
+        }
+        match offset.checked_add(SEARCH_CHUNK_SIZE) {
+            Some(next) if next <= last_start => offset = next,
+            _ => break,
+        }
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_target::FakeMemory;
+
+    #[test]
+    fn value_pattern_is_little_endian() {
+        assert_eq!(value_pattern(&[Some(0x1234), None], 2).unwrap(), [Some(0x34), Some(0x12), None, None]);
+        assert_eq!(value_pattern(&[Some(0xff)], 1).unwrap(), [Some(0xff)]);
+        assert!(value_pattern(&[Some(0x100)], 1).is_err());
+        assert_eq!(value_pattern(&[Some(u64::MAX)], 8).unwrap(), [Some(0xff); 8]);
+    }
+
+    #[test]
+    fn string_pattern_encodes_wide_strings() {
+        assert_eq!(string_pattern("ab", false), [Some(b'a'), Some(b'b')]);
+        assert_eq!(string_pattern("ab", true), [Some(b'a'), Some(0), Some(b'b'), Some(0)]);
+    }
+
+    #[test]
+    fn wildcards_match_any_readable_byte() {
+        let mut memory = FakeMemory::default();
+        memory.add_region(0x1000, b"xay bzy cz");
+        let mut pattern = string_pattern("?z", false);
+        pattern[0] = None;
+        assert_eq!(search_memory(&memory, 0x1000, 10, &pattern), [0x1004, 0x1008]);
+        // The wildcard still needs a byte that can be read, so a match can't start before the memory does
+        assert!(search_memory(&memory, 0xfff, 3, &value_pattern(&[None, Some(b'x' as u64)], 1).unwrap()).is_empty());
+    }
+
+    #[test]
+    fn matches_across_chunks_are_found() {
+        let mut data = vec![0; SEARCH_CHUNK_SIZE as usize * 2];
+        data[SEARCH_CHUNK_SIZE as usize - 2..SEARCH_CHUNK_SIZE as usize + 2].copy_from_slice(&[1, 2, 3, 4]);
+        let mut memory = FakeMemory::default();
+        memory.add_region(0x10000, &data);
+        let pattern = value_pattern(&[Some(0x04030201)], 4).unwrap();
+        assert_eq!(search_memory(&memory, 0x10000, data.len() as u64, &pattern), [0x10000 + SEARCH_CHUNK_SIZE - 2]);
+    }
+
+    #[test]
+    fn unreadable_gaps_are_skipped() {
+        let mut memory = FakeMemory::default();
+        memory.add_region(0x1000, &[7, 7, 7]);
+        memory.add_region(0x3000, &[7, 7]);
+        let pattern = value_pattern(&[Some(0x0707)], 2).unwrap();
+        assert_eq!(search_memory(&memory, 0x1000, 0x3000, &pattern), [0x1000, 0x1001, 0x3000]);
+    }
+
+    #[test]
+    fn range_stops_at_end_of_address_space() {
+        let mut memory = FakeMemory::default();
+        memory.add_region(u64::MAX - 0xf, &[0; 0x10]);
+        let found = search_memory(&memory, u64::MAX - 0xff, 0x20000, &value_pattern(&[Some(0)], 1).unwrap());
+        assert_eq!(found, (u64::MAX - 0xf..=u64::MAX).collect::<Vec<_>>());
+        assert_eq!(search_memory(&memory, u64::MAX, u64::MAX, &value_pattern(&[Some(0)], 1).unwrap()), [u64::MAX]);
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+
+use crate::memory::MemorySource;
+use crate::name_resolution;
+use crate::process::Process;
+
+// Changes are shown a qword at a time, counted from the start of the snapshot
+const DIFF_ELEMENT_SIZE: usize = 8;
+
+// A copy of a range of memory to compare against later
+struct Snapshot {
+    address: u64,
+    data: Vec<Option<u8>>,
+    // Watched snapshots are compared at every stop and then taken again, so each stop shows what changed since the last
+    watched: bool,
+}
+
+// The snapshots for one process, by name
+#[derive(Default)]
+pub struct Snapshots {
+    snapshots: BTreeMap<String, Snapshot>,
+}
+
+fn read_range(memory_source: &dyn MemorySource, address: u64, len: usize) -> Vec<Option<u8>> {
+    memory_source.read_memory(address, len).unwrap_or_else(|_| vec![None; len])
+}
+
+// Bytes that couldn't be read show up as ??, like in the d commands
+fn format_element(bytes: &[Option<u8>]) -> String {
+    bytes.iter().rev().map(|b| match b {
+        Some(b) => format!("{:02X}", b),
+        None => "??".to_string(),
+    }).collect()
+}
+
+// Prints every qword that has a changed byte, and returns how many bytes changed
+fn display_changes(snapshot: &Snapshot, current: &[Option<u8>], process: &mut Process) -> usize {
+    let mut changed_bytes = 0;
+    for (index, (old, new)) in snapshot.data.chunks(DIFF_ELEMENT_SIZE).zip(current.chunks(DIFF_ELEMENT_SIZE)).enumerate() {
+        let changed: Vec<String> = old.iter().zip(new).enumerate().filter(|(_, (o, n))| o != n).map(|(offset, _)| format!("+{}", offset)).collect();
+        if changed.is_empty() {
+            continue;
+        }
+        changed_bytes += changed.len();
+
+        let address = snapshot.address + (index * DIFF_ELEMENT_SIZE) as u64;
+        match name_resolution::resolve_address_to_name(address, process) {
+            Some(symbol) => println!("{:016X}  {} -> {}  bytes {}  {}", address, format_element(old), format_element(new), changed.join(","), symbol),
+            None => println!("{:016X}  {} -> {}  bytes {}", address, format_element(old), format_element(new), changed.join(",")),
+        }
+    }
+    changed_bytes
+}
+
+impl Snapshots {
+    // Taking a snapshot with a name that's already in use replaces it
+    pub fn take(&mut self, name: &str, address: u64, len: u64, watched: bool, memory_source: &dyn MemorySource) {
+        let data = read_range(memory_source, address, len as usize);
+        let readable = data.iter().filter(|b| b.is_some()).count();
+        if readable < data.len() {
+            println!("Only {:#x} of {:#x} bytes could be read, the rest will be compared as ??", readable, data.len());
+        }
+        self.snapshots.insert(name.to_string(), Snapshot { address, data, watched });
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<(), &'static str> {
+        self.snapshots.remove(name).map(|_| ()).ok_or("No snapshot with that name")
+    }
+
+    pub fn list(&self) {
+        if self.snapshots.is_empty() {
+            println!("No snapshots");
+        }
+        for (name, snapshot) in self.snapshots.iter() {
+            let watched = if snapshot.watched { " (watched)" } else { "" };
+            println!("{:<16} {:016X} L{:#x}{}", name, snapshot.address, snapshot.data.len(), watched);
+        }
+    }
+
+    // Compares memory with the snapshot, which is left as it is so that later diffs are still against the same point
+    pub fn diff(&self, name: &str, memory_source: &dyn MemorySource, process: &mut Process) -> Result<(), &'static str> {
+        let snapshot = self.snapshots.get(name).ok_or("No snapshot with that name")?;
+        let current = read_range(memory_source, snapshot.address, snapshot.data.len());
+        match display_changes(snapshot, &current, process) {
+            0 => println!("No changes since the snapshot was taken"),
+            changed => println!("{:#x} bytes changed", changed),
+        }
+        Ok(())
+    }
+
+    // Shows what changed in each watched snapshot since the last stop, then takes them again for the next one
+    pub fn report_watched(&mut self, memory_source: &dyn MemorySource, process: &mut Process) {
+        for (name, snapshot) in self.snapshots.iter_mut().filter(|(_, s)| s.watched) {
+            let current = read_range(memory_source, snapshot.address, snapshot.data.len());
+            if current != snapshot.data {
+                println!("Memory changed in watched snapshot {}:", name);
+                display_changes(snapshot, &current, process);
+                snapshot.data = current;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_target::FakeMemory;
+
+    const ADDRESS: u64 = 0x1000;
+
+    // Four qwords, where the last one was never readable
+    fn memory_before() -> FakeMemory {
+        let mut memory = FakeMemory::default();
+        memory.add_region(ADDRESS, &[0x11; 0x18]);
+        memory
+    }
+
+    // Two bytes of the first qword changed, the second is the same, and the third can't be read any more
+    fn memory_after() -> FakeMemory {
+        let mut memory = FakeMemory::default();
+        memory.add_region(ADDRESS, &[0x11, 0x22, 0x11, 0x11, 0x11, 0x11, 0x33, 0x11]);
+        memory.add_region(ADDRESS + 8, &[0x11; 8]);
+        memory
+    }
+
+    #[test]
+    fn changed_and_newly_unreadable_bytes_are_counted() {
+        let mut snapshots = Snapshots::default();
+        snapshots.take("a", ADDRESS, 0x20, false, &memory_before());
+        let snapshot = &snapshots.snapshots["a"];
+        assert_eq!(snapshot.data.iter().filter(|b| b.is_none()).count(), 8);
+
+        let mut process = Process::new();
+        let current = read_range(&memory_after(), ADDRESS, 0x20);
+        assert_eq!(display_changes(snapshot, &current, &mut process), 2 + 8);
+        assert_eq!(display_changes(snapshot, &read_range(&memory_before(), ADDRESS, 0x20), &mut process), 0);
+    }
+
+    #[test]
+    fn diff_leaves_the_snapshot_alone() {
+        let mut snapshots = Snapshots::default();
+        snapshots.take("a", ADDRESS, 0x20, false, &memory_before());
+        let mut process = Process::new();
+        assert!(snapshots.diff("a", &memory_after(), &mut process).is_ok());
+        assert_eq!(snapshots.snapshots["a"].data, read_range(&memory_before(), ADDRESS, 0x20));
+        assert!(snapshots.diff("b", &memory_after(), &mut process).is_err());
+    }
+
+    #[test]
+    fn watched_snapshots_are_taken_again() {
+        let mut snapshots = Snapshots::default();
+        snapshots.take("watched", ADDRESS, 0x20, true, &memory_before());
+        snapshots.take("unwatched", ADDRESS, 0x20, false, &memory_before());
+        let mut process = Process::new();
+        snapshots.report_watched(&memory_after(), &mut process);
+        assert_eq!(snapshots.snapshots["watched"].data, read_range(&memory_after(), ADDRESS, 0x20));
+        assert_eq!(snapshots.snapshots["unwatched"].data, read_range(&memory_before(), ADDRESS, 0x20));
+    }
+
+    #[test]
+    fn snapshots_are_replaced_and_removed_by_name() {
+        let mut snapshots = Snapshots::default();
+        snapshots.take("a", ADDRESS, 0x20, false, &memory_before());
+        snapshots.take("a", ADDRESS + 8, 0x8, true, &memory_before());
+        snapshots.list();
+        assert_eq!(snapshots.snapshots.len(), 1);
+        assert_eq!(snapshots.snapshots["a"].address, ADDRESS + 8);
+        assert!(snapshots.snapshots["a"].watched);
+
+        assert!(snapshots.remove("a").is_ok());
+        assert!(snapshots.remove("a").is_err());
+        assert!(snapshots.snapshots.is_empty());
+    }
+}
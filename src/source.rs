@@ -18,6 +18,10 @@ fn line_program_references_file(line_program: &LineProgram, src_file: &str, stri
 
 pub fn resolve_source_line_to_address(module_name: &str, src_file: &str, src_line: u32, process: &mut Process) -> Result<u64> {
     let process_module = process.get_module_by_name_mut(module_name).ok_or(anyhow!("Module not found"))?;
+    if let Some(dwarf) = process_module.dwarf.as_ref() {
+        return dwarf.find_line_address(src_file, src_line).ok_or(anyhow!("Source line not found"));
+    }
+
     let pdb = process_module.pdb.as_mut().ok_or(anyhow!("Symbols not available"))?;
     let address_map = process_module.address_map.as_mut().ok_or(anyhow!("Address map not found for module"))?;
     let string_table = pdb.string_table()?;
@@ -31,7 +35,7 @@ pub fn resolve_source_line_to_address(module_name: &str, src_file: &str, src_lin
                     while let Some(line) = lines.next()? {
                         let cur_file_name = string_table.get(line_program.get_file_info(line.file_index)?.name)?.to_string();
                         if cur_file_name == src_file && line.line_start <= src_line && src_line <= line.line_end {
-                            let rva = line.offset.to_rva(&address_map).ok_or(anyhow!("Could not map source entry to RVA"))?;
+                            let rva = line.offset.to_rva(address_map).ok_or(anyhow!("Could not map source entry to RVA"))?;
                             let address = process_module.address + rva.0 as u64;
                             return Ok(address);
                         }
@@ -46,6 +50,11 @@ pub fn resolve_source_line_to_address(module_name: &str, src_file: &str, src_lin
 
 pub fn resolve_address_to_source_line(address: u64, process: &mut Process) -> Result<(String, u32)> {
     let module = process.get_containing_module_mut(address).ok_or(anyhow!("Module not found"))?;
+    if let Some(dwarf) = module.dwarf.as_ref() {
+        let (file_name, line) = dwarf.find_line(address).ok_or(anyhow!("Address not found"))?;
+        return Ok((file_name.to_string(), line));
+    }
+
     let pdb = module.pdb.as_mut().ok_or(anyhow!("Symbols not available"))?;
 
     let address_map = module.address_map.as_mut().ok_or(anyhow!("Address map not found for module"))?;
@@ -1,9 +1,8 @@
 use windows::Win32::System::Diagnostics::Debug::IMAGE_DIRECTORY_ENTRY_EXCEPTION;
-use windows_sys::Win32::System::Diagnostics::Debug::CONTEXT;
-use crate::{process::Process, memory::{MemorySource, read_memory_full_array, read_memory_data}};
+use crate::{module::Module, process::Process, memory::{impl_pod, MemorySource, Pod, read_memory_full_array, read_memory_data}, registers::RegisterContext};
 
 #[repr(C)]
-#[derive(Default, Clone)]
+#[derive(Default, Clone, Copy)]
 #[allow(non_snake_case, non_camel_case_types)]
 pub struct RUNTIME_FUNCTION {
     pub BeginAddress: u32,
@@ -11,6 +10,8 @@ pub struct RUNTIME_FUNCTION {
     pub UnwindInfo: u32,
 }
 
+impl_pod!(RUNTIME_FUNCTION { BeginAddress: u32, EndAddress: u32, UnwindInfo: u32 });
+
 #[repr(C)]
 #[derive(Default, Clone, Copy)]
 #[allow(non_snake_case, non_camel_case_types)]
@@ -21,9 +22,11 @@ pub struct UNWIND_INFO {
     pub frame_register_offset: u8,
 }
 
+impl_pod!(UNWIND_INFO { version_flags: u8, size_of_prolog: u8, count_of_codes: u8, frame_register_offset: u8 });
+
 #[repr(C)]
 #[derive(Default, Clone, Copy)]
-#[allow(non_snake_case, non_camel_case_types)]
+#[allow(non_snake_case, non_camel_case_types, dead_code)]
 pub struct UNWIND_CODE {
     pub code_offset: u8,
     pub unwind_op_info: u8,
@@ -37,8 +40,10 @@ const UWOP_SAVE_NONVOL: u8 = 4;     /* info == register number, offset in next s
 const UWOP_SAVE_NONVOL_FAR: u8 = 5; /* info == register number, offset in next 2 slots */
 const UWOP_SAVE_XMM128: u8 = 8;     /* info == XMM reg number, offset in next slot */
 const UWOP_SAVE_XMM128_FAR: u8 = 9; /* info == XMM reg number, offset in next 2 slots */
+#[allow(dead_code)]
 const UWOP_PUSH_MACHFRAME: u8 = 10; /* info == 0: no error-code, 1: error-code */
 
+#[allow(dead_code)]
 const UNW_FLAG_NHANDLER: u8 = 0x0;
 const UNW_FLAG_EHANDLER: u8 = 0x1;
 const UNW_FLAG_UHANDLER: u8 = 0x2;
@@ -46,6 +51,7 @@ const UNW_FLAG_CHAININFO: u8 = 0x4;
 
 // These represent the logical operations, so large/small and far/near are merged
 #[derive(Debug)]
+#[allow(dead_code)]
 enum UnwindOp {
     PushNonVolatile { reg: u8 },
     Alloc { size: u32 },
@@ -70,9 +76,9 @@ fn find_runtime_function(addr: u32, function_list: &[RUNTIME_FUNCTION]) -> Optio
         Ok(pos) => function_list.get(pos),
         // Inexact match
         Err(pos) => {
-            if pos > 0 && function_list.get(pos - 1).map_or(false, |func| func.BeginAddress <= addr && addr < func.EndAddress) {
+            if pos > 0 && function_list.get(pos - 1).is_some_and(|func| func.BeginAddress <= addr && addr < func.EndAddress) {
                 function_list.get(pos - 1)
-            } else if pos < function_list.len() && function_list.get(pos).map_or(false, |func| func.BeginAddress <= addr && addr < func.EndAddress) {
+            } else if pos < function_list.len() && function_list.get(pos).is_some_and(|func| func.BeginAddress <= addr && addr < func.EndAddress) {
                 function_list.get(pos)
             } else {
                 None
@@ -87,14 +93,16 @@ macro_rules! split_up {
     ($value:expr => $($len:expr),+) => {
         {
             let mut value = $value;
-            // Use a tuple to collect the fields
-            ( $(
+            // Use a tuple to collect the fields. The shift after the last field is never read, which is expected.
+            #[allow(unused_assignments)]
+            let fields = ( $(
                 {
                     let field = value & ((1 << $len) - 1); // Mask the value to get the field
                     value >>= $len; // Shift the value for the next field
                     field
                 }
-            ),+ ) // The '+' sign indicates one or more repetitions
+            ),+ ); // The '+' sign indicates one or more repetitions
+            fields
         }
     };
 }
@@ -139,7 +147,8 @@ fn get_unwind_ops(code_slots: &[u16], frame_register: u8, frame_offset: u16) ->
                 if i + 1 >= code_slots.len() {
                     return Err("UWOP_SAVE_NONVOL was incomplete");
                 }
-                let offset = code_slots[i + 1] as u32;
+                // The near form of the offset is scaled by 8, the far form isn't
+                let offset = code_slots[i + 1] as u32 * 8;
                 ops.push(UnwindCode { code_offset, op: UnwindOp::SaveNonVolatile { reg: op_info, offset } });
                 i += 1;
             }
@@ -155,8 +164,9 @@ fn get_unwind_ops(code_slots: &[u16], frame_register: u8, frame_offset: u16) ->
                 if i + 1 >= code_slots.len() {
                     return Err("UWOP_SAVE_XMM128 was incomplete");
                 }
-                let offset = code_slots[i + 1] as u32;
-                ops.push(UnwindCode { code_offset, op: UnwindOp::SaveXmm128 { reg: op_info, offset: offset } });
+                // The near form is scaled by 16, since xmm registers are saved to 16 byte aligned slots
+                let offset = code_slots[i + 1] as u32 * 16;
+                ops.push(UnwindCode { code_offset, op: UnwindOp::SaveXmm128 { reg: op_info, offset } });
                 i += 1;
             }
             UWOP_SAVE_XMM128_FAR => {
@@ -164,7 +174,7 @@ fn get_unwind_ops(code_slots: &[u16], frame_register: u8, frame_offset: u16) ->
                     return Err("UWOP_SAVE_XMM128_FAR was incomplete");
                 }
                 let offset = code_slots[i + 1] as u32 + ((code_slots[i + 2] as u32) << 16);
-                ops.push(UnwindCode { code_offset, op: UnwindOp::SaveXmm128 { reg: op_info, offset: offset } });
+                ops.push(UnwindCode { code_offset, op: UnwindOp::SaveXmm128 { reg: op_info, offset } });
                 i += 2;
             }
             _ => return Err("Unrecognized unwind op")
@@ -175,70 +185,138 @@ fn get_unwind_ops(code_slots: &[u16], frame_register: u8, frame_offset: u16) ->
     Ok(ops)
 }
 
-fn get_op_register<'a>(context: &'a mut CONTEXT, reg: u8) -> &'a mut u64 {
+const REGISTER_NAMES: [&str; 16] = ["rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15"];
+
+fn describe_unwind_op(op: &UnwindOp) -> String {
+    match *op {
+        UnwindOp::PushNonVolatile { reg } => format!("UWOP_PUSH_NONVOL reg: {}", REGISTER_NAMES[reg as usize]),
+        UnwindOp::Alloc { size } => format!("UWOP_ALLOC size: {:#x}", size),
+        UnwindOp::SetFpreg { frame_register, frame_offset } => format!("UWOP_SET_FPREG reg: {}, offset: {:#x}", REGISTER_NAMES[frame_register as usize], frame_offset),
+        UnwindOp::SaveNonVolatile { reg, offset } => format!("UWOP_SAVE_NONVOL reg: {}, offset: {:#x}", REGISTER_NAMES[reg as usize], offset),
+        UnwindOp::SaveXmm128 { reg, offset } => format!("UWOP_SAVE_XMM128 reg: xmm{}, offset: {:#x}", reg, offset),
+        UnwindOp::PushMachFrame { error_code } => format!("UWOP_PUSH_MACHFRAME error code: {}", error_code),
+    }
+}
+
+fn get_op_register(context: &mut RegisterContext, reg: u8) -> &mut u64 {
     match reg {
-        0 => &mut context.Rax,
-        1 => &mut context.Rcx,
-        2 => &mut context.Rdx,
-        3 => &mut context.Rbx,
-        4 => &mut context.Rsp,
-        5 => &mut context.Rbp,
-        6 => &mut context.Rsi,
-        7 => &mut context.Rdi,
-        8 => &mut context.R8,
-        9 => &mut context.R9,
-        10 => &mut context.R10,
-        11 => &mut context.R11,
-        12 => &mut context.R12,
-        13 => &mut context.R13,
-        14 => &mut context.R14,
-        15 => &mut context.R15,
+        0 => &mut context.rax,
+        1 => &mut context.rcx,
+        2 => &mut context.rdx,
+        3 => &mut context.rbx,
+        4 => &mut context.rsp,
+        5 => &mut context.rbp,
+        6 => &mut context.rsi,
+        7 => &mut context.rdi,
+        8 => &mut context.r8,
+        9 => &mut context.r9,
+        10 => &mut context.r10,
+        11 => &mut context.r11,
+        12 => &mut context.r12,
+        13 => &mut context.r13,
+        14 => &mut context.r14,
+        15 => &mut context.r15,
         _ => panic!("Bad register given to get_op_register()")
     }
 }
 
-fn apply_unwind_ops(context: &CONTEXT, unwind_ops: &[UnwindCode], func_address: u64, memory_source: &dyn MemorySource) -> Result<Option<CONTEXT>, &'static str> {
-    let mut unwound_context = context.clone();
+fn apply_unwind_ops(context: &RegisterContext, unwind_ops: &[UnwindCode], func_address: u64, memory_source: &dyn MemorySource) -> Result<Option<RegisterContext>, &'static str> {
+    let mut unwound_context = *context;
     for unwind in unwind_ops.iter() {
-        let func_offset = unwound_context.Rip - func_address;
+        let func_offset = unwound_context.rip - func_address;
         if unwind.code_offset as u64 <= func_offset {
             match unwind.op {
                 UnwindOp::Alloc { size } => {
-                    unwound_context.Rsp += size as u64;
+                    unwound_context.rsp = unwound_context.rsp.wrapping_add(size as u64);
                 }
                 UnwindOp::PushNonVolatile { reg } => {
-                    let addr = unwound_context.Rsp;
+                    let addr = unwound_context.rsp;
                     let val = read_memory_data::<u64>(memory_source, addr)?;
                     *get_op_register(&mut unwound_context, reg) = val;
-                    unwound_context.Rsp += 8;
+                    unwound_context.rsp += 8;
                 }
                 UnwindOp::SaveNonVolatile { reg, offset } => {
-                    let addr = unwound_context.Rsp + offset as u64;
+                    let addr = unwound_context.rsp.wrapping_add(offset as u64);
                     let val = read_memory_data::<u64>(memory_source, addr)?;
                     *get_op_register(&mut unwound_context, reg) = val;
                 }
                 UnwindOp::SetFpreg { frame_register, frame_offset } => {
-                    unwound_context.Rsp = *get_op_register(&mut unwound_context, frame_register) - (frame_offset as u64);
+                    unwound_context.rsp = get_op_register(&mut unwound_context, frame_register).wrapping_sub(frame_offset as u64);
                 }
-                _ => panic!("NYI unwind op")
+                // We don't keep track of the xmm registers, so there's nothing to restore
+                UnwindOp::SaveXmm128 { .. } => {}
+                UnwindOp::PushMachFrame { .. } => return Err("UWOP_PUSH_MACHFRAME is not supported"),
             }
         }
     }
     Ok(Some(unwound_context))
 }
 
-pub fn unwind_context(process: &mut Process, context: CONTEXT, memory_source: &dyn MemorySource) -> Result<Option<CONTEXT>, &'static str> {
-    let module = process.get_containing_module_mut(context.Rip);
-    if let Some(module) = module {
-        let data_directory = module.get_data_directory(IMAGE_DIRECTORY_ENTRY_EXCEPTION);
-        if data_directory.VirtualAddress != 0 && data_directory.Size != 0 {
-            let count = data_directory.Size as usize / std::mem::size_of::<RUNTIME_FUNCTION>();
-            let table_address = module.address + data_directory.VirtualAddress as u64;
+// Reads the table of RUNTIME_FUNCTION entries from the exception directory, if the module has one
+fn read_function_table(module: &Module, memory_source: &dyn MemorySource) -> Result<Option<Vec<RUNTIME_FUNCTION>>, &'static str> {
+    let data_directory = module.get_data_directory(IMAGE_DIRECTORY_ENTRY_EXCEPTION);
+    if data_directory.VirtualAddress == 0 || data_directory.Size == 0 {
+        return Ok(None);
+    }
+    let count = data_directory.Size as usize / RUNTIME_FUNCTION::SIZE;
+    let table_address = module.address + data_directory.VirtualAddress as u64;
+
+    // Note: In a real debugger you might want to cache these.
+    Ok(Some(read_memory_full_array(memory_source, table_address, count)?))
+}
+
+// Prints the function table entry and unwind info for the function that contains the address, like .fnent in WinDbg
+pub fn display_function_entry(process: &mut Process, address: u64, memory_source: &dyn MemorySource) -> Result<(), &'static str> {
+    let module = process.get_containing_module_mut(address).ok_or("Address is not in a module")?;
+    let functions = read_function_table(module, memory_source)?.ok_or("Module does not have any unwind data")?;
+    let mut func = *find_runtime_function((address - module.address) as u32, &functions).ok_or("No function entry found, this may be a leaf function")?;
+
+    loop {
+        println!("Function entry for {}:", module.name);
+        println!("  BeginAddress      = {:#018x}", module.address + func.BeginAddress as u64);
+        println!("  EndAddress        = {:#018x}", module.address + func.EndAddress as u64);
+        println!("  UnwindInfoAddress = {:#018x}", module.address + func.UnwindInfo as u64);
+
+        let info_addr = module.address + func.UnwindInfo as u64;
+        let info = read_memory_data::<UNWIND_INFO>(memory_source, info_addr)?;
+        let (version, flags) = split_up!(info.version_flags => 3, 5);
+        let (frame_register, frame_offset) = split_up!(info.frame_register_offset => 4, 4);
+        let frame_offset = (frame_offset as u16) * 16;
+        println!("Unwind info:");
+        println!("  Version         = {}", version);
+        println!("  Flags           = {:#x}", flags);
+        println!("  SizeOfProlog    = {:#x}", info.size_of_prolog);
+        println!("  CountOfCodes    = {}", info.count_of_codes);
+        if frame_register != 0 {
+            println!("  FrameRegister   = {}", REGISTER_NAMES[frame_register as usize]);
+            println!("  FrameOffset     = {:#x}", frame_offset);
+        }
+
+        let codes = read_memory_full_array::<u16>(memory_source, info_addr + 4, info.count_of_codes as usize)?;
+        for unwind in get_unwind_ops(&codes, frame_register, frame_offset)? {
+            println!("  {:02x}: {}", unwind.code_offset, describe_unwind_op(&unwind.op));
+        }
 
-            // Note: In a real debugger you might want to cache these.
-            let functions: Vec<RUNTIME_FUNCTION> = read_memory_full_array(memory_source, table_address, count)?;
+        // The handler or chained entry comes after the codes, which are padded to an even number of slots
+        let trailer_addr = info_addr + 4 + ((info.count_of_codes as u64 + 1) & !1) * 2;
+        if flags & UNW_FLAG_CHAININFO == UNW_FLAG_CHAININFO {
+            func = read_memory_data::<RUNTIME_FUNCTION>(memory_source, trailer_addr)?;
+            println!("Chained to:");
+        } else {
+            if flags & (UNW_FLAG_EHANDLER | UNW_FLAG_UHANDLER) != 0 {
+                let handler = read_memory_data::<u32>(memory_source, trailer_addr)?;
+                println!("  Handler         = {:#018x}", module.address + handler as u64);
+            }
+            return Ok(());
+        }
+    }
+}
 
-            let rva = context.Rip - module.address;
+pub fn unwind_context(process: &mut Process, context: RegisterContext, memory_source: &dyn MemorySource) -> Result<Option<RegisterContext>, &'static str> {
+    let module = process.get_containing_module_mut(context.rip);
+    if let Some(module) = module {
+        if let Some(functions) = read_function_table(module, memory_source)? {
+            let rva = context.rip - module.address;
             let func = find_runtime_function(rva as u32, &functions);
 
             if let Some(func) = func {
@@ -259,11 +337,11 @@ pub fn unwind_context(process: &mut Process, context: CONTEXT, memory_source: &d
                 match apply_unwind_ops(&context, &unwind_ops, module.address + func.BeginAddress as u64, memory_source)? {
                     Some(ctx) => {
                         let mut ctx = ctx;
-                        ctx.Rip = read_memory_data::<u64>(memory_source, ctx.Rsp)?;
-                        ctx.Rsp += 8;
+                        ctx.rip = read_memory_data::<u64>(memory_source, ctx.rsp)?;
+                        ctx.rsp += 8;
 
                         // TODO: There are other conditions that should be checked
-                        if ctx.Rip == 0 {
+                        if ctx.rip == 0 {
                             return Ok(None);
                         }
                         return Ok(Some(ctx))
@@ -274,12 +352,75 @@ pub fn unwind_context(process: &mut Process, context: CONTEXT, memory_source: &d
             } else {
                 // Leaf function: the return address is simply at [RSP]
                 let mut ctx = context;
-                ctx.Rip = read_memory_data::<u64>(memory_source, ctx.Rsp)?;
-                ctx.Rsp += 8;
+                ctx.rip = read_memory_data::<u64>(memory_source, ctx.rsp)?;
+                ctx.rsp += 8;
                 return Ok(Some(ctx));
             }
         }
+
+        // ELF modules don't have a function table, but they usually have call frame information instead
+        if let Some(frame_info) = &module.frame_info {
+            return frame_info.unwind(&context, memory_source);
+        }
     }
     
     Ok(None)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_target::FakeMemory;
+
+    const FUNCTION: u64 = 0x1000;
+    const UNWIND_INFO_ADDRESS: u64 = 0x2000;
+    const STACK: u64 = 0x8000;
+
+    // The unwind info for this prolog, the way MSVC lays it out, with the codes in reverse order:
+    //   mov [rsp+8], rbx
+    //   push rdi
+    //   sub rsp, 30h
+    //   movaps [rsp+20h], xmm6
+    const UNWIND_INFO_DATA: [u8; 16] = [
+        0x01, 0x0f, 0x06, 0x00,
+        0x0f, 0x68, 0x02, 0x00, // UWOP_SAVE_XMM128 xmm6, 2 * 16
+        0x0a, 0x34, 0x08, 0x00, // UWOP_SAVE_NONVOL rbx, 8 * 8
+        0x0a, 0x52,             // UWOP_ALLOC_SMALL 5 * 8 + 8
+        0x06, 0x70,             // UWOP_PUSH_NONVOL rdi
+    ];
+
+    fn read_unwind_ops(memory: &FakeMemory) -> Vec<UnwindCode> {
+        let info = read_memory_data::<UNWIND_INFO>(memory, UNWIND_INFO_ADDRESS).unwrap();
+        let (frame_register, frame_offset) = split_up!(info.frame_register_offset => 4, 4);
+        let codes = read_memory_full_array::<u16>(memory, UNWIND_INFO_ADDRESS + 4, info.count_of_codes as usize).unwrap();
+        get_unwind_ops(&codes, frame_register, frame_offset as u16 * 16).unwrap()
+    }
+
+    #[test]
+    fn near_save_offsets_are_scaled() {
+        let mut memory = FakeMemory::default();
+        memory.add_region(UNWIND_INFO_ADDRESS, &UNWIND_INFO_DATA);
+        let ops: Vec<String> = read_unwind_ops(&memory).iter().map(|u| format!("{:02x}: {}", u.code_offset, describe_unwind_op(&u.op))).collect();
+        assert_eq!(ops, [
+            "0f: UWOP_SAVE_XMM128 reg: xmm6, offset: 0x20",
+            "0a: UWOP_SAVE_NONVOL reg: rbx, offset: 0x40",
+            "0a: UWOP_ALLOC size: 0x30",
+            "06: UWOP_PUSH_NONVOL reg: rdi",
+        ]);
+    }
+
+    #[test]
+    fn saved_registers_are_restored_from_scaled_offsets() {
+        let mut memory = FakeMemory::default();
+        memory.add_region(UNWIND_INFO_ADDRESS, &UNWIND_INFO_DATA);
+        // The pushed rdi is right above the allocation, and rbx went in the home space above the return address
+        memory.add_region(STACK + 0x30, &0x7777u64.to_le_bytes());
+        memory.add_region(STACK + 0x40, &0x3333u64.to_le_bytes());
+
+        let context = RegisterContext { rip: FUNCTION + 0x20, rsp: STACK, ..Default::default() };
+        let unwound = apply_unwind_ops(&context, &read_unwind_ops(&memory), FUNCTION, &memory).unwrap().unwrap();
+        assert_eq!(unwound.rbx, 0x3333);
+        assert_eq!(unwound.rdi, 0x7777);
+        // Pointing at the return address
+        assert_eq!(unwound.rsp, STACK + 0x38);
+    }
+}
@@ -0,0 +1,105 @@
+use crate::event::{DebugEvent, EventContext};
+use crate::memory::MemorySource;
+use crate::registers::RegisterContext;
+
+use windows_sys::Win32::System::Memory::MEM_COMMIT;
+
+const TRAP_FLAG: u32 = 1 << 8;
+
+// A range of the target's address space that may have something in it. Not every page has to be readable.
+pub struct MemoryRegion {
+    pub address: u64,
+    pub size: u64,
+}
+
+// Everything the target knows about a region of its address space. The state, protection and type use the same
+// values as VirtualQueryEx, and anything the target doesn't know is left as zero.
+#[derive(Clone)]
+pub struct MemoryRegionInfo {
+    pub address: u64,
+    pub size: u64,
+    // Regions that were allocated together, like the reserved and committed parts of a stack, have the same base
+    pub allocation_base: u64,
+    pub state: u32,
+    pub protect: u32,
+    pub region_type: u32,
+    // The file that is mapped into the region, or what Linux calls it if it's something special like [heap]
+    pub name: Option<String>,
+}
+
+impl MemoryRegionInfo {
+    // All that's known about memory that a target can only list is that it's there
+    pub fn committed(region: &MemoryRegion) -> MemoryRegionInfo {
+        MemoryRegionInfo { address: region.address, size: region.size, allocation_base: 0, state: MEM_COMMIT, protect: 0, region_type: 0, name: None }
+    }
+}
+
+// How the target should treat the event that it stopped for when it resumes. This only matters for exceptions, where
+// NotHandled gives the target a chance to handle the exception itself.
+#[derive(Clone, Copy)]
+pub enum ContinueStatus {
+    Handled,
+    NotHandled,
+}
+
+// Everything the debugger engine needs from something that it can debug. The live Win32 implementation is the main
+// one, but keeping the engine behind this trait means the command loop, evaluator, and unwinder don't need to know
+// where the events, registers, and memory are actually coming from.
+pub trait DebugTarget {
+    // Blocks until the next debug event arrives.
+    fn wait_for_event(&mut self) -> Result<(EventContext, DebugEvent), &'static str>;
+
+    fn memory_source(&self) -> &dyn MemorySource;
+
+    // Write as many bytes as possible, and return the number of bytes written.
+    fn write_memory(&mut self, address: u64, data: &[u8]) -> Result<usize, &'static str>;
+
+    // Targets that debug child processes report events from all of them. Memory is read from the process that
+    // reported the last event, unless the debugger switches to another one with this.
+    fn switch_process(&mut self, _process_id: u32) -> Result<(), &'static str> {
+        Err("The target only has one process")
+    }
+
+    fn get_thread_context(&self, thread_id: u32) -> Result<RegisterContext, &'static str>;
+
+    fn set_thread_context(&mut self, thread_id: u32, context: &RegisterContext) -> Result<(), &'static str>;
+
+    // Arrange for the thread to execute a single instruction the next time the target is continued. The default
+    // uses the trap flag, which works for anything that lets us modify EFLAGS.
+    fn step_thread(&mut self, thread_id: u32) -> Result<(), &'static str> {
+        let mut context = self.get_thread_context(thread_id)?;
+        context.eflags |= TRAP_FLAG;
+        self.set_thread_context(thread_id, &context)
+    }
+
+    // Let the target run again after the event described by event_context.
+    fn continue_event(&mut self, event_context: &EventContext, status: ContinueStatus) -> Result<(), &'static str>;
+
+    // Let the target run on its own after the event described by event_context, without a debugger attached. Any
+    // breakpoints should already have been removed.
+    fn detach(&mut self, _event_context: &EventContext, _status: ContinueStatus) -> Result<(), &'static str> {
+        Err("The target can't be detached from")
+    }
+
+    // Lists the committed parts of the address space, for things that need to look at all of the target's memory.
+    fn memory_regions(&self) -> Result<Vec<MemoryRegion>, &'static str> {
+        Err("The target can't list its memory regions")
+    }
+
+    // Describes the whole address space, including memory that isn't committed when the target knows about it. Targets
+    // that can only list their memory say that it's committed and leave everything else out.
+    fn memory_map(&self) -> Result<Vec<MemoryRegionInfo>, &'static str> {
+        Ok(self.memory_regions()?.iter().map(MemoryRegionInfo::committed).collect())
+    }
+
+    // The address of the thread's TEB, for targets that have them. This is how the stack of a thread is found.
+    fn thread_environment_block(&self, _thread_id: u32) -> Option<u64> {
+        None
+    }
+
+    // Targets that aren't running, like dump files, can't be resumed. They report the state of the process as a
+    // series of events that don't need to stop at the prompt.
+    fn is_live(&self) -> bool {
+        true
+    }
+}
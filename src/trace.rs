@@ -0,0 +1,474 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::rc::Rc;
+
+use crate::event::{DebugEvent, EventContext};
+use crate::memory::MemorySource;
+use crate::registers::RegisterContext;
+use crate::rsp::{decode_hex, encode_hex, parse_hex_u64};
+use crate::target::{ContinueStatus, DebugTarget, MemoryRegion};
+
+// A trace is a text file with one record per line. Every event is followed by the thread contexts, memory, and memory
+// regions that the debugger looked at while it was stopped for that event. Strings and data are hex encoded so that
+// the fields can be split on spaces, and bytes that couldn't be read are written as "??".
+const TRACE_HEADER: &str = "dbgrs-trace 1";
+
+enum TraceRecord {
+    Event(EventContext, DebugEvent),
+    // None means the context couldn't be read, like for a thread that has just exited
+    Context(u32, Option<Box<RegisterContext>>),
+    Live(bool),
+    Read(u64, Vec<Option<u8>>),
+    Regions(Vec<MemoryRegion>),
+}
+
+fn encode_string(text: &Option<String>) -> String {
+    match text {
+        Some(text) => encode_hex(text.as_bytes()),
+        None => "-".to_string(),
+    }
+}
+
+fn decode_string(text: &str) -> Result<Option<String>, &'static str> {
+    match text {
+        "-" => Ok(None),
+        _ => Ok(Some(String::from_utf8_lossy(&decode_hex(text.as_bytes())?).to_string())),
+    }
+}
+
+pub fn encode_event(event: &DebugEvent) -> String {
+    match event {
+        DebugEvent::Exception { first_chance, exception_code } => format!("exception {} {:x}", *first_chance as u8, *exception_code as u32),
+        DebugEvent::CreateProcess { exe_name, exe_base } => format!("create_process {} {:x}", encode_string(exe_name), exe_base),
+        DebugEvent::CreateThread { thread_id } => format!("create_thread {:x}", thread_id),
+        DebugEvent::ExitThread { thread_id } => format!("exit_thread {:x}", thread_id),
+        DebugEvent::LoadModule { module_name, module_base } => format!("load_module {} {:x}", encode_string(module_name), module_base),
+        DebugEvent::OutputDebugString(text) => format!("output {}", encode_hex(text.as_bytes())),
+        DebugEvent::ExitProcess => "exit_process".to_string(),
+        DebugEvent::Other(text) => format!("other {}", encode_hex(text.as_bytes())),
+    }
+}
+
+pub fn decode_event(fields: &[&str]) -> Result<DebugEvent, &'static str> {
+    let field = |index: usize| fields.get(index).copied().ok_or("Trace event is missing a field");
+    let number = |index: usize| field(index).and_then(|f| parse_hex_u64(f.as_bytes()));
+    Ok(match field(0)? {
+        "exception" => DebugEvent::Exception { first_chance: field(1)? == "1", exception_code: number(2)? as u32 as i32 },
+        "create_process" => DebugEvent::CreateProcess { exe_name: decode_string(field(1)?)?, exe_base: number(2)? },
+        "create_thread" => DebugEvent::CreateThread { thread_id: number(1)? as u32 },
+        "exit_thread" => DebugEvent::ExitThread { thread_id: number(1)? as u32 },
+        "load_module" => DebugEvent::LoadModule { module_name: decode_string(field(1)?)?, module_base: number(2)? },
+        "output" => DebugEvent::OutputDebugString(decode_string(field(1)?)?.unwrap_or_default()),
+        "exit_process" => DebugEvent::ExitProcess,
+        "other" => DebugEvent::Other(decode_string(field(1)?)?.unwrap_or_default()),
+        _ => return Err("Unknown event in trace"),
+    })
+}
+
+pub fn encode_context(context: &RegisterContext) -> String {
+    let values = [
+        context.rax, context.rbx, context.rcx, context.rdx, context.rsi, context.rdi, context.rbp, context.rsp,
+        context.r8, context.r9, context.r10, context.r11, context.r12, context.r13, context.r14, context.r15,
+        context.rip, context.eflags as u64,
+        context.cs as u64, context.ds as u64, context.es as u64, context.fs as u64, context.gs as u64, context.ss as u64,
+        context.dr0, context.dr1, context.dr2, context.dr3, context.dr6, context.dr7,
+    ];
+    let values: Vec<String> = values.iter().map(|v| format!("{:x}", v)).collect();
+    format!("{} {}", values.join(","), encode_hex(&context.fx_save))
+}
+
+pub fn decode_context(values: &str, fx_save: &str) -> Result<RegisterContext, &'static str> {
+    let values: Vec<u64> = values.split(',').map(|v| parse_hex_u64(v.as_bytes())).collect::<Result<_, _>>()?;
+    let [rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8, r9, r10, r11, r12, r13, r14, r15, rip, eflags, cs, ds, es, fs, gs, ss, dr0, dr1, dr2, dr3, dr6, dr7] = values[..] else {
+        return Err("Trace context has the wrong number of registers");
+    };
+    let mut context = RegisterContext {
+        rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8, r9, r10, r11, r12, r13, r14, r15, rip,
+        eflags: eflags as u32,
+        cs: cs as u16, ds: ds as u16, es: es as u16, fs: fs as u16, gs: gs as u16, ss: ss as u16,
+        dr0, dr1, dr2, dr3, dr6, dr7,
+        ..Default::default()
+    };
+    let fx_save = decode_hex(fx_save.as_bytes())?;
+    if fx_save.len() != context.fx_save.len() {
+        return Err("Trace context has the wrong amount of floating point state");
+    }
+    context.fx_save.copy_from_slice(&fx_save);
+    Ok(context)
+}
+
+pub fn encode_bytes(data: &[Option<u8>]) -> String {
+    data.iter().map(|b| b.map_or("??".to_string(), |b| format!("{:02x}", b))).collect()
+}
+
+// Traces of a whole address space get big, so this avoids allocating anything per byte
+pub fn decode_bytes(text: &str) -> Result<Vec<Option<u8>>, &'static str> {
+    let nibble = |c: u8| (c as char).to_digit(16).map(|n| n as u8).ok_or("Invalid hex data in trace");
+    text.as_bytes().chunks(2).map(|pair| match pair {
+        b"??" => Ok(None),
+        [high, low] => Ok(Some((nibble(*high)? << 4) | nibble(*low)?)),
+        _ => Err("Invalid hex data in trace"),
+    }).collect()
+}
+
+pub fn encode_regions(regions: &[MemoryRegion]) -> String {
+    let text: Vec<String> = regions.iter().map(|r| format!("{:x}:{:x}", r.address, r.size)).collect();
+    text.join(" ")
+}
+
+pub fn decode_regions(fields: &[&str]) -> Result<Vec<MemoryRegion>, &'static str> {
+    fields.iter().filter(|f| !f.is_empty()).map(|region| {
+        let (address, size) = region.split_once(':').ok_or("Invalid memory region")?;
+        Ok(MemoryRegion { address: parse_hex_u64(address.as_bytes())?, size: parse_hex_u64(size.as_bytes())? })
+    }).collect()
+}
+
+fn decode_record(line: &str) -> Result<TraceRecord, &'static str> {
+    let fields: Vec<&str> = line.split(' ').collect();
+    let field = |index: usize| fields.get(index).copied().ok_or("Trace record is missing a field");
+    let number = |index: usize| field(index).and_then(|f| parse_hex_u64(f.as_bytes()));
+    Ok(match field(0)? {
+        "event" => TraceRecord::Event(EventContext { process_id: number(1)? as u32, thread_id: number(2)? as u32 }, decode_event(&fields[3..])?),
+        "context" if field(2)? == "-" => TraceRecord::Context(number(1)? as u32, None),
+        "context" => TraceRecord::Context(number(1)? as u32, Some(Box::new(decode_context(field(2)?, field(3)?)?))),
+        "live" => TraceRecord::Live(field(1)? == "1"),
+        "read" => TraceRecord::Read(number(1)?, decode_bytes(field(2)?)?),
+        "regions" => TraceRecord::Regions(decode_regions(&fields[1..])?),
+        _ => return Err("Unknown record in trace"),
+    })
+}
+
+// Shared between the recording targets of a session, since attaching to another process starts a new target but the
+// trace carries on.
+#[derive(Clone)]
+pub struct TraceWriter {
+    file: Rc<RefCell<BufWriter<File>>>,
+}
+
+impl TraceWriter {
+    pub fn create(path: &str) -> Result<TraceWriter, &'static str> {
+        let file = File::create(path).map_err(|_| "Could not create trace file")?;
+        let writer = TraceWriter { file: Rc::new(RefCell::new(BufWriter::new(file))) };
+        writer.write_line(TRACE_HEADER);
+        Ok(writer)
+    }
+
+    fn write_line(&self, line: &str) {
+        // A trace that is missing a few records is still useful, so there's no point failing the command over it
+        let _ = writeln!(self.file.borrow_mut(), "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = self.file.borrow_mut().flush();
+    }
+}
+
+// Passes everything through to another target, and writes down everything it told us
+pub struct RecordingTarget {
+    target: Box<dyn DebugTarget>,
+    trace: TraceWriter,
+}
+
+impl RecordingTarget {
+    pub fn new(target: Box<dyn DebugTarget>, trace: TraceWriter) -> RecordingTarget {
+        trace.write_line(&format!("live {}", target.is_live() as u8));
+        RecordingTarget { target, trace }
+    }
+}
+
+impl Drop for RecordingTarget {
+    fn drop(&mut self) {
+        self.trace.flush();
+    }
+}
+
+impl MemorySource for RecordingTarget {
+    fn read_memory(&self, address: u64, len: usize) -> Result<Vec<Option<u8>>, &'static str> {
+        let data = self.target.memory_source().read_memory(address, len)?;
+        if !data.is_empty() {
+            self.trace.write_line(&format!("read {:x} {}", address, encode_bytes(&data)));
+        }
+        Ok(data)
+    }
+
+    fn read_raw_memory(&self, address: u64, len: usize) -> Vec<u8> {
+        let data = self.target.memory_source().read_raw_memory(address, len);
+        if !data.is_empty() {
+            let recorded: Vec<Option<u8>> = data.iter().map(|b| Some(*b)).collect();
+            self.trace.write_line(&format!("read {:x} {}", address, encode_bytes(&recorded)));
+        }
+        data
+    }
+}
+
+impl DebugTarget for RecordingTarget {
+    fn wait_for_event(&mut self) -> Result<(EventContext, DebugEvent), &'static str> {
+        // Everything up to here belongs to the last event, so make sure it's saved before we block
+        self.trace.flush();
+        let (ctx, event) = self.target.wait_for_event()?;
+        self.trace.write_line(&format!("event {:x} {:x} {}", ctx.process_id, ctx.thread_id, encode_event(&event)));
+        Ok((ctx, event))
+    }
+
+    fn memory_source(&self) -> &dyn MemorySource {
+        self
+    }
+
+    // Writes don't need to be recorded, since the replay will make the same changes when the same commands are run
+    fn write_memory(&mut self, address: u64, data: &[u8]) -> Result<usize, &'static str> {
+        self.target.write_memory(address, data)
+    }
+
+    fn switch_process(&mut self, process_id: u32) -> Result<(), &'static str> {
+        self.target.switch_process(process_id)
+    }
+
+    fn get_thread_context(&self, thread_id: u32) -> Result<RegisterContext, &'static str> {
+        let context = self.target.get_thread_context(thread_id);
+        match &context {
+            Ok(context) => self.trace.write_line(&format!("context {:x} {}", thread_id, encode_context(context))),
+            Err(_) => self.trace.write_line(&format!("context {:x} -", thread_id)),
+        }
+        context
+    }
+
+    fn set_thread_context(&mut self, thread_id: u32, context: &RegisterContext) -> Result<(), &'static str> {
+        self.target.set_thread_context(thread_id, context)
+    }
+
+    fn step_thread(&mut self, thread_id: u32) -> Result<(), &'static str> {
+        self.target.step_thread(thread_id)
+    }
+
+    fn continue_event(&mut self, event_context: &EventContext, status: ContinueStatus) -> Result<(), &'static str> {
+        self.target.continue_event(event_context, status)
+    }
+
+    fn detach(&mut self, event_context: &EventContext, status: ContinueStatus) -> Result<(), &'static str> {
+        self.target.detach(event_context, status)
+    }
+
+    fn memory_regions(&self) -> Result<Vec<MemoryRegion>, &'static str> {
+        let regions = self.target.memory_regions()?;
+        self.trace.write_line(format!("regions {}", encode_regions(&regions)).trim_end());
+        Ok(regions)
+    }
+
+    fn is_live(&self) -> bool {
+        self.target.is_live()
+    }
+}
+
+const PAGE_SIZE: u64 = 0x1000;
+
+// The memory that the trace has seen so far. Later reads replace earlier ones, so each stop sees the memory as it was
+// at that point in the recording.
+struct ReplayMemorySource {
+    pages: HashMap<u64, Box<[Option<u8>; PAGE_SIZE as usize]>>,
+}
+
+impl ReplayMemorySource {
+    fn set(&mut self, address: u64, data: &[Option<u8>]) {
+        let mut done = 0;
+        while done < data.len() {
+            let cur_address = address + done as u64;
+            let page_offset = (cur_address % PAGE_SIZE) as usize;
+            let chunk_len = std::cmp::min(data.len() - done, PAGE_SIZE as usize - page_offset);
+            let page = self.pages.entry(cur_address / PAGE_SIZE).or_insert_with(|| Box::new([None; PAGE_SIZE as usize]));
+            page[page_offset..page_offset + chunk_len].copy_from_slice(&data[done..done + chunk_len]);
+            done += chunk_len;
+        }
+    }
+}
+
+impl MemorySource for ReplayMemorySource {
+    fn read_memory(&self, address: u64, len: usize) -> Result<Vec<Option<u8>>, &'static str> {
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            // Copy a page at a time, since big reads are common when writing dumps
+            let cur_address = address + data.len() as u64;
+            let page_offset = (cur_address % PAGE_SIZE) as usize;
+            let chunk_len = std::cmp::min(len - data.len(), PAGE_SIZE as usize - page_offset);
+            match self.pages.get(&(cur_address / PAGE_SIZE)) {
+                Some(page) => data.extend_from_slice(&page[page_offset..page_offset + chunk_len]),
+                None => data.extend(std::iter::repeat_n(None, chunk_len)),
+            }
+        }
+        Ok(data)
+    }
+
+    fn read_raw_memory(&self, address: u64, len: usize) -> Vec<u8> {
+        match self.read_memory(address, len) {
+            Ok(data) => data.into_iter().map_while(|b| b).collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+// Plays back a recorded trace. The debugger can do anything it did during the recording, and gets the same answers.
+pub struct ReplayTarget {
+    records: VecDeque<TraceRecord>,
+    memory: ReplayMemorySource,
+    contexts: HashMap<u32, RegisterContext>,
+    regions: Option<Vec<MemoryRegion>>,
+    live: bool,
+}
+
+impl ReplayTarget {
+    pub fn open(path: &str) -> Result<ReplayTarget, &'static str> {
+        let file = File::open(path).map_err(|_| "Could not open trace file")?;
+        let mut lines = BufReader::new(file).lines();
+        if lines.next().and_then(|l| l.ok()).as_deref() != Some(TRACE_HEADER) {
+            return Err("Not a trace file");
+        }
+        let records = lines
+            .map(|line| line.map_err(|_| "Could not read trace file").and_then(|line| decode_record(&line)))
+            .collect::<Result<_, _>>()?;
+        Ok(ReplayTarget { records, memory: ReplayMemorySource { pages: HashMap::new() }, contexts: HashMap::new(), regions: None, live: true })
+    }
+
+    fn apply_record(&mut self, record: TraceRecord) {
+        match record {
+            TraceRecord::Event(..) => {}
+            TraceRecord::Context(thread_id, Some(context)) => {
+                self.contexts.insert(thread_id, *context);
+            }
+            TraceRecord::Context(thread_id, None) => {
+                self.contexts.remove(&thread_id);
+            }
+            TraceRecord::Live(live) => self.live = live,
+            TraceRecord::Read(address, data) => self.memory.set(address, &data),
+            TraceRecord::Regions(regions) => self.regions = Some(regions),
+        }
+    }
+}
+
+impl DebugTarget for ReplayTarget {
+    fn wait_for_event(&mut self) -> Result<(EventContext, DebugEvent), &'static str> {
+        let event = loop {
+            match self.records.pop_front() {
+                Some(TraceRecord::Event(ctx, event)) => break (ctx, event),
+                Some(record) => self.apply_record(record),
+                None => return Err("End of trace"),
+            }
+        };
+
+        // Everything the debugger looked at during this stop is available right away. Anything it read after changing
+        // memory or registers itself shows those changes, which the replay will make on its own, so the first record
+        // of anything is the one that shows what the target was like when it stopped.
+        let mut stop_records = Vec::new();
+        while self.records.front().is_some_and(|r| !matches!(r, TraceRecord::Event(..))) {
+            stop_records.push(self.records.pop_front().unwrap());
+        }
+        for record in stop_records.into_iter().rev() {
+            self.apply_record(record);
+        }
+        Ok(event)
+    }
+
+    fn memory_source(&self) -> &dyn MemorySource {
+        &self.memory
+    }
+
+    fn write_memory(&mut self, address: u64, data: &[u8]) -> Result<usize, &'static str> {
+        self.apply_record(TraceRecord::Read(address, data.iter().map(|b| Some(*b)).collect()));
+        Ok(data.len())
+    }
+
+    fn get_thread_context(&self, thread_id: u32) -> Result<RegisterContext, &'static str> {
+        self.contexts.get(&thread_id).copied().ok_or("Thread context is not in the trace")
+    }
+
+    fn set_thread_context(&mut self, thread_id: u32, context: &RegisterContext) -> Result<(), &'static str> {
+        self.contexts.insert(thread_id, *context);
+        Ok(())
+    }
+
+    fn step_thread(&mut self, _thread_id: u32) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    fn continue_event(&mut self, _event_context: &EventContext, _status: ContinueStatus) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    fn detach(&mut self, _event_context: &EventContext, _status: ContinueStatus) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    fn memory_regions(&self) -> Result<Vec<MemoryRegion>, &'static str> {
+        let regions = self.regions.as_ref().ok_or("Memory regions are not in the trace")?;
+        Ok(regions.iter().map(|r| MemoryRegion { address: r.address, size: r.size }).collect())
+    }
+
+    // A replay of a dump file shouldn't be any more runnable than the dump was
+    fn is_live(&self) -> bool {
+        self.live
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_target::{temp_file_path, FakeTarget};
+
+    const ENTRY: u64 = 0x1000;
+    const DATA: u64 = 0x2000;
+
+    // What the debugger saw at one stop: the event, where the thread was, the data, and the memory regions
+    type Stop = (String, u64, String, String);
+
+    // Single steps a few times and then runs to the end, writing to the data at each stop so that the next one sees
+    // something different
+    fn drive(target: &mut dyn DebugTarget) -> Vec<Stop> {
+        let mut stops = Vec::new();
+        loop {
+            let (ctx, event) = target.wait_for_event().unwrap();
+            if matches!(event, DebugEvent::ExitProcess) {
+                stops.push((encode_event(&event), 0, String::new(), String::new()));
+                return stops;
+            }
+            let rip = target.get_thread_context(ctx.thread_id).unwrap().rip;
+            let data = encode_bytes(&target.memory_source().read_memory(DATA, 8).unwrap());
+            let regions = encode_regions(&target.memory_regions().unwrap());
+            stops.push((encode_event(&event), rip, data, regions));
+
+            target.write_memory(DATA, &[stops.len() as u8]).unwrap();
+            if (2..5).contains(&stops.len()) {
+                target.step_thread(ctx.thread_id).unwrap();
+            }
+            target.continue_event(&ctx, ContinueStatus::Handled).unwrap();
+        }
+    }
+
+    #[test]
+    fn replay_matches_recording() {
+        let path = temp_file_path("replay.trace");
+        let mut fake = FakeTarget::new(ENTRY, ENTRY + 0x10);
+        fake.add_memory(ENTRY, &[0x90; 0x10]);
+        fake.add_memory(DATA, &[0xaa, 0xbb, 0xcc, 0xdd]);
+        let mut recording = RecordingTarget::new(Box::new(fake), TraceWriter::create(&path).unwrap());
+        let recorded = drive(&mut recording);
+        drop(recording);
+
+        let mut replay = ReplayTarget::open(&path).unwrap();
+        let replayed = drive(&mut replay);
+        std::fs::remove_file(&path).unwrap();
+
+        let regions = "1000:10 2000:4";
+        let expected: Vec<Stop> = vec![
+            ("create_process 66616b65 1000".to_string(), ENTRY, "aabbccdd????????".to_string(), regions.to_string()),
+            ("exception 1 80000003".to_string(), ENTRY, "01bbccdd????????".to_string(), regions.to_string()),
+            ("exception 1 80000004".to_string(), ENTRY + 1, "02bbccdd????????".to_string(), regions.to_string()),
+            ("exception 1 80000004".to_string(), ENTRY + 2, "03bbccdd????????".to_string(), regions.to_string()),
+            ("exception 1 80000004".to_string(), ENTRY + 3, "04bbccdd????????".to_string(), regions.to_string()),
+            ("exit_process".to_string(), 0, String::new(), String::new()),
+        ];
+        assert_eq!(recorded, expected);
+        assert_eq!(replayed, expected);
+        // The process exiting is the last thing in the trace
+        assert!(replay.wait_for_event().is_err());
+    }
+}
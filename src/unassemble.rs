@@ -1,17 +1,22 @@
-use iced_x86::{Decoder, DecoderOptions, Formatter, Instruction, MasmFormatter};
+use iced_x86::{Decoder, DecoderOptions, FlowControl, Formatter, Instruction, MasmFormatter};
 
 use crate::memory::MemorySource;
 
-pub fn unassemble(memory_source: &dyn MemorySource, va: u64, lines: usize) -> u64 {
+pub struct DisassembledInstruction {
+    pub address: u64,
+    pub bytes: Vec<u8>,
+    pub text: String,
+    // Calls return to the next instruction, which matters for stepping over them
+    pub is_call: bool,
+}
 
-    // We'll never need more than lines * 15
-    let bytes = memory_source.read_raw_memory(va, lines * 15);
-    if bytes.len() == 0 {
-        println!("Failed to read memory at {:X}", va);
-    }
+// Decodes up to "count" instructions starting at va, stopping early if the memory runs out
+pub fn disassemble(memory_source: &dyn MemorySource, va: u64, count: usize) -> Vec<DisassembledInstruction> {
+
+    // We'll never need more than count * 15
+    let bytes = memory_source.read_raw_memory(va, count * 15);
 
     let code_bitness = 64;
-    let hexbytes_column_byte_length = 10;
     let mut decoder = Decoder::with_ip(
         code_bitness,
         bytes.as_slice(),
@@ -39,9 +44,8 @@ pub fn unassemble(memory_source: &dyn MemorySource, va: u64, lines: usize) -> u6
     // or collect():
     //      let instructions: Vec<_> = decoder.into_iter().collect();
     // but can_decode()/decode_out() is a little faster:
-    let mut instruction_count = 0;
-    let mut last_rip = 0;
-    while decoder.can_decode() && instruction_count < lines {
+    let mut instructions = Vec::new();
+    while decoder.can_decode() && instructions.len() < count {
         // There's also a decode() method that returns an instruction but that also
         // means it copies an instruction (40 bytes):
         //     instruction = decoder.decode();
@@ -51,21 +55,38 @@ pub fn unassemble(memory_source: &dyn MemorySource, va: u64, lines: usize) -> u6
         output.clear();
         formatter.format(&instruction, &mut output);
 
-        // Eg. "00007FFAC46ACDB2 488DAC2400FFFFFF     lea       rbp,[rsp-100h]"
-        print!("{:016X} ", instruction.ip());
         let start_index = (instruction.ip() - va) as usize;
-        let instr_bytes = &bytes[start_index..start_index + instruction.len()];
-        for b in instr_bytes.iter() {
+        instructions.push(DisassembledInstruction {
+            address: instruction.ip(),
+            bytes: bytes[start_index..start_index + instruction.len()].to_vec(),
+            text: output.clone(),
+            is_call: matches!(instruction.flow_control(), FlowControl::Call | FlowControl::IndirectCall),
+        });
+    }
+    instructions
+}
+
+pub fn unassemble(memory_source: &dyn MemorySource, va: u64, lines: usize) -> u64 {
+    let instructions = disassemble(memory_source, va, lines);
+    if instructions.is_empty() {
+        println!("Failed to read memory at {:X}", va);
+    }
+
+    let hexbytes_column_byte_length = 10;
+    let mut last_rip = 0;
+    for instruction in instructions.iter() {
+        // Eg. "00007FFAC46ACDB2 488DAC2400FFFFFF     lea       rbp,[rsp-100h]"
+        print!("{:016X} ", instruction.address);
+        for b in instruction.bytes.iter() {
             print!("{:02X}", b);
         }
-        if instr_bytes.len() < hexbytes_column_byte_length {
-            for _ in 0..hexbytes_column_byte_length - instr_bytes.len() {
+        if instruction.bytes.len() < hexbytes_column_byte_length {
+            for _ in 0..hexbytes_column_byte_length - instruction.bytes.len() {
                 print!("  ");
             }
         }
-        println!(" {}", output);
-        instruction_count += 1;
-        last_rip = instruction.ip() + instr_bytes.len() as u64;
+        println!(" {}", instruction.text);
+        last_rip = instruction.address + instruction.bytes.len() as u64;
     }
     last_rip
 }
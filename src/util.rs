@@ -1,21 +1,6 @@
 use windows_sys::Win32::System::Diagnostics::Debug::CONTEXT;
 use windows_sys::Win32::Foundation::*;
 
-// Not sure why these are missing from windows_sys, but the definitions are in winnt.h
-pub const CONTEXT_AMD64: u32 = 0x00100000;
-pub const CONTEXT_CONTROL: u32 = CONTEXT_AMD64 | 0x00000001;
-pub const CONTEXT_INTEGER: u32 = CONTEXT_AMD64 | 0x00000002;
-pub const CONTEXT_SEGMENTS: u32 = CONTEXT_AMD64 | 0x00000004;
-pub const CONTEXT_FLOATING_POINT: u32 = CONTEXT_AMD64 | 0x00000008;
-pub const CONTEXT_DEBUG_REGISTERS: u32 = CONTEXT_AMD64 | 0x00000010;
-#[allow(dead_code)]
-pub const CONTEXT_FULL: u32 = CONTEXT_CONTROL | CONTEXT_INTEGER | CONTEXT_FLOATING_POINT;
-pub const CONTEXT_ALL: u32 = CONTEXT_CONTROL
-        | CONTEXT_INTEGER
-        | CONTEXT_SEGMENTS
-        | CONTEXT_FLOATING_POINT
-        | CONTEXT_DEBUG_REGISTERS;
-
 #[repr(align(16))]
 pub struct AlignedContext {
     pub context: CONTEXT,
@@ -36,4 +21,4 @@ impl AutoClosedHandle {
     pub fn handle(&self) -> HANDLE {
         self.0
     }
-}
\ No newline at end of file
+}
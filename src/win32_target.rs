@@ -0,0 +1,427 @@
+use core::ffi::c_void;
+use std::collections::HashMap;
+use std::os::windows::prelude::OsStringExt;
+use std::{mem::MaybeUninit, ptr::null};
+
+use windows_sys::Win32::{
+    Foundation::*,
+    Storage::FileSystem::GetFinalPathNameByHandleW,
+    System::{Diagnostics::Debug::*, Memory::*, Threading::*},
+};
+
+use crate::event::{DebugEvent, EventContext};
+use crate::memory::{self, MemorySource};
+use crate::memory_cache::CachedMemorySource;
+use crate::registers::{RegisterContext, CONTEXT_ALL};
+use crate::target::{ContinueStatus, DebugTarget, MemoryRegion, MemoryRegionInfo};
+use crate::util::*;
+
+struct LiveMemorySource {
+    hprocess: HANDLE,
+}
+
+impl MemorySource for LiveMemorySource {
+    fn read_memory(&self, address: u64, len: usize) -> Result<Vec<Option<u8>>, &'static str> {
+        let mut buffer: Vec<u8> = vec![0; len];
+        let mut data: Vec<Option<u8>> = vec![None; len];
+        let mut offset: usize = 0;
+
+        while offset < len {
+            let mut bytes_read: usize = 0;
+            let len_left = len - offset;
+            let cur_address = address + (offset as u64);
+
+            let result = unsafe {
+                ReadProcessMemory(
+                    self.hprocess,
+                    cur_address as *const c_void,
+                    buffer.as_mut_ptr() as *mut c_void,
+                    len_left,
+                    &mut bytes_read as *mut usize,
+                )
+            };
+
+            if result == 0 {
+                return Err("ReadProcessMemory failed");
+            };
+
+            for (index, byte) in buffer.iter().take(bytes_read).enumerate() {
+                data[offset + index] = Some(*byte);
+            }
+
+            if bytes_read > 0 {
+                offset += bytes_read;
+            } else {
+                offset += 1;
+            }
+        }
+
+        Ok(data)
+    }
+
+    fn read_raw_memory(&self, address: u64, len: usize) -> Vec<u8> {
+        let mut buffer: Vec<u8> = vec![0; len];
+        let mut bytes_read: usize = 0;
+
+        let result = unsafe {
+            ReadProcessMemory(
+                self.hprocess,
+                address as *const c_void,
+                buffer.as_mut_ptr() as *mut c_void,
+                len,
+                &mut bytes_read as *mut usize,
+            )
+        };
+
+        if result == 0 {
+            bytes_read = 0;
+        }
+
+        buffer.truncate(bytes_read);
+
+        buffer
+    }
+}
+
+pub struct Win32Target {
+    process_id: u32,
+    // Keeps the process handle open for as long as the memory source is using it
+    _process: AutoClosedHandle,
+    memory_source: CachedMemorySource<LiveMemorySource>,
+    // Processes started by the target, when it was launched with DEBUG_PROCESS. Their handles come from the create
+    // process events, and belong to the system.
+    child_processes: HashMap<u32, CachedMemorySource<LiveMemorySource>>,
+    // Memory is read from this process, which is the one that reported the last event unless the debugger switched
+    current_process_id: u32,
+    // The system closes the handle of a process that has exited once the exit event is continued
+    exited_child: Option<u32>,
+    // The TEB of every thread, from the events that created them. Thread IDs are unique across processes.
+    thread_tebs: HashMap<u32, u64>,
+}
+
+impl Win32Target {
+    pub fn launch(command_line: &mut [u16], debug_children: bool) -> Result<Win32Target, &'static str> {
+        let mut si: STARTUPINFOEXW = unsafe { std::mem::zeroed() };
+        si.StartupInfo.cb = std::mem::size_of::<STARTUPINFOEXW>() as u32;
+        let mut pi: MaybeUninit<PROCESS_INFORMATION> = MaybeUninit::uninit();
+        let debug_flags = if debug_children { DEBUG_PROCESS } else { DEBUG_ONLY_THIS_PROCESS };
+        let ret = unsafe {
+            CreateProcessW(
+                null(),
+                command_line.as_mut_ptr(),
+                null(),
+                null(),
+                FALSE,
+                debug_flags | CREATE_NEW_CONSOLE,
+                null(),
+                null(),
+                &si.StartupInfo,
+                pi.as_mut_ptr(),
+            )
+        };
+
+        if ret == 0 {
+            return Err("CreateProcessW failed");
+        }
+
+        let pi = unsafe { pi.assume_init() };
+
+        unsafe { CloseHandle(pi.hThread) };
+
+        Ok(Win32Target {
+            process_id: pi.dwProcessId,
+            _process: AutoClosedHandle(pi.hProcess),
+            memory_source: CachedMemorySource::new(LiveMemorySource { hprocess: pi.hProcess }),
+            child_processes: HashMap::new(),
+            current_process_id: pi.dwProcessId,
+            exited_child: None,
+            thread_tebs: HashMap::new(),
+        })
+    }
+
+    // Windows sends the same create process, create thread, and load DLL events for a process we attach to as it
+    // would for one we launched, followed by a breakpoint from a thread that it injects to break in.
+    pub fn attach(process_id: u32) -> Result<Win32Target, &'static str> {
+        let hprocess = unsafe {
+            OpenProcess(
+                PROCESS_VM_READ | PROCESS_VM_WRITE | PROCESS_VM_OPERATION | PROCESS_QUERY_INFORMATION,
+                FALSE,
+                process_id,
+            )
+        };
+        if hprocess == 0 {
+            return Err("OpenProcess failed");
+        }
+        let process = AutoClosedHandle(hprocess);
+
+        if unsafe { DebugActiveProcess(process_id) } == 0 {
+            return Err("DebugActiveProcess failed");
+        }
+
+        Ok(Win32Target {
+            process_id,
+            _process: process,
+            memory_source: CachedMemorySource::new(LiveMemorySource { hprocess }),
+            child_processes: HashMap::new(),
+            current_process_id: process_id,
+            exited_child: None,
+            thread_tebs: HashMap::new(),
+        })
+    }
+
+    fn invalidate_caches(&self) {
+        self.memory_source.invalidate();
+        for process in self.child_processes.values() {
+            process.invalidate();
+        }
+    }
+
+    fn current_process(&self) -> &CachedMemorySource<LiveMemorySource> {
+        self.child_processes.get(&self.current_process_id).unwrap_or(&self.memory_source)
+    }
+
+    // Walks the whole address space of the current process, one region at a time
+    fn query_memory(&self) -> Vec<MEMORY_BASIC_INFORMATION> {
+        let mut regions = Vec::new();
+        let mut address: u64 = 0;
+        loop {
+            let mut info: MEMORY_BASIC_INFORMATION = unsafe { std::mem::zeroed() };
+            let ret = unsafe {
+                VirtualQueryEx(
+                    self.current_process().source().hprocess,
+                    address as *const c_void,
+                    &mut info,
+                    std::mem::size_of::<MEMORY_BASIC_INFORMATION>(),
+                )
+            };
+            if ret == 0 {
+                break;
+            }
+            address = info.BaseAddress as u64 + info.RegionSize as u64;
+            regions.push(info);
+        }
+        regions
+    }
+}
+
+fn open_thread(thread_id: u32) -> Result<AutoClosedHandle, &'static str> {
+    let thread = unsafe {
+        OpenThread(
+            THREAD_GET_CONTEXT | THREAD_SET_CONTEXT,
+            FALSE,
+            thread_id,
+        )
+    };
+    if thread == 0 {
+        return Err("OpenThread failed");
+    }
+    Ok(AutoClosedHandle(thread))
+}
+
+impl DebugTarget for Win32Target {
+    fn wait_for_event(&mut self) -> Result<(EventContext, DebugEvent), &'static str> {
+        let mut debug_event: DEBUG_EVENT = unsafe { std::mem::zeroed() };
+        let ret = unsafe { WaitForDebugEventEx(&mut debug_event, INFINITE) };
+        if ret == 0 {
+            return Err("WaitForDebugEventEx failed");
+        }
+
+        let ctx = EventContext{ process_id: debug_event.dwProcessId, thread_id: debug_event.dwThreadId };
+        if debug_event.dwDebugEventCode == CREATE_PROCESS_DEBUG_EVENT && ctx.process_id != self.process_id {
+            let hprocess = unsafe { debug_event.u.CreateProcessInfo.hProcess };
+            self.child_processes.insert(ctx.process_id, CachedMemorySource::new(LiveMemorySource { hprocess }));
+        }
+        if debug_event.dwDebugEventCode == EXIT_PROCESS_DEBUG_EVENT && ctx.process_id != self.process_id {
+            self.exited_child = Some(ctx.process_id);
+        }
+        match debug_event.dwDebugEventCode {
+            CREATE_PROCESS_DEBUG_EVENT => {
+                self.thread_tebs.insert(ctx.thread_id, unsafe { debug_event.u.CreateProcessInfo.lpThreadLocalBase } as u64);
+            }
+            CREATE_THREAD_DEBUG_EVENT => {
+                self.thread_tebs.insert(ctx.thread_id, unsafe { debug_event.u.CreateThread.lpThreadLocalBase } as u64);
+            }
+            EXIT_THREAD_DEBUG_EVENT => {
+                self.thread_tebs.remove(&ctx.thread_id);
+            }
+            _ => {}
+        }
+        self.current_process_id = ctx.process_id;
+        let mem_source = self.current_process();
+
+        let event = match debug_event.dwDebugEventCode {
+            EXCEPTION_DEBUG_EVENT => {
+                let code = unsafe { debug_event.u.Exception.ExceptionRecord.ExceptionCode };
+                let first_chance = unsafe { debug_event.u.Exception.dwFirstChance };
+                DebugEvent::Exception { first_chance: first_chance != 0, exception_code: code }
+            },
+            CREATE_THREAD_DEBUG_EVENT => {
+                let create_thread = unsafe { debug_event.u.CreateThread };
+                let thread_id = unsafe { GetThreadId(create_thread.hThread) };
+                unsafe { CloseHandle(create_thread.hThread) };
+                DebugEvent::CreateThread { thread_id }
+            },
+            EXIT_THREAD_DEBUG_EVENT => {
+                DebugEvent::ExitThread { thread_id: debug_event.dwThreadId}
+            },
+            CREATE_PROCESS_DEBUG_EVENT => {
+                let create_process = unsafe { debug_event.u.CreateProcessInfo };
+                let exe_base = create_process.lpBaseOfImage as u64;
+                let mut exe_name = vec![0u16; 260];
+                let exe_name_len = unsafe { GetFinalPathNameByHandleW(create_process.hFile, exe_name.as_mut_ptr(), 260, 0) } as usize;
+                let exe_name = if exe_name_len != 0 {
+                    // This will be the full name, e.g. \\?\C:\git\HelloWorld\hello.exe
+                    // It might be useful to have the full name, but it's not available for all
+                    // modules in all cases.
+                    let full_path = std::ffi::OsString::from_wide(&exe_name[0..exe_name_len]);
+                    let file_name = std::path::Path::new(&full_path).file_name();
+
+                    file_name.map(|s| s.to_string_lossy().to_string())
+                } else {
+                    None
+                };
+
+                DebugEvent::CreateProcess { exe_name, exe_base }
+            },
+            EXIT_PROCESS_DEBUG_EVENT => DebugEvent::ExitProcess,
+            LOAD_DLL_DEBUG_EVENT => {
+                let load_dll = unsafe { debug_event.u.LoadDll };
+                let module_base: u64 = load_dll.lpBaseOfDll as u64;
+                let module_name = if load_dll.lpImageName.is_null() {
+                    None
+                } else {
+                    let is_wide = load_dll.fUnicode != 0;
+                    memory::read_memory_string_indirect(mem_source, load_dll.lpImageName as u64, 260, is_wide).ok()
+                };
+
+                DebugEvent::LoadModule { module_name, module_base }
+            }
+            UNLOAD_DLL_DEBUG_EVENT => DebugEvent::Other("UnloadDll".to_string()),
+            OUTPUT_DEBUG_STRING_EVENT => {
+                let debug_string_info = unsafe { debug_event.u.DebugString };
+                let is_wide = debug_string_info.fUnicode != 0;
+                let address = debug_string_info.lpDebugStringData as u64;
+                let len = debug_string_info.nDebugStringLength as usize;
+                let debug_string =
+                    memory::read_memory_string(mem_source, address, len, is_wide)?;
+                DebugEvent::OutputDebugString(debug_string)
+            }
+            RIP_EVENT => DebugEvent::Other("RipEvent".to_string()),
+            _ => return Err("Unexpected debug event"),
+        };
+
+        Ok((ctx, event))
+    }
+
+    fn memory_source(&self) -> &dyn MemorySource {
+        self.current_process()
+    }
+
+    fn write_memory(&mut self, address: u64, data: &[u8]) -> Result<usize, &'static str> {
+        let mut bytes_written: usize = 0;
+        let result = unsafe {
+            WriteProcessMemory(
+                self.current_process().source().hprocess,
+                address as *const c_void,
+                data.as_ptr() as *const c_void,
+                data.len(),
+                &mut bytes_written as *mut usize,
+            )
+        };
+
+        // Even a failed write might have changed some of the memory
+        self.current_process().invalidate_range(address, data.len());
+        if result == 0 && bytes_written == 0 {
+            return Err("WriteProcessMemory failed");
+        }
+
+        Ok(bytes_written)
+    }
+
+    fn memory_regions(&self) -> Result<Vec<MemoryRegion>, &'static str> {
+        // Reserved memory and guard pages can't be read, so there's no point including them
+        Ok(self.query_memory().iter()
+            .filter(|info| info.State == MEM_COMMIT && info.Protect & (PAGE_NOACCESS | PAGE_GUARD) == 0)
+            .map(|info| MemoryRegion { address: info.BaseAddress as u64, size: info.RegionSize as u64 })
+            .collect())
+    }
+
+    fn memory_map(&self) -> Result<Vec<MemoryRegionInfo>, &'static str> {
+        Ok(self.query_memory().iter().map(|info| MemoryRegionInfo {
+            address: info.BaseAddress as u64,
+            size: info.RegionSize as u64,
+            allocation_base: info.AllocationBase as u64,
+            state: info.State,
+            protect: info.Protect,
+            region_type: info.Type,
+            name: None,
+        }).collect())
+    }
+
+    fn thread_environment_block(&self, thread_id: u32) -> Option<u64> {
+        self.thread_tebs.get(&thread_id).copied()
+    }
+
+    fn switch_process(&mut self, process_id: u32) -> Result<(), &'static str> {
+        if process_id != self.process_id && !self.child_processes.contains_key(&process_id) {
+            return Err("Not debugging that process");
+        }
+        self.current_process_id = process_id;
+        Ok(())
+    }
+
+    fn get_thread_context(&self, thread_id: u32) -> Result<RegisterContext, &'static str> {
+        let thread = open_thread(thread_id)?;
+        let mut ctx: AlignedContext = unsafe { std::mem::zeroed() };
+        ctx.context.ContextFlags = CONTEXT_ALL;
+        let ret = unsafe { GetThreadContext(thread.handle(), &mut ctx.context) };
+        if ret == 0 {
+            return Err("GetThreadContext failed");
+        }
+        Ok(RegisterContext::from(&ctx.context))
+    }
+
+    fn set_thread_context(&mut self, thread_id: u32, context: &RegisterContext) -> Result<(), &'static str> {
+        let thread = open_thread(thread_id)?;
+        let ctx = AlignedContext { context: context.into() };
+        let ret = unsafe { SetThreadContext(thread.handle(), &ctx.context) };
+        if ret == 0 {
+            return Err("SetThreadContext failed");
+        }
+        Ok(())
+    }
+
+    fn continue_event(&mut self, event_context: &EventContext, status: ContinueStatus) -> Result<(), &'static str> {
+        let continue_status = match status {
+            ContinueStatus::Handled => DBG_CONTINUE,
+            ContinueStatus::NotHandled => DBG_EXCEPTION_NOT_HANDLED,
+        };
+        let ret = unsafe {
+            ContinueDebugEvent(
+                event_context.process_id,
+                event_context.thread_id,
+                continue_status,
+            )
+        };
+        if ret == 0 {
+            return Err("ContinueDebugEvent failed");
+        }
+        // Once the target runs, anything we've read from it could be out of date
+        self.invalidate_caches();
+        if let Some(process_id) = self.exited_child.take() {
+            self.child_processes.remove(&process_id);
+        }
+        Ok(())
+    }
+
+    fn detach(&mut self, event_context: &EventContext, status: ContinueStatus) -> Result<(), &'static str> {
+        // The event has to be continued first, otherwise the thread that reported it stays suspended
+        self.continue_event(event_context, status)?;
+        for process_id in std::iter::once(self.process_id).chain(self.child_processes.keys().copied()) {
+            if unsafe { DebugActiveProcessStop(process_id) } == 0 {
+                return Err("DebugActiveProcessStop failed");
+            }
+        }
+        Ok(())
+    }
+}